@@ -1,22 +1,53 @@
 use std::io::BufRead;
+use std::io::Write;
 use std::str::FromStr;
 
 use clap::Clap;
 use image::GenericImageView;
 
 
+/// Logs message about given path at given level.  Unlike the `log` macros,
+/// the path is printed as is even if it isn’t valid UTF-8.
 #[macro_export]
-macro_rules! perr {
-    ($path:expr, $fmt:literal, $($arg:tt)*) => {{
+macro_rules! plog {
+    ($level:ident, $path:expr, $fmt:literal, $($arg:tt)*) => {{
         let path: &::std::ffi::OsStr = $path.as_ref();
-        crate::cli::perr_impl(path, std::format_args!($fmt, $($arg)*));
+        crate::cli::plog_impl(
+            log::Level::$level,
+            path,
+            std::format_args!($fmt, $($arg)*),
+        );
     }};
-    ($path:expr, $msg:expr) => {
-        crate::perr!($path, "{}", $msg);
+    ($level:ident, $path:expr, $msg:expr) => {
+        crate::plog!($level, $path, "{}", $msg);
     };
 }
 
-pub fn perr_impl(path: &std::ffi::OsStr, msg: std::fmt::Arguments) {
+#[macro_export]
+macro_rules! perr {
+    ($($arg:tt)*) => { crate::plog!(Error, $($arg)*) };
+}
+
+#[macro_export]
+macro_rules! pwarn {
+    ($($arg:tt)*) => { crate::plog!(Warn, $($arg)*) };
+}
+
+#[macro_export]
+macro_rules! pinfo {
+    ($($arg:tt)*) => { crate::plog!(Info, $($arg)*) };
+}
+
+#[macro_export]
+macro_rules! pdebug {
+    ($($arg:tt)*) => { crate::plog!(Debug, $($arg)*) };
+}
+
+pub fn plog_impl(
+    level: log::Level,
+    path: &std::ffi::OsStr,
+    msg: std::fmt::Arguments,
+) {
     fn inner(
         mut out: impl std::io::Write,
         path: &[u8],
@@ -27,11 +58,34 @@ pub fn perr_impl(path: &std::ffi::OsStr, msg: std::fmt::Arguments) {
             out.write_fmt(msg).is_ok() &&
             out.write_all(b"\n").is_ok()
     }
-    let path = std::os::unix::ffi::OsStrExt::as_bytes(path);
-    inner(std::io::stderr().lock(), path, msg);
+    // Log records can carry only valid UTF-8 so rather than going through the
+    // logger, consult it whether the level is enabled and write the message
+    // directly to stderr (which is where the logger writes as well).
+    if log::log_enabled!(target: "image_decompose", level) {
+        let path = std::os::unix::ffi::OsStrExt::as_bytes(path);
+        inner(std::io::stderr().lock(), path, msg);
+    }
+}
+
+/// Initialises logger.  Messages at info level and above are printed by
+/// default (warnings and above with `--quiet`); `RUST_LOG` environment
+/// variable overrides that.
+pub fn init_logger(opts: &Opts) {
+    let level = if opts.quiet { "warn" } else { "info" };
+    let env = env_logger::Env::default().default_filter_or(level);
+    env_logger::Builder::from_env(env)
+        .format(|buf, record| {
+            use std::io::Write;
+            writeln!(buf, "{}", record.args())
+        })
+        .init();
 }
 
 
+/// Environment variable with default WebP quality.
+const QUALITY_VAR: &str = "IMAGE_DECOMPOSE_QUALITY";
+
+#[derive(Clone)]
 struct Quality(pub f32);
 
 impl std::str::FromStr for Quality {
@@ -49,23 +103,149 @@ impl std::str::FromStr for Quality {
     }
 }
 
+/// Quality to save WebP images of a single colour space with.
+#[derive(Clone)]
+struct SpaceQuality(SpaceArg, Quality);
+
+impl std::str::FromStr for SpaceQuality {
+    type Err = std::string::String;
+
+    fn from_str(arg: &str) -> Result<Self, Self::Err> {
+        let (space, quality) = arg
+            .split_once('=')
+            .ok_or_else(|| "expected ‘<space>=<quality>’".to_string())?;
+        Ok(Self(space.trim().parse()?, quality.trim().parse()?))
+    }
+}
+
+#[test]
+fn test_space_quality_from_str() {
+    use super::spaces::ColorSpaceId;
+
+    let parse = |arg: &str| {
+        SpaceQuality::from_str(arg).map(|sq| (sq.0 .0.id, sq.1 .0))
+    };
+    assert_eq!(Ok((ColorSpaceId::Lab, 100.0)), parse("lab=100"));
+    assert_eq!(Ok((ColorSpaceId::Hsl, 80.0)), parse("HSL = 80"));
+    assert_eq!(Ok((ColorSpaceId::Rgb, f32::INFINITY)), parse("rgb=lossless"));
+    assert!(parse("lab").is_err());
+    assert!(parse("foo=80").is_err());
+    assert!(parse("lab=101").is_err());
+}
+
+
+/// Corner of the image crop offsets are measured from.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Gravity {
+    NorthWest,
+    NorthEast,
+    SouthWest,
+    SouthEast,
+}
+
+impl Gravity {
+    fn new(is_west: bool, is_north: bool) -> Self {
+        match (is_west, is_north) {
+            (true, true) => Self::NorthWest,
+            (false, true) => Self::NorthEast,
+            (true, false) => Self::SouthWest,
+            (false, false) => Self::SouthEast,
+        }
+    }
+
+    fn is_west(self) -> bool {
+        matches!(self, Self::NorthWest | Self::SouthWest)
+    }
+    fn is_north(self) -> bool {
+        matches!(self, Self::NorthWest | Self::NorthEast)
+    }
+}
 
-#[derive(PartialEq, Eq, Debug)]
+/// Region of an image to crop to.  Parsed from ‘<w>x<h>[±<x>±<y>]’ string on
+/// the command line.
+#[derive(Clone, PartialEq, Eq, Debug)]
 pub struct Crop {
     width: u32,
     height: u32,
-    is_west: bool,
-    is_north: bool,
+    gravity: Gravity,
     x: u32,
     y: u32,
 }
 
-#[derive(PartialEq, Eq, Debug)]
+impl Crop {
+    /// Creates crop region of given size placed in corner of the image
+    /// indicated by the gravity.  Returns `None` if width or height is zero.
+    pub fn new(width: u32, height: u32, gravity: Gravity) -> Option<Self> {
+        if width == 0 || height == 0 {
+            None
+        } else {
+            Some(Self {
+                width,
+                height,
+                gravity,
+                x: 0,
+                y: 0,
+            })
+        }
+    }
+
+    /// Moves the region by given offset away from the corner indicated by the
+    /// gravity.
+    pub fn with_offset(self, x: u32, y: u32) -> Self { Self { x, y, ..self } }
+
+    pub fn width(&self) -> u32 { self.width }
+    pub fn height(&self) -> u32 { self.height }
+    pub fn gravity(&self) -> Gravity { self.gravity }
+    pub fn offset(&self) -> (u32, u32) { (self.x, self.y) }
+
+    /// Crops the image to the region.  The region is clamped to image’s
+    /// dimensions.
+    pub fn crop_image(&self, img: image::DynamicImage) -> image::DynamicImage {
+        let (img_width, img_height) = img.dimensions();
+        let width = self.width().min(img_width);
+        let height = self.height().min(img_height);
+        if width == img_width && height == img_height {
+            return img;
+        }
+        let (x, y) = self.offset();
+        let x = x.min(img_width - width);
+        let y = y.min(img_height - height);
+        let x = if self.gravity().is_west() {
+            x
+        } else {
+            img_width - width - x
+        };
+        let y = if self.gravity().is_north() {
+            y
+        } else {
+            img_height - height - y
+        };
+        img.crop_imm(x, y, width, height)
+    }
+}
+
+/// Dimensions of an image.  Parsed from ‘<width>x<height>’ string on the
+/// command line.
+#[derive(Clone, PartialEq, Eq, Debug)]
 pub struct Dimensions {
     width: u32,
     height: u32,
 }
 
+impl Dimensions {
+    /// Creates new dimensions.  Returns `None` if width or height is zero.
+    pub fn new(width: u32, height: u32) -> Option<Self> {
+        if width == 0 || height == 0 {
+            None
+        } else {
+            Some(Self { width, height })
+        }
+    }
+
+    pub fn width(&self) -> u32 { self.width }
+    pub fn height(&self) -> u32 { self.height }
+}
+
 impl std::str::FromStr for Crop {
     type Err = &'static str;
 
@@ -79,17 +259,150 @@ impl std::str::FromStr for Dimensions {
 
     fn from_str(arg: &str) -> Result<Self, Self::Err> {
         if let Some((w, sep, h, rest)) = parse_number_pair(arg.as_bytes()) {
-            if w > 0 && sep == b'x' && h > 0 && rest.is_empty() {
-                return Ok(Dimensions {
-                    width: w,
-                    height: h,
-                });
+            if sep == b'x' && rest.is_empty() {
+                if let Some(dim) = Dimensions::new(w, h) {
+                    return Ok(dim);
+                }
             }
         }
         Err("expected ‘<width>x<height>’")
     }
 }
 
+/// Target size of `--resize`.  Parsed from ‘<width>x<height>[^]’ string on
+/// the command line.  With the ‘^’ suffix the image is resized preserving its
+/// aspect ratio so that it covers the box and then cropped to it.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Geometry {
+    dim: Dimensions,
+    fill: bool,
+}
+
+impl Geometry {
+    /// Returns size to resize image of given size to.  With fill semantics
+    /// this is the smallest size which preserves aspect ratio of the image
+    /// and covers the box.
+    fn resize_dimensions(&self, width: u32, height: u32) -> (u32, u32) {
+        let (w, h) = (self.dim.width(), self.dim.height());
+        if !self.fill || width == 0 || height == 0 {
+            return (w, h);
+        }
+        let div_ceil = |a: u64, b: u64| ((a + b - 1) / b).min(u32::MAX as u64);
+        if w as u64 * height as u64 >= h as u64 * width as u64 {
+            (w, div_ceil(height as u64 * w as u64, width as u64) as u32)
+        } else {
+            (div_ceil(width as u64 * h as u64, height as u64) as u32, h)
+        }
+    }
+
+    /// With fill semantics, crops centre of the resized image to the box.
+    /// Otherwise returns the image unchanged.
+    fn crop_image(&self, img: image::DynamicImage) -> image::DynamicImage {
+        let (width, height) = img.dimensions();
+        let w = self.dim.width().min(width);
+        let h = self.dim.height().min(height);
+        if !self.fill || (w, h) == (width, height) {
+            img
+        } else {
+            img.crop_imm((width - w) / 2, (height - h) / 2, w, h)
+        }
+    }
+}
+
+impl std::str::FromStr for Geometry {
+    type Err = &'static str;
+
+    fn from_str(arg: &str) -> Result<Self, Self::Err> {
+        let (arg, fill) = match arg.strip_suffix('^') {
+            Some(arg) => (arg, true),
+            None => (arg, false),
+        };
+        let dim = Dimensions::from_str(arg)
+            .map_err(|_| "expected ‘<width>x<height>’ or ‘<width>x<height>^’")?;
+        Ok(Self { dim, fill })
+    }
+}
+
+#[test]
+fn test_geometry() {
+    let geometry = |arg: &str| Geometry::from_str(arg).unwrap();
+    assert!(!geometry("10x20").fill);
+    assert!(geometry("10x20^").fill);
+    assert_eq!(Dimensions::new(10, 20), Some(geometry("10x20^").dim));
+    assert_eq!(None, Geometry::from_str("10x20^^").ok());
+    assert_eq!(None, Geometry::from_str("^").ok());
+
+    assert_eq!((10, 20), geometry("10x20").resize_dimensions(100, 100));
+    assert_eq!((20, 20), geometry("10x20^").resize_dimensions(100, 100));
+    assert_eq!((40, 20), geometry("10x20^").resize_dimensions(200, 100));
+    assert_eq!((100, 50), geometry("100x40^").resize_dimensions(200, 100));
+    assert_eq!((10, 7), geometry("10x5^").resize_dimensions(3, 2));
+}
+
+/// Position of a pixel.  Parsed from ‘<x>,<y>’ string on the command line.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Point {
+    pub x: u32,
+    pub y: u32,
+}
+
+impl std::str::FromStr for Point {
+    type Err = &'static str;
+
+    fn from_str(arg: &str) -> Result<Self, Self::Err> {
+        match parse_number_pair(arg.as_bytes()) {
+            Some((x, b',', y, rest)) if rest.is_empty() => Ok(Self { x, y }),
+            _ => Err("expected ‘<x>,<y>’"),
+        }
+    }
+}
+
+#[test]
+fn test_point_from_str() {
+    assert_eq!(Ok(Point { x: 10, y: 20 }), Point::from_str("10,20"));
+    assert_eq!(Ok(Point { x: 0, y: 0 }), Point::from_str("0,0"));
+    assert_eq!(None, Point::from_str("10x20").ok());
+    assert_eq!(None, Point::from_str("10,").ok());
+    assert_eq!(None, Point::from_str("-1,5").ok());
+    assert_eq!(None, Point::from_str("1,2,3").ok());
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Scale(f32);
+
+impl std::str::FromStr for Scale {
+    type Err = &'static str;
+
+    fn from_str(arg: &str) -> Result<Self, Self::Err> {
+        let (arg, divisor) = match arg.strip_suffix('%') {
+            Some(arg) => (arg, 100.0),
+            None => (arg, 1.0),
+        };
+        match f32::from_str(arg) {
+            Ok(scale) if scale > 0.0 && scale.is_finite() => {
+                Ok(Self(scale / divisor))
+            }
+            _ => Err("expected positive number or percentage"),
+        }
+    }
+}
+
+#[test]
+fn test_scale_from_str() {
+    assert_eq!(Ok(Scale(0.5)), Scale::from_str("0.5"));
+    assert_eq!(Ok(Scale(0.5)), Scale::from_str("50%"));
+    assert_eq!(Ok(Scale(2.0)), Scale::from_str("200%"));
+    assert_eq!(Ok(Scale(1.0)), Scale::from_str("1"));
+    assert_eq!(None, Scale::from_str("").ok());
+    assert_eq!(None, Scale::from_str("%").ok());
+    assert_eq!(None, Scale::from_str("0").ok());
+    assert_eq!(None, Scale::from_str("0%").ok());
+    assert_eq!(None, Scale::from_str("-50%").ok());
+    assert_eq!(None, Scale::from_str("50%%").ok());
+    assert_eq!(None, Scale::from_str("inf").ok());
+    assert_eq!(None, Scale::from_str("NaN").ok());
+}
+
 fn parse_number_pair(arg: &[u8]) -> Option<(u32, u8, u32, &[u8])> {
     let n = arg.iter().take_while(|&&d| b'0' <= d && d <= b'9').count();
     let (a, arg) = arg.split_at(n);
@@ -139,9 +452,73 @@ fn test_dimensions_from_str() {
     assert_eq!(None, Dimensions::from_str("10X20+0+0").ok());
 }
 
+/// Parses colour given as ‘#rgb’, ‘#rrggbb’ or one of the basic CSS colour
+/// names.  Hexadecimal digits and names are case-insensitive.
+fn parse_hex_color(arg: &[u8]) -> Option<[u8; 3]> {
+    #[rustfmt::skip]
+    const NAMES: [(&str, [u8; 3]); 18] = [
+        ("black",   [  0,   0,   0]), ("silver",  [192, 192, 192]),
+        ("gray",    [128, 128, 128]), ("grey",    [128, 128, 128]),
+        ("white",   [255, 255, 255]), ("maroon",  [128,   0,   0]),
+        ("red",     [255,   0,   0]), ("purple",  [128,   0, 128]),
+        ("magenta", [255,   0, 255]), ("green",   [  0, 128,   0]),
+        ("lime",    [  0, 255,   0]), ("olive",   [128, 128,   0]),
+        ("yellow",  [255, 255,   0]), ("navy",    [  0,   0, 128]),
+        ("blue",    [  0,   0, 255]), ("teal",    [  0, 128, 128]),
+        ("cyan",    [  0, 255, 255]), ("orange",  [255, 165,   0]),
+    ];
+
+    let hex = match arg.split_first() {
+        Some((b'#', hex)) => hex,
+        _ => {
+            return NAMES
+                .iter()
+                .find(|(name, _)| arg.eq_ignore_ascii_case(name.as_bytes()))
+                .map(|&(_, rgb)| rgb)
+        }
+    };
+    let digit = |d: u8| (d as char).to_digit(16).map(|d| d as u8);
+    let mut rgb = [0; 3];
+    match hex.len() {
+        3 => {
+            for (v, &d) in rgb.iter_mut().zip(hex) {
+                *v = digit(d)? * 17;
+            }
+        }
+        6 => {
+            for (v, pair) in rgb.iter_mut().zip(hex.chunks_exact(2)) {
+                *v = digit(pair[0])? * 16 + digit(pair[1])?;
+            }
+        }
+        _ => return None,
+    }
+    Some(rgb)
+}
+
+#[test]
+fn test_parse_hex_color() {
+    assert_eq!(Some([0x11, 0x22, 0x33]), parse_hex_color(b"#123"));
+    assert_eq!(Some([0xaa, 0xbb, 0xcc]), parse_hex_color(b"#AbC"));
+    assert_eq!(Some([0x12, 0x34, 0x56]), parse_hex_color(b"#123456"));
+    assert_eq!(Some([0xab, 0xcd, 0xef]), parse_hex_color(b"#aBcDeF"));
+    assert_eq!(Some([0, 0, 0]), parse_hex_color(b"black"));
+    assert_eq!(Some([255, 255, 255]), parse_hex_color(b"White"));
+    assert_eq!(Some([128, 128, 128]), parse_hex_color(b"GREY"));
+
+    assert_eq!(None, parse_hex_color(b""));
+    assert_eq!(None, parse_hex_color(b"#"));
+    assert_eq!(None, parse_hex_color(b"#12"));
+    assert_eq!(None, parse_hex_color(b"#1234"));
+    assert_eq!(None, parse_hex_color(b"#1234567"));
+    assert_eq!(None, parse_hex_color(b"#12345g"));
+    assert_eq!(None, parse_hex_color(b"123456"));
+    assert_eq!(None, parse_hex_color(b"#+12"));
+    assert_eq!(None, parse_hex_color(b"blackish"));
+}
+
 fn parse_crop_str(arg: &[u8]) -> Option<Crop> {
     let (width, sep, height, arg) = parse_number_pair(arg)?;
-    if sep != b'x' || width == 0 || height == 0 {
+    if sep != b'x' {
         return None;
     }
     let (xch, arg) = match arg.split_first() {
@@ -151,14 +528,8 @@ fn parse_crop_str(arg: &[u8]) -> Option<Crop> {
     };
     let (x, ych, y, arg) = parse_number_pair(arg)?;
     if (ych == b'+' || ych == b'-') && arg.is_empty() {
-        Some(Crop {
-            width,
-            height,
-            is_west: xch == b'+',
-            is_north: ych == b'+',
-            x,
-            y,
-        })
+        let gravity = Gravity::new(xch == b'+', ych == b'+');
+        Some(Crop::new(width, height, gravity)?.with_offset(x, y))
     } else {
         None
     }
@@ -168,14 +539,15 @@ fn parse_crop_str(arg: &[u8]) -> Option<Crop> {
 fn test_crop_from_str() {
     fn ok(want: &str, arg: &str) {
         let got = Crop::from_str(arg).map(|crop| {
+            let (x, y) = crop.offset();
             format!(
                 "{}x{}{}{}{}{}",
-                crop.width,
-                crop.height,
-                if crop.is_west { '+' } else { '-' },
-                crop.x,
-                if crop.is_north { '+' } else { '-' },
-                crop.y
+                crop.width(),
+                crop.height(),
+                if crop.gravity().is_west() { '+' } else { '-' },
+                x,
+                if crop.gravity().is_north() { '+' } else { '-' },
+                y
             )
         });
         assert_eq!(Ok(std::string::String::from(want)), got);
@@ -192,20 +564,50 @@ fn test_crop_from_str() {
     assert_eq!(None, Crop::from_str("10x20+30*40").ok());
     assert_eq!(None, Crop::from_str("10x20++30+40").ok());
     assert_eq!(None, Crop::from_str("10x20+-30+40").ok());
+    assert_eq!(None, Crop::from_str("0x20").ok());
+    assert_eq!(None, Crop::from_str("10x0+5+5").ok());
+}
+
+#[test]
+fn test_geometry_new() {
+    assert_eq!(None, Dimensions::new(0, 20));
+    assert_eq!(None, Dimensions::new(10, 0));
+    assert_eq!(Ok(Dimensions::new(10, 20).unwrap()), "10x20".parse());
+    assert_eq!(None, Crop::new(0, 20, Gravity::NorthWest));
+    let crop = Crop::new(10, 20, Gravity::SouthEast)
+        .unwrap()
+        .with_offset(3, 4);
+    assert_eq!(Ok(crop), "10x20-3-4".parse());
+}
+
+
+/// Colour given on the command line as ‘#rgb’, ‘#rrggbb’ or a basic CSS
+/// colour name.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Color(pub [u8; 3]);
+
+impl std::str::FromStr for Color {
+    type Err = &'static str;
+
+    fn from_str(arg: &str) -> Result<Self, Self::Err> {
+        parse_hex_color(arg.as_bytes()).map(Self).ok_or(
+            "expected ‘#rgb’, ‘#rrggbb’ or colour name (e.g. ‘black’, ‘white’)",
+        )
+    }
 }
 
 
+#[derive(Clone, Copy)]
 pub struct SpaceArg(pub &'static super::spaces::Space);
 
 impl std::str::FromStr for SpaceArg {
     type Err = std::string::String;
 
     fn from_str(arg: &str) -> Result<Self, Self::Err> {
-        if let Some(space) = super::spaces::SPACES
-            .iter()
-            .find(|&space| arg.eq_ignore_ascii_case(space.name))
+        if let Some(id) = super::spaces::ColorSpaceId::all()
+            .find(|id| arg.eq_ignore_ascii_case(id.space().name))
         {
-            Ok(SpaceArg(space))
+            Ok(SpaceArg(id.space()))
         } else {
             let spaces = super::spaces::SPACES
                 .iter()
@@ -217,226 +619,2559 @@ impl std::str::FromStr for SpaceArg {
     }
 }
 
+/// List of colour spaces separated by commas or white space.
+#[derive(Clone)]
+pub struct SpaceList(Vec<SpaceArg>);
 
-#[derive(Clap)]
-#[clap(
-    max_term_width = 80,
-    setting = clap::AppSettings::ArgRequiredElseHelp,
-    setting = clap::AppSettings::UnifiedHelpMessage,
-    version = env!("CARGO_PKG_VERSION"),
-    about = "Decomposes images into individual channels",
-    help_template = r#"{about}
-usage: {usage}
+impl std::str::FromStr for SpaceList {
+    type Err = std::string::String;
 
-{all-args}
+    fn from_str(arg: &str) -> Result<Self, Self::Err> {
+        arg.split(|ch: char| ch == ',' || ch.is_whitespace())
+            .filter(|name| !name.is_empty())
+            .map(SpaceArg::from_str)
+            .collect::<Result<Vec<_>, _>>()
+            .map(Self)
+    }
+}
 
-Loads specified image files and decomposes it into channels constructing a new
-image with all the individual channels side-by-side."#)]
-pub struct Opts {
-    /// Directory to save output files in.  If not present, output files will be
-    /// located in the same directory as the input.
-    #[clap(short, long, parse(from_os_str))]
-    pub out_dir: Option<std::path::PathBuf>,
-    /// List of image files to process.
-    #[clap(parse(from_os_str))]
-    pub files: Vec<std::path::PathBuf>,
+/// Pair of colour spaces separated by a comma or white space.
+#[derive(Clone)]
+pub struct SpacePair(pub SpaceArg, pub SpaceArg);
 
-    /// Overwrite existing files without asking.  Overrides the `-i` flag.
-    /// Without this or `-i` flag, output files which already exist will be
-    /// skipped.
-    #[clap(short, long, overrides_with = "interactive")]
-    pub yes: bool,
-    /// Ask before overwriting existing files.  Overrides the `-y` flag. Without
-    /// this or `-y` flag, output files which already exist will be skipped.
-    #[clap(short, long)]
-    pub interactive: bool,
+impl std::str::FromStr for SpacePair {
+    type Err = std::string::String;
 
-    /// Generate decomposition images for specified colours spaces.  If not
-    /// provided, generate images for all supported colour spaces.  Supported
-    /// spaces are RGB, lin-RGB (linear RGB w/o gamma correction), XYZ, xyY,
-    /// HSL, HSV, HWB, Lab, LCHab, Luv and LCHuv.  Names are compared
-    /// case-insensitively.
-    #[clap(short, long, value_delimiter(","))]
-    pub spaces: Vec<SpaceArg>,
+    fn from_str(arg: &str) -> Result<Self, Self::Err> {
+        match SpaceList::from_str(arg)?.0.as_slice() {
+            &[a, b] => Ok(Self(a, b)),
+            _ => Err("expected two colour spaces".into()),
+        }
+    }
+}
 
-    /// Save resulting WebP images with given quality.  Quality can be any
-    /// number from 0 to 100 or ‘lossless’ to save as a lossless WebP.  The
-    /// default quality is 90
-    #[clap(short, long, default_value = "90")]
-    quality: Quality,
-    /// Alias of ‘--quality=lossless’.
-    #[clap(long, overrides_with = "quality")]
-    lossless: bool,
+/// Threshold of a channel given as ‘space:channel=value’ where channel is
+/// either an index or (case-insensitive) name of the channel.
+#[derive(Clone, Copy)]
+pub struct Threshold(super::spaces::Threshold);
 
-    /// Resize the source image to specified size.  The size is specified in
-    /// ‘<width>x<height>` format.
-    ///
-    /// If specified together with `--crop`, resizing happens first.
-    ///
-    /// Note that if multiple images are specified, the resize operation will be
-    /// applied to all of them.  To be able to resize different images to
-    /// different sizes, the command needs to be called multiple times.
-    #[clap(long)]
-    resize: Option<Dimensions>,
-    /// Crop the source image according to the specified geometry.  The geometry
-    /// is in ‘<width>x<height>+<offset-x>+<offset-y>’ form.  The offset is
-    /// optional and if it’s not specified it’s assumed to be ‘+0+0’.  Either
-    /// coordinate of the offset can be negative to specify offset from the
-    /// other side of the image (e.g. `320x200-50+10` selects a rectangle 50
-    /// pixels from the right edge of the image and 10 pixels from the top).
-    ///
-    /// Note that if multiple images are specified, the cropping will be
-    /// performed on all of them.  To be able to crop different images based on
-    /// different specifications, the command needs to be called multiple times.
-    #[clap(long)]
-    crop: Option<Crop>,
+impl std::str::FromStr for Threshold {
+    type Err = std::string::String;
 
-    /// Run at most given number of threads in parallel.  By default, program
-    /// will run one thread per logical CPU core.  Specifying zero or one
-    /// effectively disables parallelism.
-    #[clap(short, long)]
-    pub jobs: Option<usize>,
+    fn from_str(arg: &str) -> Result<Self, Self::Err> {
+        let (space, rest) = arg
+            .split_once(':')
+            .ok_or_else(|| String::from("expected ‘space:channel=value’"))?;
+        let (channel, value) = rest
+            .split_once('=')
+            .ok_or_else(|| String::from("expected ‘space:channel=value’"))?;
+        let space = SpaceArg::from_str(space.trim())?.0;
+        let channel = channel.trim();
+        let names = space.channel_names();
+        // Exact match takes precedence since some spaces (e.g. xyY) have
+        // channels whose names differ only by case.
+        let channel = channel
+            .parse::<usize>()
+            .ok()
+            .filter(|&index| index < names.len())
+            .or_else(|| names.iter().position(|&name| name == channel))
+            .or_else(|| {
+                names.iter().position(|name| name.eq_ignore_ascii_case(channel))
+            })
+            .ok_or_else(|| {
+                let (name, names) = (space.name, names.join(", "));
+                format!("{}: channels of {} are: {}", channel, name, names)
+            })?;
+        let value = value
+            .trim()
+            .parse::<f32>()
+            .ok()
+            .filter(|value| !value.is_nan())
+            .ok_or_else(|| format!("{}: invalid threshold", value))?;
+        Ok(Self(super::spaces::Threshold { space: space.id, channel, value }))
+    }
 }
 
-impl Opts {
-    pub fn encode(&self, enc: webp::Encoder) -> webp::WebPMemory {
-        let q = self.quality.0;
-        if self.lossless || q == f32::INFINITY {
-            enc.encode_lossless()
-        } else {
-            enc.encode(q.clamp(0.0, 100.0))
-        }
+#[test]
+fn test_threshold() {
+    use super::spaces::ColorSpaceId;
+
+    fn parse(arg: &str) -> Option<(ColorSpaceId, usize, f32)> {
+        let th = Threshold::from_str(arg).ok()?.0;
+        Some((th.space, th.channel, th.value))
     }
 
-    pub fn resize_image(
-        &self,
-        img: image::DynamicImage,
-    ) -> image::DynamicImage {
-        if let Some(Dimensions {
-            width: w,
-            height: h,
-        }) = self.resize
-        {
-            img.resize_exact(w, h, image::imageops::Lanczos3)
-        } else {
-            img
-        }
+    assert_eq!(Some((ColorSpaceId::Lab, 0, 50.0)), parse("lab:0=50"));
+    assert_eq!(Some((ColorSpaceId::Lab, 2, -10.0)), parse("LAB:2=-10"));
+    assert_eq!(Some((ColorSpaceId::Hsl, 1, 0.5)), parse("hsl:1 = 0.5"));
+    assert_eq!(None, parse("lab:3=50"));
+    assert_eq!(None, parse("lab:0"));
+    assert_eq!(None, parse("lab=50"));
+    assert_eq!(None, parse("lab:0=foo"));
+    assert_eq!(None, parse("lab:0=NaN"));
+    assert_eq!(None, parse("foo:0=50"));
+    assert_eq!(Some((ColorSpaceId::XyY, 1, 0.5)), parse("xyY:y=0.5"));
+    assert_eq!(Some((ColorSpaceId::XyY, 2, 0.5)), parse("xyY:Y=0.5"));
+    let names = ColorSpaceId::Lab.space().channel_names();
+    let arg = format!("lab:{}=50", names[1].to_uppercase());
+    assert_eq!(Some((ColorSpaceId::Lab, 1, 50.0)), parse(&arg));
+}
+
+/// Sorts colour spaces in the order they are defined in `SPACES` array and
+/// removes duplicates.  If the list is empty, returns all supported spaces.
+fn dedup_spaces(mut spaces: Vec<SpaceArg>) -> Vec<SpaceArg> {
+    if spaces.is_empty() {
+        spaces.extend(super::spaces::SPACES.iter().map(SpaceArg));
+    } else {
+        spaces.sort_by_key(|space| space.0.id);
+        spaces.dedup_by_key(|space| space.0.id);
     }
+    spaces
+}
 
-    pub fn crop_image(&self, img: image::DynamicImage) -> image::DynamicImage {
-        if let Some(crop) = &self.crop {
-            let (img_width, img_height) = img.dimensions();
-            let width = crop.width.min(img_width);
-            let height = crop.height.min(img_height);
-            if width == img_width && height == img_height {
-                return img;
-            }
-            let x = crop.x.min(img_width - width);
-            let y = crop.y.min(img_height - height);
-            let x = if crop.is_west {
-                x
-            } else {
-                img_width - width - x
-            };
-            let y = if crop.is_north {
-                y
-            } else {
-                img_height - height - y
-            };
-            img.crop_imm(x, y, width, height)
-        } else {
-            img
+#[test]
+fn test_space_list() {
+    fn names(args: &[&str]) -> Result<Vec<&'static str>, String> {
+        let mut spaces = Vec::new();
+        for arg in args {
+            spaces.extend(SpaceList::from_str(arg)?.0);
         }
+        Ok(dedup_spaces(spaces)
+            .iter()
+            .map(|space| space.0.name)
+            .collect())
     }
 
-    pub fn resize_and_crop_image(
-        &self,
-        i: image::DynamicImage,
-    ) -> image::DynamicImage {
-        self.crop_image(self.resize_image(i))
-    }
+    assert_eq!(Ok(vec!["lab"]), names(&["lab"]));
+    assert_eq!(Ok(vec!["lab", "luv"]), names(&["lab,luv"]));
+    assert_eq!(Ok(vec!["lab", "luv"]), names(&["lab luv"]));
+    assert_eq!(Ok(vec!["lab", "luv"]), names(&[" lab,\tluv  "]));
+    assert_eq!(Ok(vec!["lab", "luv"]), names(&["luv", "lab"]));
+    assert_eq!(
+        Ok(vec!["hsl", "lab", "luv"]),
+        names(&["luv lab", "LAB,hsl"])
+    );
+    assert_eq!(Ok(vec!["lab"]), names(&["lab", "lab lab", "lab,Lab"]));
+    assert_eq!(super::spaces::SPACES.len(), names(&[]).unwrap().len());
+    assert_eq!(super::spaces::SPACES.len(), names(&[" , "]).unwrap().len());
+    assert!(names(&["lab foo"]).is_err());
+    assert!(names(&["lab;luv"]).is_err());
 }
 
 
-pub enum Confirmer {
-    Skip,
-    Overwrite,
-    Interactive(std::sync::Mutex<ConfirmerInner>),
+/// Format of the output images.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Format {
+    WebP,
+    Png,
+    Tiff,
+    Ppm,
+    Farbfeld,
 }
 
-#[allow(private_in_public)]
-struct ConfirmerInner;
-
-impl Confirmer {
-    pub fn new(opts: &Opts) -> Self {
-        if opts.yes {
-            Self::Overwrite
-        } else if opts.interactive {
-            Self::Interactive(std::sync::Mutex::new(ConfirmerInner))
-        } else {
-            Self::Skip
+impl Format {
+    /// Returns extension used for files in this format.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::WebP => "webp",
+            Self::Png => "png",
+            Self::Tiff => "tiff",
+            Self::Ppm => "ppm",
+            Self::Farbfeld => "ff",
         }
     }
 
-    pub fn confirm(&self, file: &std::path::Path) -> bool {
-        match self {
-            Self::Overwrite => return true,
-            _ if !file.exists() => return true,
-            Self::Skip => (),
-            Self::Interactive(mutex) => {
-                let res = mutex
-                    .lock()
-                    .map_err(|p| p.into_inner())
-                    .into_ok_or_err()
-                    .confirm(file);
-                match res {
-                    Ok(ans) => return ans,
-                    Err((a, b)) => eprintln!("{}: {}", a, b),
-                }
-            }
-        }
-        super::perr!(file, "file already exists, skipping");
-        false
+    /// Returns whether images in this format can be encoded one row at
+    /// a time.
+    pub fn can_stream(self) -> bool {
+        matches!(self, Self::Png | Self::Ppm | Self::Farbfeld)
     }
 }
 
-fn write_prompt(
-    mut out: impl std::io::Write,
-    file: &std::path::Path,
-) -> std::io::Result<()> {
-    out.write_all(std::os::unix::ffi::OsStrExt::as_bytes(file.as_os_str()))?;
-    write!(out, ": file exists, overwrite? [y/N] ")?;
-    out.flush()
-}
+impl std::str::FromStr for Format {
+    type Err = &'static str;
 
-impl ConfirmerInner {
-    fn confirm(
+    fn from_str(arg: &str) -> Result<Self, Self::Err> {
+        if arg.eq_ignore_ascii_case("webp") {
+            Ok(Self::WebP)
+        } else if arg.eq_ignore_ascii_case("png") {
+            Ok(Self::Png)
+        } else if arg.eq_ignore_ascii_case("tiff") ||
+            arg.eq_ignore_ascii_case("tif")
+        {
+            Ok(Self::Tiff)
+        } else if arg.eq_ignore_ascii_case("ppm") {
+            Ok(Self::Ppm)
+        } else if arg.eq_ignore_ascii_case("ff") ||
+            arg.eq_ignore_ascii_case("farbfeld")
+        {
+            Ok(Self::Farbfeld)
+        } else {
+            Err("supported formats: webp, png, tiff, ppm, ff")
+        }
+    }
+}
+
+
+/// Formats given with `--format`: comma-separated list of formats each
+/// optionally preceded by name of a colour space it applies to (e.g.
+/// ‘png,lab=webp’).
+#[derive(Clone)]
+struct FormatList(Vec<(Option<SpaceArg>, Format)>);
+
+impl std::str::FromStr for FormatList {
+    type Err = std::string::String;
+
+    fn from_str(arg: &str) -> Result<Self, Self::Err> {
+        arg.split(',')
+            .map(|item| match item.split_once('=') {
+                Some((space, format)) => Ok((
+                    Some(space.trim().parse()?),
+                    Format::from_str(format.trim())?,
+                )),
+                None => Ok((None, Format::from_str(item.trim())?)),
+            })
+            .collect::<Result<Vec<_>, std::string::String>>()
+            .map(Self)
+    }
+}
+
+#[test]
+fn test_format_list_from_str() {
+    use super::spaces::ColorSpaceId;
+
+    let parse = |arg: &str| {
+        FormatList::from_str(arg).map(|list| {
+            list.0
+                .into_iter()
+                .map(|(space, format)| (space.map(|s| s.0.id), format))
+                .collect::<Vec<_>>()
+        })
+    };
+    assert_eq!(Ok(vec![(None, Format::Png)]), parse("png"));
+    assert_eq!(
+        Ok(vec![
+            (Some(ColorSpaceId::Lab), Format::Png),
+            (Some(ColorSpaceId::Hsl), Format::WebP)
+        ]),
+        parse("lab=png, HSL=webp")
+    );
+    assert_eq!(
+        Ok(vec![(None, Format::Tiff), (Some(ColorSpaceId::Rgb), Format::Ppm)]),
+        parse("tif,rgb=ppm")
+    );
+    assert!(parse("").is_err());
+    assert!(parse("lab=").is_err());
+    assert!(parse("foo=png").is_err());
+    assert!(parse("lab=jpeg").is_err());
+}
+
+
+/// Number of threads to run in parallel.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Jobs {
+    /// One thread per logical CPU core.
+    Auto,
+    /// Given number of threads.  Always at least one.
+    Count(usize),
+}
+
+impl std::str::FromStr for Jobs {
+    type Err = &'static str;
+
+    fn from_str(arg: &str) -> Result<Self, Self::Err> {
+        const MAX_JOBS: usize = 1024;
+        if arg.eq_ignore_ascii_case("auto") {
+            return Ok(Self::Auto);
+        }
+        match usize::from_str(arg) {
+            Ok(num) if num <= MAX_JOBS => Ok(Self::Count(num.max(1))),
+            _ => Err("expected ‘auto’ or number from 0 to 1024"),
+        }
+    }
+}
+
+#[test]
+fn test_jobs_from_str() {
+    assert_eq!(Ok(Jobs::Auto), Jobs::from_str("auto"));
+    assert_eq!(Ok(Jobs::Auto), Jobs::from_str("AUTO"));
+    assert_eq!(Ok(Jobs::Count(1)), Jobs::from_str("0"));
+    assert_eq!(Ok(Jobs::Count(1)), Jobs::from_str("1"));
+    assert_eq!(Ok(Jobs::Count(8)), Jobs::from_str("8"));
+    assert_eq!(Ok(Jobs::Count(1024)), Jobs::from_str("1024"));
+    assert_eq!(None, Jobs::from_str("").ok());
+    assert_eq!(None, Jobs::from_str("-1").ok());
+    assert_eq!(None, Jobs::from_str("1025").ok());
+    assert_eq!(None, Jobs::from_str("99999999999999999999999").ok());
+}
+
+
+/// Position of the copy of the source image in the output.
+#[derive(Clone)]
+struct OriginalPosition(super::spaces::OriginalPosition);
+
+impl std::str::FromStr for OriginalPosition {
+    type Err = &'static str;
+
+    fn from_str(arg: &str) -> Result<Self, Self::Err> {
+        use super::spaces::OriginalPosition as Pos;
+        if arg.eq_ignore_ascii_case("first") {
+            Ok(Self(Pos::First))
+        } else if arg.eq_ignore_ascii_case("last") {
+            Ok(Self(Pos::Last))
+        } else if arg.eq_ignore_ascii_case("none") {
+            Ok(Self(Pos::None))
+        } else {
+            Err("supported positions: first, last, none")
+        }
+    }
+}
+
+
+/// Resampling filter used by `--resize` and `--scale`.
+#[derive(Clone)]
+struct ResizeFilter(super::spaces::ResizeFilter);
+
+impl std::str::FromStr for ResizeFilter {
+    type Err = &'static str;
+
+    fn from_str(arg: &str) -> Result<Self, Self::Err> {
+        use super::spaces::ResizeFilter as Filter;
+        const FILTERS: [(&str, Filter); 6] = [
+            ("nearest", Filter::Nearest),
+            ("triangle", Filter::Triangle),
+            ("catmull-rom", Filter::CatmullRom),
+            ("gaussian", Filter::Gaussian),
+            ("lanczos2", Filter::Lanczos2),
+            ("lanczos3", Filter::Lanczos3),
+        ];
+        FILTERS
+            .iter()
+            .find(|(name, _)| arg.eq_ignore_ascii_case(name))
+            .map(|&(_, filter)| Self(filter))
+            .ok_or(
+                "supported filters: nearest, triangle, catmull-rom, \
+                 gaussian, lanczos2, lanczos3",
+            )
+    }
+}
+
+/// Model used to convert CMYK source images into RGB.
+#[derive(Clone, Copy)]
+struct CmykModel(super::spaces::CmykModel);
+
+impl std::str::FromStr for CmykModel {
+    type Err = &'static str;
+
+    fn from_str(arg: &str) -> Result<Self, Self::Err> {
+        use super::spaces::CmykModel as Model;
+        [Model::Naive, Model::Gcr]
+            .iter()
+            .find(|model| arg.eq_ignore_ascii_case(model.name()))
+            .map(|&model| Self(model))
+            .ok_or("supported models: naive, gcr")
+    }
+}
+
+
+/// Dithering method.
+#[derive(Clone, Copy)]
+pub struct Dither(super::spaces::Dither);
+
+impl std::str::FromStr for Dither {
+    type Err = &'static str;
+
+    fn from_str(arg: &str) -> Result<Self, Self::Err> {
+        use super::spaces::Dither as Method;
+        if arg.eq_ignore_ascii_case("ordered") {
+            Ok(Self(Method::Ordered))
+        } else {
+            Err("supported methods: ordered")
+        }
+    }
+}
+
+
+/// What to do when multiple source files would produce the same output file.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Collision {
+    /// Report an error and don’t process any files.
+    Error,
+    /// Report a warning and write the files anyway.
+    Warn,
+    /// Add a counter to names of the later output files.
+    Rename,
+}
+
+impl std::str::FromStr for Collision {
+    type Err = &'static str;
+
+    fn from_str(arg: &str) -> Result<Self, Self::Err> {
+        if arg.eq_ignore_ascii_case("error") {
+            Ok(Self::Error)
+        } else if arg.eq_ignore_ascii_case("warn") {
+            Ok(Self::Warn)
+        } else if arg.eq_ignore_ascii_case("rename") {
+            Ok(Self::Rename)
+        } else {
+            Err("supported actions: error, warn, rename")
+        }
+    }
+}
+
+
+/// Case colour space names are converted to in names of the output files.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum NameCase {
+    AsIs,
+    Lower,
+    Upper,
+}
+
+impl NameCase {
+    /// Converts colour space name to the case.
+    pub fn apply(self, name: &str) -> std::borrow::Cow<'_, str> {
+        match self {
+            Self::AsIs => std::borrow::Cow::Borrowed(name),
+            Self::Lower => std::borrow::Cow::Owned(name.to_ascii_lowercase()),
+            Self::Upper => std::borrow::Cow::Owned(name.to_ascii_uppercase()),
+        }
+    }
+}
+
+impl std::str::FromStr for NameCase {
+    type Err = &'static str;
+
+    fn from_str(arg: &str) -> Result<Self, Self::Err> {
+        if arg.eq_ignore_ascii_case("as-is") {
+            Ok(Self::AsIs)
+        } else if arg.eq_ignore_ascii_case("lower") {
+            Ok(Self::Lower)
+        } else if arg.eq_ignore_ascii_case("upper") {
+            Ok(Self::Upper)
+        } else {
+            Err("supported cases: as-is, lower, upper")
+        }
+    }
+}
+
+#[test]
+fn test_name_case() {
+    fn apply(case: &str, name: &'static str) -> std::borrow::Cow<'static, str> {
+        NameCase::from_str(case).unwrap().apply(name)
+    }
+
+    assert_eq!("XYZ", apply("as-is", "XYZ"));
+    assert_eq!("xyy", apply("lower", "xyY"));
+    assert_eq!("LIN-RGB", apply("UPPER", "lin-rgb"));
+    assert!(NameCase::from_str("title").is_err());
+}
+
+
+/// Colour profile source images are assumed to be encoded in.
+#[derive(Clone)]
+pub enum Profile {
+    Srgb,
+    DisplayP3,
+}
+
+impl std::str::FromStr for Profile {
+    type Err = &'static str;
+
+    fn from_str(arg: &str) -> Result<Self, Self::Err> {
+        if arg.eq_ignore_ascii_case("srgb") {
+            Ok(Self::Srgb)
+        } else if arg.eq_ignore_ascii_case("display-p3") {
+            Ok(Self::DisplayP3)
+        } else {
+            Err("supported profiles: srgb, display-p3")
+        }
+    }
+}
+
+
+/// Decoder of a gzip-compressed tar archive.
+type GzReader = flate2::read::GzDecoder<std::io::BufReader<std::fs::File>>;
+
+/// Index of images in a tar archive given with `--from-tar`.  Only location of
+/// each image within the archive is kept; contents are read when the image is
+/// loaded.
+pub struct TarArchive {
+    path: std::path::PathBuf,
+    /// Offset within the (decompressed) archive and size of each image.
+    entries: std::collections::HashMap<std::path::PathBuf, (u64, u64)>,
+    /// Whether the archive is compressed with gzip.
+    gzip: bool,
+    /// Decoders of a compressed archive together with their position in the
+    /// decompressed data.  They are reused so that reading images in order
+    /// doesn’t decompress the archive from the start for each of them.
+    decoders: std::sync::Mutex<Vec<(u64, GzReader)>>,
+}
+
+impl TarArchive {
+    /// Reads the archive and indexes regular files with a recognised image
+    /// extension.
+    fn open(path: std::path::PathBuf) -> std::io::Result<Self> {
+        let mut rd = std::io::BufReader::new(std::fs::File::open(&path)?);
+        let gzip = rd.fill_buf()?.starts_with(&[0x1f, 0x8b]);
+        let rd: Box<dyn std::io::Read> = if gzip {
+            Box::new(flate2::read::GzDecoder::new(rd))
+        } else {
+            Box::new(rd)
+        };
+        let mut entries = std::collections::HashMap::new();
+        for entry in tar::Archive::new(rd).entries()? {
+            let entry = entry?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+            let name = entry.path()?.into_owned();
+            if !is_image_path(&name) {
+                pdebug!(name, "skipping; not an image");
+                continue;
+            }
+            entries.insert(name, (entry.raw_file_position(), entry.size()));
+        }
+        let decoders = Default::default();
+        Ok(Self { path, entries, gzip, decoders })
+    }
+
+    /// Reads contents of given image.  Returns `None` if there’s no such
+    /// image in the archive.
+    fn read(&self, name: &std::path::Path) -> Option<std::io::Result<Vec<u8>>> {
+        let &(offset, size) = self.entries.get(name)?;
+        Some(self.read_at(offset, size))
+    }
+
+    fn read_at(&self, offset: u64, size: u64) -> std::io::Result<Vec<u8>> {
+        use std::io::Read;
+
+        let mut data = Vec::new();
+        if !self.gzip {
+            let mut fd = std::fs::File::open(&self.path)?;
+            std::io::Seek::seek(&mut fd, std::io::SeekFrom::Start(offset))?;
+            fd.take(size).read_to_end(&mut data)?;
+        } else {
+            // Pick decoder which got furthest without passing the image.
+            let lock = || {
+                self.decoders
+                    .lock()
+                    .unwrap_or_else(std::sync::PoisonError::into_inner)
+            };
+            let mut decoders = lock();
+            let idx = (0..decoders.len())
+                .filter(|&idx| decoders[idx].0 <= offset)
+                .max_by_key(|&idx| decoders[idx].0);
+            let (pos, mut rd) = match idx {
+                Some(idx) => decoders.swap_remove(idx),
+                None => {
+                    let fd = std::fs::File::open(&self.path)?;
+                    let rd = std::io::BufReader::new(fd);
+                    (0, flate2::read::GzDecoder::new(rd))
+                }
+            };
+            drop(decoders);
+            let skip = offset - pos;
+            let mut sink = std::io::sink();
+            if std::io::copy(&mut (&mut rd).take(skip), &mut sink)? == skip {
+                (&mut rd).take(size).read_to_end(&mut data)?;
+                lock().push((offset + data.len() as u64, rd));
+            }
+        }
+        if data.len() as u64 != size {
+            let kind = std::io::ErrorKind::UnexpectedEof;
+            return Err(std::io::Error::new(kind, "archive truncated"));
+        }
+        Ok(data)
+    }
+}
+
+
+#[derive(Clap, Clone)]
+#[clap(
+    max_term_width = 80,
+    setting = clap::AppSettings::ArgRequiredElseHelp,
+    setting = clap::AppSettings::UnifiedHelpMessage,
+    version = env!("CARGO_PKG_VERSION"),
+    about = "Decomposes images into individual channels",
+    help_template = r#"{about}
+usage: {usage}
+
+{all-args}
+
+Loads specified image files and decomposes it into channels constructing a new
+image with all the individual channels side-by-side."#)]
+pub struct Opts {
+    /// Directory to save output files in.  If not present, output files will be
+    /// located in the same directory as the input.
+    #[clap(short, long, parse(from_os_str))]
+    pub out_dir: Option<std::path::PathBuf>,
+    /// Save all generated images in a ZIP archive at given path rather than
+    /// as separate files.  Names of the files in the archive follow the same
+    /// convention as when saving to a directory.  If specified, `--out-dir` is
+    /// ignored.
+    #[clap(long, parse(from_os_str))]
+    pub zip: Option<std::path::PathBuf>,
+    /// After generating the images, write an HTML page at given path which
+    /// shows all of them grouped by source file with names of the colour
+    /// spaces as captions.  Images are referenced by paths relative to the
+    /// page’s directory.
+    #[clap(
+        long,
+        parse(from_os_str),
+        conflicts_with = "zip",
+        value_name = "path"
+    )]
+    pub html: Option<std::path::PathBuf>,
+    /// Rather than generating separate images, write a single SVG document
+    /// at given path with decompositions of all the files in all colour
+    /// spaces.  Each channel tile is embedded as a PNG image and labelled
+    /// with name of the channel; each row of tiles is labelled with name of
+    /// the file and the colour space.  Copy of the source image and extra
+    /// tiles (`--reconstruct`, `--luma-tile`) are not included.
+    #[clap(
+        long,
+        parse(from_os_str),
+        conflicts_with_all = &["diff", "compare", "zip", "html"],
+        value_name = "path"
+    )]
+    pub svg: Option<std::path::PathBuf>,
+
+    /// Rather than decomposing the images, plot chromaticities of all pixels
+    /// of all the files on a CIE 1931 xy chromaticity diagram and save it at
+    /// given path.  Density of the pixels is shown as a heat map on
+    /// a logarithmic scale with the spectral locus drawn in grey and the
+    /// sRGB gamut drawn in blue.  This shows how much of the gamut the
+    /// images cover.
+    #[clap(
+        long,
+        parse(from_os_str),
+        conflicts_with_all = &[
+            "diff", "compare", "zip", "html", "svg", "space-diff", "probe"
+        ],
+        value_name = "path"
+    )]
+    pub chromaticity_plot: Option<std::path::PathBuf>,
+    /// After processing all files, write a JSON array at given path which
+    /// describes each output file: the source image, colour space, path of
+    /// the output, status (‘written’, ‘skipped’, ‘failed’ or ‘aborted’),
+    /// dimensions of the image and time spent generating it in seconds.
+    #[clap(long, parse(from_os_str), value_name = "path")]
+    pub summary_json: Option<std::path::PathBuf>,
+    /// Print to standard error how long decoding, preparing (resizing,
+    /// cropping etc.) and generating output in each colour space took for
+    /// every file, followed by totals across all files.  Generating output
+    /// includes decomposition, encoding and writing the file.  The totals
+    /// add up time spent on all threads.
+    #[clap(long)]
+    pub profile: bool,
+    /// Rather than generating images, print to standard output coordinates
+    /// of pixel at given ‘<x>,<y>’ position in each of the selected colour
+    /// spaces.  The values are the channels’ raw values before they are
+    /// rendered as colours which is useful for checking conversions against
+    /// other tools.  The position refers to the image after `--resize`,
+    /// `--scale` and `--crop` (first region only) are applied.
+    #[clap(long, value_name = "x,y", conflicts_with = "diff")]
+    pub probe: Option<Point>,
+    /// Rather than decomposing the images, render for each of them per-pixel
+    /// differences between comparable channels of the two given colour
+    /// spaces (e.g. ‘lab,hsl’) as a heat map.  Channels are comparable if
+    /// they measure the same kind of quantity: lightness (L*, L, V, P and so
+    /// on), linear luminance (Y of XYZ, xyY and UCS), hue or saturation of
+    /// the cylindrical RGB-based spaces.  TSL’s tint is not a hue.  For
+    /// example, ‘lab,hsl’ shows where perceptual lightness disagrees with HSL
+    /// lightness while ‘lchab,hsv’ compares lightness and hue.  Output file
+    /// is named after both spaces (e.g. ‘image-lab-vs-hsl.webp’).
+    #[clap(
+        long,
+        value_name = "space,space",
+        conflicts_with_all = &["diff", "compare", "svg", "probe", "zip"]
+    )]
+    pub space_diff: Option<SpacePair>,
+
+    /// List supported colour spaces together with names of their channels
+    /// and exit.
+    #[clap(long)]
+    pub list_spaces: bool,
+    /// With `--list-spaces`, print the list as a JSON array of objects with
+    /// ‘name’, ‘channels’, ‘description’ and ‘channel_names’ keys rather than
+    /// as a table.
+    #[clap(long, requires = "list-spaces")]
+    pub json: bool,
+
+    /// Rather than decomposing files, decompose a synthetic test image in
+    /// given colour space.  The test image consists of colour bars, hue
+    /// sweeps, a grey ramp and skin tone patches so it shows what channels
+    /// of the space look like on known content.  Output is saved as
+    /// ‘reference-card-<space>’ in `--out-dir` or the current directory.
+    #[clap(
+        long,
+        value_name = "space",
+        conflicts_with_all = &[
+            "files", "manifest", "from-tar", "diff", "compare", "svg", "probe",
+            "zip", "space-diff"
+        ]
+    )]
+    pub reference_card: Option<SpaceArg>,
+    /// List of image files to process.  If the crate has been built with the
+    /// ‘net’ feature, HTTP and HTTPS URLs are accepted as well in which case
+    /// `--out-dir` must be given.
+    #[clap(parse(from_os_str))]
+    pub files: Vec<std::path::PathBuf>,
+
+    /// Read list of files to process from given manifest rather than from
+    /// command line.  Each line of the manifest names a file optionally
+    /// followed by tab-separated ‘<key>=<value>’ fields overriding options
+    /// for that file: ‘out-dir’, ‘spaces’, ‘resize’ and ‘crop’ (which may be
+    /// given multiple times).  Relative file names and ‘out-dir’ values are
+    /// resolved against directory the manifest is in.  Empty lines and lines
+    /// starting with ‘#’ are ignored.  This makes it possible to e.g. crop
+    /// each image differently in a single invocation.
+    #[clap(
+        long,
+        parse(from_os_str),
+        value_name = "path",
+        conflicts_with_all = &[
+            "files", "compare", "html", "summary-json", "svg", "probe"
+        ]
+    )]
+    manifest: Option<std::path::PathBuf>,
+
+    /// Expand glob patterns (such as ‘*.png’ or ‘photos/**/*.jpg’) in the
+    /// list of files.  Useful when the shell doesn’t expand them or when the
+    /// patterns were quoted.  ‘**’ matches any number of nested directories.
+    /// Arguments which contain no wildcard characters are used as is.
+    #[clap(long)]
+    glob: bool,
+
+    /// Rather than processing files given on command line, watch given
+    /// directory and decompose every image which is created or modified in it
+    /// until interrupted.  Files are processed only once their size stops
+    /// changing so images which are still being copied aren’t picked up.
+    /// Outputs are saved next to the images unless `--out-dir` is given and
+    /// are themselves ignored when they appear in the watched directory.
+    /// Note that, as usual, existing outputs are skipped so to regenerate
+    /// decompositions of modified images use `--yes`.
+    #[clap(
+        long,
+        parse(from_os_str),
+        value_name = "dir",
+        conflicts_with_all = &[
+            "files", "manifest", "from-tar", "zip", "compare", "html",
+            "summary-json", "svg", "probe", "space-diff", "preview-scale"
+        ]
+    )]
+    pub watch: Option<std::path::PathBuf>,
+
+    /// Abort without processing anything if the list of files (after
+    /// expanding glob patterns, reading the manifest or the tar archive)
+    /// contains more than given number of files.  This guards scripts against
+    /// patterns which accidentally match far more files than intended.  By
+    /// default there’s no limit.
+    #[clap(long, value_name = "count")]
+    max_files: Option<usize>,
+
+    /// Read source images from given tar archive (optionally compressed with
+    /// gzip) rather than from files given on command line.  All regular files
+    /// in the archive with a recognised image extension are processed.  Images
+    /// are read from the archive as they are processed rather than extracted
+    /// or loaded into memory up front.  Requires `--out-dir`.
+    #[clap(
+        long,
+        parse(from_os_str),
+        value_name = "archive",
+        requires = "out-dir",
+        conflicts_with_all = &["files", "manifest", "diff"]
+    )]
+    from_tar: Option<std::path::PathBuf>,
+    /// Index of images in `--from-tar` archive.
+    #[clap(skip)]
+    archive: Option<std::sync::Arc<TarArchive>>,
+
+    /// Overwrite existing files without asking.  `--no-skip` and `--force`
+    /// are aliases of this flag.  Existence of output files isn’t checked at
+    /// all in this mode.
+    #[clap(
+        short,
+        long,
+        aliases = &["no-skip", "force"],
+        overrides_with = "interactive"
+    )]
+    pub yes: bool,
+    /// Ask before overwriting existing files.  Answering ‘a’ overwrites the
+    /// file and all remaining files without asking again while ‘q’ skips the
+    /// file and all remaining existing files.
+    #[clap(short, long)]
+    pub interactive: bool,
+    /// Skip output files which already exist.  This is the default if none of
+    /// `-y`, `-i` and `--skip-existing` flags are given; if more than one is
+    /// given, the last one takes effect.  For example, ‘-y --skip-existing’
+    /// skips existing files while ‘--skip-existing -i’ asks about each of
+    /// them.
+    #[clap(long, overrides_with_all = &["yes", "interactive"])]
+    pub skip_existing: bool,
+
+    /// Print path of each output file to standard output, one per line, as
+    /// soon as it’s written.  Skipped and failed files aren’t printed.  This
+    /// makes it easy to pass the outputs to other tools, e.g. with xargs.
+    /// Cannot be used with options which prompt on standard output.
+    #[clap(
+        long,
+        conflicts_with_all = &["zip", "interactive", "preview-scale"]
+    )]
+    pub print_outputs: bool,
+    /// Like `--print-outputs` but terminate each path with a NUL byte rather
+    /// than a new line (as expected by ‘xargs -0’).
+    #[clap(
+        long,
+        conflicts_with_all = &[
+            "zip", "print-outputs", "interactive", "preview-scale"
+        ]
+    )]
+    pub print_outputs0: bool,
+
+    /// Generate decomposition images for specified colours spaces.  If not
+    /// provided, spaces listed in IMAGE_DECOMPOSE_SPACES environment variable
+    /// are used and if that’s not set either, images for all supported colour
+    /// spaces are generated.  Supported spaces are RGB, lin-RGB (linear RGB w/o
+    /// gamma correction), adobe-rgb, display-p3, rec2020, XYZ, xyY, UCS, HSL,
+    /// HSV, HWB, HSP, TSL, YUV, Lab, LCHab, Luv, LCHuv, DIN99, CMY, CMYK and
+    /// complement (warm and cool components of colours).  Names are compared
+    /// case-insensitively and can be separated by commas or white space.  The
+    /// option can be given multiple times.
+    #[clap(short = 's', long = "spaces", value_name = "spaces")]
+    space_lists: Vec<SpaceList>,
+    /// Rather than decomposing the images, save just the source images
+    /// (possibly resized and cropped) in the output format.  This may be used
+    /// to convert images or to benchmark the encoder.  Output files are given
+    /// ‘original’ suffix.  `--spaces` argument is ignored.
+    #[clap(long)]
+    composite_only: bool,
+    /// Rather than decomposing the images, compare them against given image.
+    /// For each channel, the output shows per-pixel difference between
+    /// channel values of the two images rendered as a heat map going from
+    /// black (no difference) through red and yellow to white.  The other image
+    /// is resized and cropped the same way as the source images and must end
+    /// up with the same dimensions.  Output files are given ‘diff’ suffix.
+    #[clap(long, parse(from_os_str), value_name = "other")]
+    pub diff: Option<std::path::PathBuf>,
+    /// Rather than decomposing each image separately, generate for each colour
+    /// space a single image with decompositions of all the source images
+    /// stacked one beneath another in the order the files were given.  Each
+    /// decomposition is labelled with name of its file.  All images must end
+    /// up with the same dimensions after resizing and cropping.  Output files
+    /// are named after the first image and given ‘compare’ suffix.
+    #[clap(long, conflicts_with_all = &["diff", "multipage"])]
+    pub compare: bool,
+    /// Deduplicated list of colour spaces to generate images for.  Filled
+    /// based on `--spaces` arguments by [`Opts::collect_spaces`].
+    #[clap(skip)]
+    pub spaces: Vec<SpaceArg>,
+
+    /// Save resulting images in given format.  Supported formats are ‘webp’
+    /// (the default), ‘png’, ‘tiff’, ‘ppm’ (binary PPM which may be useful
+    /// when piping into other tools) and ‘ff’ (farbfeld; 8-bit values are
+    /// scaled to 16 bits and all pixels are opaque).
+    ///
+    /// Format can also be chosen for individual colour spaces with
+    /// ‘<space>=<format>’ entries, for example ‘--format png,lab=webp’ saves
+    /// Lab decomposition as WebP and all others as PNG.  Entries are separated
+    /// by commas and the option can be given multiple times; later entries
+    /// take precedence.
+    #[clap(short, long = "format", value_name = "format")]
+    format_lists: Vec<FormatList>,
+    /// Save decompositions in all colour spaces as pages of a single TIFF file
+    /// named after the source image rather than as separate files.  Requires
+    /// `--format=tiff`.
+    #[clap(long)]
+    pub multipage: bool,
+    /// Rather than rendering channels as side-by-side images, save raw values
+    /// of the channels as a planar buffer of little-endian 32-bit floats: all
+    /// values of the first channel in row-major order, followed by all values
+    /// of the second channel and so on.  Files get ‘f32’ extension and
+    /// `--format` is ignored.  This is meant for feeding the decomposition
+    /// into numeric tools rather than for viewing.
+    #[clap(long, conflicts_with_all = &["diff", "compare", "multipage"])]
+    pub planar: bool,
+    /// Render and encode decompositions one row at a time writing them
+    /// directly to the output so that whole decomposition image is never held
+    /// in memory.  This lowers peak memory usage when processing large
+    /// images.  Supported with ‘png’, ‘ppm’ and ‘farbfeld’ formats only and
+    /// has no effect in `--diff` or `--multipage` modes.  Streamed outputs
+    /// aren’t checked by `--skip-redundant`.
+    #[clap(long)]
+    pub stream: bool,
+    /// Record name of the source file in the output files.  PNG files get
+    /// an iTXt ‘Comment’ chunk and PPM files a header comment reading
+    /// ‘decomposed from <file>’.  WebP and TIFF files are saved without
+    /// metadata.  EXIF data of the source image is not copied.
+    #[clap(long)]
+    pub copy_metadata: bool,
+    /// Save decompositions in each colour space in a separate subdirectory
+    /// named after the space (e.g. ‘out/lab/image.webp’) rather than adding
+    /// name of the space to the file name (e.g. ‘out/image-lab.webp’).  The
+    /// subdirectories are created as needed.  Has no effect with
+    /// `--multipage`.
+    #[clap(long)]
+    pub group_by_space: bool,
+    /// Add given prefix to names of the output files.
+    #[clap(long, parse(from_os_str), value_name = "prefix")]
+    prefix: Option<std::ffi::OsString>,
+    /// Add given suffix to names of the output files.  The suffix is added
+    /// after name of the colour space and before the extension.
+    #[clap(long, parse(from_os_str), value_name = "suffix")]
+    suffix: Option<std::ffi::OsString>,
+    /// Case of colour space names used in names of the output files (and
+    /// subdirectories with `--group-by-space`).  Can be ‘as-is’ (the
+    /// default) which uses names such as ‘XYZ’ and ‘hsl’ unchanged, ‘lower’
+    /// or ‘upper’.
+    #[clap(long, default_value = "as-is", value_name = "case")]
+    pub name_case: NameCase,
+    /// What to do if output files of different source files have the same
+    /// name, e.g. when source files from different directories with the same
+    /// name are saved to a single `--out-dir`.  ‘error’ aborts before
+    /// processing any file, ‘warn’ (the default) reports the collision and
+    /// carries on (with later files overwriting or being skipped depending on
+    /// `-y` and `-i` flags) while ‘rename’ adds a counter to the names of
+    /// later files (e.g. ‘image-lab-2.webp’).
+    #[clap(long, default_value = "warn", value_name = "action")]
+    pub on_collision: Collision,
+
+    /// Save resulting WebP images with given quality.  Quality can be any
+    /// number from 0 to 100 or ‘lossless’ to save as a lossless WebP.  The
+    /// default quality is 90 unless different default is set in
+    /// IMAGE_DECOMPOSE_QUALITY environment variable.
+    #[clap(short, long)]
+    quality: Option<Quality>,
+    /// Whether `quality` was taken from IMAGE_DECOMPOSE_QUALITY environment
+    /// variable by [`Opts::collect_quality`] rather than given on command
+    /// line.
+    #[clap(skip)]
+    quality_from_env: bool,
+    /// Alias of ‘--quality=lossless’.
+    #[clap(long, overrides_with = "quality")]
+    lossless: bool,
+    /// Save WebP images of given colour space with given quality overriding
+    /// `--quality` and `--lossless` for that space.  The argument has
+    /// ‘<space>=<quality>’ format (e.g. ‘lab=100’) where quality is the same
+    /// as for `--quality`.  The option can be given multiple times; if a space
+    /// is listed more than once, the last value is used.
+    #[clap(long, value_name = "space=quality")]
+    space_quality: Vec<SpaceQuality>,
+    /// Dither channel values with given method (currently only ‘ordered’ is
+    /// supported) when rendering decomposition images saved as lossy WebP.
+    /// Dithering is applied while values are still at full precision and adds
+    /// fine noise which prevents smooth gradients (such as in hue channels)
+    /// from turning into visible bands at low `--quality`.  Has no effect on
+    /// lossless output, i.e. with ‘--quality=lossless’ (including spaces made
+    /// lossless with `--space-quality`) or formats other than WebP.
+    #[clap(long, value_name = "method")]
+    dither: Option<Dither>,
+    /// Compression effort used when saving WebP images, from 0 (fastest) to 6
+    /// (slowest but producing smallest files).  Higher effort pays off for
+    /// channel images which often consist of large flat regions.  If not
+    /// given, the encoder’s default is used.
+    #[clap(
+        long,
+        value_name = "effort",
+        possible_values = &["0", "1", "2", "3", "4", "5", "6"]
+    )]
+    webp_effort: Option<u8>,
+
+    /// Resize the source image to specified size.  The size is specified in
+    /// ‘<width>x<height>` format.  With ‘^’ suffix (e.g. ‘640x480^’), the
+    /// image is instead resized preserving its aspect ratio so that it fills
+    /// the box and then its centre is cropped to exactly the box.
+    ///
+    /// If specified together with `--crop`, resizing happens first.
+    ///
+    /// Note that if multiple images are specified, the resize operation will be
+    /// applied to all of them.  To resize different images to different sizes
+    /// use `--manifest`.
+    #[clap(long, value_name = "geometry")]
+    resize: Option<Geometry>,
+    /// Scale the source image by given factor preserving its aspect ratio.  The
+    /// factor can be given as a number (e.g. ‘0.5’) or a percentage (e.g.
+    /// ‘50%’).  Resulting image is at least one pixel wide and tall.
+    ///
+    /// If specified together with `--crop`, scaling happens first.
+    #[clap(long, conflicts_with = "resize")]
+    scale: Option<Scale>,
+    /// Before generating the decompositions, generate their quick previews
+    /// with the source image (after `--resize`, `--crop` etc.) scaled by
+    /// given factor and ask whether to proceed.  Previews are saved with
+    /// ‘-preview’ added to the file names (always overwriting existing
+    /// previews) and are removed once full resolution images are generated.
+    /// This allows dialling in the options on a big image without waiting
+    /// for full resolution output each time.  Files are processed one at
+    /// a time.
+    #[clap(
+        long,
+        value_name = "factor",
+        conflicts_with_all = &["zip", "compare", "multipage", "svg"]
+    )]
+    preview_scale: Option<Scale>,
+    /// Perform resampling done by `--resize`, `--scale` and `--tile-size` in
+    /// linear light rather than directly on gamma-encoded sRGB values.
+    /// Resampling encoded values darkens edges of high-contrast details; this
+    /// avoids the artefact at the cost of slower processing.
+    #[clap(long)]
+    linear_resize: bool,
+    /// Filter used for resampling done by `--resize`, `--scale` and
+    /// `--tile-size`.  Supported filters are ‘nearest’, ‘triangle’,
+    /// ‘catmull-rom’, ‘gaussian’, ‘lanczos2’ and ‘lanczos3’ (the default).
+    /// ‘lanczos2’ has a smaller radius than ‘lanczos3’ and thus produces less
+    /// ringing around sharp edges at the cost of slightly softer result.
+    #[clap(long, value_name = "filter", default_value = "lanczos3")]
+    resize_filter: ResizeFilter,
+    /// Model used to convert CMYK JPEG images into RGB before decomposition.
+    /// ‘naive’ (the default) multiplies complements of the inks which often
+    /// makes print-origin images look washed-out.  ‘gcr’ assumes the black
+    /// ink replaced the grey component of the other inks and adds it back
+    /// which gives darker and more saturated colours.  A warning is printed
+    /// whenever a CMYK image is converted.
+    #[clap(long, value_name = "model", default_value = "naive")]
+    cmyk_input_profile: CmykModel,
+    /// Never enlarge the source image with `--resize` or `--scale`.  If the
+    /// requested width or height exceeds that of the source image, the
+    /// source’s dimension is kept instead, so images are only ever shrunk to
+    /// fit the requested size (like ImageMagick’s ‘>’ geometry modifier).
+    #[clap(long)]
+    no_upscale: bool,
+    /// Apply unsharp mask of given strength to restore perceived detail
+    /// after downscaling.  The amount is how much of the difference between
+    /// the image and its blurred copy (with blur radius of one pixel) is
+    /// added back to the image and is clamped to range from 0 to 10.  Zero
+    /// (the default) disables sharpening.  Sharpening is done after
+    /// `--resize` or `--scale` but before `--crop`.
+    #[clap(long, default_value = "0", value_name = "amount")]
+    sharpen: f32,
+    /// Crop the source image according to the specified geometry.  The geometry
+    /// is in ‘<width>x<height>+<offset-x>+<offset-y>’ form.  The offset is
+    /// optional and if it’s not specified it’s assumed to be ‘+0+0’.  Either
+    /// coordinate of the offset can be negative to specify offset from the
+    /// other side of the image (e.g. `320x200-50+10` selects a rectangle 50
+    /// pixels from the right edge of the image and 10 pixels from the top).
+    ///
+    /// Note that if multiple images are specified, the cropping will be
+    /// performed on all of them.  To crop different images differently use
+    /// `--manifest`.
+    ///
+    /// The option can be given multiple times to decompose several regions of
+    /// each image.  In that case each region is processed separately and index
+    /// of the region is added to names of the output files (e.g.
+    /// ‘image-lab-r0.webp’, ‘image-lab-r1.webp’ and so on).  Multiple regions
+    /// cannot be used with `--compare`.
+    #[clap(long)]
+    crop: Vec<Crop>,
+    /// Trim uniform borders of the source image before it’s resized and
+    /// cropped.  Colour of the top-left pixel is taken as the colour of the
+    /// border and the image is cropped to the smallest rectangle containing
+    /// all pixels of a different colour.  Useful for scanned documents or
+    /// screenshots with wide margins.
+    #[clap(long)]
+    trim: bool,
+    /// Treat colours which differ from the border colour by at most given
+    /// percentage of the full range in every channel as the border colour
+    /// when trimming.  Zero (the default) requires exact match.  Has effect
+    /// only with `--trim`.
+    #[clap(long, default_value = "0", value_name = "percent")]
+    trim_fuzz: f32,
+
+    /// Invert colours of the channel images.  This may be useful to see where
+    /// a channel has low values.  The copy of the source image included in the
+    /// output is not affected.
+    #[clap(long)]
+    invert: bool,
+
+    /// Add a gradient strip beneath each opponent-axis channel (such as a* and
+    /// b* of CIELAB or u* and v* of CIELUV) showing how values of the channel
+    /// map to colours.  The strip spans the whole range of the axis within the
+    /// sRGB gamut, from the lowest value on the left to the highest on the
+    /// right.
+    #[clap(long, conflicts_with = "diff")]
+    legend: bool,
+
+    /// Colour of the area beneath channels which have no legend strip (and
+    /// beneath the copy of the source image).  The colour can be given as
+    /// ‘#rgb’, ‘#rrggbb’ or a basic CSS colour name such as ‘white’.  Black by
+    /// default.  Has effect only with `--legend`.
+    #[clap(long, value_name = "colour")]
+    background: Option<Color>,
+
+    /// Colour used in hue channels (of HSL, HSV, HWB, HSP and TSL spaces) for
+    /// pixels whose hue is undefined, i.e. shades of grey.  Black by default
+    /// which makes such pixels indistinguishable from dark hues; setting it to
+    /// e.g. ‘magenta’ flags them clearly.  Accepts the same forms as
+    /// `--background`.
+    #[clap(long, value_name = "colour")]
+    undefined_hue_color: Option<Color>,
+
+    /// Fill the area beneath channels which have no legend strip (and beneath
+    /// the copy of the source image) with mean colour of the source image.
+    /// Overrides `--background`.  Has effect only with `--legend`.
+    #[clap(long)]
+    pub background_from_average: bool,
+
+    /// Add a tile showing the image rebuilt by adding the channel tiles
+    /// together.  The tile is placed next to the copy of the source image (or
+    /// at the end if the copy is omitted).  Reconstruction is meaningful only
+    /// for additive spaces (rgb, adobe-rgb, display-p3 and rec2020) and is
+    /// skipped for all other spaces.  With wide-gamut spaces colours outside
+    /// of the sRGB gamut are clipped so the result is only approximate.
+    #[clap(long, conflicts_with = "diff")]
+    reconstruct: bool,
+
+    /// Add a tile with luminance of the source image (as defined by Rec. 709
+    /// and encoded with the sRGB transfer function) after the channel tiles
+    /// of every colour space.  This makes it easy to correlate channels of
+    /// a space with overall brightness of the image.
+    #[clap(long, conflicts_with = "diff")]
+    luma_tile: bool,
+
+    /// Add a black and white tile for given channel which is white where the
+    /// channel’s value exceeds the threshold.  The argument has form
+    /// ‘space:channel=value’ where channel is an index (starting from zero)
+    /// or name of the channel, e.g. ‘lab:0=50’ marks pixels whose L* is
+    /// above 50.  Values use the channel’s raw units as shown by --probe
+    /// (e.g. degrees for hue or 0–1 for RGB).  The option may be given
+    /// multiple times; each threshold adds its own tile after the channel
+    /// tiles of the space.
+    #[clap(long, value_name = "space:channel=value", conflicts_with = "diff")]
+    threshold: Vec<Threshold>,
+
+    /// Draw name of the channel (e.g. ‘a*’ for a* of Lab) in top-left corner
+    /// of each channel tile.  The names are the conventional names used by
+    /// each colour space.  Labels are scaled with the image and are omitted
+    /// for tiles which are too small to fit them.
+    #[clap(long)]
+    channel_labels: bool,
+
+    /// Decompose only pixels inside of the mask read from given image; the
+    /// rest of the channel tiles is filled with the background colour.  If
+    /// the mask has an alpha channel, pixels with alpha of at least 50% are
+    /// inside of the mask; otherwise pixels whose luminance is at least 50%
+    /// (i.e. white) are.  The mask is matched against the source image after
+    /// it is resized and cropped; if dimensions differ, the mask is scaled
+    /// (without preserving aspect ratio) to match.  Copy of the source image
+    /// is not masked.
+    #[clap(long, parse(from_os_str), value_name = "path")]
+    pub mask: Option<std::path::PathBuf>,
+    /// Mask loaded from `mask`.
+    #[clap(skip)]
+    pub mask_image: Option<image::GrayImage>,
+
+    /// Render opponent-axis channels (a and b of Lab and DIN99, u and v of
+    /// Luv) with given constant L* lightness from 0 to 100 rather than one
+    /// which grows with distance from the neutral axis.  This can make subtle
+    /// differences in chroma more legible.  Note that at high (or very low)
+    /// lightness many of the colours fall outside of the sRGB gamut and are
+    /// clipped, which flattens the extremes of the channels.
+    #[clap(long, value_name = "lightness")]
+    chroma_lightness: Option<f32>,
+
+    /// Apply gamma curve with given gamma from 0.1 to 10 to the channel tiles
+    /// after they are rendered.  Values above one brighten the midtones which
+    /// makes dark channels (such as b* of Lab or Z of XYZ) easier to see.
+    /// The copy of the source image is left untouched.  Note that the curve
+    /// makes tiles non-linear so their values can no longer be compared
+    /// directly.
+    #[clap(long, value_name = "gamma")]
+    display_gamma: Option<f32>,
+
+    /// Mark pixels whose colour in a channel tile falls outside of the sRGB
+    /// gamut, and would thus be silently clipped, with a warning colour.
+    /// Affects channels of wide-gamut RGB spaces (adobe-rgb, display-p3 and
+    /// rec2020) as well as xyY, UCS, Lab, Luv and DIN99 channels.  Legend
+    /// strips are never marked.
+    #[clap(long)]
+    gamut_warn: bool,
+
+    /// Colour to mark out-of-gamut pixels with when `--gamut-warn` is given.
+    /// Accepts the same forms as `--background`.  Magenta by default.
+    #[clap(long, value_name = "colour")]
+    gamut_warn_color: Option<Color>,
+
+    /// Where to put copy of the source image in the output relative to the
+    /// channel images.  Can be ‘first’ (the default), ‘last’ or ‘none’ to omit
+    /// the copy altogether.
+    #[clap(long, default_value = "first", value_name = "position")]
+    original_position: OriginalPosition,
+
+    /// Omit copy of the source image and lay the channel tiles out in the
+    /// most square grid possible (e.g. two by two for three or four tiles)
+    /// rather than in a single row.  Makes for compact previews of a colour
+    /// space.  Implies `--original-position=none`; unused cells of the grid
+    /// are filled with the `--background` colour.
+    #[clap(long, conflicts_with = "composite-only")]
+    grid: bool,
+
+    /// Composite source images with an alpha channel over a grey checkerboard
+    /// in the copy of the source image included in the output so that
+    /// transparent regions are visible.  Channel images are not affected.
+    #[clap(long)]
+    alpha_checker: bool,
+
+    /// Size of the checkerboard squares used by `--alpha-checker`.
+    #[clap(long, default_value = "8", value_name = "pixels")]
+    checker_size: u32,
+
+    /// Skip source images whose width or height is smaller than given
+    /// dimensions.  The size is specified in ‘<width>x<height>’ format and is
+    /// compared against dimensions of the image as loaded, i.e. before
+    /// `--resize`, `--scale` and `--crop` are applied.
+    #[clap(long, value_name = "size")]
+    min_size: Option<Dimensions>,
+
+    /// Resample every tile of the output (the copy of the source image and
+    /// each of the channels) to given size specified in ‘<width>x<height>’
+    /// format.  Unlike `--resize`, this happens after decomposition and thus
+    /// affects only presentation which is useful for uniform galleries of
+    /// large images.  Resampling uses `--resize-filter` and honours
+    /// `--linear-resize`.  Legend strips are resampled separately from their
+    /// tiles so colours of the two don’t bleed into each other.  Implies
+    /// that images aren’t encoded with `--stream`.
+    #[clap(long, value_name = "size")]
+    pub tile_size: Option<Dimensions>,
+
+    /// Don’t write output for a colour space if it’s identical to output
+    /// already generated for the same source image in another space.  This
+    /// is common for grey or low-colour images for which e.g. HSL and HSV
+    /// decompositions look the same.  When spaces are processed in parallel,
+    /// which of the identical outputs is kept is unspecified.
+    #[clap(long)]
+    pub skip_redundant: bool,
+
+    /// Report, for each channel of every colour space, how many distinct
+    /// values (as shown by --probe) the channel takes and thus how many bits
+    /// would be needed to represent it.  Low numbers reveal quantisation and
+    /// banding introduced by conversion into the space.  Pixels outside of
+    /// --mask are not counted.  The report is printed as informational
+    /// messages alongside regular output.
+    #[clap(long)]
+    pub effective_bits: bool,
+
+    /// Open the first written output file in the system’s default image
+    /// viewer.  Only a single file is opened even if many are generated.
+    /// The option is ignored unless standard output is a terminal so that it
+    /// never spawns a viewer in scripts or CI.
+    #[clap(long, conflicts_with = "zip")]
+    pub preview: bool,
+
+    /// Read source image given as ‘-’ from standard input as raw RGB24 pixel
+    /// data (three bytes per pixel, rows from top to bottom) of given
+    /// dimensions rather than as an encoded image.  The size is specified in
+    /// ‘<width>x<height>’ format and the input must contain exactly
+    /// `width * height * 3` bytes.  Output files are named after ‘stdin’.
+    #[clap(long, value_name = "size")]
+    pub raw: Option<Dimensions>,
+
+    /// Decompose thumbnail embedded in EXIF data of JPEG source images rather
+    /// than the full image.  Images without an embedded thumbnail (or with
+    /// one which cannot be decoded) are processed in full.
+    #[clap(long)]
+    pub use_embedded_thumbnail: bool,
+
+    /// Downscale source images whose width or height exceeds given number of
+    /// pixels so that they fit within such limit.  Aspect ratio of the image is
+    /// preserved.  The limit is applied after `--resize` and `--crop`.
+    #[clap(long, value_name = "pixels")]
+    max_dimension: Option<u32>,
+
+    /// Interpret source images as encoded in given colour profile.  Supported
+    /// profiles are ‘srgb’ (the default) and ‘display-p3’.  Images are
+    /// converted to sRGB before being decomposed with colours outside of sRGB
+    /// gamut being clipped.  ICC profiles embedded in the source files are
+    /// ignored.
+    #[clap(long, default_value = "srgb")]
+    assume_profile: Profile,
+
+    /// Don’t print informational messages.  Errors and warnings are still
+    /// reported.  Verbosity can be controlled in more detail with `RUST_LOG`
+    /// environment variable (e.g. ‘RUST_LOG=debug’ prints timing of each file)
+    /// which takes precedence over this flag.
+    #[clap(long)]
+    pub quiet: bool,
+
+    /// Stop processing on the first error.  By default, program carries on
+    /// with remaining files and colour spaces and reports failure through the
+    /// exit code once all of them have been processed.  With this flag, files
+    /// and colour spaces which haven’t been started yet when the first error
+    /// occurs are abandoned.
+    #[clap(long)]
+    pub fail_fast: bool,
+
+    /// Run at most given number of threads in parallel.  By default (or with
+    /// ‘auto’ value), program will run one thread per logical CPU core.
+    /// Specifying zero or one disables parallelism and processes files and
+    /// colour spaces one after another.  Both files and colour spaces are
+    /// processed in parallel (unless `--file-jobs` is given) so the number
+    /// limits total concurrency across the two.
+    #[clap(short, long, default_value = "auto")]
+    pub jobs: Jobs,
+
+    /// Process at most given number of files in parallel and process colour
+    /// spaces of each file one after another.  This limits number of
+    /// decompositions held in memory at the same time which may be necessary
+    /// when processing large images on machines with little memory.  With
+    /// ‘auto’ value, number of files processed in parallel is governed by
+    /// `--jobs`.
+    #[clap(long)]
+    pub file_jobs: Option<Jobs>,
+}
+
+/// Returns whether path has extension of a supported image format.
+pub fn is_image_path(path: &std::path::Path) -> bool {
+    image::ImageFormat::from_path(path).is_ok() ||
+        path.extension().map_or(false, |ext| ext == "ff")
+}
+
+/// Returns whether argument contains glob wildcard characters.  URLs are never
+/// treated as patterns.
+fn is_glob_pattern(arg: &str) -> bool {
+    !arg.starts_with("http://") &&
+        !arg.starts_with("https://") &&
+        arg.contains(|ch| matches!(ch, '*' | '?' | '['))
+}
+
+#[test]
+fn test_is_glob_pattern() {
+    assert!(is_glob_pattern("*.png"));
+    assert!(is_glob_pattern("photos/**/img?.jpg"));
+    assert!(is_glob_pattern("[ab].png"));
+    assert!(!is_glob_pattern("photo.png"));
+    assert!(!is_glob_pattern("-"));
+    assert!(!is_glob_pattern("https://example.com/img.png?size=big"));
+}
+
+impl Opts {
+    /// Fills `spaces` vector based on colour spaces given by the user.
+    /// If no colour spaces were given on command line, they are read from
+    /// IMAGE_DECOMPOSE_SPACES environment variable.  Returns error if the
+    /// variable has invalid value.
+    pub fn collect_spaces(&mut self) -> Result<(), std::string::String> {
+        const VAR: &str = "IMAGE_DECOMPOSE_SPACES";
+        if self.space_lists.is_empty() && !self.composite_only {
+            if let Some(value) = std::env::var_os(VAR) {
+                let list = value
+                    .to_str()
+                    .ok_or_else(|| std::string::String::from("invalid UTF-8"))
+                    .and_then(SpaceList::from_str)
+                    .map_err(|err| format!("{}: {}", VAR, err))?;
+                self.space_lists.push(list);
+            }
+        }
+        if self.composite_only &&
+            self.original_position.0 == super::spaces::OriginalPosition::None
+        {
+            return Err("--composite-only cannot be used with \
+                        --original-position=none"
+                .into());
+        }
+        let spaces = self.space_lists.drain(..).flat_map(|list| list.0);
+        self.spaces = if self.composite_only {
+            vec![SpaceArg(&super::spaces::ORIGINAL)]
+        } else {
+            dedup_spaces(spaces.collect())
+        };
+        Ok(())
+    }
+
+    /// Fills `--quality` from IMAGE_DECOMPOSE_QUALITY environment variable if
+    /// it wasn’t given on command line.  Returns error if the variable holds
+    /// invalid quality.
+    pub fn collect_quality(&mut self) -> Result<(), std::string::String> {
+        if self.quality.is_some() {
+            return Ok(());
+        }
+        if let Some(value) = std::env::var_os(QUALITY_VAR) {
+            let quality = value
+                .to_str()
+                .ok_or_else(|| std::string::String::from("invalid UTF-8"))
+                .and_then(Quality::from_str)
+                .map_err(|err| format!("{}: {}", QUALITY_VAR, err))?;
+            self.quality = Some(quality);
+            self.quality_from_env = true;
+        }
+        Ok(())
+    }
+
+    /// Reads `--manifest` file if one was given.  Returns options for each of
+    /// the entries with `files` holding just the entry’s file.  Returns empty
+    /// vector if no manifest was given.
+    pub fn read_manifest(&self) -> Result<Vec<Opts>, std::string::String> {
+        let path = match &self.manifest {
+            Some(path) => path,
+            None => return Ok(Vec::new()),
+        };
+        let data = std::fs::read_to_string(path)
+            .map_err(|err| format!("{}: {}", path.to_string_lossy(), err))?;
+        let base = path.parent().unwrap_or_else(|| std::path::Path::new(""));
+        let mut entries = Vec::new();
+        for (idx, line) in data.lines().enumerate() {
+            let line = line.trim_end_matches('\r');
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let entry = self.manifest_entry(base, line).map_err(|err| {
+                format!("{}:{}: {}", path.to_string_lossy(), idx + 1, err)
+            })?;
+            entries.push(entry);
+        }
+        Ok(entries)
+    }
+
+    /// Parses a single line of the manifest and returns options for it.
+    /// Relative paths are resolved against `base`.
+    fn manifest_entry(
+        &self,
+        base: &std::path::Path,
+        line: &str,
+    ) -> Result<Opts, std::string::String> {
+        let mut fields = line.split('\t');
+        let file = fields.next().unwrap_or_default();
+        if file.is_empty() {
+            return Err("missing file name".into());
+        }
+        let mut opts = self.clone();
+        opts.files = vec![base.join(file)];
+        let mut crop = Vec::new();
+        for field in fields.filter(|field| !field.is_empty()) {
+            let (key, value) = field
+                .split_once('=')
+                .ok_or_else(|| format!("expected ‘<key>=<value>’: {}", field))?;
+            let err = |err: &dyn std::fmt::Display| format!("{}: {}", key, err);
+            match key {
+                "out-dir" => opts.out_dir = Some(base.join(value)),
+                "spaces" if !opts.composite_only => {
+                    let list = SpaceList::from_str(value).map_err(|e| err(&e))?;
+                    opts.spaces = dedup_spaces(list.0);
+                }
+                "spaces" => (),
+                "resize" => {
+                    opts.resize =
+                        Some(Geometry::from_str(value).map_err(|e| err(&e))?);
+                    opts.scale = None;
+                }
+                "crop" => {
+                    crop.push(Crop::from_str(value).map_err(|e| err(&e))?)
+                }
+                _ => return Err(format!("unknown key ‘{}’", key)),
+            }
+        }
+        if !crop.is_empty() {
+            opts.crop = crop;
+        }
+        Ok(opts)
+    }
+
+    /// Lists images in `--from-tar` archive if one was given and sets `files`
+    /// to names of the entries.  Contents of the images are read only once
+    /// they are loaded.
+    pub fn read_tar(&mut self) -> Result<(), std::string::String> {
+        let path = match self.from_tar.clone() {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let archive = TarArchive::open(path.clone())
+            .map_err(|err| format!("{}: {}", path.to_string_lossy(), err))?;
+        self.files.extend(archive.entries.keys().cloned());
+        self.files.sort_by_key(|name| archive.entries[name].0);
+        if self.files.is_empty() {
+            pwarn!(path, "archive contains no images");
+        }
+        self.archive = Some(std::sync::Arc::new(archive));
+        Ok(())
+    }
+
+    /// Reads contents of given file from `--from-tar` archive.  Returns `None`
+    /// if the file doesn’t come from an archive.
+    pub fn archive_entry(
+        &self,
+        path: &std::path::Path,
+    ) -> Option<std::io::Result<Vec<u8>>> {
+        self.archive.as_ref()?.read(path)
+    }
+
+    /// Expands glob patterns in `files` if `--glob` was given.  Patterns
+    /// which match no files are dropped with a warning.
+    pub fn expand_globs(&mut self) {
+        if !self.glob {
+            return;
+        }
+        let files = std::mem::take(&mut self.files);
+        for file in files {
+            let pattern = match file.to_str() {
+                Some(pattern) if is_glob_pattern(pattern) => pattern,
+                _ => {
+                    self.files.push(file);
+                    continue;
+                }
+            };
+            let paths = match glob::glob(pattern) {
+                Ok(paths) => paths,
+                Err(err) => {
+                    pwarn!(file, "invalid pattern: {}", err);
+                    continue;
+                }
+            };
+            let count = self.files.len();
+            for path in paths {
+                match path {
+                    Ok(path) => self.files.push(path),
+                    Err(err) => pwarn!(err.path(), err.error()),
+                }
+            }
+            if self.files.len() == count {
+                pwarn!(file, "pattern matches no files");
+            }
+        }
+    }
+
+    /// Returns error if there are more files to process than allowed by
+    /// `--max-files`.
+    pub fn check_max_files(&self) -> Result<(), std::string::String> {
+        match self.max_files {
+            Some(max) if self.files.len() > max => Err(format!(
+                "{} files to process exceeds --max-files limit of {}",
+                self.files.len(),
+                max
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Warns if options which affect only WebP output were given on command
+    /// line while a different format is used.
+    pub fn warn_unused_quality(&self) {
+        // Quality set through the environment variable is just a default so
+        // don’t complain about it.
+        let explicit = self.quality.is_some() && !self.quality_from_env;
+        let webp_only = explicit ||
+            self.lossless ||
+            !self.space_quality.is_empty() ||
+            self.webp_effort.is_some() ||
+            self.dither.is_some();
+        if !self.uses_format(|format| format == Format::WebP) && webp_only {
+            log::warn!(
+                "--quality, --lossless, --space-quality, --webp-effort and \
+                 --dither have no effect with --format={}",
+                self.format().extension()
+            );
+        }
+    }
+
+    /// Warns about `--threshold` arguments for colour spaces which aren’t
+    /// among the `--spaces` since they have no effect.
+    pub fn warn_unused_thresholds(&self) {
+        for th in self.threshold.iter().map(|th| th.0) {
+            if !self.spaces.iter().any(|space| space.0.id == th.space) {
+                let name = th.space.space().name;
+                log::warn!(
+                    "--threshold for {} has no effect since {0} is not among \
+                     --spaces",
+                    name
+                );
+            }
+        }
+    }
+
+    /// Returns dithering method to render decomposition in given colour space
+    /// with.  Dithering is used only for images saved as lossy WebP.
+    pub fn dither(
+        &self,
+        space: &super::spaces::Space,
+    ) -> Option<super::spaces::Dither> {
+        let lossy = self.space_format(space) == Format::WebP &&
+            self.webp_quality(space).is_some();
+        self.dither.filter(|_| lossy).map(|dither| dither.0)
+    }
+
+    /// Returns quality WebP images of given colour space should be saved with
+    /// or `None` if they should be saved as lossless WebP.
+    fn webp_quality(&self, space: &super::spaces::Space) -> Option<f32> {
+        let q = self
+            .space_quality
+            .iter()
+            .rev()
+            .find(|sq| sq.0 .0.id == space.id)
+            .map(|sq| sq.1 .0);
+        let lossless = q.is_none() && self.lossless;
+        let default = self.quality.as_ref().map_or(90.0, |q| q.0);
+        let q = q.unwrap_or(default);
+        if lossless || q == f32::INFINITY {
+            None
+        } else {
+            Some(q.clamp(0.0, 100.0))
+        }
+    }
+
+    pub fn encode_webp(
+        &self,
+        enc: webp::Encoder,
+        space: &super::spaces::Space,
+    ) -> webp::WebPMemory {
+        match self.webp_quality(space) {
+            Some(q) => enc.encode(q),
+            None => enc.encode_lossless(),
+        }
+    }
+
+    /// Returns model to convert CMYK source images into RGB with.
+    pub fn cmyk_model(&self) -> super::spaces::CmykModel {
+        self.cmyk_input_profile.0
+    }
+
+    /// Returns prefix to add to names of the output files.
+    pub fn name_prefix(&self) -> &[u8] { os_str_bytes(&self.prefix) }
+
+    /// Returns suffix to add to names of the output files.
+    pub fn name_suffix(&self) -> &[u8] { os_str_bytes(&self.suffix) }
+
+    /// Returns whether files should be processed serially.
+    pub fn serial_files(&self) -> bool {
+        // Prompts of the preview pass mustn’t interleave.
+        if self.preview_scale.is_some() {
+            return true;
+        }
+        match self.file_jobs {
+            Some(Jobs::Count(num)) => num == 1,
+            _ => self.jobs == Jobs::Count(1),
+        }
+    }
+
+    /// Returns whether colour spaces of a single file should be processed
+    /// serially.
+    pub fn serial_spaces(&self) -> bool {
+        self.file_jobs.is_some() || self.jobs == Jobs::Count(1)
+    }
+
+    /// Returns settings affecting how the decomposition images are rendered.
+    pub fn settings(&self) -> super::spaces::Settings {
+        super::spaces::Settings {
+            invert: self.invert,
+            legend: self.legend,
+            original: if self.grid {
+                super::spaces::OriginalPosition::None
+            } else {
+                self.original_position.0
+            },
+            background: self.background.map_or([0, 0, 0], |color| color.0),
+            undefined_hue: self
+                .undefined_hue_color
+                .map_or([0, 0, 0], |color| color.0),
+            reconstruct: self.reconstruct,
+            luma_tile: self.luma_tile,
+            chroma_lightness: self
+                .chroma_lightness
+                .map(|l| if l.is_nan() { 50.0 } else { l.clamp(0.0, 100.0) }),
+            gamut_warning: if self.gamut_warn {
+                let color = self.gamut_warn_color.map(|color| color.0);
+                Some(color.unwrap_or([255, 0, 255]))
+            } else {
+                None
+            },
+            grid: self.grid,
+            display_gamma: self
+                .display_gamma
+                .filter(|gamma| !gamma.is_nan())
+                .map(|gamma| gamma.clamp(0.1, 10.0)),
+            thresholds: self.threshold.iter().map(|th| th.0).collect(),
+            // Mask depends on dimensions of the source image so it’s set by
+            // the caller.
+            mask: None,
+            channel_labels: self.channel_labels,
+            // Dithering depends on format of the image so it’s set by the
+            // caller.
+            dither: None,
+        }
+    }
+
+    /// Warns if `--copy-metadata` was given while a format which cannot carry
+    /// metadata is used.
+    pub fn warn_unused_metadata(&self) {
+        if self.copy_metadata &&
+            !self.uses_format(|fmt| matches!(fmt, Format::Png | Format::Ppm))
+        {
+            log::warn!(
+                "--copy-metadata has no effect with --format={}",
+                self.format().extension()
+            );
+        }
+    }
+
+    /// Returns format to save images in unless overridden for specific colour
+    /// space.
+    pub fn format(&self) -> Format {
+        self.format_lists
+            .iter()
+            .flat_map(|list| list.0.iter())
+            .filter(|(space, _)| space.is_none())
+            .last()
+            .map_or(Format::WebP, |&(_, format)| format)
+    }
+
+    /// Returns format to save images of given colour space in.
+    pub fn space_format(&self, space: &super::spaces::Space) -> Format {
+        self.format_lists
+            .iter()
+            .flat_map(|list| list.0.iter())
+            .filter(|(arg, _)| arg.map_or(true, |arg| arg.0.id == space.id))
+            .last()
+            .map_or(Format::WebP, |&(_, format)| format)
+    }
+
+    /// Returns whether format was given for any specific colour space.
+    pub fn has_space_formats(&self) -> bool {
+        self.format_lists
+            .iter()
+            .any(|list| list.0.iter().any(|(space, _)| space.is_some()))
+    }
+
+    /// Returns whether any of the requested colour spaces is saved in format
+    /// matching given predicate.
+    pub fn uses_format(&self, pred: impl Fn(Format) -> bool) -> bool {
+        self.spaces.iter().any(|space| pred(self.space_format(space.0)))
+    }
+
+    /// Encodes RGB image with decomposition in given colour space in the
+    /// output format and writes it to `out`.  If given, the comment is stored
+    /// in the file if the format supports it.
+    pub fn encode(
         &self,
-        file: &std::path::Path,
-    ) -> std::result::Result<bool, (&'static str, std::io::Error)> {
-        let mut buf = Vec::<u8>::new();
-        loop {
-            if let Err(err) = write_prompt(std::io::stdout().lock(), file) {
-                break Err(("stdout", err));
+        mut out: impl std::io::Write + std::io::Seek,
+        img: &image::RgbImage,
+        space: &super::spaces::Space,
+        comment: Option<&str>,
+    ) -> std::io::Result<()> {
+        fn other(err: impl std::fmt::Display) -> std::io::Error {
+            std::io::Error::new(std::io::ErrorKind::Other, err.to_string())
+        }
+
+        let (width, height) = img.dimensions();
+        let data = img.as_raw().as_slice();
+        match self.space_format(space) {
+            Format::WebP => {
+                if let Some(effort) = self.webp_effort {
+                    let quality = self.webp_quality(space);
+                    return encode_webp_with_effort(
+                        data, width, height, quality, effort,
+                    )
+                    .ok_or_else(|| other("error encoding WebP image"))
+                    .and_then(|data| out.write_all(&data));
+                }
+                let enc = webp::Encoder::from_rgb(data, width, height);
+                out.write_all(&self.encode_webp(enc, space))
+            }
+            Format::Png => png_writer(out, width, height, comment)?
+                .write_image_data(data)
+                .map_err(other),
+            Format::Tiff => image::tiff::TiffEncoder::new(out)
+                .encode(data, width, height, image::ColorType::Rgb8)
+                .map_err(other),
+            Format::Ppm => {
+                write_ppm_header(&mut out, width, height, comment)?;
+                out.write_all(data)
+            }
+            Format::Farbfeld => {
+                write_farbfeld_header(&mut out, width, height)?;
+                for row in data.chunks_exact(width as usize * 3) {
+                    write_farbfeld_row(&mut out, row)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Encodes decomposition image in the output format rendering and writing
+    /// it one row at a time.  The format must support streaming.
+    pub fn encode_rows(
+        &self,
+        mut out: impl std::io::Write,
+        rows: &super::spaces::Decomposition,
+        space: &super::spaces::Space,
+        comment: Option<&str>,
+    ) -> std::io::Result<()> {
+        let (width, height) = rows.dimensions();
+        let mut row = vec![0; width as usize * 3];
+        match self.space_format(space) {
+            Format::Png => {
+                let mut writer = png_writer(out, width, height, comment)?;
+                let mut stream = writer.stream_writer();
+                for y in 0..height {
+                    rows.render_row(y, &mut row);
+                    stream.write_all(&row)?;
+                }
+                stream.finish()?;
+            }
+            Format::Ppm => {
+                write_ppm_header(&mut out, width, height, comment)?;
+                for y in 0..height {
+                    rows.render_row(y, &mut row);
+                    out.write_all(&row)?;
+                }
+            }
+            Format::Farbfeld => {
+                write_farbfeld_header(&mut out, width, height)?;
+                for y in 0..height {
+                    rows.render_row(y, &mut row);
+                    write_farbfeld_row(&mut out, &row)?;
+                }
+            }
+            Format::WebP | Format::Tiff => unreachable!(),
+        }
+        Ok(())
+    }
+
+    /// Returns whether image is smaller than `--min-size` and should be
+    /// skipped.
+    pub fn is_below_min_size(&self, img: &image::DynamicImage) -> bool {
+        let (width, height) = img.dimensions();
+        self.min_size
+            .as_ref()
+            .map_or(false, |min| width < min.width() || height < min.height())
+    }
+
+    pub fn resize_image(
+        &self,
+        img: image::DynamicImage,
+    ) -> image::DynamicImage {
+        let (src_width, src_height) = img.dimensions();
+        let (width, height) = match (&self.resize, &self.scale) {
+            (Some(geo), _) => geo.resize_dimensions(src_width, src_height),
+            (None, Some(Scale(scale))) => {
+                let scale =
+                    |dim: u32| ((dim as f32 * scale).round() as u32).max(1);
+                (scale(src_width), scale(src_height))
             }
-            buf.clear();
-            if let Err(err) =
-                std::io::stdin().lock().read_until(b'\n', &mut buf)
-            {
-                break Err(("stdin", err));
-            } else if buf.is_empty() {
-                println!("N");
-                break Ok(false);
+            (None, None) => return img,
+        };
+        let (width, height) = if !self.no_upscale {
+            (width, height)
+        } else if self.resize.as_ref().map_or(false, |geo| geo.fill) {
+            // Clamping each dimension separately would distort the image.
+            if width > src_width || height > src_height {
+                (src_width, src_height)
+            } else {
+                (width, height)
             }
-            while !buf.is_empty() &&
-                (buf[buf.len() - 1] == b'\n' || buf[buf.len() - 1] == b'\r')
-            {
-                buf.pop();
+        } else {
+            (width.min(src_width), height.min(src_height))
+        };
+        let img = if (width, height) == (src_width, src_height) {
+            img
+        } else if self.linear_resize {
+            let img = super::spaces::resize_linear(
+                &img.to_rgb8(),
+                width,
+                height,
+                self.resize_filter.0,
+            );
+            image::DynamicImage::ImageRgb8(img)
+        } else if let Some(filter) = self.resize_filter.0.filter_type() {
+            img.resize_exact(width, height, filter)
+        } else {
+            let img = super::spaces::resize_rgba_lanczos2(
+                &img.to_rgba8(),
+                width,
+                height,
+            );
+            image::DynamicImage::ImageRgba8(img)
+        };
+        match &self.resize {
+            Some(geo) => geo.crop_image(img),
+            None => img,
+        }
+    }
+
+    /// Returns filter used for resampling images.
+    pub fn resize_filter(&self) -> super::spaces::ResizeFilter {
+        self.resize_filter.0
+    }
+
+    /// Returns whether resampling should be performed in linear light.
+    pub fn linear_resize(&self) -> bool { self.linear_resize }
+
+    /// Returns number of regions of each source image to decompose.  This is
+    /// the number of `--crop` options or one if there were none.
+    pub fn regions(&self) -> usize { self.crop.len().max(1) }
+
+    /// Crops the image to region with given index.  Returns the image
+    /// unchanged if no `--crop` options were given.
+    pub fn crop_image(
+        &self,
+        img: image::DynamicImage,
+        region: usize,
+    ) -> image::DynamicImage {
+        match self.crop.get(region) {
+            Some(crop) => crop.crop_image(img),
+            None => img,
+        }
+    }
+
+    pub fn limit_image_dimensions(
+        &self,
+        file: &std::path::Path,
+        img: image::DynamicImage,
+    ) -> image::DynamicImage {
+        let limit = match self.max_dimension {
+            Some(limit) => limit.max(1),
+            None => return img,
+        };
+        let (width, height) = img.dimensions();
+        if width <= limit && height <= limit {
+            return img;
+        }
+        let img = img.resize(limit, limit, image::imageops::Lanczos3);
+        let (w, h) = img.dimensions();
+        super::pinfo!(
+            file,
+            "downscaling from {}x{} to {}x{}",
+            width,
+            height,
+            w,
+            h
+        );
+        img
+    }
+
+    /// Applies to the image all the resizing and cropping requested by the
+    /// user.  `preview_pass` tells whether the preview pass of
+    /// `--preview-scale` is being done in which case the image is also
+    /// scaled by the preview factor.
+    pub fn resize_and_crop_image(
+        &self,
+        file: &std::path::Path,
+        i: image::DynamicImage,
+        region: usize,
+        preview_pass: bool,
+    ) -> image::DynamicImage {
+        let img = self.sharpen_image(self.resize_image(self.trim_image(i)));
+        let img = self.crop_image(img, region);
+        let img = self.limit_image_dimensions(file, img);
+        if preview_pass {
+            self.scale_for_preview(img)
+        } else {
+            img
+        }
+    }
+
+    /// Returns whether previews should be generated with `--preview-scale`
+    /// before full resolution images.
+    pub fn has_preview_pass(&self) -> bool { self.preview_scale.is_some() }
+
+    /// Scales the image by `--preview-scale` factor.
+    fn scale_for_preview(
+        &self,
+        img: image::DynamicImage,
+    ) -> image::DynamicImage {
+        let scale = match &self.preview_scale {
+            Some(Scale(scale)) if *scale < 1.0 => *scale,
+            _ => return img,
+        };
+        let (width, height) = img.dimensions();
+        let scale = |dim: u32| ((dim as f32 * scale).round() as u32).max(1);
+        let filter = image::imageops::FilterType::Triangle;
+        img.resize_exact(scale(width), scale(height), filter)
+    }
+
+    /// Crops uniform borders of the image if `--trim` was given.
+    fn trim_image(&self, img: image::DynamicImage) -> image::DynamicImage {
+        if !self.trim {
+            return img;
+        }
+        // Comparison is false for NaN so it’s treated as zero as well.
+        let fuzz = if self.trim_fuzz > 0.0 {
+            self.trim_fuzz.min(100.0)
+        } else {
+            0.0
+        };
+        let tolerance = (fuzz * 2.55).round() as u8;
+        match content_bounds(&img.to_rgba8(), tolerance) {
+            Some((x, y, width, height)) => img.crop_imm(x, y, width, height),
+            None => img,
+        }
+    }
+
+    fn sharpen_image(&self, img: image::DynamicImage) -> image::DynamicImage {
+        // Comparison is false for NaN so it’s treated as zero as well.
+        if self.sharpen > 0.0 {
+            let amount = self.sharpen.min(10.0);
+            let img =
+                super::spaces::unsharp_mask(&img.to_rgba8(), 1.0, amount);
+            image::DynamicImage::ImageRgba8(img)
+        } else {
+            img
+        }
+    }
+
+    /// Returns image composited over a checkerboard if `--alpha-checker` was
+    /// given and the image has an alpha channel.
+    pub fn checker_composite(
+        &self,
+        img: &image::DynamicImage,
+    ) -> Option<image::RgbImage> {
+        if self.alpha_checker && img.color().has_alpha() {
+            let img = img.to_rgba8();
+            Some(super::spaces::composite_over_checker(
+                &img,
+                self.checker_size,
+            ))
+        } else {
+            None
+        }
+    }
+
+    pub fn convert_profile(&self, mut img: image::RgbImage) -> image::RgbImage {
+        match self.assume_profile {
+            Profile::Srgb => (),
+            Profile::DisplayP3 => super::spaces::srgb_from_display_p3(&mut img),
+        }
+        img
+    }
+}
+
+#[test]
+fn test_manifest_entry() {
+    let mut opts = <Opts as Clap>::parse_from(&["image-decompose", "a.png"]);
+    opts.collect_spaces().unwrap();
+
+    let base = std::path::Path::new("");
+    let line = "b.png\tout-dir=out\tspaces=hsl,lab\tcrop=10x10+5+5";
+    let entry = opts.manifest_entry(base, line).unwrap();
+    assert_eq!(vec![std::path::PathBuf::from("b.png")], entry.files);
+    assert_eq!(Some(std::path::PathBuf::from("out")), entry.out_dir);
+    assert_eq!(
+        vec!["hsl", "lab"],
+        entry.spaces.iter().map(|space| space.0.name).collect::<Vec<_>>()
+    );
+    assert_eq!(1, entry.crop.len());
+
+    let entry = opts.manifest_entry(base, "c.png\tresize=64x64^").unwrap();
+    assert!(entry.resize.map_or(false, |geo| geo.fill));
+    assert_eq!(opts.spaces.len(), entry.spaces.len());
+
+    assert!(opts.manifest_entry(base, "\tspaces=hsl").is_err());
+    assert!(opts.manifest_entry(base, "d.png\tcolour=red").is_err());
+    assert!(opts.manifest_entry(base, "d.png\tresize").is_err());
+    assert!(opts.manifest_entry(base, "d.png\tspaces=bogus").is_err());
+
+    // Paths are relative to the manifest.
+    let base = std::path::Path::new("dir");
+    let entry = opts.manifest_entry(base, "e.png\tout-dir=out").unwrap();
+    assert_eq!(vec![std::path::PathBuf::from("dir/e.png")], entry.files);
+    assert_eq!(Some(std::path::PathBuf::from("dir/out")), entry.out_dir);
+    let entry = opts.manifest_entry(base, "/e.png").unwrap();
+    assert_eq!(vec![std::path::PathBuf::from("/e.png")], entry.files);
+}
+
+#[test]
+fn test_check_max_files() {
+    let args = ["image-decompose", "--max-files=2", "a.png", "b.png"];
+    let mut opts = <Opts as Clap>::parse_from(&args);
+    assert_eq!(Ok(()), opts.check_max_files());
+    opts.files.push("c.png".into());
+    let err = opts.check_max_files().unwrap_err();
+    assert!(err.starts_with("3 files"), "{}", err);
+
+    let opts = <Opts as Clap>::parse_from(&["image-decompose", "a.png"]);
+    assert_eq!(Ok(()), opts.check_max_files());
+}
+
+/// Writes planar channel values as little-endian 32-bit floats.
+pub fn write_planar(
+    mut out: impl std::io::Write,
+    data: &[f32],
+) -> std::io::Result<()> {
+    let mut buf = Vec::with_capacity(data.len() * 4);
+    for value in data {
+        buf.extend_from_slice(&value.to_le_bytes());
+    }
+    out.write_all(&buf)
+}
+
+/// Returns bounding box of pixels which differ from the top-left pixel by more
+/// than `tolerance` in any channel as `(x, y, width, height)` tuple.  Returns
+/// `None` if the image is empty or uniform.
+fn content_bounds(
+    img: &image::RgbaImage,
+    tolerance: u8,
+) -> Option<(u32, u32, u32, u32)> {
+    if img.width() == 0 || img.height() == 0 {
+        return None;
+    }
+    let background = img.get_pixel(0, 0).0;
+    let is_content = |pixel: &image::Rgba<u8>| {
+        pixel.0.iter().zip(background.iter()).any(|(&value, &bg)| {
+            (value as i32 - bg as i32).abs() > tolerance as i32
+        })
+    };
+    let (mut left, mut top) = (u32::MAX, u32::MAX);
+    let (mut right, mut bottom) = (0, 0);
+    for (x, y, pixel) in img.enumerate_pixels() {
+        if is_content(pixel) {
+            left = left.min(x);
+            top = top.min(y);
+            right = right.max(x);
+            bottom = bottom.max(y);
+        }
+    }
+    if left > right {
+        None
+    } else {
+        Some((left, top, right - left + 1, bottom - top + 1))
+    }
+}
+
+#[test]
+fn test_content_bounds() {
+    let white = image::Rgba([255, 255, 255, 255]);
+    let mut img = image::RgbaImage::from_pixel(6, 5, white);
+    assert_eq!(None, content_bounds(&img, 0));
+    assert_eq!(None, content_bounds(&image::RgbaImage::new(0, 0), 0));
+
+    img.put_pixel(2, 1, image::Rgba([0, 0, 0, 255]));
+    img.put_pixel(3, 3, image::Rgba([250, 255, 255, 255]));
+    assert_eq!(Some((2, 1, 2, 3)), content_bounds(&img, 0));
+    assert_eq!(Some((2, 1, 1, 1)), content_bounds(&img, 5));
+    assert_eq!(None, content_bounds(&img, 255));
+}
+
+/// Encodes RGB image as WebP using libwebp’s advanced API which, unlike the
+/// `webp` crate, lets the compression method be chosen.  `effort` is the
+/// method from 0 (fastest) to 6 (smallest output).  If `quality` is `None`,
+/// the image is encoded losslessly.  Returns `None` on failure.
+fn encode_webp_with_effort(
+    data: &[u8],
+    width: u32,
+    height: u32,
+    quality: Option<f32>,
+    effort: u8,
+) -> Option<Vec<u8>> {
+    use libwebp_sys as sys;
+
+    // Start with libwebp’s defaults for lossy or, if quality isn’t given,
+    // lossless compression (level 6 is libwebp’s default) and only override
+    // the compression method.
+    // SAFETY: WebPConfig is a plain C struct for which zero is a valid value.
+    // The struct is zeroed so that padding has defined value.
+    let mut config: sys::WebPConfig = unsafe { std::mem::zeroed() };
+    let ok = unsafe {
+        sys::WebPConfigInitInternal(
+            &mut config,
+            sys::WebPPreset::WEBP_PRESET_DEFAULT,
+            quality.unwrap_or(75.0),
+            sys::WEBP_ENCODER_ABI_VERSION as _,
+        ) != 0 &&
+            (quality.is_some() ||
+                sys::WebPConfigLosslessPreset(&mut config, 6) != 0)
+    };
+    if !ok {
+        return None;
+    }
+    config.method = effort.min(6) as _;
+
+    // SAFETY: The structs are initialised by libwebp functions before use,
+    // `data` holds `width * height` RGB pixels and the picture and writer are
+    // freed before returning.
+    unsafe {
+        if sys::WebPValidateConfig(&config) == 0 {
+            return None;
+        }
+        let mut picture: sys::WebPPicture = std::mem::zeroed();
+        if sys::WebPPictureInitInternal(
+            &mut picture,
+            sys::WEBP_ENCODER_ABI_VERSION as _,
+        ) == 0
+        {
+            return None;
+        }
+        let mut writer: sys::WebPMemoryWriter = std::mem::zeroed();
+        sys::WebPMemoryWriterInit(&mut writer);
+        picture.use_argb = quality.is_none() as _;
+        picture.width = width as _;
+        picture.height = height as _;
+        picture.writer = Some(sys::WebPMemoryWrite);
+        picture.custom_ptr = &mut writer as *mut _ as *mut _;
+        let ok = sys::WebPPictureImportRGB(
+            &mut picture,
+            data.as_ptr(),
+            (width * 3) as _,
+        ) != 0 &&
+            sys::WebPEncode(&config, &mut picture) != 0;
+        let res = if ok {
+            Some(std::slice::from_raw_parts(writer.mem, writer.size).to_vec())
+        } else {
+            None
+        };
+        sys::WebPPictureFree(&mut picture);
+        sys::WebPMemoryWriterClear(&mut writer);
+        res
+    }
+}
+
+/// Writes PNG header for an RGB image of given dimensions followed by an iTXt
+/// chunk with the comment if one is given.
+fn png_writer<W: std::io::Write>(
+    out: W,
+    width: u32,
+    height: u32,
+    comment: Option<&str>,
+) -> std::io::Result<png::Writer<W>> {
+    let mut enc = png::Encoder::new(out, width, height);
+    enc.set_color(png::ColorType::RGB);
+    enc.set_depth(png::BitDepth::Eight);
+    let mut writer = enc.write_header()?;
+    if let Some(comment) = comment {
+        // Keyword followed by uncompressed UTF-8 text with no language tag
+        // and no translated keyword.
+        let data = [&b"Comment\0\0\0\0\0"[..], comment.as_bytes()].concat();
+        writer.write_chunk(*b"iTXt", &data)?;
+    }
+    Ok(writer)
+}
+
+/// Encodes image as PNG.  Used to embed images in other documents.
+pub fn encode_png(img: &image::RgbImage) -> std::io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    png_writer(&mut buf, img.width(), img.height(), None)?
+        .write_image_data(img.as_raw())
+        .map_err(|err| {
+            std::io::Error::new(std::io::ErrorKind::Other, err.to_string())
+        })?;
+    Ok(buf)
+}
+
+/// Writes header of a binary PPM image of given dimensions.  The comment, if
+/// given, is included in the header with line breaks replaced by spaces.
+fn write_ppm_header(
+    mut out: impl std::io::Write,
+    width: u32,
+    height: u32,
+    comment: Option<&str>,
+) -> std::io::Result<()> {
+    out.write_all(b"P6\n")?;
+    if let Some(comment) = comment {
+        let comment = comment.replace(&['\n', '\r'][..], " ");
+        writeln!(out, "# {}", comment)?;
+    }
+    writeln!(out, "{} {}\n255", width, height)
+}
+
+/// Writes header of a farbfeld image of given dimensions.
+fn write_farbfeld_header(
+    mut out: impl std::io::Write,
+    width: u32,
+    height: u32,
+) -> std::io::Result<()> {
+    out.write_all(b"farbfeld")?;
+    out.write_all(&width.to_be_bytes())?;
+    out.write_all(&height.to_be_bytes())
+}
+
+/// Writes row of 8-bit RGB pixels as 16-bit big-endian RGBA farbfeld pixels.
+/// The values are scaled so that 255 maps to 65535 and alpha is opaque.
+fn write_farbfeld_row(
+    mut out: impl std::io::Write,
+    row: &[u8],
+) -> std::io::Result<()> {
+    let mut buf = Vec::with_capacity(row.len() / 3 * 8);
+    for pixel in row.chunks_exact(3) {
+        for &value in pixel {
+            buf.extend_from_slice(&[value, value]);
+        }
+        buf.extend_from_slice(&[255, 255]);
+    }
+    out.write_all(&buf)
+}
+
+#[test]
+fn test_write_farbfeld() {
+    let mut buf = Vec::new();
+    write_farbfeld_header(&mut buf, 2, 1).unwrap();
+    write_farbfeld_row(&mut buf, &[0, 0x12, 255, 1, 2, 3]).unwrap();
+    assert_eq!(
+        &b"farbfeld\0\0\0\x02\0\0\0\x01\
+           \0\0\x12\x12\xff\xff\xff\xff\
+           \x01\x01\x02\x02\x03\x03\xff\xff"[..],
+        &buf[..]
+    );
+}
+
+#[test]
+fn test_write_ppm_header() {
+    let header = |comment| {
+        let mut buf = Vec::new();
+        write_ppm_header(&mut buf, 3, 2, comment).unwrap();
+        String::from_utf8(buf).unwrap()
+    };
+    assert_eq!("P6\n3 2\n255\n", header(None));
+    assert_eq!(
+        "P6\n# decomposed from a b c.png\n3 2\n255\n",
+        header(Some("decomposed from a\rb\nc.png"))
+    );
+}
+
+fn os_str_bytes(value: &Option<std::ffi::OsString>) -> &[u8] {
+    value.as_ref().map_or(&[][..], |value| {
+        std::os::unix::ffi::OsStrExt::as_bytes(value.as_os_str())
+    })
+}
+
+
+pub enum Confirmer {
+    Skip(ExistingFiles),
+    Overwrite,
+    Interactive(std::sync::Mutex<ConfirmerInner>, ExistingFiles),
+}
+
+/// State of interactive confirmation.  `all` holds answer given for all the
+/// remaining files (with ‘a’ or ‘q’) if any.  Since it lives behind the mutex
+/// shared by all threads, once set it applies to all subsequent questions.
+#[allow(private_in_public)]
+struct ConfirmerInner {
+    all: Option<bool>,
+}
+
+/// Cache of names of files in directories output files are written to.
+/// Listing a directory once is much cheaper than checking existence of each
+/// existing output file separately, especially on network file systems.
+/// Directories which cannot be listed are marked with `None` in which case
+/// existence of each file is checked individually.
+#[allow(private_in_public)]
+#[derive(Default)]
+struct ExistingFiles(
+    std::sync::Mutex<
+        std::collections::HashMap<
+            std::path::PathBuf,
+            Option<std::collections::HashSet<std::ffi::OsString>>,
+        >,
+    >,
+);
+
+impl ExistingFiles {
+    /// Returns whether given file exists.  Names missing from the listing are
+    /// checked individually since the file may have been created by another
+    /// process after the listing or its name may differ only in case on
+    /// a case-insensitive file system.
+    fn contains(&self, file: &std::path::Path) -> bool {
+        let (dir, name) = match (file.parent(), file.file_name()) {
+            (Some(dir), Some(name)) => (dir, name),
+            _ => return file.exists(),
+        };
+        let mut dirs =
+            self.0.lock().unwrap_or_else(std::sync::PoisonError::into_inner);
+        let names = dirs
+            .entry(dir.to_path_buf())
+            .or_insert_with(|| list_directory(dir));
+        match names {
+            Some(names) if names.contains(name) => true,
+            _ => file.exists(),
+        }
+    }
+
+    /// Records that given file has been written.
+    fn insert(&self, file: &std::path::Path) {
+        let (dir, name) = match (file.parent(), file.file_name()) {
+            (Some(dir), Some(name)) => (dir, name),
+            _ => return,
+        };
+        let mut dirs =
+            self.0.lock().unwrap_or_else(std::sync::PoisonError::into_inner);
+        // If directory hasn’t been listed yet, listing it will find the file.
+        if let Some(Some(names)) = dirs.get_mut(dir) {
+            names.insert(name.to_os_string());
+        }
+    }
+}
+
+/// Returns names of all files in given directory.  Returns empty set if the
+/// directory doesn’t exist and `None` if it couldn’t be listed.
+fn list_directory(
+    dir: &std::path::Path,
+) -> Option<std::collections::HashSet<std::ffi::OsString>> {
+    let dir = if dir.as_os_str().is_empty() {
+        std::path::Path::new(".")
+    } else {
+        dir
+    };
+    match std::fs::read_dir(dir) {
+        Ok(entries) => entries
+            .map(|entry| entry.ok().map(|entry| entry.file_name()))
+            .collect(),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            Some(Default::default())
+        }
+        Err(_) => None,
+    }
+}
+
+#[test]
+fn test_existing_files() {
+    let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR"));
+    let existing = ExistingFiles::default();
+    assert!(existing.contains(&dir.join("Cargo.toml")));
+    assert!(existing.contains(&dir.join("src")));
+    assert!(!existing.contains(&dir.join("no-such-file.png")));
+    assert!(!existing.contains(&dir.join("no-such-dir").join("file.png")));
+
+    // Files created after the directory was listed are noticed.
+    let tmp = std::env::temp_dir()
+        .join(format!("image-decompose-existing-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&tmp);
+    std::fs::create_dir_all(&tmp).unwrap();
+    let (a, b) = (tmp.join("a.png"), tmp.join("b.png"));
+    assert!(!existing.contains(&a));
+    std::fs::write(&a, b"").unwrap();
+    assert!(existing.contains(&a));
+    // Files written by us are added to the listing.
+    existing.insert(&b);
+    let names = existing.0.lock().unwrap()[&tmp].clone().unwrap();
+    assert!(names.contains(b.file_name().unwrap()));
+    std::fs::remove_dir_all(&tmp).unwrap();
+}
+
+impl Confirmer {
+    pub fn new(opts: &Opts) -> Self {
+        if opts.skip_existing {
+            Self::Skip(Default::default())
+        } else if opts.yes {
+            Self::Overwrite
+        } else if opts.interactive {
+            Self::Interactive(
+                std::sync::Mutex::new(ConfirmerInner { all: None }),
+                Default::default(),
+            )
+        } else {
+            Self::Skip(Default::default())
+        }
+    }
+
+    /// Records that given file has been written so that later outputs with
+    /// the same name aren’t silently written over it.
+    pub fn written(&self, file: &std::path::Path) {
+        if let Self::Skip(existing) | Self::Interactive(_, existing) = self {
+            existing.insert(file);
+        }
+    }
+
+    pub fn confirm(&self, file: &std::path::Path) -> bool {
+        let existing = match self {
+            Self::Overwrite => return true,
+            Self::Skip(existing) | Self::Interactive(_, existing) => existing,
+        };
+        if !existing.contains(file) {
+            return true;
+        }
+        match self {
+            Self::Overwrite | Self::Skip(_) => (),
+            Self::Interactive(mutex, _) => {
+                let res = mutex
+                    .lock()
+                    .unwrap_or_else(std::sync::PoisonError::into_inner)
+                    .confirm(file);
+                match res {
+                    Ok(ans) => return ans,
+                    Err(err) => log::error!("{}", err),
+                }
             }
-            if buf == b"y" || buf == b"Y" {
-                break Ok(true);
-            } else if buf.is_empty() || buf == b"n" || buf == b"N" {
-                break Ok(false);
+        }
+        super::pwarn!(file, "file already exists, skipping");
+        false
+    }
+}
+
+/// Asks whether to generate full resolution images after previews of given
+/// file were generated with `--preview-scale`.  The default answer is yes;
+/// end of input or error reading it counts as no.
+pub fn confirm_full_pass(file: &std::path::Path) -> bool {
+    match ask(file, "previews written; generate full images? [Y/n]") {
+        Ok(Some(answer)) => matches!(answer.as_slice(), b"" | b"y" | b"Y"),
+        Ok(None) => {
+            println!("n");
+            false
+        }
+        Err(err) => {
+            log::error!("{}", err);
+            false
+        }
+    }
+}
+
+/// Prints given question about given file and reads answer from standard
+/// input.  Returns the answer without the trailing new line or `None` at end
+/// of input.  Errors are prefixed with name of the stream they occurred on.
+fn ask(
+    file: &std::path::Path,
+    question: &str,
+) -> std::io::Result<Option<Vec<u8>>> {
+    let stream_error = |name: &'static str| {
+        move |err: std::io::Error| {
+            std::io::Error::new(err.kind(), format!("{}: {}", name, err))
+        }
+    };
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    out.write_all(std::os::unix::ffi::OsStrExt::as_bytes(file.as_os_str()))
+        .and_then(|()| write!(out, ": {} ", question))
+        .and_then(|()| out.flush())
+        .map_err(stream_error("stdout"))?;
+    let mut buf = Vec::new();
+    let len = std::io::stdin()
+        .lock()
+        .read_until(b'\n', &mut buf)
+        .map_err(stream_error("stdin"))?;
+    if len == 0 {
+        return Ok(None);
+    }
+    while matches!(buf.last(), Some(b'\n') | Some(b'\r')) {
+        buf.pop();
+    }
+    Ok(Some(buf))
+}
+
+/// Parses answer to the overwrite prompt.  Returns whether to overwrite the
+/// file and whether the answer applies to all remaining files.  Returns `None`
+/// if the answer isn’t recognised.
+fn parse_answer(answer: &[u8]) -> Option<(bool, bool)> {
+    match answer {
+        b"y" | b"Y" => Some((true, false)),
+        b"" | b"n" | b"N" => Some((false, false)),
+        b"a" | b"A" => Some((true, true)),
+        b"q" | b"Q" | b"s" | b"S" => Some((false, true)),
+        _ => None,
+    }
+}
+
+#[test]
+fn test_parse_answer() {
+    assert_eq!(Some((true, false)), parse_answer(b"y"));
+    assert_eq!(Some((false, false)), parse_answer(b""));
+    assert_eq!(Some((false, false)), parse_answer(b"N"));
+    assert_eq!(Some((true, true)), parse_answer(b"a"));
+    assert_eq!(Some((false, true)), parse_answer(b"q"));
+    assert_eq!(Some((false, true)), parse_answer(b"s"));
+    assert_eq!(None, parse_answer(b"yes"));
+}
+
+impl ConfirmerInner {
+    fn confirm(&mut self, file: &std::path::Path) -> std::io::Result<bool> {
+        if let Some(all) = self.all {
+            return Ok(all);
+        }
+        loop {
+            let answer = match ask(file, "file exists, overwrite? [y/N/a/q]")? {
+                Some(answer) => answer,
+                None => {
+                    println!("N");
+                    return Ok(false);
+                }
+            };
+            if let Some((ans, all)) = parse_answer(&answer) {
+                if all {
+                    self.all = Some(ans);
+                }
+                return Ok(ans);
             }
         }
     }
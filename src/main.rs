@@ -1,7 +1,5 @@
 #![feature(
     maybe_uninit_write_slice,
-    process_exitcode_placeholder,
-    result_into_ok_or_err,
     slice_as_chunks,
     new_uninit,
     vec_into_raw_parts
@@ -16,7 +14,345 @@ mod cli;
 mod spaces;
 
 
-fn load(path: &std::path::PathBuf) -> Option<image::DynamicImage> {
+/// Returns whether path looks like an HTTP or HTTPS URL.
+fn is_url(path: &std::path::Path) -> bool {
+    let bytes = std::os::unix::ffi::OsStrExt::as_bytes(path.as_os_str());
+    bytes.starts_with(b"http://") || bytes.starts_with(b"https://")
+}
+
+/// Fetches image from given URL and decodes it.
+#[cfg(feature = "net")]
+fn fetch(
+    opts: &cli::Opts,
+    url: &std::path::Path,
+) -> Option<image::DynamicImage> {
+    let mut data = Vec::new();
+    let res = ureq::get(&url.to_string_lossy())
+        .call()
+        .map_err(|err| err.to_string())
+        .and_then(|resp| {
+            std::io::Read::read_to_end(&mut resp.into_reader(), &mut data)
+                .map_err(|err| err.to_string())
+        });
+    if let Err(err) = res {
+        perr!(url, err);
+        return None;
+    }
+    decode(opts, url, &data)
+}
+
+#[cfg(not(feature = "net"))]
+fn fetch(
+    _opts: &cli::Opts,
+    url: &std::path::Path,
+) -> Option<image::DynamicImage> {
+    perr!(url, "reading from URLs requires the ‘net’ feature");
+    None
+}
+
+/// Returns whether path refers to standard input.
+fn is_stdin(path: &std::path::Path) -> bool { path.as_os_str() == "-" }
+
+/// Reads raw RGB24 pixel data of given dimensions from standard input.
+fn load_raw(
+    path: &std::path::Path,
+    dim: Option<&cli::Dimensions>,
+) -> Option<image::DynamicImage> {
+    let dim = match dim {
+        Some(dim) => dim,
+        None => {
+            perr!(path, "reading from standard input requires --raw");
+            return None;
+        }
+    };
+    let mut data = Vec::new();
+    let mut stdin = std::io::stdin();
+    if let Err(err) = std::io::Read::read_to_end(&mut stdin, &mut data) {
+        perr!(path, err);
+        return None;
+    }
+    let (width, height) = (dim.width(), dim.height());
+    let want = width as u64 * height as u64 * 3;
+    if data.len() as u64 != want {
+        perr!(
+            path,
+            "expected {} bytes of raw RGB data for {}x{} image but got {}",
+            want,
+            width,
+            height,
+            data.len()
+        );
+        return None;
+    }
+    image::RgbImage::from_raw(width, height, data)
+        .map(image::DynamicImage::ImageRgb8)
+}
+
+/// Returns thumbnail embedded in EXIF data of a JPEG file.  Returns `None` if
+/// the file isn’t a JPEG or has no EXIF thumbnail.
+fn embedded_thumbnail(data: &[u8]) -> Option<&[u8]> {
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+    let mut pos = 2;
+    loop {
+        let marker = match data.get(pos..pos + 2)? {
+            &[0xFF, marker] => marker,
+            _ => return None,
+        };
+        // Metadata segments precede the image data so stop at start of scan
+        // or end of image.
+        if marker == 0xDA || marker == 0xD9 {
+            return None;
+        }
+        let len = data.get(pos + 2..pos + 4)?;
+        let len = u16::from_be_bytes([len[0], len[1]]) as usize;
+        let segment = data.get(pos + 4..pos + 2 + len)?;
+        if marker == 0xE1 && segment.starts_with(b"Exif\0\0") {
+            return exif_thumbnail(&segment[6..]);
+        }
+        pos += 2 + len;
+    }
+}
+
+/// Returns JPEG thumbnail referenced from the second IFD of EXIF data.
+fn exif_thumbnail(tiff: &[u8]) -> Option<&[u8]> {
+    let big_endian = match tiff.get(..4)? {
+        b"MM\0*" => true,
+        b"II*\0" => false,
+        _ => return None,
+    };
+    let read = |offset: usize, len: usize| -> Option<usize> {
+        let bytes = tiff.get(offset..offset.checked_add(len)?)?;
+        let fold = |acc: usize, &byte: &u8| acc << 8 | byte as usize;
+        Some(if big_endian {
+            bytes.iter().fold(0, fold)
+        } else {
+            bytes.iter().rev().fold(0, fold)
+        })
+    };
+    let ifd0 = read(4, 4)?;
+    let ifd1 = read(ifd0 + 2 + read(ifd0, 2)? * 12, 4)?;
+    if ifd1 == 0 {
+        return None;
+    }
+    let (mut offset, mut length) = (None, None);
+    for idx in 0..read(ifd1, 2)? {
+        let entry = ifd1 + 2 + idx * 12;
+        match read(entry, 2)? {
+            0x0201 => offset = read(entry + 8, 4),
+            0x0202 => length = read(entry + 8, 4),
+            _ => (),
+        }
+    }
+    tiff.get(offset?..offset?.checked_add(length?)?)
+}
+
+#[test]
+fn test_embedded_thumbnail() {
+    #[rustfmt::skip]
+    let tiff: &[u8] = &[
+        b'I', b'I', b'*', 0, 8, 0, 0, 0,
+        // IFD0 with no entries pointing to IFD1.
+        0, 0, 14, 0, 0, 0,
+        // IFD1 with thumbnail offset and length.
+        2, 0,
+        0x01, 0x02, 4, 0, 1, 0, 0, 0, 44, 0, 0, 0,
+        0x02, 0x02, 4, 0, 1, 0, 0, 0, 3, 0, 0, 0,
+        0, 0, 0, 0,
+        // Thumbnail.
+        1, 2, 3,
+    ];
+    assert_eq!(Some(&[1, 2, 3][..]), exif_thumbnail(tiff));
+
+    let mut jpeg = vec![0xFF, 0xD8, 0xFF, 0xE0, 0, 4, 0, 0, 0xFF, 0xE1];
+    jpeg.extend_from_slice(&(tiff.len() as u16 + 8).to_be_bytes());
+    jpeg.extend_from_slice(b"Exif\0\0");
+    jpeg.extend_from_slice(tiff);
+    jpeg.extend_from_slice(&[0xFF, 0xDA]);
+    assert_eq!(Some(&[1, 2, 3][..]), embedded_thumbnail(&jpeg));
+
+    // No IFD1 means no thumbnail.
+    let mut tiff = tiff.to_vec();
+    tiff[10] = 0;
+    assert_eq!(None, exif_thumbnail(&tiff));
+    assert_eq!(None, embedded_thumbnail(&jpeg[..8]));
+    assert_eq!(None, embedded_thumbnail(b"\x89PNG"));
+}
+
+/// Decodes thumbnail embedded in the file if there is one.  Returns `None`
+/// if the file has no thumbnail or it couldn’t be decoded.
+fn load_embedded_thumbnail(
+    path: &std::path::Path,
+) -> Option<image::DynamicImage> {
+    let data = std::fs::read(path).ok()?;
+    let img = embedded_thumbnail(&data)
+        .and_then(|thumb| image::load_from_memory(thumb).ok());
+    if img.is_none() {
+        pinfo!(path, "no embedded thumbnail; using full image");
+    }
+    img
+}
+
+/// Decodes farbfeld image, i.e. ‘farbfeld’ magic followed by big-endian
+/// width and height and 16-bit big-endian RGBA samples.  Returns `None` if
+/// the data is malformed.
+fn decode_farbfeld(data: &[u8]) -> Option<image::DynamicImage> {
+    let data = data.strip_prefix(b"farbfeld")?;
+    let dimension = |bytes: &[u8]| {
+        Some(u32::from_be_bytes(std::convert::TryInto::try_into(bytes).ok()?))
+    };
+    let width = dimension(data.get(0..4)?)?;
+    let height = dimension(data.get(4..8)?)?;
+    let samples = (width as u64 * height as u64).checked_mul(4)?;
+    let data = &data[8..];
+    if data.len() as u64 != samples.checked_mul(2)? {
+        return None;
+    }
+    let samples = data
+        .chunks_exact(2)
+        .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+        .collect();
+    image::ImageBuffer::from_raw(width, height, samples)
+        .map(image::DynamicImage::ImageRgba16)
+}
+
+#[test]
+fn test_decode_farbfeld() {
+    let mut data = b"farbfeld\0\0\0\x02\0\0\0\x01".to_vec();
+    data.extend_from_slice(&[0, 0, 0x12, 0x34, 0xff, 0xff, 0xff, 0xff]);
+    data.extend_from_slice(&[1, 1, 2, 2, 3, 3, 0x80, 0]);
+    let img = decode_farbfeld(&data).unwrap().to_rgba16();
+    assert_eq!((2, 1), img.dimensions());
+    assert_eq!(
+        &[0, 0x1234, 0xffff, 0xffff, 0x0101, 0x0202, 0x0303, 0x8000][..],
+        img.as_raw().as_slice()
+    );
+
+    assert!(decode_farbfeld(&data[..data.len() - 1]).is_none());
+    assert!(decode_farbfeld(&data[..12]).is_none());
+    assert!(decode_farbfeld(b"farbfelt\0\0\0\0\0\0\0\0").is_none());
+    let img = decode_farbfeld(b"farbfeld\0\0\0\0\0\0\0\0").unwrap();
+    assert_eq!((0, 0), image::GenericImageView::dimensions(&img));
+}
+
+/// Returns whether path has farbfeld extension.
+fn is_farbfeld(path: &std::path::Path) -> bool {
+    path.extension().map_or(false, |ext| ext.eq_ignore_ascii_case("ff"))
+}
+
+fn is_jpeg(path: &std::path::Path) -> bool {
+    path.extension().map_or(false, |ext| {
+        ext.eq_ignore_ascii_case("jpg") || ext.eq_ignore_ascii_case("jpeg")
+    })
+}
+
+/// Decodes JPEG image if it uses CMYK colour model converting it to RGB with
+/// model chosen with `--cmyk-input-profile`.  Returns `None` if data isn’t
+/// a CMYK JPEG (or it can’t be decoded) in which case it should be decoded
+/// as any other image.
+fn decode_cmyk_jpeg(
+    opts: &cli::Opts,
+    path: &std::path::Path,
+    data: &[u8],
+) -> Option<image::DynamicImage> {
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+    let mut decoder = jpeg_decoder::Decoder::new(data);
+    decoder.read_info().ok()?;
+    if decoder.info()?.pixel_format != jpeg_decoder::PixelFormat::CMYK32 {
+        return None;
+    }
+    let pixels = decoder.decode().ok()?;
+    let info = decoder.info()?;
+    let model = opts.cmyk_model();
+    pwarn!(
+        path,
+        "CMYK image; converting to RGB with {} model (see \
+         --cmyk-input-profile)",
+        model.name()
+    );
+    let mut rgb = Vec::with_capacity(pixels.len() / 4 * 3);
+    for &cmyk in pixels.as_chunks::<4>().0 {
+        rgb.extend_from_slice(&model.rgb(cmyk));
+    }
+    let (width, height) = (info.width as u32, info.height as u32);
+    image::RgbImage::from_raw(width, height, rgb)
+        .map(image::DynamicImage::ImageRgb8)
+}
+
+/// Decodes image held in memory.  `path` is used to recognise farbfeld images
+/// and in error messages; otherwise format is guessed from the data.
+fn decode(
+    opts: &cli::Opts,
+    path: &std::path::Path,
+    data: &[u8],
+) -> Option<image::DynamicImage> {
+    if is_farbfeld(path) {
+        return decode_farbfeld(data).or_else(|| {
+            perr!(path, "error decoding: malformed farbfeld image");
+            None
+        });
+    }
+    if let Some(img) = decode_cmyk_jpeg(opts, path, data) {
+        return Some(img);
+    }
+    match image::io::Reader::new(std::io::Cursor::new(data))
+        .with_guessed_format()
+        .map(|rd| rd.decode())
+    {
+        Err(e) => {
+            perr!(path, e);
+            None
+        }
+        Ok(Err(e)) => {
+            perr!(path, "error decoding: {}", e);
+            None
+        }
+        Ok(Ok(img)) => Some(img),
+    }
+}
+
+/// Reads whole file into memory and decodes it.  Used for formats which need
+/// special handling in `decode`, i.e. farbfeld and JPEG images.
+fn load_file(
+    opts: &cli::Opts,
+    path: &std::path::Path,
+) -> Option<image::DynamicImage> {
+    match std::fs::read(path) {
+        Err(e) => {
+            perr!(path, e);
+            None
+        }
+        Ok(data) => decode(opts, path, &data),
+    }
+}
+
+fn load(
+    opts: &cli::Opts,
+    path: &std::path::Path,
+) -> Option<image::DynamicImage> {
+    if is_url(path) {
+        return fetch(opts, path);
+    } else if is_stdin(path) {
+        return load_raw(path, opts.raw.as_ref());
+    } else if opts.use_embedded_thumbnail {
+        if let Some(img) = load_embedded_thumbnail(path) {
+            return Some(img);
+        }
+    }
+    if let Some(data) = opts.archive_entry(path) {
+        return match data {
+            Ok(data) => decode(opts, path, &data),
+            Err(err) => {
+                perr!(path, err);
+                None
+            }
+        };
+    } else if is_farbfeld(path) || is_jpeg(path) {
+        return load_file(opts, path);
+    }
     match image::io::Reader::open(path).map(|rd| rd.decode()) {
         Err(e) => {
             perr!(path, e);
@@ -37,6 +373,11 @@ fn output_directory<'a>(
 ) -> std::io::Result<std::borrow::Cow<'a, std::path::Path>> {
     if let Some(dir) = out_dir {
         Ok(std::borrow::Cow::Borrowed(dir.as_path()))
+    } else if is_url(src_file) {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "--out-dir is required when reading from URLs",
+        ))
     } else if let Some(parent) = src_file.parent() {
         Ok(std::borrow::Cow::Borrowed(parent))
     } else {
@@ -45,112 +386,2275 @@ fn output_directory<'a>(
 }
 
 
+/// Constructs path of the output file.  If `group_by_space` is set, the file
+/// is placed in a subdirectory named after the colour space; otherwise name of
+/// the space is appended to the file stem.  Prefix and suffix are added to the
+/// file name as is so they don’t need to be valid UTF-8.
 fn output_file_name(
-    space: &spaces::Space,
     out_dir: &std::path::Path,
     file_stem: &std::ffi::OsStr,
+    space_name: Option<&str>,
+    group_by_space: bool,
+    prefix: &[u8],
+    suffix: &[u8],
+    extension: &str,
 ) -> std::path::PathBuf {
     let bytes = std::os::unix::ffi::OsStrExt::as_bytes(file_stem);
-    let suffix = space.name;
-    let mut buf = Vec::<u8>::with_capacity(bytes.len() + suffix.len() + 6);
+    let (out_dir, name) = match space_name {
+        Some(name) if group_by_space => {
+            (std::borrow::Cow::Owned(out_dir.join(name)), "")
+        }
+        Some(name) => (std::borrow::Cow::Borrowed(out_dir), name),
+        None => (std::borrow::Cow::Borrowed(out_dir), ""),
+    };
+    let mut buf = Vec::<u8>::with_capacity(
+        prefix.len() +
+            bytes.len() +
+            name.len() +
+            suffix.len() +
+            extension.len() +
+            2,
+    );
+    buf.extend_from_slice(prefix);
     buf.extend_from_slice(bytes);
-    buf.push(b'-');
-    buf.extend_from_slice(suffix.as_bytes());
-    buf.extend_from_slice(b".webp");
+    if !name.is_empty() {
+        buf.push(b'-');
+        buf.extend_from_slice(name.as_bytes());
+    }
+    buf.extend_from_slice(suffix);
+    buf.push(b'.');
+    buf.extend_from_slice(extension.as_bytes());
     let file_name: std::ffi::OsString =
         std::os::unix::ffi::OsStringExt::from_vec(buf);
     out_dir.join(file_name)
 }
 
-
-fn process_file(
-    opts: &cli::Opts,
-    confirmer: &cli::Confirmer,
-    file: &std::path::PathBuf,
-) -> bool {
-    let out_dir = match output_directory(&opts.out_dir, file) {
-        Ok(dir) => dir,
-        Err(err) => {
-            perr!(file, "unable to determine parent directory: {}", err);
-            return false;
-        }
+#[test]
+fn test_output_file_name() {
+    let lab = Some("lab");
+    let dir = std::path::Path::new("out");
+    let stem = std::ffi::OsStr::new("img");
+    let name = |space, group, prefix: &[u8], suffix: &[u8]| {
+        output_file_name(dir, stem, space, group, prefix, suffix, "webp")
     };
-    let file_stem = match file.file_stem() {
-        Some(name) => name,
-        None => {
-            perr!(file, "unable to determine file stem");
-            return false;
-        }
+    let path = |bytes: &[u8]| -> std::path::PathBuf {
+        let path: &std::ffi::OsStr =
+            std::os::unix::ffi::OsStrExt::from_bytes(bytes);
+        path.into()
     };
-    eprintln!("Loading {}...", file.to_string_lossy());
-    let img = if let Some(img) = load(file) {
-        opts.resize_and_crop_image(img).to_rgb8()
+
+    assert_eq!(path(b"out/img-lab.webp"), name(lab, false, b"", b""));
+    assert_eq!(path(b"out/img.webp"), name(None, false, b"", b""));
+    assert_eq!(path(b"out/lab/img.webp"), name(lab, true, b"", b""));
+    assert_eq!(
+        path(b"out/v2_img-lab-diff.webp"),
+        name(lab, false, b"v2_", b"-diff")
+    );
+    assert_eq!(
+        path("out/zażółć-img-lab.webp".as_bytes()),
+        name(lab, false, "zażółć-".as_bytes(), b"")
+    );
+    // Affixes needn’t be valid UTF-8.
+    assert_eq!(
+        path(b"out/\xff-img-lab-\xfe.webp"),
+        name(lab, false, b"\xff-", b"-\xfe")
+    );
+}
+
+
+/// What happened to a single output file.
+enum Status {
+    Written,
+    Skipped,
+    Failed,
+    /// File wasn’t generated because processing was aborted after an earlier
+    /// error in `--fail-fast` mode.
+    Aborted,
+}
+
+/// Result of generating decomposition of a file in a single colour space.
+struct Outcome {
+    out_file: std::path::PathBuf,
+    status: Status,
+    /// Dimensions of the generated image.  `None` if no image was generated
+    /// or the file holds multiple images of different sizes.
+    dimensions: Option<(u32, u32)>,
+    /// Time spent generating and writing the file.
+    elapsed: std::time::Duration,
+}
+
+impl Outcome {
+    /// Creates outcome for a file which wasn’t generated.
+    fn new(out_file: std::path::PathBuf, status: Status) -> Self {
+        Self {
+            out_file,
+            status,
+            dimensions: None,
+            elapsed: std::time::Duration::default(),
+        }
+    }
+}
+
+/// Encoded output file.
+struct Encoded {
+    /// Encoded file or `None` if it has already been streamed to the output
+    /// with `--stream`.
+    data: Option<Vec<u8>>,
+    /// Dimensions of the encoded image or `None` if the file holds multiple
+    /// images.
+    dimensions: Option<(u32, u32)>,
+    /// Whether identical output has already been generated for the same
+    /// source in another colour space.  Such file is skipped with
+    /// `--skip-redundant`.
+    redundant: bool,
+}
+
+
+/// Set once any error occurs.  Tasks check it to stop early in `--fail-fast`
+/// mode.
+static FAILED: std::sync::atomic::AtomicBool =
+    std::sync::atomic::AtomicBool::new(false);
+
+/// Records that an error occurred.
+fn note_failure() { FAILED.store(true, std::sync::atomic::Ordering::Relaxed); }
+
+/// Set once an output file has been opened for `--preview`.
+static PREVIEWED: std::sync::atomic::AtomicBool =
+    std::sync::atomic::AtomicBool::new(false);
+
+/// Opens written output file in the default viewer if `--preview` was given
+/// and no other file has been opened yet.  Files are generated in parallel so
+/// whichever is written first wins.
+fn preview(opts: &cli::Opts, out_file: &std::path::Path) {
+    use std::io::IsTerminal;
+    if !opts.preview ||
+        opts.planar ||
+        !std::io::stdout().is_terminal() ||
+        PREVIEWED.swap(true, std::sync::atomic::Ordering::Relaxed)
+    {
+        return;
+    }
+    if let Err(err) = opener::open(out_file) {
+        pwarn!(out_file, "cannot open preview: {}", err);
+    }
+}
+
+/// Total time, in nanoseconds, spent across all threads decoding source images
+/// and preparing them (resizing, cropping and so on) for decomposition.
+/// Reported with `--profile`.
+static DECODE_NANOS: std::sync::atomic::AtomicU64 =
+    std::sync::atomic::AtomicU64::new(0);
+static PREPARE_NANOS: std::sync::atomic::AtomicU64 =
+    std::sync::atomic::AtomicU64::new(0);
+
+/// With `--print-outputs` or `--print-outputs0`, prints path of written
+/// output file terminated with a new line or NUL byte respectively.  The
+/// path is written in a single locked write so paths printed by different
+/// threads don’t interleave.
+fn print_output(opts: &cli::Opts, out_file: &std::path::Path) {
+    let terminator = if opts.print_outputs0 {
+        b'\0'
+    } else if opts.print_outputs {
+        b'\n'
     } else {
-        return false;
+        return;
     };
-    let errors = opts
-        .spaces
-        .par_iter()
-        .filter(|space| {
-            let out_file =
-                output_file_name(space.0, out_dir.as_ref(), file_stem);
-            if !confirmer.confirm(&out_file) {
-                return true;
-            }
-            eprintln!("Generating {}...", out_file.to_string_lossy());
-            let (width, height, img) =
-                if let Some(res) = spaces::build_image(space.0, &img) {
-                    res
-                } else {
-                    let (w, h) = img.dimensions();
-                    perr!(file, "image too large ({}x{})", w, h);
-                    return false;
-                };
-            let enc =
-                opts.encode(webp::Encoder::from_rgb(&img[..], width, height));
-            if let Err(err) = std::fs::File::create(&out_file)
-                .and_then(|mut fd| fd.write_all(&enc))
+    let path = std::os::unix::ffi::OsStrExt::as_bytes(out_file.as_os_str());
+    let mut buf = Vec::with_capacity(path.len() + 1);
+    buf.extend_from_slice(path);
+    buf.push(terminator);
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    if let Err(err) = out.write_all(&buf).and_then(|()| out.flush()) {
+        log::error!("stdout: {}", err);
+    }
+}
+
+/// Adds duration to a total counter.
+fn add_time(total: &std::sync::atomic::AtomicU64, time: std::time::Duration) {
+    let nanos = time.as_nanos().min(u128::from(u64::MAX)) as u64;
+    total.fetch_add(nanos, std::sync::atomic::Ordering::Relaxed);
+}
+
+/// Returns total duration recorded in a counter.
+fn total_time(total: &std::sync::atomic::AtomicU64) -> std::time::Duration {
+    let nanos = total.load(std::sync::atomic::Ordering::Relaxed);
+    std::time::Duration::from_nanos(nanos)
+}
+
+/// Prints, with `--profile`, how long each phase of processing of a file
+/// took.  `outcomes` are the outcomes for each of the output files.
+fn print_file_profile(
+    opts: &cli::Opts,
+    file: &std::path::Path,
+    decode: std::time::Duration,
+    prepare: std::time::Duration,
+    outcomes: &[Outcome],
+) {
+    let mut line = format!(
+        "{}: decode {:.2?}, prepare {:.2?}",
+        file.to_string_lossy(),
+        decode,
+        prepare
+    );
+    for (idx, outcome) in outcomes.iter().enumerate() {
+        let name = outcome_space(opts, idx).map_or("multipage", |s| s.name);
+        line.push_str(&format!(", {} {:.2?}", name, outcome.elapsed));
+    }
+    eprintln!("{}", line);
+}
+
+/// Prints, with `--profile`, time spent in each phase of processing summed
+/// over all files.  `reports` are outcomes for each of the files together with
+/// options the file was processed with.  Because files and spaces are
+/// processed in parallel, the totals may exceed the wall-clock time which is
+/// printed as well.
+fn print_total_profile<'a>(
+    reports: impl Iterator<Item = (&'a cli::Opts, &'a Option<Vec<Outcome>>)>,
+    wall: std::time::Duration,
+) {
+    let mut totals = Vec::<(&str, std::time::Duration)>::new();
+    for (opts, outcomes) in reports {
+        let outcomes = match outcomes {
+            Some(outcomes) => outcomes,
+            None => continue,
+        };
+        for (idx, outcome) in outcomes.iter().enumerate() {
+            let name = outcome_space(opts, idx).map_or("multipage", |s| s.name);
+            match totals.iter_mut().find(|(n, _)| *n == name) {
+                Some((_, total)) => *total += outcome.elapsed,
+                None => totals.push((name, outcome.elapsed)),
+            }
+        }
+    }
+    eprintln!("total: decode {:.2?}", total_time(&DECODE_NANOS));
+    eprintln!("total: prepare {:.2?}", total_time(&PREPARE_NANOS));
+    for (name, total) in totals {
+        eprintln!("total: {} {:.2?}", name, total);
+    }
+    eprintln!("total: wall-clock {:.2?}", wall);
+}
+
+/// Returns whether remaining work should be abandoned because an error already
+/// occurred and `--fail-fast` was given.
+fn is_aborted(opts: &cli::Opts) -> bool {
+    opts.fail_fast && FAILED.load(std::sync::atomic::Ordering::Relaxed)
+}
+
+
+/// Destination the generated images are saved to.
+enum Output {
+    /// Each image is saved in a separate file.
+    Files(cli::Confirmer),
+    /// All images are added to a single ZIP archive.  The writer isn’t thread
+    /// safe so adding of the files is serialised.
+    Zip(std::sync::Mutex<zip::ZipWriter<std::io::BufWriter<std::fs::File>>>),
+}
+
+impl Output {
+    /// Checks whether output file should be written.  Always returns `true`
+    /// if images are saved into an archive.
+    fn confirm(&self, out_file: &std::path::Path) -> bool {
+        match self {
+            Self::Files(confirmer) => confirmer.confirm(out_file),
+            Self::Zip(_) => true,
+        }
+    }
+
+    /// Saves data as given output file.  When writing to an archive, the file
+    /// name is used as name of the entry in the archive.
+    fn write(
+        &self,
+        out_file: &std::path::Path,
+        data: &[u8],
+    ) -> std::io::Result<()> {
+        match self {
+            Self::Files(confirmer) => {
+                // With --group-by-space, the per-space subdirectory may not
+                // exist yet.
+                if let Some(dir) = out_file.parent() {
+                    create_dir_all(dir)?;
+                }
+                std::fs::write(out_file, data)?;
+                confirmer.written(out_file);
+                Ok(())
+            }
+            Self::Zip(mutex) => {
+                let mut archive = mutex
+                    .lock()
+                    .unwrap_or_else(std::sync::PoisonError::into_inner);
+                let options = zip::write::FileOptions::default()
+                    .compression_method(zip::CompressionMethod::Stored);
+                archive.start_file(out_file.to_string_lossy(), options)?;
+                archive.write_all(data)
+            }
+        }
+    }
+
+    /// Writes given output file using given function which gets a buffered
+    /// writer to the destination.  The function reports its own errors and
+    /// returns `None` on failure.  Files are first written under a temporary
+    /// name so an incomplete file never replaces existing output; archive
+    /// entries are rendered into memory before being added.  Reports I/O
+    /// errors and returns `None` on failure.
+    fn write_with<T>(
+        &self,
+        out_file: &std::path::Path,
+        write: impl FnOnce(&mut dyn std::io::Write) -> Option<T>,
+    ) -> Option<T> {
+        match self {
+            Self::Files(confirmer) => {
+                let tmp = temporary_path(out_file);
+                let res = stream_to_file(&tmp, write).and_then(|res| {
+                    if res.is_some() {
+                        std::fs::rename(&tmp, out_file)?;
+                        confirmer.written(out_file);
+                    }
+                    Ok(res)
+                });
+                let res = res.unwrap_or_else(|err| {
+                    perr!(out_file, err);
+                    None
+                });
+                if res.is_none() {
+                    let _ = std::fs::remove_file(&tmp);
+                }
+                res
+            }
+            Self::Zip(_) => {
+                // Render the file before locking the archive so that other
+                // threads aren’t blocked meanwhile and a failure doesn’t
+                // leave a truncated entry behind.
+                let mut data = Vec::new();
+                let res = write(&mut data)?;
+                if let Err(err) = self.write(out_file, &data) {
+                    perr!(out_file, err);
+                    return None;
+                }
+                Some(res)
+            }
+        }
+    }
+
+    /// Generates an output file using given function and returns outcome of
+    /// the operation.  The function is called only if file is to be written
+    /// and processing hasn’t been aborted.  Outputs of the preview pass of
+    /// `--preview-scale`, as indicated by `preview_pass`, are temporary and
+    /// aren’t printed.
+    fn generate(
+        &self,
+        opts: &cli::Opts,
+        preview_pass: bool,
+        out_file: std::path::PathBuf,
+        generate: impl FnOnce(&std::path::Path) -> Option<Encoded>,
+    ) -> Outcome {
+        if is_aborted(opts) {
+            return Outcome::new(out_file, Status::Aborted);
+        } else if !self.confirm(&out_file) {
+            return Outcome::new(out_file, Status::Skipped);
+        }
+        let start = std::time::Instant::now();
+        let mut dimensions = None;
+        let status = if let Some(encoded) = generate(&out_file) {
+            dimensions = encoded.dimensions;
+            if encoded.redundant {
+                pinfo!(
+                    out_file,
+                    "skipping; identical to output in another colour space"
+                );
+                Status::Skipped
+            } else if let Err(err) = encoded
+                .data
+                .map_or(Ok(()), |data| self.write(&out_file, &data))
             {
                 perr!(out_file, err);
-                false
+                Status::Failed
+            } else {
+                if let Self::Files(_) = self {
+                    if !preview_pass {
+                        print_output(opts, &out_file);
+                    }
+                    preview(opts, &out_file);
+                }
+                Status::Written
+            }
+        } else {
+            Status::Failed
+        };
+        if let Status::Failed = status {
+            note_failure();
+        }
+        let elapsed = start.elapsed();
+        Outcome { out_file, status, dimensions, elapsed }
+    }
+}
+
+
+/// Returns path of a hidden temporary file in the same directory as given
+/// output file.  Renaming it to the output file is then atomic.
+fn temporary_path(out_file: &std::path::Path) -> std::path::PathBuf {
+    let mut name = std::ffi::OsString::from(".");
+    name.push(out_file.file_name().unwrap_or_default());
+    name.push(".tmp");
+    out_file.with_file_name(name)
+}
+
+/// Creates given file, creating its parent directory if necessary, and writes
+/// it using given function which gets a buffered writer.  Returns result of
+/// the function.
+fn stream_to_file<T>(
+    path: &std::path::Path,
+    write: impl FnOnce(&mut dyn std::io::Write) -> Option<T>,
+) -> std::io::Result<Option<T>> {
+    if let Some(dir) = path.parent() {
+        create_dir_all(dir)?;
+    }
+    let mut wr = std::io::BufWriter::new(std::fs::File::create(path)?);
+    let res = write(&mut wr);
+    if res.is_some() {
+        wr.flush()?;
+    }
+    Ok(res)
+}
+
+/// Creates directory and all its parents if they don’t exist.  Threads writing
+/// outputs may race creating the same directory; losing the race isn’t an
+/// error so long as the directory exists afterwards.
+fn create_dir_all(dir: &std::path::Path) -> std::io::Result<()> {
+    match std::fs::create_dir_all(dir) {
+        Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+            if dir.is_dir() {
+                Ok(())
             } else {
-                true
+                Err(err)
             }
+        }
+        res => res,
+    }
+}
+
+#[test]
+fn test_create_dir_all() {
+    let base = std::env::temp_dir()
+        .join(format!("image-decompose-test-{}", std::process::id()));
+    let dir = base.join("a").join("b").join("c");
+    let dirs = vec![dir.as_path(); 16];
+    let results = map_items(false, &dirs, |dir| create_dir_all(dir).is_ok());
+    assert!(results.iter().all(|&ok| ok));
+    assert!(dir.is_dir());
+
+    let file = base.join("file");
+    std::fs::write(&file, b"").unwrap();
+    assert!(create_dir_all(&file).is_err());
+    std::fs::remove_dir_all(&base).unwrap();
+}
+
+/// Maps all items of a slice, in parallel unless `serial` is true, and returns
+/// results in the order of the items.
+fn map_items<T: Sync, R: Send>(
+    serial: bool,
+    items: &[T],
+    func: impl Fn(&T) -> R + Sync + Send,
+) -> Vec<R> {
+    if serial {
+        items.iter().map(func).collect()
+    } else {
+        items.par_iter().map(func).collect()
+    }
+}
+
+#[test]
+fn test_deterministic_output() {
+    // Output must not depend on the number of threads used.  All encoders,
+    // including libwebp which runs single-threaded with the configuration we
+    // use, produce identical bytes for identical input so outputs are compared
+    // directly rather than after decoding.
+    let path = concat!(env!("CARGO_MANIFEST_DIR"), "/data/umbrella-sky.jpg");
+    let img = image::open(path).unwrap().thumbnail(64, 64).to_rgb8();
+    let settings = spaces::Settings { legend: true, ..Default::default() };
+    let encode_all = |format: &str, serial: bool| -> Vec<Vec<u8>> {
+        let args = ["image-decompose", "--format", format, path];
+        let opts = <cli::Opts as clap::Clap>::parse_from(&args);
+        map_items(serial, &spaces::SPACES[..], |space| {
+            let img = spaces::build_rgb_image(space, &img, &settings).unwrap();
+            let mut out = std::io::Cursor::new(Vec::new());
+            opts.encode(&mut out, &img, space, None).unwrap();
+            out.into_inner()
         })
-        .count();
-    errors == 0
+    };
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+    for format in &["webp", "png", "tiff", "ppm", "ff"] {
+        let want = encode_all(format, true);
+        assert!(want == encode_all(format, true), "{}: not repeatable", format);
+        let got = pool.install(|| encode_all(format, false));
+        assert!(want == got, "{}: output depends on threads", format);
+    }
 }
 
-fn main() -> std::process::ExitCode {
-    let mut opts = <cli::Opts as clap::Clap>::parse();
-    if let Some(dir) = &opts.out_dir {
-        if let Err(err) = std::fs::create_dir_all(dir) {
-            perr!(dir, err);
-            return std::process::ExitCode::FAILURE;
+
+/// Source image being decomposed.
+struct Source<'a> {
+    file: &'a std::path::Path,
+    img: image::RgbImage,
+    /// Image used for the copy of the source if it differs from `img`; with
+    /// `--alpha-checker` this is the image composited over a checkerboard.
+    original: Option<image::RgbImage>,
+    /// Image the source is compared against in `--diff` mode.
+    other: Option<image::RgbImage>,
+    /// Mean colour of the source image used as background with
+    /// `--background-from-average`.
+    background: Option<[u8; 3]>,
+    /// Mask scaled to dimensions of the image if `--mask` was given.
+    mask: Option<std::sync::Arc<image::GrayImage>>,
+    /// Outputs generated so far, keyed by their hashes, used to detect
+    /// identical outputs with `--skip-redundant`.  Shared by threads
+    /// processing the spaces.
+    outputs: std::sync::Mutex<std::collections::HashMap<u64, Vec<Vec<u8>>>>,
+}
+
+impl<'a> Source<'a> {
+    fn new(
+        opts: &cli::Opts,
+        file: &'a std::path::Path,
+        img: image::RgbImage,
+        original: Option<image::RgbImage>,
+        other: Option<image::RgbImage>,
+    ) -> Self {
+        let background = if opts.background_from_average {
+            Some(spaces::average_colour(&img))
+        } else {
+            None
+        };
+        let mask = opts.mask_image.as_ref().map(|mask| {
+            std::sync::Arc::new(spaces::fit_mask(mask, img.dimensions()))
+        });
+        Self {
+            file,
+            img,
+            original,
+            other,
+            background,
+            mask,
+            outputs: Default::default(),
+        }
+    }
+
+    /// With `--effective-bits`, reports number of distinct values in each
+    /// channel of the image in given colour space.
+    fn report_effective_bits(&self, opts: &cli::Opts, space: &spaces::Space) {
+        if !opts.effective_bits {
+            return;
+        }
+        let mask = self.mask.as_deref();
+        let counts = spaces::distinct_channel_values(space, &self.img, mask);
+        let report = space
+            .channel_names()
+            .iter()
+            .zip(counts)
+            .map(|(name, count)| {
+                let bits = spaces::effective_bits(count);
+                format!("{} {} bits ({} values)", name, bits, count)
+            })
+            .collect::<Vec<_>>();
+        pinfo!(self.file, "{}: {}", space.name, report.join(", "));
+    }
+
+    /// With `--skip-redundant`, records encoded output and returns whether
+    /// identical output has already been generated from this source.  Always
+    /// returns `false` without the flag.
+    fn is_redundant(&self, opts: &cli::Opts, data: &[u8]) -> bool {
+        if !opts.skip_redundant {
+            return false;
+        }
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        std::hash::Hasher::write(&mut hasher, data);
+        let hash = std::hash::Hasher::finish(&hasher);
+        // The hash is only a lookup key; contents are compared so that
+        // a collision doesn’t cause an output to be dropped.
+        let mut outputs = self
+            .outputs
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        let seen = outputs.entry(hash).or_default();
+        if seen.iter().any(|other| other[..] == *data) {
+            true
+        } else {
+            seen.push(data.to_vec());
+            false
+        }
+    }
+
+    /// Returns settings affecting how the decomposition images are rendered.
+    fn settings(&self, opts: &cli::Opts) -> spaces::Settings {
+        let mut settings = opts.settings();
+        if let Some(background) = self.background {
+            settings.background = background;
+        }
+        settings.mask = self.mask.clone();
+        settings
+    }
+
+    /// Builds decomposition of the source image in given colour space.  In
+    /// `--diff` mode, builds image showing differences between decompositions
+    /// of the source and the other image.  Reports error and returns `None` on
+    /// failure.
+    fn decompose(
+        &self,
+        opts: &cli::Opts,
+        space: &spaces::Space,
+    ) -> Option<image::RgbImage> {
+        let mut settings = self.settings(opts);
+        settings.dither = opts.dither(space);
+        let res = if let Some(other) = &self.other {
+            spaces::build_diff_image(space, &self.img, other, &settings)
+        } else if let Some(original) = &self.original {
+            spaces::build_image_with_original(
+                space, &self.img, original, &settings,
+            )
+        } else {
+            spaces::build_image(space, &self.img, &settings)
+        };
+        let res = res.and_then(spaces::image_from_raw);
+        let res = match (res, &opts.tile_size) {
+            (Some(img), Some(size)) => spaces::resize_tiles(
+                &img,
+                spaces::tile_dimensions(space, &self.img, &settings),
+                spaces::tile_legend_height(space, &self.img, &settings),
+                (size.width(), size.height()),
+                opts.resize_filter(),
+                opts.linear_resize(),
+            ),
+            (res, _) => res,
+        };
+        if res.is_none() {
+            self.report_too_large();
+        }
+        res
+    }
+
+    /// Encodes decomposition of the image in given colour space rendering it
+    /// one row at a time.  Not supported in `--diff` mode.  Returns dimensions
+    /// of the image.  Reports error and returns `None` on failure.
+    fn encode_rows(
+        &self,
+        opts: &cli::Opts,
+        space: &spaces::Space,
+        out: impl std::io::Write,
+        out_file: &std::path::Path,
+    ) -> Option<(u32, u32)> {
+        let settings = self.settings(opts);
+        let original = self.original.as_ref().unwrap_or(&self.img);
+        let rows =
+            spaces::Decomposition::new(space, &self.img, original, &settings);
+        let rows = rows.or_else(|| {
+            self.report_too_large();
+            None
+        })?;
+        let comment = self.comment(opts);
+        let res = opts.encode_rows(out, &rows, space, comment.as_deref());
+        if let Err(err) = res {
+            perr!(out_file, err);
+            None
+        } else {
+            Some(rows.dimensions())
+        }
+    }
+
+    /// Returns comment to store in the output files with `--copy-metadata`.
+    fn comment(&self, opts: &cli::Opts) -> Option<String> {
+        if opts.copy_metadata {
+            let name = self.file.file_name().unwrap_or(self.file.as_os_str());
+            Some(format!("decomposed from {}", name.to_string_lossy()))
+        } else {
+            None
         }
     }
-    if opts.spaces.is_empty() {
-        opts.spaces.extend(spaces::SPACES.iter().map(cli::SpaceArg));
+
+    fn report_too_large(&self) {
+        let (w, h) = self.img.dimensions();
+        perr!(self.file, "image too large ({}x{})", w, h);
+    }
+}
+
+
+/// Applies to loaded image all the transformations requested by the user.
+/// With `--alpha-checker`, if the image has an alpha channel, also returns the
+/// image composited over a checkerboard.  `preview_pass` tells whether the
+/// preview pass of `--preview-scale` is being done.
+fn prepare_source(
+    opts: &cli::Opts,
+    file: &std::path::Path,
+    img: image::DynamicImage,
+    region: usize,
+    preview_pass: bool,
+) -> (image::RgbImage, Option<image::RgbImage>) {
+    let img = opts.resize_and_crop_image(file, img, region, preview_pass);
+    let original = opts
+        .checker_composite(&img)
+        .map(|original| opts.convert_profile(original));
+    (opts.convert_profile(img.to_rgb8()), original)
+}
+
+
+/// Determines paths of output files for given source file.  Returns the paths
+/// in the order of `opts.spaces` or a single path in `--multipage` mode.  With
+/// multiple `--crop` regions, the paths for each of the regions follow one
+/// another.  Reports error and returns `None` on failure.
+fn output_files(
+    opts: &cli::Opts,
+    file: &std::path::Path,
+) -> Option<Vec<std::path::PathBuf>> {
+    let out_dir = if opts.zip.is_some() {
+        std::borrow::Cow::Borrowed(std::path::Path::new(""))
     } else {
-        opts.spaces
-            .sort_by_key(|space| space.0 as *const _ as usize);
-        opts.spaces.dedup_by_key(|space| space.0 as *const _);
+        match output_directory(&opts.out_dir, file) {
+            Ok(dir) => dir,
+            Err(err) => {
+                perr!(file, "unable to determine parent directory: {}", err);
+                return None;
+            }
+        }
+    };
+    let file_stem = match file.file_stem() {
+        _ if is_stdin(file) => std::ffi::OsStr::new("stdin"),
+        Some(name) => name,
+        None => {
+            perr!(file, "unable to determine file stem");
+            return None;
+        }
+    };
+    let mut suffix = Vec::new();
+    if opts.diff.is_some() {
+        suffix.extend_from_slice(b"-diff");
+    } else if opts.compare {
+        suffix.extend_from_slice(b"-compare");
     }
-    let opts = opts;
-    if let Some(num) = opts.jobs {
-        rayon::ThreadPoolBuilder::new()
-            .num_threads(num.max(1))
-            .build_global()
-            .err()
-            .map(|err| eprintln!("{}", err));
+    let mut names = Vec::new();
+    for region in 0..opts.regions() {
+        let mut suffix = suffix.clone();
+        if opts.regions() > 1 {
+            suffix.extend_from_slice(format!("-r{}", region).as_bytes());
+        }
+        suffix.extend_from_slice(opts.name_suffix());
+        let name = |space: Option<&str>, group_by_space, format: cli::Format| {
+            output_file_name(
+                out_dir.as_ref(),
+                file_stem,
+                space,
+                group_by_space,
+                opts.name_prefix(),
+                &suffix,
+                if opts.planar { "f32" } else { format.extension() },
+            )
+        };
+        if opts.multipage {
+            names.push(name(None, false, opts.format()));
+        } else {
+            names.extend(opts.spaces.iter().map(|space| {
+                let space_name = opts.name_case.apply(space.0.name);
+                let format = opts.space_format(space.0);
+                name(Some(&space_name), opts.group_by_space, format)
+            }));
+        }
     }
-    let confirmer = cli::Confirmer::new(&opts);
-    let errors = opts
-        .files
-        .par_iter()
-        .filter(|file| !process_file(&opts, &confirmer, file))
-        .count();
-    if errors == 0 {
+    Some(names)
+}
+
+
+/// Detects output files which would be written more than once.  Returns list
+/// of colliding paths together with index of the source file whose output
+/// collides with output of an earlier file.  If `rename` is true, colliding
+/// paths are changed by adding a counter to the file stem so that all paths
+/// become unique.
+fn resolve_collisions(
+    names: &mut [Option<Vec<std::path::PathBuf>>],
+    rename: bool,
+) -> Vec<(usize, std::path::PathBuf)> {
+    let mut seen = std::collections::HashSet::new();
+    let mut collisions = Vec::new();
+    for (idx, names) in names.iter_mut().enumerate() {
+        for name in names.iter_mut().flatten() {
+            if seen.contains(name) {
+                collisions.push((idx, name.clone()));
+                if rename {
+                    *name = unique_name(&seen, name);
+                }
+            }
+            seen.insert(name.clone());
+        }
+    }
+    collisions
+}
+
+/// Returns path with a counter added to its file stem such that it isn’t in
+/// the `seen` set.
+fn unique_name(
+    seen: &std::collections::HashSet<std::path::PathBuf>,
+    path: &std::path::Path,
+) -> std::path::PathBuf {
+    let stem = path.file_stem().unwrap_or_default();
+    (2..)
+        .map(|counter| {
+            let mut name = stem.to_os_string();
+            name.push(format!("-{}", counter));
+            if let Some(extension) = path.extension() {
+                name.push(".");
+                name.push(extension);
+            }
+            path.with_file_name(name)
+        })
+        .find(|path| !seen.contains(path))
+        .unwrap()
+}
+
+#[test]
+fn test_resolve_collisions() {
+    fn paths(names: &[&str]) -> Option<Vec<std::path::PathBuf>> {
+        Some(names.iter().map(std::path::PathBuf::from).collect())
+    }
+
+    let mut names = vec![
+        paths(&["out/a-lab.webp", "out/a-luv.webp"]),
+        None,
+        paths(&["out/b-lab.webp", "out/b-luv.webp"]),
+        paths(&["out/a-lab.webp", "out/a-luv.webp"]),
+        paths(&["out/a-lab.webp", "out/a-lab-2.webp"]),
+    ];
+    let mut want = vec![
+        (3, "out/a-lab.webp".into()),
+        (3, "out/a-luv.webp".into()),
+        (4, "out/a-lab.webp".into()),
+    ];
+    assert_eq!(want, resolve_collisions(&mut names.clone(), false));
+
+    // When renaming, the new names may collide with later files.
+    want.push((4, "out/a-lab-2.webp".into()));
+    assert_eq!(want, resolve_collisions(&mut names, true));
+    assert_eq!(paths(&["out/a-lab-2.webp", "out/a-luv-2.webp"]), names[3]);
+    assert_eq!(paths(&["out/a-lab-3.webp", "out/a-lab-2-2.webp"]), names[4]);
+}
+
+
+/// Escapes characters with special meaning in HTML text and attribute values.
+fn html_escape(text: &str) -> String {
+    let mut buf = String::with_capacity(text.len());
+    for ch in text.chars() {
+        match ch {
+            '&' => buf.push_str("&amp;"),
+            '<' => buf.push_str("&lt;"),
+            '>' => buf.push_str("&gt;"),
+            '"' => buf.push_str("&quot;"),
+            _ => buf.push(ch),
+        }
+    }
+    buf
+}
+
+/// Converts relative path into a URL path percent-encoding all bytes other
+/// than unreserved characters and path separators.
+fn url_from_path(path: &std::path::Path) -> String {
+    let bytes = std::os::unix::ffi::OsStrExt::as_bytes(path.as_os_str());
+    let mut buf = String::with_capacity(bytes.len());
+    for &byte in bytes {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' => buf.push(byte as char),
+            b'-' | b'.' | b'_' | b'~' | b'/' => buf.push(byte as char),
+            _ => buf.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    buf
+}
+
+/// Lexically normalises the path by dropping `.` components and resolving
+/// `..` components against preceding ones.  `..` components which cannot be
+/// resolved (e.g. right after the root) are dropped.
+fn normalise_path(path: &std::path::Path) -> std::path::PathBuf {
+    use std::path::Component;
+    let mut buf = std::path::PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => match buf.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    buf.pop();
+                }
+                None | Some(Component::ParentDir) => buf.push(component),
+                _ => {}
+            },
+            _ => buf.push(component),
+        }
+    }
+    buf
+}
+
+/// Returns `path` relative to directory `base`.  Relative paths are resolved
+/// against the current working directory first and both paths are normalised
+/// lexically.  Symbolic links are not resolved.
+fn relative_path(
+    base: &std::path::Path,
+    path: &std::path::Path,
+) -> std::path::PathBuf {
+    let cwd = std::env::current_dir().unwrap_or_default();
+    let base = normalise_path(&cwd.join(base));
+    let path = normalise_path(&cwd.join(path));
+    let common = base
+        .components()
+        .zip(path.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let mut buf = std::path::PathBuf::new();
+    for _ in base.components().skip(common) {
+        buf.push("..");
+    }
+    buf.extend(path.components().skip(common));
+    buf
+}
+
+#[test]
+fn test_html_helpers() {
+    assert_eq!("a&amp;b &lt;i&gt; &quot;c&quot;", html_escape("a&b <i> \"c\""));
+    let path = std::path::Path::new("out dir/100%/a#b-lab.webp");
+    assert_eq!("out%20dir/100%25/a%23b-lab.webp", url_from_path(path));
+
+    let rel = |base: &str, path: &str| {
+        let path = relative_path(base.as_ref(), path.as_ref());
+        path.to_string_lossy().into_owned()
+    };
+    assert_eq!("c/d.webp", rel("/a/b", "/a/b/c/d.webp"));
+    assert_eq!("../c/d.webp", rel("/a/b", "/a/c/d.webp"));
+    assert_eq!("../../d.webp", rel("/a/b/", "/d.webp"));
+    assert_eq!(rel("out", "img-lab.webp"), rel("out/", "./img-lab.webp"));
+    assert_eq!("../x/d.webp", rel("/a/b", "/a/b/../x/./d.webp"));
+    assert_eq!("d.webp", rel("/a/../x/", "/x/d.webp"));
+    assert_eq!("x/d.webp", rel("/a/b/..", "/../a/x/d.webp"));
+    assert_eq!(rel("../x", "../x/img-lab.webp"), "img-lab.webp");
+
+    let norm = |path: &str| {
+        normalise_path(path.as_ref()).to_string_lossy().into_owned()
+    };
+    assert_eq!("/a/c", norm("/a/./b/../c"));
+    assert_eq!("/c", norm("/../../c"));
+    assert_eq!("../../c", norm("a/../../../c"));
+}
+
+/// Returns colour space of the outcome with given index in outcomes of
+/// a single file.  Returns `None` in `--multipage` mode where a single output
+/// file holds all the spaces.
+fn outcome_space(
+    opts: &cli::Opts,
+    idx: usize,
+) -> Option<&'static spaces::Space> {
+    if opts.multipage {
+        None
+    } else {
+        Some(opts.spaces[idx % opts.spaces.len()].0)
+    }
+}
+
+/// Writes HTML page showing all generated images grouped by source file.
+/// `reports` are outcomes for each of the files (or a single outcome in
+/// `--compare` mode) as returned by `process_file` or `process_compare`.
+fn write_html(
+    opts: &cli::Opts,
+    path: &std::path::Path,
+    reports: &[Option<Vec<Outcome>>],
+) -> std::io::Result<()> {
+    let base = path.parent().unwrap_or_else(|| std::path::Path::new(""));
+    let mut out = std::io::BufWriter::new(std::fs::File::create(path)?);
+    writeln!(out, "<!DOCTYPE html>")?;
+    writeln!(out, "<html><head><meta charset=\"utf-8\">")?;
+    writeln!(out, "<title>Image decompositions</title>")?;
+    writeln!(out, "<style>figure {{ display: inline-block }}</style>")?;
+    writeln!(out, "</head><body>")?;
+    let files = opts.files.iter().map(|file| file.to_string_lossy());
+    let headings = if opts.compare {
+        vec![files.collect::<Vec<_>>().join(", ")]
+    } else {
+        files.map(|file| file.into_owned()).collect()
+    };
+    for (heading, report) in headings.iter().zip(reports) {
+        let outcomes = match report {
+            Some(outcomes) => outcomes,
+            None => continue,
+        };
+        writeln!(out, "<section><h2>{}</h2>", html_escape(heading))?;
+        for (idx, outcome) in outcomes.iter().enumerate() {
+            let exists = match outcome.status {
+                Status::Written => true,
+                // File may have been skipped because it already exists.
+                Status::Skipped => outcome.out_file.exists(),
+                Status::Failed | Status::Aborted => false,
+            };
+            if !exists {
+                continue;
+            }
+            let caption = outcome_space(opts, idx)
+                .map_or("all spaces", |space| space.name);
+            let caption = if opts.regions() > 1 {
+                let per_region =
+                    if opts.multipage { 1 } else { opts.spaces.len() };
+                format!("{} (region {})", caption, idx / per_region)
+            } else {
+                caption.to_string()
+            };
+            let url = url_from_path(&relative_path(base, &outcome.out_file));
+            writeln!(
+                out,
+                "<figure><img src=\"{}\" alt=\"{1}\">\
+                 <figcaption>{1}</figcaption></figure>",
+                html_escape(&url),
+                caption
+            )?;
+        }
+        writeln!(out, "</section>")?;
+    }
+    writeln!(out, "</body></html>")?;
+    out.flush()
+}
+
+
+/// Encodes data in base64 with padding.
+fn base64_encode(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] =
+        b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut buf = String::with_capacity((data.len() + 2) / 3 * 4);
+    for chunk in data.chunks(3) {
+        let mut bytes = [0; 3];
+        bytes[..chunk.len()].copy_from_slice(chunk);
+        let n = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                let idx = (n >> (18 - 6 * i)) & 63;
+                buf.push(ALPHABET[idx as usize] as char);
+            } else {
+                buf.push('=');
+            }
+        }
+    }
+    buf
+}
+
+#[test]
+fn test_base64_encode() {
+    assert_eq!("", base64_encode(b""));
+    assert_eq!("Zg==", base64_encode(b"f"));
+    assert_eq!("Zm8=", base64_encode(b"fo"));
+    assert_eq!("Zm9v", base64_encode(b"foo"));
+    assert_eq!("Zm9vYmFy", base64_encode(b"foobar"));
+    assert_eq!("/+8=", base64_encode(b"\xff\xef"));
+}
+
+/// Writes SVG document with decompositions of all the files in all requested
+/// colour spaces.  Used in `--svg` mode.  Returns `false` on failure.
+fn write_svg(opts: &cli::Opts, path: &std::path::Path) -> bool {
+    const MARGIN: u32 = 8;
+    const LABEL: u32 = 20;
+    let mut body = String::new();
+    let mut ok = true;
+    let (mut total_width, mut y) = (MARGIN, MARGIN);
+    for file in &opts.files {
+        let img = match load(opts, file) {
+            Some(img) => img,
+            None => {
+                ok = false;
+                continue;
+            }
+        };
+        let (img, _) = prepare_source(opts, file, img, 0, false);
+        let src = Source::new(opts, file, img, None, None);
+        let mut settings = src.settings(opts);
+        settings.original = spaces::OriginalPosition::None;
+        settings.reconstruct = false;
+        settings.luma_tile = false;
+        settings.thresholds.clear();
+        settings.channel_labels = false;
+        settings.grid = false;
+        let name = html_escape(&file.to_string_lossy());
+        for space in &opts.spaces {
+            let space = space.0;
+            let img = match spaces::build_rgb_image(space, &src.img, &settings)
+            {
+                Some(img) => img,
+                None => {
+                    src.report_too_large();
+                    ok = false;
+                    continue;
+                }
+            };
+            body.push_str(&format!(
+                "<text x=\"{}\" y=\"{}\" class=\"row\">{} — {}</text>\n",
+                MARGIN,
+                y + LABEL - 6,
+                name,
+                space.name
+            ));
+            y += LABEL;
+            let (width, height) = (src.img.width(), img.height());
+            let names = space.channel_names();
+            for (idx, channel) in names.iter().enumerate() {
+                let x = MARGIN + idx as u32 * (width + MARGIN);
+                let tile = image::imageops::crop_imm(
+                    &img,
+                    idx as u32 * width,
+                    0,
+                    width,
+                    height,
+                )
+                .to_image();
+                let png = match cli::encode_png(&tile) {
+                    Ok(png) => png,
+                    Err(err) => {
+                        perr!(file, err);
+                        ok = false;
+                        continue;
+                    }
+                };
+                // SVG 2 uses bare href but SVG 1.1 renderers only understand
+                // xlink:href so emit both.
+                body.push_str(&format!(
+                    "<text x=\"{}\" y=\"{}\">{}</text>\n\
+                     <image x=\"{0}\" y=\"{}\" width=\"{}\" \
+                     height=\"{}\" href=\"data:image/png;base64,{6}\" \
+                     xlink:href=\"data:image/png;base64,{6}\"/>\n",
+                    x,
+                    y + LABEL - 6,
+                    html_escape(channel),
+                    y + LABEL,
+                    width,
+                    height,
+                    base64_encode(&png)
+                ));
+                total_width = total_width.max(x + width + MARGIN);
+            }
+            y += LABEL + height + MARGIN;
+        }
+    }
+    let svg = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <svg xmlns=\"http://www.w3.org/2000/svg\" \
+         xmlns:xlink=\"http://www.w3.org/1999/xlink\" width=\"{0}\" \
+         height=\"{1}\" viewBox=\"0 0 {0} {1}\">\n\
+         <style>text {{ font: 12px sans-serif; }} \
+         .row {{ font-weight: bold; }}</style>\n\
+         {2}</svg>\n",
+        total_width, y, body
+    );
+    if let Err(err) = std::fs::write(path, svg) {
+        perr!(path, err);
+        ok = false;
+    }
+    ok
+}
+
+/// Plots chromaticities of pixels of all the files on a single CIE xy
+/// chromaticity diagram saved at given path.  Format of the image is
+/// determined from the path’s extension.  Returns whether all files were
+/// processed successfully.
+fn write_chromaticity_plot(opts: &cli::Opts, path: &std::path::Path) -> bool {
+    let mut plot = spaces::ChromaticityPlot::new();
+    let mut ok = true;
+    for file in &opts.files {
+        match load(opts, file) {
+            Some(img) => plot.add(&prepare_source(opts, file, img, 0, false).0),
+            None => ok = false,
+        }
+    }
+    if let Err(err) = plot.render().save(path) {
+        perr!(path, err);
+        ok = false;
+    }
+    ok
+}
+
+
+/// Formats string as a JSON string literal.
+fn json_string(text: &str) -> String {
+    let mut buf = String::with_capacity(text.len() + 2);
+    buf.push('"');
+    for ch in text.chars() {
+        match ch {
+            '"' => buf.push_str("\\\""),
+            '\\' => buf.push_str("\\\\"),
+            '\n' => buf.push_str("\\n"),
+            ch if (ch as u32) < 0x20 => {
+                buf.push_str(&format!("\\u{:04x}", ch as u32))
+            }
+            ch => buf.push(ch),
+        }
+    }
+    buf.push('"');
+    buf
+}
+
+#[test]
+fn test_json_string() {
+    assert_eq!(r#""img.png""#, json_string("img.png"));
+    assert_eq!(r#""a\"b\\c\nd\u0007""#, json_string("a\"b\\c\nd\x07"));
+    assert_eq!(r#""zażółć""#, json_string("zażółć"));
+}
+
+/// Formats list of supported colour spaces for `--list-spaces`, either as
+/// a human-readable table or, if `json` is set, as a JSON array.
+fn format_space_list(json: bool) -> String {
+    let mut buf = String::new();
+    if json {
+        buf.push_str("[\n");
+        for (idx, space) in spaces::SPACES.iter().enumerate() {
+            let names = space
+                .channel_names()
+                .iter()
+                .map(|name| json_string(name))
+                .collect::<Vec<_>>();
+            buf.push_str(&format!(
+                "  {{\"name\": {}, \"channels\": {}, \"description\": {}, \
+                 \"channel_names\": [{}]}}{}\n",
+                json_string(space.name),
+                space.channels,
+                json_string(space.description()),
+                names.join(", "),
+                if idx + 1 < spaces::SPACES.len() { "," } else { "" }
+            ));
+        }
+        buf.push_str("]\n");
+    } else {
+        for space in spaces::SPACES.iter() {
+            buf.push_str(&format!(
+                "{:<12} {:<14} {}\n",
+                space.name,
+                space.channel_names().join(" "),
+                space.description()
+            ));
+        }
+    }
+    buf
+}
+
+#[test]
+fn test_format_space_list() {
+    let table = format_space_list(false);
+    assert_eq!(spaces::SPACES.len(), table.lines().count());
+    assert!(table.lines().any(|line| line.starts_with("lab ")));
+    let json = format_space_list(true);
+    assert!(json.starts_with("[\n") && json.ends_with("}\n]\n"), "{}", json);
+    assert!(json.contains(
+        "{\"name\": \"lab\", \"channels\": 3, \"description\": \
+         \"CIE L*a*b*\", \"channel_names\": [\"L*\", \"a*\", \"b*\"]},"
+    ));
+}
+
+/// Writes JSON array describing outcome of each output file.  `reports` are
+/// as for `write_html`.  Files which failed before any output could be
+/// generated are described by a single entry with ‘failed’ status.
+fn write_summary_json(
+    opts: &cli::Opts,
+    path: &std::path::Path,
+    reports: &[Option<Vec<Outcome>>],
+) -> std::io::Result<()> {
+    let mut entries = Vec::new();
+    // In --compare mode there is a single report named after the first file.
+    for (file, report) in opts.files.iter().zip(reports) {
+        let input = json_string(&file.to_string_lossy());
+        let outcomes = match report {
+            Some(outcomes) => outcomes,
+            None => {
+                entries.push(format!(
+                    "{{\"input\": {}, \"status\": \"failed\"}}",
+                    input
+                ));
+                continue;
+            }
+        };
+        for (idx, outcome) in outcomes.iter().enumerate() {
+            let space = outcome_space(opts, idx)
+                .map_or("null".to_string(), |space| json_string(space.name));
+            let status = match outcome.status {
+                Status::Written => "written",
+                Status::Skipped => "skipped",
+                Status::Failed => "failed",
+                Status::Aborted => "aborted",
+            };
+            let (width, height) = match outcome.dimensions {
+                Some((w, h)) => (w.to_string(), h.to_string()),
+                None => ("null".to_string(), "null".to_string()),
+            };
+            entries.push(format!(
+                "{{\"input\": {}, \"space\": {}, \"output\": {}, \
+                 \"status\": \"{}\", \"width\": {}, \"height\": {}, \
+                 \"seconds\": {:.3}}}",
+                input,
+                space,
+                json_string(&outcome.out_file.to_string_lossy()),
+                status,
+                width,
+                height,
+                outcome.elapsed.as_secs_f64()
+            ));
+        }
+    }
+    let mut out = std::io::BufWriter::new(std::fs::File::create(path)?);
+    writeln!(out, "[")?;
+    for (idx, entry) in entries.iter().enumerate() {
+        let sep = if idx + 1 < entries.len() { "," } else { "" };
+        writeln!(out, "  {}{}", entry, sep)?;
+    }
+    writeln!(out, "]")?;
+    out.flush()
+}
+
+/// Processes a single file generating its decompositions in all requested
+/// colour spaces and saving them to given output files.  Returns `None` if the
+/// file couldn’t be loaded; otherwise returns outcomes for each of the spaces
+/// in the order of `opts.spaces`.  `preview_pass` tells whether the preview
+/// pass of `--preview-scale` is being done.
+fn process_file(
+    opts: &cli::Opts,
+    output: &Output,
+    file: &std::path::Path,
+    out_files: Vec<std::path::PathBuf>,
+    preview_pass: bool,
+) -> Option<Vec<Outcome>> {
+    let start = std::time::Instant::now();
+    let img = load(opts, file)?;
+    let mut decode = start.elapsed();
+    if opts.is_below_min_size(&img) {
+        let (w, h) = image::GenericImageView::dimensions(&img);
+        pinfo!(file, "skipping image smaller than --min-size ({}x{})", w, h);
+        let skipped = out_files
+            .into_iter()
+            .map(|out_file| Outcome::new(out_file, Status::Skipped));
+        return Some(skipped.collect());
+    }
+    let start = std::time::Instant::now();
+    let other = match &opts.diff {
+        Some(path) => Some((path.as_path(), load(opts, path)?)),
+        None => None,
+    };
+    decode += start.elapsed();
+    add_time(&DECODE_NANOS, decode);
+    let regions = opts.regions();
+    let mut prepare = std::time::Duration::default();
+    let per_region = (out_files.len() / regions).max(1);
+    let mut outcomes = Vec::with_capacity(out_files.len());
+    let mut src = Some((img, other));
+    for (region, out_files) in out_files.chunks(per_region).enumerate() {
+        // Avoid copying the images when processing the last region.
+        let (img, other) = if region + 1 < regions {
+            src.clone().unwrap()
+        } else {
+            src.take().unwrap()
+        };
+        let (region_outcomes, time) = process_region(
+            opts,
+            output,
+            file,
+            img,
+            other,
+            region,
+            out_files.to_vec(),
+            preview_pass,
+        )?;
+        outcomes.extend(region_outcomes);
+        prepare += time;
+    }
+    if opts.profile {
+        print_file_profile(opts, file, decode, prepare, &outcomes);
+    }
+    Some(outcomes)
+}
+
+/// Returns path of the preview generated for given output file with
+/// `--preview-scale`, i.e. path with ‘-preview’ added to the file stem.
+fn preview_file_name(out_file: &std::path::Path) -> std::path::PathBuf {
+    let mut name = out_file.file_stem().unwrap_or_default().to_os_string();
+    name.push("-preview");
+    if let Some(ext) = out_file.extension() {
+        name.push(".");
+        name.push(ext);
+    }
+    out_file.with_file_name(name)
+}
+
+#[test]
+fn test_preview_file_name() {
+    let name = |path: &str| preview_file_name(std::path::Path::new(path));
+    assert_eq!(
+        std::path::PathBuf::from("out/img-lab-preview.webp"),
+        name("out/img-lab.webp")
+    );
+    assert_eq!(std::path::PathBuf::from("img-preview"), name("img"));
+}
+
+/// With `--preview-scale`, generates previews of the decompositions first and
+/// generates full resolution images only if user confirms.  Previews are
+/// removed once the full images are generated.  Without the flag, simply
+/// processes the file.
+fn process_file_with_preview(
+    opts: &cli::Opts,
+    output: &Output,
+    file: &std::path::Path,
+    out_files: Vec<std::path::PathBuf>,
+) -> Option<Vec<Outcome>> {
+    if !opts.has_preview_pass() {
+        return process_file(opts, output, file, out_files, false);
+    }
+    let preview_files =
+        out_files.iter().map(|path| preview_file_name(path)).collect();
+    // Previews are temporary so they are always overwritten.
+    let preview_output = Output::Files(cli::Confirmer::Overwrite);
+    let outcomes =
+        process_file(opts, &preview_output, file, preview_files, true)?;
+    let written = outcomes
+        .iter()
+        .filter(|outcome| matches!(outcome.status, Status::Written))
+        .map(|outcome| outcome.out_file.clone())
+        .collect::<Vec<_>>();
+    if written.is_empty() || !cli::confirm_full_pass(file) {
+        // Full resolution images haven’t been generated so rather than
+        // listing the previews as outputs, report the images as skipped.
+        let outcomes = outcomes.into_iter().zip(out_files).map(
+            |(outcome, out_file)| {
+                let status = match outcome.status {
+                    Status::Written => Status::Skipped,
+                    status => status,
+                };
+                let elapsed = outcome.elapsed;
+                Outcome { out_file, status, dimensions: None, elapsed }
+            },
+        );
+        return Some(outcomes.collect());
+    }
+    let outcomes = process_file(opts, output, file, out_files, false)?;
+    for path in written {
+        if let Err(err) = std::fs::remove_file(&path) {
+            pwarn!(path, err);
+        }
+    }
+    Some(outcomes)
+}
+
+/// Delay after which filesystem events are delivered in `--watch` mode.  Rapid
+/// successive writes to a file within this time result in a single event.
+const WATCH_DEBOUNCE: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Interval at which size of a file is checked to determine whether it’s
+/// still being written.
+const WATCH_SETTLE: std::time::Duration = std::time::Duration::from_millis(500);
+
+/// Waits until size and modification time of the file stop changing so that
+/// files which are still being written aren’t processed.  Returns `false` if
+/// the file disappeared in the meantime.
+fn wait_until_stable(path: &std::path::Path) -> bool {
+    let stat = || {
+        std::fs::metadata(path).map(|md| (md.len(), md.modified().ok()))
+    };
+    let mut last = match stat() {
+        Ok(last) => last,
+        Err(_) => return false,
+    };
+    loop {
+        std::thread::sleep(WATCH_SETTLE);
+        match stat() {
+            Ok(current) if current == last => return true,
+            Ok(current) => last = current,
+            Err(_) => return false,
+        }
+    }
+}
+
+/// Watches directory and decomposes images created or modified in it.  Runs
+/// until interrupted; returns only if watching fails.
+fn watch(opts: &cli::Opts, dir: &std::path::Path) -> bool {
+    let (tx, rx) = std::sync::mpsc::channel();
+    let mut watcher = match notify::watcher(tx, WATCH_DEBOUNCE) {
+        Ok(watcher) => watcher,
+        Err(err) => {
+            perr!(dir, err);
+            return false;
+        }
+    };
+    let mode = notify::RecursiveMode::NonRecursive;
+    if let Err(err) = notify::Watcher::watch(&mut watcher, dir, mode) {
+        perr!(dir, err);
+        return false;
+    }
+    pinfo!(dir, "watching for images; interrupt to stop");
+    // Outputs may be written to the watched directory; they must not be
+    // decomposed again.
+    let mut outputs = std::collections::HashSet::new();
+    for event in rx {
+        let path = match event {
+            notify::DebouncedEvent::Create(path) |
+            notify::DebouncedEvent::Write(path) |
+            notify::DebouncedEvent::Rename(_, path) => path,
+            notify::DebouncedEvent::Error(err, path) => {
+                perr!(path.as_deref().unwrap_or(dir), err);
+                continue;
+            }
+            _ => continue,
+        };
+        if outputs.contains(&path) ||
+            !cli::is_image_path(&path) ||
+            !wait_until_stable(&path)
+        {
+            continue;
+        }
+        let out_files = match output_files(opts, &path) {
+            Some(out_files) => out_files,
+            None => continue,
+        };
+        outputs.extend(out_files.iter().cloned());
+        // Directory listings cached by the confirmer would go stale so use
+        // a fresh one for each file.
+        let output = Output::Files(cli::Confirmer::new(opts));
+        let start = std::time::Instant::now();
+        if process_file(opts, &output, &path, out_files, false).is_some() {
+            pinfo!(path, "processed in {:.2?}", start.elapsed());
+        }
+    }
+    perr!(dir, "watching stopped");
+    false
+}
+
+/// Processes a single region of a source file (or the whole file if no
+/// `--crop` was given) generating its decompositions in all requested colour
+/// spaces.  `other` is the image loaded from `--diff` path if one was given.
+/// Returns outcomes together with time spent preparing the source images or
+/// `None` if processing failed.
+fn process_region(
+    opts: &cli::Opts,
+    output: &Output,
+    file: &std::path::Path,
+    img: image::DynamicImage,
+    other: Option<(&std::path::Path, image::DynamicImage)>,
+    region: usize,
+    out_files: Vec<std::path::PathBuf>,
+    preview_pass: bool,
+) -> Option<(Vec<Outcome>, std::time::Duration)> {
+    let start = std::time::Instant::now();
+    let (img, original) = prepare_source(opts, file, img, region, preview_pass);
+    if img.width() == 0 || img.height() == 0 {
+        let (w, h) = img.dimensions();
+        perr!(file, "image has no pixels ({}x{})", w, h);
+        return None;
+    }
+    let other = if let Some((path, other)) = other {
+        let (other, _) =
+            prepare_source(opts, path, other, region, preview_pass);
+        if img.dimensions() != other.dimensions() {
+            let (w, h) = img.dimensions();
+            let (ow, oh) = other.dimensions();
+            perr!(
+                file,
+                "dimensions ({}x{}) differ from {} ({}x{})",
+                w,
+                h,
+                path.to_string_lossy(),
+                ow,
+                oh
+            );
+            return None;
+        }
+        Some(other)
+    } else {
+        None
+    };
+    let src = Source::new(opts, file, img, original, other);
+    let prepare = start.elapsed();
+    add_time(&PREPARE_NANOS, prepare);
+    if opts.multipage {
+        let out_file = out_files.into_iter().next().unwrap();
+        let outcome = output.generate(opts, preview_pass, out_file, |out_file| {
+            generate_multipage(opts, &src, out_file)
+        });
+        return Some((vec![outcome], prepare));
+    }
+    let jobs = opts.spaces.iter().zip(out_files).collect::<Vec<_>>();
+    let outcomes =
+        map_items(opts.serial_spaces(), &jobs, |(space, out_file)| {
+            src.report_effective_bits(opts, space.0);
+            output.generate(opts, preview_pass, out_file.clone(), |out_file| {
+                let mut encoded = if opts.planar {
+                    generate_planar(&src, space.0, out_file)?
+                } else {
+                    generate(opts, output, &src, space.0, out_file)?
+                };
+                encoded.redundant = encoded
+                    .data
+                    .as_ref()
+                    .map_or(false, |data| src.is_redundant(opts, data));
+                Some(encoded)
+            })
+        });
+    Some((outcomes, prepare))
+}
+
+/// Prints coordinates of pixel at given position of the source image in all
+/// requested colour spaces.  Used in `--probe` mode.  Returns `false` on
+/// failure.
+fn probe_file(
+    opts: &cli::Opts,
+    file: &std::path::Path,
+    point: &cli::Point,
+) -> bool {
+    let img = match load(opts, file) {
+        Some(img) => img,
+        None => return false,
+    };
+    let (img, _) = prepare_source(opts, file, img, 0, false);
+    if point.x >= img.width() || point.y >= img.height() {
+        let (w, h) = img.dimensions();
+        perr!(
+            file,
+            "pixel {},{} outside of the image ({}x{})",
+            point.x,
+            point.y,
+            w,
+            h
+        );
+        return false;
+    }
+    let rgb = img.get_pixel(point.x, point.y).0;
+    let mut out = format!(
+        "{} {},{} #{:02x}{:02x}{:02x}\n",
+        file.to_string_lossy(),
+        point.x,
+        point.y,
+        rgb[0],
+        rgb[1],
+        rgb[2]
+    );
+    for space in &opts.spaces {
+        out.push_str(&format!("  {:<10}", space.0.name));
+        for value in spaces::channel_values(space.0, rgb) {
+            out.push_str(&format!(" {:9.4}", value));
+        }
+        out.push('\n');
+    }
+    print!("{}", out);
+    true
+}
+
+/// Renders differences between comparable channels of two colour spaces of
+/// the image and saves them in a single file.  Used in `--space-diff` mode.
+/// Returns `false` on failure.
+fn space_diff_file(
+    opts: &cli::Opts,
+    confirmer: &cli::Confirmer,
+    file: &std::path::Path,
+    pair: &cli::SpacePair,
+) -> bool {
+    let (a, b) = (pair.0 .0, pair.1 .0);
+    let out_dir = match output_directory(&opts.out_dir, file) {
+        Ok(dir) => dir,
+        Err(err) => {
+            perr!(file, "unable to determine parent directory: {}", err);
+            return false;
+        }
+    };
+    let file_stem = match file.file_stem() {
+        _ if is_stdin(file) => std::ffi::OsStr::new("stdin"),
+        Some(name) => name,
+        None => {
+            perr!(file, "unable to determine file stem");
+            return false;
+        }
+    };
+    let name = format!(
+        "{}-vs-{}",
+        opts.name_case.apply(a.name),
+        opts.name_case.apply(b.name)
+    );
+    let out_file = output_file_name(
+        &out_dir,
+        file_stem,
+        Some(&name),
+        false,
+        opts.name_prefix(),
+        opts.name_suffix(),
+        opts.space_format(a).extension(),
+    );
+    if !confirmer.confirm(&out_file) {
+        return true;
+    }
+    let img = match load(opts, file) {
+        Some(img) => img,
+        None => return false,
+    };
+    let (img, _) = prepare_source(opts, file, img, 0, false);
+    let img = match spaces::build_space_diff_image(a, b, &img) {
+        Some(img) => img,
+        None => {
+            let (w, h) = img.dimensions();
+            perr!(file, "image too large ({}x{})", w, h);
+            return false;
+        }
+    };
+    let mut buf = Vec::new();
+    let res = opts
+        .encode(std::io::Cursor::new(&mut buf), &img, a, None)
+        .and_then(|()| std::fs::write(&out_file, &buf));
+    if let Err(err) = res {
+        perr!(out_file, err);
+        return false;
+    }
+    true
+}
+
+/// Decomposes synthetic test image in given colour space and saves it as
+/// ‘reference-card-<space>’ in the output directory (or current directory).
+/// Returns whether the image was written successfully.
+fn write_reference_card(opts: &cli::Opts, space: &spaces::Space) -> bool {
+    let out_dir = match &opts.out_dir {
+        Some(dir) => dir.clone(),
+        None => std::path::PathBuf::from("."),
+    };
+    let out_file = output_file_name(
+        &out_dir,
+        std::ffi::OsStr::new("reference-card"),
+        Some(&opts.name_case.apply(space.name)),
+        false,
+        opts.name_prefix(),
+        opts.name_suffix(),
+        opts.space_format(space).extension(),
+    );
+    if !cli::Confirmer::new(opts).confirm(&out_file) {
+        return true;
+    }
+    let img = spaces::reference_card();
+    let img = match spaces::build_rgb_image(space, &img, &opts.settings()) {
+        Some(img) => img,
+        None => {
+            perr!(out_file, "image too large");
+            return false;
+        }
+    };
+    let mut buf = Vec::new();
+    let res = opts
+        .encode(std::io::Cursor::new(&mut buf), &img, space, None)
+        .and_then(|()| std::fs::write(&out_file, &buf));
+    if let Err(err) = res {
+        perr!(out_file, err);
+        return false;
+    }
+    true
+}
+
+/// Processes all files in `--compare` mode generating for each of requested
+/// colour spaces a single image with decompositions of the files stacked one
+/// beneath another.  Returns `None` if any of the files couldn’t be loaded or
+/// their dimensions differ; otherwise returns outcomes for each of the spaces
+/// in the order of `opts.spaces`.
+fn process_compare(
+    opts: &cli::Opts,
+    output: &Output,
+    out_files: Vec<std::path::PathBuf>,
+) -> Option<Vec<Outcome>> {
+    let sources = map_items(opts.serial_files(), &opts.files, |file| {
+        let start = std::time::Instant::now();
+        let img = load(opts, file)?;
+        add_time(&DECODE_NANOS, start.elapsed());
+        let start = std::time::Instant::now();
+        let (img, original) = prepare_source(opts, file, img, 0, false);
+        let src = Source::new(opts, file, img, original, None);
+        add_time(&PREPARE_NANOS, start.elapsed());
+        Some(src)
+    })
+    .into_iter()
+    .collect::<Option<Vec<_>>>()?;
+    let first = sources.first()?;
+    if first.img.width() == 0 || first.img.height() == 0 {
+        let (w, h) = first.img.dimensions();
+        perr!(first.file, "image has no pixels ({}x{})", w, h);
+        return None;
+    }
+    for src in &sources[1..] {
+        if src.img.dimensions() != first.img.dimensions() {
+            let (w, h) = src.img.dimensions();
+            let (fw, fh) = first.img.dimensions();
+            perr!(
+                src.file,
+                "dimensions ({}x{}) differ from {} ({}x{})",
+                w,
+                h,
+                first.file.to_string_lossy(),
+                fw,
+                fh
+            );
+            return None;
+        }
+    }
+    let jobs = opts.spaces.iter().zip(out_files).collect::<Vec<_>>();
+    let outcomes =
+        map_items(opts.serial_spaces(), &jobs, |(space, out_file)| {
+            output.generate(opts, false, out_file.clone(), |out_file| {
+                generate_compare(opts, &sources, space.0, out_file)
+            })
+        });
+    Some(outcomes)
+}
+
+/// Generates decompositions of given images in given colour space, stacks them
+/// one beneath another, each below a strip labelling it with name of its file,
+/// and encodes the result in the output format.  All the images must have the
+/// same dimensions.  Returns `None` on failure.
+fn generate_compare(
+    opts: &cli::Opts,
+    sources: &[Source],
+    space: &spaces::Space,
+    out_file: &std::path::Path,
+) -> Option<Encoded> {
+    let mut width = 0;
+    let mut height = 0u32;
+    let mut data = Vec::new();
+    for src in sources {
+        let img = src.decompose(opts, space)?;
+        let name = src.file.file_name().unwrap_or(src.file.as_os_str());
+        let strip = spaces::label_strip(
+            img.width(),
+            img.height(),
+            &name.to_string_lossy(),
+            src.settings(opts).background,
+        );
+        width = img.width();
+        height = match height
+            .checked_add(strip.height())
+            .and_then(|height| height.checked_add(img.height()))
+        {
+            Some(height) => height,
+            None => {
+                perr!(out_file, "image too large");
+                return None;
+            }
+        };
+        data.extend_from_slice(strip.as_raw());
+        data.extend_from_slice(img.as_raw());
+    }
+    let img = image::RgbImage::from_raw(width, height, data)?;
+    let mut buf = Vec::new();
+    let res = opts.encode(std::io::Cursor::new(&mut buf), &img, space, None);
+    if let Err(err) = res {
+        perr!(out_file, err);
+        None
+    } else {
+        Some(Encoded {
+            data: Some(buf),
+            dimensions: Some((width, height)),
+            redundant: false,
+        })
+    }
+}
+
+/// Generates decomposition of given image in given colour space and encodes it
+/// in the output format.  With `--stream`, if possible, the image is written
+/// directly to the output rather than returned.  Returns `None` on failure.
+fn generate(
+    opts: &cli::Opts,
+    output: &Output,
+    src: &Source,
+    space: &spaces::Space,
+    out_file: &std::path::Path,
+) -> Option<Encoded> {
+    if opts.stream &&
+        opts.space_format(space).can_stream() &&
+        src.other.is_none() &&
+        opts.tile_size.is_none()
+    {
+        let dimensions = output.write_with(out_file, |out| {
+            src.encode_rows(opts, space, out, out_file)
+        })?;
+        return Some(Encoded {
+            data: None,
+            dimensions: Some(dimensions),
+            redundant: false,
+        });
+    }
+    let mut buf = Vec::new();
+    let img = src.decompose(opts, space)?;
+    let comment = src.comment(opts);
+    let res = opts.encode(
+        std::io::Cursor::new(&mut buf),
+        &img,
+        space,
+        comment.as_deref(),
+    );
+    if let Err(err) = res {
+        perr!(out_file, err);
+        None
+    } else {
+        Some(Encoded {
+            data: Some(buf),
+            dimensions: Some(img.dimensions()),
+            redundant: false,
+        })
+    }
+}
+
+/// Computes values of channels of given image in given colour space and
+/// encodes them as a planar buffer of floats.  Returns `None` on failure.
+fn generate_planar(
+    src: &Source,
+    space: &spaces::Space,
+    out_file: &std::path::Path,
+) -> Option<Encoded> {
+    let data = match spaces::build_planar(space, &src.img) {
+        Some(data) => data,
+        None if space.channel_names().is_empty() => {
+            perr!(out_file, "‘{}’ has no channels to save", space.name);
+            return None;
+        }
+        None => {
+            src.report_too_large();
+            return None;
+        }
+    };
+    let mut buf = Vec::new();
+    if let Err(err) = cli::write_planar(&mut buf, &data) {
+        perr!(out_file, err);
+        return None;
+    }
+    Some(Encoded {
+        data: Some(buf),
+        dimensions: Some(src.img.dimensions()),
+        redundant: false,
+    })
+}
+
+/// Generates decompositions of given image in all requested colour spaces and
+/// encodes them as pages of a single TIFF file.  Returns `None` on failure.
+fn generate_multipage(
+    opts: &cli::Opts,
+    src: &Source,
+    out_file: &std::path::Path,
+) -> Option<Encoded> {
+    let pages = map_items(opts.serial_spaces(), &opts.spaces, |space| {
+        src.report_effective_bits(opts, space.0);
+        src.decompose(opts, space.0)
+    })
+    .into_iter()
+    .collect::<Option<Vec<_>>>()?;
+    let mut buf = Vec::new();
+    let res = tiff::encoder::TiffEncoder::new(std::io::Cursor::new(&mut buf))
+        .and_then(|mut enc| {
+            for page in pages {
+                enc.write_image::<tiff::encoder::colortype::RGB8>(
+                    page.width(),
+                    page.height(),
+                    page.as_raw(),
+                )?;
+            }
+            Ok(())
+        });
+    if let Err(err) = res {
+        perr!(out_file, err);
+        None
+    } else {
+        Some(Encoded {
+            data: Some(buf),
+            dimensions: None,
+            redundant: false,
+        })
+    }
+}
+
+fn main() -> std::process::ExitCode {
+    let mut opts = <cli::Opts as clap::Clap>::parse();
+    cli::init_logger(&opts);
+    if opts.list_spaces {
+        print!("{}", format_space_list(opts.json));
+        return std::process::ExitCode::SUCCESS;
+    }
+    if let Some(dir) = &opts.out_dir {
+        if let Err(err) = create_dir_all(dir) {
+            perr!(dir, err);
+            return std::process::ExitCode::FAILURE;
+        }
+    }
+    if let Err(err) = opts.collect_spaces() {
+        log::error!("{}", err);
+        return std::process::ExitCode::FAILURE;
+    }
+    if let Err(err) = opts.collect_quality() {
+        log::error!("{}", err);
+        return std::process::ExitCode::FAILURE;
+    }
+    opts.expand_globs();
+    if let Err(err) = opts.read_tar() {
+        log::error!("{}", err);
+        return std::process::ExitCode::FAILURE;
+    }
+    // Manifest entries are copies of the options so the mask must be loaded
+    // before they are read.
+    if let Some(path) = opts.mask.clone() {
+        match load(&opts, &path) {
+            Some(img) => opts.mask_image = Some(spaces::mask_from_image(&img)),
+            None => return std::process::ExitCode::FAILURE,
+        }
+    }
+    let entries = match opts.read_manifest() {
+        Ok(entries) => entries,
+        Err(err) => {
+            log::error!("{}", err);
+            return std::process::ExitCode::FAILURE;
+        }
+    };
+    for dir in entries.iter().filter_map(|entry| entry.out_dir.as_ref()) {
+        if let Err(err) = create_dir_all(dir) {
+            perr!(dir, err);
+            return std::process::ExitCode::FAILURE;
+        }
+    }
+    if !entries.is_empty() {
+        opts.files = entries
+            .iter()
+            .flat_map(|entry| entry.files.iter().cloned())
+            .collect();
+    }
+    if let Err(err) = opts.check_max_files() {
+        log::error!("{}", err);
+        return std::process::ExitCode::FAILURE;
+    }
+    if let Some(path) = &opts.svg {
+        if !cli::Confirmer::new(&opts).confirm(path) {
+            return std::process::ExitCode::SUCCESS;
+        }
+        return if write_svg(&opts, path) {
+            std::process::ExitCode::SUCCESS
+        } else {
+            std::process::ExitCode::FAILURE
+        };
+    }
+    if let Some(space) = &opts.reference_card {
+        return if write_reference_card(&opts, space.0) {
+            std::process::ExitCode::SUCCESS
+        } else {
+            std::process::ExitCode::FAILURE
+        };
+    }
+    if let Some(path) = &opts.chromaticity_plot {
+        if !cli::Confirmer::new(&opts).confirm(path) {
+            return std::process::ExitCode::SUCCESS;
+        }
+        return if write_chromaticity_plot(&opts, path) {
+            std::process::ExitCode::SUCCESS
+        } else {
+            std::process::ExitCode::FAILURE
+        };
+    }
+    if let Some(pair) = &opts.space_diff {
+        let (a, b) = (pair.0 .0, pair.1 .0);
+        if spaces::comparable_channels(a, b).is_empty() {
+            let (a, b) = (a.name, b.name);
+            log::error!("{} and {} have no comparable channels", a, b);
+            return std::process::ExitCode::FAILURE;
+        }
+        let confirmer = cli::Confirmer::new(&opts);
+        let mut ok = true;
+        for file in &opts.files {
+            ok &= space_diff_file(&opts, &confirmer, file, pair);
+        }
+        return if ok {
+            std::process::ExitCode::SUCCESS
+        } else {
+            std::process::ExitCode::FAILURE
+        };
+    }
+    if let Some(point) = &opts.probe {
+        let mut ok = true;
+        for file in &opts.files {
+            ok &= probe_file(&opts, file, point);
+        }
+        return if ok {
+            std::process::ExitCode::SUCCESS
+        } else {
+            std::process::ExitCode::FAILURE
+        };
+    }
+    if opts.multipage &&
+        (opts.format() != cli::Format::Tiff || opts.has_space_formats())
+    {
+        log::error!("--multipage requires --format=tiff");
+        return std::process::ExitCode::FAILURE;
+    }
+    if opts.compare && opts.regions() > 1 {
+        log::error!("--compare cannot be used with multiple --crop regions");
+        return std::process::ExitCode::FAILURE;
+    }
+    opts.warn_unused_quality();
+    opts.warn_unused_metadata();
+    opts.warn_unused_thresholds();
+    if opts.stream && !opts.uses_format(cli::Format::can_stream) {
+        log::warn!(
+            "--stream has no effect with --format={}",
+            opts.format().extension()
+        );
+    }
+    let opts = opts;
+    if let cli::Jobs::Count(num) = opts.jobs {
+        if num > 1 {
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(num)
+                .build_global()
+                .err()
+                .map(|err| log::error!("{}", err));
+        }
+    }
+    if let Some(dir) = &opts.watch {
+        return if watch(&opts, dir) {
+            std::process::ExitCode::SUCCESS
+        } else {
+            std::process::ExitCode::FAILURE
+        };
+    }
+    // With --manifest each file may be processed with different options.
+    let file_opts = if entries.is_empty() {
+        opts.files.iter().map(|_| &opts).collect::<Vec<_>>()
+    } else {
+        entries.iter().collect::<Vec<_>>()
+    };
+    let mut names = opts
+        .files
+        .iter()
+        .zip(&file_opts)
+        .map(|(file, file_opts)| output_files(file_opts, file))
+        .collect::<Vec<_>>();
+    let rename = opts.on_collision == cli::Collision::Rename;
+    let collisions = resolve_collisions(&mut names, rename);
+    // Messages are logged for the output file so that its path is printed as
+    // is rather than embedded, lossily, in the message.
+    for (_, path) in &collisions {
+        let msg = "output file collides with output of another input file";
+        match opts.on_collision {
+            cli::Collision::Error => perr!(path, msg),
+            cli::Collision::Warn => pwarn!(path, msg),
+            cli::Collision::Rename => (),
+        }
+    }
+    if opts.on_collision == cli::Collision::Error && !collisions.is_empty() {
+        return std::process::ExitCode::FAILURE;
+    }
+    let confirmer = cli::Confirmer::new(&opts);
+    let output = if let Some(path) = &opts.zip {
+        if !confirmer.confirm(path) {
+            return std::process::ExitCode::SUCCESS;
+        }
+        match std::fs::File::create(path) {
+            Ok(fd) => Output::Zip(std::sync::Mutex::new(zip::ZipWriter::new(
+                std::io::BufWriter::new(fd),
+            ))),
+            Err(err) => {
+                perr!(path, err);
+                return std::process::ExitCode::FAILURE;
+            }
+        }
+    } else {
+        Output::Files(confirmer)
+    };
+    // map_items preserves order of the items so outcomes are reported in the
+    // order of input files and then in the order of SPACES array regardless of
+    // how the work got scheduled.
+    let jobs = opts
+        .files
+        .iter()
+        .zip(file_opts)
+        .zip(names)
+        .map(|((file, file_opts), names)| (file, file_opts, names))
+        .collect::<Vec<_>>();
+    let start = std::time::Instant::now();
+    let process_files = || {
+        map_items(opts.serial_files(), &jobs, |(file, file_opts, out_files)| {
+            if is_aborted(&opts) {
+                return Some(Vec::new());
+            }
+            let start = std::time::Instant::now();
+            let report = out_files.clone().and_then(|out_files| {
+                process_file_with_preview(file_opts, &output, file, out_files)
+            });
+            pdebug!(file, "processed in {:.2?}", start.elapsed());
+            if report.is_none() {
+                note_failure();
+            }
+            report
+        })
+    };
+    let reports = if opts.compare {
+        // In --compare mode, output is named after the first file.
+        let out_files = jobs.first().and_then(|(_, _, names)| names.clone());
+        let report = out_files.and_then(|out_files| {
+            process_compare(&opts, &output, out_files)
+        });
+        if report.is_none() {
+            note_failure();
+        }
+        vec![report]
+    } else {
+        // With --file-jobs, files are processed in a dedicated pool so that
+        // the limit applies regardless of size of the global pool.
+        match opts.file_jobs {
+            Some(cli::Jobs::Count(num)) if num > 1 => {
+                let pool =
+                    rayon::ThreadPoolBuilder::new().num_threads(num).build();
+                match pool {
+                    Ok(pool) => pool.install(process_files),
+                    Err(err) => {
+                        log::error!("{}", err);
+                        process_files()
+                    }
+                }
+            }
+            _ => process_files(),
+        }
+    };
+    let mut ok = true;
+    for report in &reports {
+        let outcomes = if let Some(outcomes) = report {
+            outcomes
+        } else {
+            ok = false;
+            continue;
+        };
+        for outcome in outcomes {
+            match outcome.status {
+                Status::Written => pinfo!(outcome.out_file, "generated"),
+                Status::Skipped => (),
+                Status::Failed | Status::Aborted => ok = false,
+            }
+        }
+    }
+    if is_aborted(&opts) {
+        log::error!("Aborting due to an earlier error");
+        ok = false;
+    }
+    if opts.profile {
+        // With --manifest, spaces may differ between the files.
+        let report_opts = jobs.iter().map(|(_, file_opts, _)| *file_opts);
+        print_total_profile(report_opts.zip(&reports), start.elapsed());
+    }
+    if let Some(path) = &opts.html {
+        if cli::Confirmer::new(&opts).confirm(path) {
+            if let Err(err) = write_html(&opts, path, &reports) {
+                perr!(path, err);
+                ok = false;
+            }
+        }
+    }
+    if let Some(path) = &opts.summary_json {
+        if cli::Confirmer::new(&opts).confirm(path) {
+            if let Err(err) = write_summary_json(&opts, path, &reports) {
+                perr!(path, err);
+                ok = false;
+            }
+        }
+    }
+    if let (Some(path), Output::Zip(mutex)) = (&opts.zip, output) {
+        let mut archive = mutex
+            .into_inner()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        if let Err(err) = archive.finish().and_then(|mut wr| Ok(wr.flush()?)) {
+            perr!(path, err);
+            ok = false;
+        }
+    }
+    if ok {
         std::process::ExitCode::SUCCESS
     } else {
         std::process::ExitCode::FAILURE
@@ -4,9 +4,14 @@ type Rgb = [u8; 3];
 type UnRgb = [std::mem::MaybeUninit<u8>; 3];
 
 
-struct Channels(*mut UnRgb, usize);
+/// Pixels of a single source pixel’s channel tiles.  The fields are pointer to
+/// the pixel in the first tile, width of a tile, settings affecting how the
+/// channels are rendered and dithering threshold of the pixel.  The threshold
+/// is in units of a single 8-bit step and is added to values before they are
+/// rounded; it is zero if dithering is disabled.
+struct Channels<'a>(*mut UnRgb, usize, &'a Settings, f32);
 
-impl Channels {
+impl Channels<'_> {
     fn set_rgb(&mut self, channel: usize, rgb: Rgb) {
         // SAFETY: Caller guarantees that self.0 is a pointer to within a slice
         // such that all the indexes are valid.
@@ -16,6 +21,94 @@ impl Channels {
     fn set_grey(&mut self, channel: usize, value: u8) {
         self.set_rgb(channel, [value, value, value]);
     }
+    /// Returns whether values are dithered before being rounded.
+    fn dithered(&self) -> bool { self.3 != 0.0 }
+    /// Converts value in [0, 1] range into an 8-bit value.
+    fn round(&self, value: f32) -> u8 {
+        mul_add(value, 255.0, 0.5 + self.3) as u8
+    }
+    /// Rounds value in [0, 255] range into an 8-bit value.
+    fn round_scaled(&self, value: f32) -> u8 { (value + 0.5 + self.3) as u8 }
+    /// Converts normalised sRGB colour into 8-bit values.
+    fn round_rgb(&self, [r, g, b]: [f32; 3]) -> Rgb {
+        [self.round(r), self.round(g), self.round(b)]
+    }
+    /// Gamma-compresses linear value into an 8-bit sRGB value.
+    fn compress(&self, linear: f32) -> u8 {
+        if self.dithered() {
+            let value = linear.max(0.0).min(1.0);
+            self.round(srgb::gamma::compress_normalised(value))
+        } else {
+            srgb::gamma::compress_u8(linear)
+        }
+    }
+    /// Converts XYZ coordinates into sRGB colour clipping it to the gamut.
+    fn rgb_from_xyz(&self, xyz: [f32; 3]) -> Rgb {
+        if self.dithered() {
+            self.round_rgb(srgb::normalised_from_xyz(xyz))
+        } else {
+            srgb::u8_from_xyz(xyz)
+        }
+    }
+    /// Converts L\*a\*b\* colour into sRGB colour clipping it to the gamut.
+    fn rgb_from_lab(&self, lab: lab::Lab) -> Rgb {
+        if self.dithered() {
+            self.rgb_from_xyz(xyz_from_lab(lab))
+        } else {
+            lab.to_rgb()
+        }
+    }
+    /// Converts L\*u\*v\* colour into sRGB colour clipping it to the gamut.
+    fn rgb_from_luv(&self, luv: luv::Luv) -> Rgb {
+        if self.dithered() {
+            self.rgb_from_xyz(xyz_from_luv(luv))
+        } else {
+            luv.to_rgb()
+        }
+    }
+    /// Sets channel to colour of given hue on the hue wheel.  NaN, used for
+    /// undefined hue, is mapped to the undefined hue colour.
+    fn set_hue(&mut self, channel: usize, hue: f32) {
+        let rgb = if hue.is_nan() {
+            self.2.undefined_hue
+        } else {
+            hue_wheel_rgb(hue, self.3)
+        };
+        self.set_rgb(channel, rgb);
+    }
+    /// Returns lightness to render opponent-axis channel whose coordinate `v`
+    /// spans given range with.
+    fn opponent_lightness(&self, v: f32, range: (f32, f32)) -> f32 {
+        self.2.chroma_lightness.unwrap_or_else(|| abuv_lstar(v, range))
+    }
+    /// Sets channel to colour with given XYZ coordinates.  Colours outside of
+    /// the sRGB gamut are clipped or, with gamut warning enabled, replaced by
+    /// the warning colour.
+    fn set_xyz(&mut self, channel: usize, xyz: [f32; 3]) {
+        let rgb = match self.2.gamut_warning {
+            Some(warning) if !is_in_srgb_gamut(xyz) => warning,
+            _ => self.rgb_from_xyz(xyz),
+        };
+        self.set_rgb(channel, rgb);
+    }
+    /// Sets channel to given L\*a\*b\* colour handling colours outside of
+    /// the sRGB gamut like `set_xyz`.
+    fn set_lab(&mut self, channel: usize, lab: lab::Lab) {
+        let rgb = match self.2.gamut_warning {
+            Some(warning) if !is_in_srgb_gamut(xyz_from_lab(lab)) => warning,
+            _ => self.rgb_from_lab(lab),
+        };
+        self.set_rgb(channel, rgb);
+    }
+    /// Sets channel to given L\*u\*v\* colour handling colours outside of
+    /// the sRGB gamut like `set_xyz`.
+    fn set_luv(&mut self, channel: usize, luv: luv::Luv) {
+        let rgb = match self.2.gamut_warning {
+            Some(warning) if !is_in_srgb_gamut(xyz_from_luv(luv)) => warning,
+            _ => self.rgb_from_luv(luv),
+        };
+        self.set_rgb(channel, rgb);
+    }
 }
 
 
@@ -29,271 +122,3586 @@ fn mul_add(multiplier: f32, multiplicand: f32, addend: f32) -> f32 {
 
 fn round_u8(value: f32) -> u8 { mul_add(value, 255.0, 0.5) as u8 }
 
+fn matrix_product(matrix: &[[f32; 3]; 3], column: [f32; 3]) -> [f32; 3] {
+    let row = |row: &[f32; 3]| {
+        mul_add(
+            row[0],
+            column[0],
+            mul_add(row[1], column[1], row[2] * column[2]),
+        )
+    };
+    [row(&matrix[0]), row(&matrix[1]), row(&matrix[2])]
+}
 
-pub struct Space {
-    pub name: &'static str,
-    channels: usize,
-    fill_channels: fn(channels: Channels, rgb: Rgb),
+
+/// The basis conversion matrix for moving from XYZ colour space to linear
+/// sRGB space.  Only used to detect colours outside of the sRGB gamut;
+/// conversion itself is done by the `srgb` crate.
+#[rustfmt::skip]
+const SRGB_FROM_XYZ_MATRIX: [[f32; 3]; 3] = [
+    [ 3.24096994, -1.53738318, -0.49861076],
+    [-0.96924364,  1.87596750,  0.04155506],
+    [ 0.05563008, -0.20397696,  1.05697151],
+];
+
+/// Returns whether colour with given XYZ coordinates can be represented in
+/// sRGB without clipping.  A small tolerance is allowed so that rounding
+/// errors don’t flag colours on the edge of the gamut.
+fn is_in_srgb_gamut(xyz: [f32; 3]) -> bool {
+    const TOLERANCE: f32 = 0.5 / 255.0;
+    matrix_product(&SRGB_FROM_XYZ_MATRIX, xyz)
+        .iter()
+        .all(|&v| -TOLERANCE <= v && v <= 1.0 + TOLERANCE)
+}
+
+/// Converts L\*a\*b\* colour into XYZ coordinates using D65 white point.
+fn xyz_from_lab(lab: lab::Lab) -> [f32; 3] {
+    const EPSILON: f32 = 6.0 / 29.0;
+    let f_inv = |t: f32| {
+        if t > EPSILON {
+            t * t * t
+        } else {
+            3.0 * EPSILON * EPSILON * (t - 4.0 / 29.0)
+        }
+    };
+    let fy = (lab.l + 16.0) / 116.0;
+    let [xn, yn, zn] = srgb::xyz::D65_XYZ;
+    [
+        xn * f_inv(fy + lab.a / 500.0),
+        yn * f_inv(fy),
+        zn * f_inv(fy - lab.b / 200.0),
+    ]
 }
 
+/// Converts L\*u\*v\* colour into XYZ coordinates using D65 white point.
+fn xyz_from_luv(luv: luv::Luv) -> [f32; 3] {
+    if luv.l <= 0.0 {
+        return [0.0, 0.0, 0.0];
+    }
+    let [xn, yn, zn] = srgb::xyz::D65_XYZ;
+    let d = xn + 15.0 * yn + 3.0 * zn;
+    let u = luv.u / (13.0 * luv.l) + 4.0 * xn / d;
+    let v = luv.v / (13.0 * luv.l) + 9.0 * yn / d;
+    let y = if luv.l > 8.0 {
+        yn * ((luv.l + 16.0) / 116.0).powi(3)
+    } else {
+        yn * luv.l * (3.0f32 / 29.0).powi(3)
+    };
+    [y * 9.0 * u / (4.0 * v), y, y * (12.0 - 3.0 * u - 20.0 * v) / (4.0 * v)]
+}
 
-pub fn build_image(
-    space: &Space,
-    src_image: &Image,
-) -> Option<(u32, u32, Box<[u8]>)> {
-    let (width, height) = src_image.dimensions();
-    width.checked_mul(space.channels as u32 + 1)?;
+/// The basis conversion matrix for moving from linear Display P3 space to XYZ
+/// colour space.
+#[rustfmt::skip]
+const XYZ_FROM_DISPLAY_P3_MATRIX: [[f32; 3]; 3] = [
+    [0.48657095, 0.26566769, 0.19821729],
+    [0.22897456, 0.69173852, 0.07928691],
+    [0.00000000, 0.04511338, 1.04394437],
+];
 
-    let src_buffer = src_image.as_raw().as_slice();
-    let mut dst_buffer = Box::<[u8]>::new_uninit_slice(
-        src_buffer.len().checked_mul(space.channels + 1)?,
-    );
+/// The basis conversion matrix for moving from XYZ colour space to linear
+/// Display P3 space.
+#[rustfmt::skip]
+const DISPLAY_P3_FROM_XYZ_MATRIX: [[f32; 3]; 3] = [
+    [ 2.49349691, -0.93138362, -0.40271078],
+    [-0.82948897,  1.76266406,  0.02362469],
+    [ 0.03584583, -0.07617239,  0.95688452],
+];
 
-    let dst_rows = dst_buffer
-        .as_chunks_mut::<3>()
-        .0
-        .chunks_exact_mut(width as usize * (space.channels + 1));
-    let src_rows = src_buffer.as_chunks::<3>().0.chunks_exact(width as usize);
+/// The basis conversion matrix for moving from XYZ colour space to linear
+/// Adobe RGB (1998) space.
+#[rustfmt::skip]
+const ADOBE_RGB_FROM_XYZ_MATRIX: [[f32; 3]; 3] = [
+    [ 2.04158790, -0.56500697, -0.34473135],
+    [-0.96924364,  1.87596750,  0.04155506],
+    [ 0.01344428, -0.11836239,  1.01517499],
+];
+
+/// The basis conversion matrix for moving from linear Adobe RGB (1998) space
+/// to XYZ colour space.
+#[rustfmt::skip]
+const XYZ_FROM_ADOBE_RGB_MATRIX: [[f32; 3]; 3] = [
+    [0.57666904, 0.18555824, 0.18822865],
+    [0.29734498, 0.62736357, 0.07529146],
+    [0.02703136, 0.07068885, 0.99133754],
+];
+
+/// The basis conversion matrix for moving from linear Rec. 2020 space to XYZ
+/// colour space.
+#[rustfmt::skip]
+const XYZ_FROM_REC2020_MATRIX: [[f32; 3]; 3] = [
+    [0.63695805, 0.14461690, 0.16888098],
+    [0.26270021, 0.67799807, 0.05930172],
+    [0.00000000, 0.02807269, 1.06098506],
+];
+
+/// The basis conversion matrix for moving from XYZ colour space to linear
+/// Rec. 2020 space.
+#[rustfmt::skip]
+const REC2020_FROM_XYZ_MATRIX: [[f32; 3]; 3] = [
+    [ 1.71665119, -0.35567078, -0.25336628],
+    [-0.66668435,  1.61648124,  0.01576855],
+    [ 0.01763986, -0.04277061,  0.94210312],
+];
+
+/// Constants of the Rec. 2020 transfer function.
+const REC2020_ALPHA: f32 = 1.0992968;
+const REC2020_BETA: f32 = 0.018053968;
+
+/// Gamma of the Adobe RGB (1998) transfer function.
+const ADOBE_RGB_GAMMA: f32 = 563.0 / 256.0;
 
-    for (src_row, dst_row) in src_rows.zip(dst_rows) {
-        let (cpy_row, dst_row) = dst_row.split_at_mut(width as usize);
-        // SAFETY: It’s safe to convert &[T; N] into &[MaybeUninit<T>; N].
-        cpy_row.copy_from_slice(unsafe { std::mem::transmute(src_row) });
+/// Converts image whose pixels are encoded in Display P3 colour space into
+/// sRGB.  Display P3 uses the same transfer function and white point as sRGB
+/// so only the primaries need to be adjusted.  Colours outside of the sRGB
+/// gamut are clipped.
+pub fn srgb_from_display_p3(img: &mut Image) {
+    for pixel in img.pixels_mut() {
+        let linear = srgb::gamma::linear_from_u8(pixel.0);
+        let xyz = matrix_product(&XYZ_FROM_DISPLAY_P3_MATRIX, linear);
+        pixel.0 = srgb::u8_from_xyz(xyz);
+    }
+}
+
+
+/// Identifier of a colour space.  Variants are declared in the same order as
+/// spaces in the `SPACES` array so sorting by the identifier sorts spaces in
+/// that order.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub enum ColorSpaceId {
+    Rgb,
+    LinRgb,
+    AdobeRgb,
+    DisplayP3,
+    Rec2020,
+    Xyz,
+    XyY,
+    Ucs,
+    Hsl,
+    Hsv,
+    Hwb,
+    Hsp,
+    Tsl,
+    Yuv,
+    Lab,
+    LChab,
+    Luv,
+    LChuv,
+    Din99,
+    Cmy,
+    Cmyk,
+    Complement,
+    /// The `ORIGINAL` pseudo-space.
+    Original,
+}
+
+impl ColorSpaceId {
+    /// Returns iterator over identifiers of all spaces in the `SPACES` array.
+    /// The `Original` pseudo-space is not included.
+    pub fn all() -> impl Iterator<Item = Self> {
+        SPACES.iter().map(|space| space.id)
+    }
 
-        for (dst, src) in dst_row.iter_mut().zip(src_row) {
-            let channels = Channels(dst as *mut _, width as usize);
-            (space.fill_channels)(channels, *src);
+    /// Returns colour space with given identifier.
+    pub fn space(self) -> &'static Space {
+        match self {
+            Self::Original => &ORIGINAL,
+            id => &SPACES[id as usize],
         }
     }
+}
 
-    // SAFETY: All data has been initialised.
-    let dst_buffer = unsafe { dst_buffer.assume_init() };
-    Some((
-        width.checked_mul(space.channels as u32 + 1)?,
-        height,
-        dst_buffer,
-    ))
+/// Composites image over a grey checkerboard with squares of given size so
+/// that transparent regions are visible.
+pub fn composite_over_checker(img: &image::RgbaImage, size: u32) -> Image {
+    let size = size.max(1);
+    Image::from_fn(img.width(), img.height(), |x, y| {
+        let bg = if (x / size + y / size) % 2 == 0 {
+            204
+        } else {
+            153
+        };
+        let [r, g, b, a] = img.get_pixel(x, y).0;
+        let blend = |v: u8| {
+            let v = v as u32 * a as u32 + bg * (255 - a as u32);
+            ((v + 127) / 255) as u8
+        };
+        image::Rgb([blend(r), blend(g), blend(b)])
+    })
 }
 
+/// Returns mean colour of the image.  Empty image is treated as black.
+pub fn average_colour(img: &Image) -> Rgb {
+    let mut sum = [0u64; 3];
+    for pixel in img.pixels() {
+        for (sum, &value) in sum.iter_mut().zip(pixel.0.iter()) {
+            *sum += value as u64;
+        }
+    }
+    let count = (img.width() as u64 * img.height() as u64).max(1);
+    let avg = |sum: u64| ((sum + count / 2) / count) as u8;
+    [avg(sum[0]), avg(sum[1]), avg(sum[2])]
+}
 
-fn rgb_fill_channels(mut channels: Channels, rgb: Rgb) {
-    channels.set_rgb(0, [rgb[0], 0, 0]);
-    channels.set_rgb(1, [0, rgb[1], 0]);
-    channels.set_rgb(2, [0, 0, rgb[2]]);
+/// Generates synthetic test image used by `--reference-card`.  From top to
+/// bottom the image consists of bands of: colour bars (white, yellow, cyan,
+/// green, magenta, red, blue and black), a hue sweep at full saturation,
+/// a hue sweep at half saturation, a grey ramp and skin tone patches.
+pub fn reference_card() -> Image {
+    const WIDTH: u32 = 384;
+    const BAND: u32 = 48;
+    #[rustfmt::skip]
+    const BARS: [Rgb; 8] = [
+        [255, 255, 255], [255, 255, 0], [0, 255, 255], [0, 255, 0],
+        [255, 0, 255], [255, 0, 0], [0, 0, 255], [0, 0, 0],
+    ];
+    #[rustfmt::skip]
+    const SKIN: [Rgb; 6] = [
+        [115, 82, 68], [194, 150, 130], [141, 85, 36],
+        [198, 134, 66], [224, 172, 105], [255, 219, 172],
+    ];
+
+    let hue = |x: u32, saturation: f32| {
+        let hue = x as f32 * 6.0 / WIDTH as f32;
+        let c = (1.0 - (hue % 2.0 - 1.0).abs()) * saturation;
+        let (lo, hi) = (1.0 - saturation, 1.0);
+        let c = lo + c;
+        let (r, g, b) = match hue as u8 {
+            0 => (hi, c, lo),
+            1 => (c, hi, lo),
+            2 => (lo, hi, c),
+            3 => (lo, c, hi),
+            4 => (c, lo, hi),
+            _ => (hi, lo, c),
+        };
+        [round_u8(r), round_u8(g), round_u8(b)]
+    };
+    Image::from_fn(WIDTH, BAND * 5, |x, y| {
+        image::Rgb(match y / BAND {
+            0 => BARS[(x * 8 / WIDTH) as usize],
+            1 => hue(x, 1.0),
+            2 => hue(x, 0.5),
+            3 => [round_u8(x as f32 / (WIDTH - 1) as f32); 3],
+            _ => SKIN[(x * 6 / WIDTH) as usize],
+        })
+    })
 }
 
-fn lin_rgb_fill_channels(mut channels: Channels, rgb: Rgb) {
-    let [r, g, b] = srgb::gamma::linear_from_u8(rgb);
-    channels.set_rgb(0, [round_u8(r) as u8, 0, 0]);
-    channels.set_rgb(1, [0, round_u8(g) as u8, 0]);
-    channels.set_rgb(2, [0, 0, round_u8(b) as u8]);
+#[test]
+fn test_reference_card() {
+    let img = reference_card();
+    assert_eq!((384, 240), img.dimensions());
+    let px = |x, y| img.get_pixel(x, y).0;
+    assert_eq!([255, 255, 255], px(0, 0));
+    assert_eq!([0, 0, 0], px(383, 47));
+    assert_eq!([255, 0, 0], px(0, 48));
+    assert_eq!([0, 255, 0], px(128, 48));
+    assert_eq!([255, 128, 128], px(0, 96));
+    assert_eq!([0, 0, 0], px(0, 144));
+    assert_eq!([255, 255, 255], px(383, 144));
+    assert_eq!([115, 82, 68], px(0, 239));
 }
 
+/// Converts image into a mask for `Settings::mask`.  If the image has an alpha
+/// channel, the alpha is used as the mask; otherwise the mask is luminance
+/// of the image.
+pub fn mask_from_image(img: &image::DynamicImage) -> image::GrayImage {
+    if img.color().has_alpha() {
+        let img = img.to_rgba8();
+        image::GrayImage::from_fn(img.width(), img.height(), |x, y| {
+            image::Luma([img.get_pixel(x, y).0[3]])
+        })
+    } else {
+        img.to_luma8()
+    }
+}
 
-fn xyz_fill_channels(mut channels: Channels, rgb: Rgb) {
-    let [x, y, z] = srgb::xyz_from_u8(rgb);
-    channels.set_grey(0, srgb::gamma::compress_u8(x / srgb::xyz::D65_XYZ[0]));
-    channels.set_grey(1, srgb::gamma::compress_u8(y));
-    channels.set_grey(2, srgb::gamma::compress_u8(z / srgb::xyz::D65_XYZ[1]));
+/// Scales mask to given dimensions using nearest neighbour filter so that
+/// the mask stays black and white.  Returns copy of the mask if it already
+/// has the dimensions.
+pub fn fit_mask(
+    mask: &image::GrayImage,
+    (width, height): (u32, u32),
+) -> image::GrayImage {
+    if mask.dimensions() == (width, height) {
+        mask.clone()
+    } else {
+        let filter = image::imageops::FilterType::Nearest;
+        image::imageops::resize(mask, width, height, filter)
+    }
 }
 
-fn xyy_fill_channels(mut channels: Channels, rgb: Rgb) {
-    let [x, y, z] = srgb::xyz_from_u8(rgb);
-    let sum = x + y + z;
+/// Model used to convert colours of CMYK source images into RGB.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CmykModel {
+    /// Multiplies complements of the inks, i.e. R = (1 − C)(1 − K).  This is
+    /// what the `image` crate does and tends to produce washed-out colours
+    /// for print-origin images.
+    Naive,
+    /// Assumes black ink replaced the grey component of the other inks (as
+    /// with grey component replacement) and adds it back, i.e.
+    /// R = 1 − min(1, C + K).  Gives darker, more saturated colours.
+    Gcr,
+}
 
-    fn rgb_from_xyy(lc_x: f32, lc_y: f32) -> Rgb {
-        let x = lc_x * 0.5 / lc_y;
-        let y = 0.5;
-        let z = (1.0 - lc_x - lc_y) * 0.5 / lc_y;
-        srgb::u8_from_xyz([x, y, z])
+impl CmykModel {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Naive => "naive",
+            Self::Gcr => "gcr",
+        }
     }
 
-    channels.set_rgb(0, rgb_from_xyy(x / sum, srgb::xyz::D65_xyY[1]));
-    channels.set_rgb(1, rgb_from_xyy(srgb::xyz::D65_xyY[0], y / sum));
-    channels.set_grey(2, srgb::gamma::compress_u8(y));
+    /// Converts CMYK colour with ink coverage from 0 (no ink) to 255 into
+    /// sRGB.
+    pub fn rgb(self, [c, m, y, k]: [u8; 4]) -> Rgb {
+        let convert = |v: u8| match self {
+            Self::Naive => {
+                let v = (255 - v) as u32 * (255 - k) as u32;
+                ((v + 127) / 255) as u8
+            }
+            Self::Gcr => 255 - v.saturating_add(k),
+        };
+        [convert(c), convert(m), convert(y)]
+    }
 }
 
+#[test]
+fn test_cmyk_model() {
+    for &model in &[CmykModel::Naive, CmykModel::Gcr] {
+        assert_eq!([255, 255, 255], model.rgb([0, 0, 0, 0]));
+        assert_eq!([0, 0, 0], model.rgb([0, 0, 0, 255]));
+        assert_eq!([0, 255, 255], model.rgb([255, 0, 0, 0]));
+    }
+    assert_eq!([64, 128, 128], CmykModel::Naive.rgb([128, 0, 0, 127]));
+    assert_eq!([0, 128, 128], CmykModel::Gcr.rgb([128, 0, 0, 127]));
+}
 
-fn hs_common_from_rgb(
-    channels: &mut Channels,
-    rgb: [u8; 3],
-) -> (u8, u8, i32, i32) {
-    let r = rgb[0];
-    let g = rgb[1];
-    let b = rgb[2];
+/// Dithering method applied to channel values with `--dither`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Dither {
+    /// Ordered dithering with a 4×4 Bayer matrix.
+    Ordered,
+}
 
-    let min = std::cmp::min(std::cmp::min(r, g), b);
-    let max = std::cmp::max(std::cmp::max(r, g), b);
-    let sum = min as i32 + max as i32;
-    let range = max as i32 - min as i32;
+impl Dither {
+    /// Returns threshold added to values of pixel at given coordinates before
+    /// they are rounded to 8 bits.  The threshold is in units of a single
+    /// 8-bit step and lies within (-0.5, 0.5) range.
+    fn threshold(self, x: usize, y: u32) -> f32 {
+        match self {
+            Self::Ordered => {
+                #[rustfmt::skip]
+                const BAYER: [[u8; 4]; 4] = [
+                    [0, 8, 2, 10], [12, 4, 14, 6],
+                    [3, 11, 1, 9], [15, 7, 13, 5],
+                ];
+                let t = BAYER[y as usize % 4][x % 4] as f32;
+                (t + 0.5) / 16.0 - 0.5
+            }
+        }
+    }
+}
 
-    let hue = if range == 0 {
-        f32::NAN
-    } else if max == r {
-        ((g as i32 - b as i32) as f32 / range as f32).rem_euclid(6.0)
-    } else if max == g {
-        (b as i32 - r as i32) as f32 / range as f32 + 2.0
-    } else {
-        (r as i32 - g as i32) as f32 / range as f32 + 4.0
-    };
+#[test]
+fn test_dither() {
+    let settings = Settings::default();
+    let channels =
+        |threshold| Channels(std::ptr::null_mut(), 1, &settings, threshold);
+    // Average of a 4×4 block approximates the value at full precision.
+    for &want in &[0.0, 17.25, 100.3, 128.5, 254.9, 255.0] {
+        let mut sum = 0;
+        for y in 0..4 {
+            for x in 0..4 {
+                let threshold = Dither::Ordered.threshold(x, y);
+                assert!(threshold.abs() < 0.5, "{}", threshold);
+                sum += channels(threshold).round(want / 255.0) as u32;
+            }
+        }
+        let mean = sum as f32 / 16.0;
+        assert!((mean - want).abs() <= 1.0 / 16.0, "{} != {}", want, mean);
+    }
+    // Without dithering values are simply rounded.
+    assert_eq!(100, channels(0.0).round(100.3 / 255.0));
+}
 
-    channels.set_rgb(
-        0,
-        if hue != hue {
-            [0, 0, 0]
-        } else {
-            let x = 0.5 - 0.5 * (hue % 2.0 - 1.0).abs();
-            let (r, g, b) = match hue as u8 {
-                0 => (0.5, x, 0.0),
-                1 => (x, 0.5, 0.0),
-                2 => (0.0, 0.5, x),
-                3 => (0.0, x, 0.5),
-                4 => (x, 0.0, 0.5),
-                5 => (0.5, 0.0, x),
-                _ => unreachable!(),
-            };
-            fn map(v: f32) -> u8 { mul_add(v, 255.0, 64.25) as u8 }
-            [map(r), map(g), map(b)]
-        },
-    );
+/// Resampling filter used when resizing source images.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ResizeFilter {
+    Nearest,
+    Triangle,
+    CatmullRom,
+    Gaussian,
+    /// Lanczos filter with two lobes.  Less prone to ringing than Lanczos3
+    /// but slightly softer.  Not provided by the `image` crate so implemented
+    /// here as a separable convolution.
+    Lanczos2,
+    Lanczos3,
+}
 
-    (min, max, sum, range)
+impl Default for ResizeFilter {
+    fn default() -> Self { Self::Lanczos3 }
 }
 
-fn hsl_fill_channels(mut channels: Channels, rgb: Rgb) {
-    let (_min, _max, sum, range) = hs_common_from_rgb(&mut channels, rgb);
+impl ResizeFilter {
+    /// Returns corresponding filter of the `image` crate or `None` if the
+    /// filter is implemented by this module.
+    pub fn filter_type(self) -> Option<image::imageops::FilterType> {
+        use image::imageops::FilterType;
+        match self {
+            Self::Nearest => Some(FilterType::Nearest),
+            Self::Triangle => Some(FilterType::Triangle),
+            Self::CatmullRom => Some(FilterType::CatmullRom),
+            Self::Gaussian => Some(FilterType::Gaussian),
+            Self::Lanczos2 => None,
+            Self::Lanczos3 => Some(FilterType::Lanczos3),
+        }
+    }
+}
 
-    let saturation = if range == 0 {
-        0.0
+/// Lanczos kernel with window of two lobes.
+fn lanczos2(x: f32) -> f32 {
+    if x == 0.0 {
+        1.0
+    } else if x.abs() < 2.0 {
+        let px = std::f32::consts::PI * x;
+        2.0 * px.sin() * (px / 2.0).sin() / (px * px)
     } else {
-        range as f32 / (255 - (sum - 255).abs()) as f32
-    };
+        0.0
+    }
+}
 
-    channels.set_grey(1, round_u8(saturation) as u8);
-    channels.set_grey(2, (sum / 2) as u8);
+/// Calculates Lanczos2 weights for resampling a line of `src_len` samples into
+/// `dst_len` samples.  For each output sample returns index of the first
+/// contributing input sample and normalised weights of consecutive samples
+/// starting at that index.  When downscaling the kernel is widened so that
+/// all input samples contribute.
+fn lanczos2_weights(src_len: u32, dst_len: u32) -> Vec<(usize, Vec<f32>)> {
+    let ratio = src_len as f32 / dst_len as f32;
+    let scale = ratio.max(1.0);
+    let support = 2.0 * scale;
+    (0..dst_len)
+        .map(|idx| {
+            let centre = (idx as f32 + 0.5) * ratio;
+            let left = (centre - support).floor().max(0.0) as usize;
+            let right = (centre + support).ceil() as usize;
+            let right = right.min(src_len as usize);
+            let mut weights = (left..right)
+                .map(|pos| lanczos2((pos as f32 + 0.5 - centre) / scale))
+                .collect::<Vec<_>>();
+            let sum = weights.iter().sum::<f32>();
+            if sum != 0.0 {
+                weights.iter_mut().for_each(|weight| *weight /= sum);
+            }
+            (left, weights)
+        })
+        .collect()
 }
 
-fn hsv_fill_channels(mut channels: Channels, rgb: Rgb) {
-    let (_min, max, _sum, range) = hs_common_from_rgb(&mut channels, rgb);
+/// Returns weighted sum of samples taken every `stride` elements.
+fn convolve<const N: usize>(
+    samples: &[[f32; N]],
+    weights: &[f32],
+    stride: usize,
+) -> [f32; N] {
+    let mut sum = [0.0; N];
+    for (sample, weight) in samples.iter().step_by(stride).zip(weights) {
+        for (acc, value) in sum.iter_mut().zip(sample.iter()) {
+            *acc += value * weight;
+        }
+    }
+    sum
+}
 
-    let saturation = if max == 0 {
-        0.0
+/// Resizes image given as row-major pixels with `N` channels using Lanczos2
+/// filter.  Horizontal and vertical passes are done separately.
+fn resize_lanczos2<const N: usize>(
+    src: &[[f32; N]],
+    (width, height): (u32, u32),
+    (new_width, new_height): (u32, u32),
+) -> Vec<[f32; N]> {
+    let columns = lanczos2_weights(width, new_width);
+    let rows = lanczos2_weights(height, new_height);
+    let new_width = new_width as usize;
+    let mut tmp = Vec::with_capacity(new_width * height as usize);
+    for row in src.chunks_exact(width as usize) {
+        tmp.extend(columns.iter().map(|(left, weights)| {
+            convolve(&row[*left..], weights, 1)
+        }));
+    }
+    let mut out = Vec::with_capacity(new_width * new_height as usize);
+    for (top, weights) in rows.iter() {
+        let tmp = &tmp[top * new_width..];
+        out.extend(
+            (0..new_width).map(|x| convolve(&tmp[x..], weights, new_width)),
+        );
+    }
+    out
+}
+
+/// Resizes RGBA image to given dimensions using Lanczos2 filter on
+/// gamma-encoded values.
+pub fn resize_rgba_lanczos2(
+    img: &image::RgbaImage,
+    width: u32,
+    height: u32,
+) -> image::RgbaImage {
+    let src = img
+        .pixels()
+        .map(|pixel| {
+            let [r, g, b, a] = pixel.0;
+            [r as f32, g as f32, b as f32, a as f32]
+        })
+        .collect::<Vec<_>>();
+    let resized = resize_lanczos2(&src, img.dimensions(), (width, height));
+    let data = resized
+        .iter()
+        .flat_map(|pixel| pixel.iter())
+        .map(|value| value.round().clamp(0.0, 255.0) as u8)
+        .collect();
+    image::RgbaImage::from_raw(width, height, data).unwrap()
+}
+
+#[test]
+fn test_resize_rgba_lanczos2() {
+    // Flat image stays flat.
+    let img = image::RgbaImage::from_pixel(7, 5, image::Rgba([10, 20, 30, 40]));
+    for &(width, height) in &[(3, 2), (7, 5), (16, 9)] {
+        let resized = resize_rgba_lanczos2(&img, width, height);
+        assert_eq!((width, height), resized.dimensions());
+        for pixel in resized.pixels() {
+            assert_eq!([10, 20, 30, 40], pixel.0);
+        }
+    }
+    // Downscaling by two averages neighbouring columns.
+    let img = image::RgbaImage::from_fn(8, 1, |x, _| {
+        let v = if x < 4 { 0 } else { 255 };
+        image::Rgba([v, v, v, 255])
+    });
+    let resized = resize_rgba_lanczos2(&img, 4, 1);
+    let row = resized.pixels().map(|pixel| pixel.0[0]).collect::<Vec<_>>();
+    assert!(row[0] < 8 && row[3] > 247, "{:?}", row);
+    assert!(row[1] < row[2], "{:?}", row);
+}
+
+/// Sharpens image with an unsharp mask.  Each colour channel becomes
+/// `orig + amount * (orig - blur(orig, sigma))` clamped to the valid range
+/// so the dark side of an edge gets darker and the light side lighter.
+/// Alpha channel is left unchanged.
+pub fn unsharp_mask(
+    img: &image::RgbaImage,
+    sigma: f32,
+    amount: f32,
+) -> image::RgbaImage {
+    let blurred = image::imageops::blur(img, sigma);
+    let mut out = img.clone();
+    for (pixel, blur) in out.pixels_mut().zip(blurred.pixels()) {
+        for (value, &blur) in pixel.0[..3].iter_mut().zip(&blur.0[..3]) {
+            let orig = *value as f32;
+            let sharp = orig + amount * (orig - blur as f32);
+            *value = sharp.round().clamp(0.0, 255.0) as u8;
+        }
+    }
+    out
+}
+
+#[test]
+fn test_unsharp_mask() {
+    // Flat image stays flat.
+    let img = image::RgbaImage::from_pixel(7, 5, image::Rgba([10, 20, 30, 40]));
+    for pixel in unsharp_mask(&img, 1.0, 2.0).pixels() {
+        assert_eq!([10, 20, 30, 40], pixel.0);
+    }
+    // Contrast of a step edge increases on both sides.
+    let img = image::RgbaImage::from_fn(10, 3, |x, _| {
+        let v = if x < 5 { 64 } else { 192 };
+        image::Rgba([v, v, v, 128])
+    });
+    let sharp = unsharp_mask(&img, 1.0, 1.0);
+    let row = (0..10).map(|x| sharp.get_pixel(x, 1).0).collect::<Vec<_>>();
+    assert!(row[4][0] < 64 && row[5][0] > 192, "{:?}", row);
+    assert!(row.iter().all(|pixel| pixel[3] == 128), "{:?}", row);
+    // Far from the edge nothing changes.
+    assert_eq!([64, 64, 64, 128], row[0]);
+    assert_eq!([192, 192, 192, 128], row[9]);
+}
+
+/// Resizes image to given dimensions performing the resampling in linear
+/// light.  Resampling gamma-encoded values directly darkens edges of
+/// high-contrast details.
+pub fn resize_linear(
+    img: &Image,
+    width: u32,
+    height: u32,
+    filter: ResizeFilter,
+) -> Image {
+    let (src_width, src_height) = img.dimensions();
+    let linear = image::ImageBuffer::from_fn(src_width, src_height, |x, y| {
+        image::Rgb(srgb::gamma::linear_from_u8(img.get_pixel(x, y).0))
+    });
+    let resized = if let Some(filter) = filter.filter_type() {
+        image::imageops::resize(&linear, width, height, filter)
     } else {
-        range as f32 / max as f32
+        let src = linear.pixels().map(|pixel| pixel.0).collect::<Vec<_>>();
+        let size = (src_width, src_height);
+        let data = resize_lanczos2(&src, size, (width, height))
+            .iter()
+            .flat_map(|pixel| pixel.iter().copied())
+            .collect();
+        image::ImageBuffer::from_raw(width, height, data).unwrap()
     };
+    Image::from_fn(width, height, |x, y| {
+        let image::Rgb(rgb) = *resized.get_pixel(x, y);
+        image::Rgb(srgb::gamma::u8_from_linear(rgb))
+    })
+}
 
-    channels.set_grey(1, round_u8(saturation) as u8);
-    channels.set_grey(2, max);
+/// Resizes image to given dimensions with given filter.  If `linear` is
+/// set, resampling is performed in linear light (see [`resize_linear`]).
+pub fn resize_image(
+    img: &Image,
+    width: u32,
+    height: u32,
+    filter: ResizeFilter,
+    linear: bool,
+) -> Image {
+    if linear {
+        resize_linear(img, width, height, filter)
+    } else if let Some(filter) = filter.filter_type() {
+        image::imageops::resize(img, width, height, filter)
+    } else {
+        let src = img
+            .pixels()
+            .map(|pixel| {
+                let [r, g, b] = pixel.0;
+                [r as f32, g as f32, b as f32]
+            })
+            .collect::<Vec<_>>();
+        let data = resize_lanczos2(&src, img.dimensions(), (width, height))
+            .iter()
+            .flat_map(|pixel| pixel.iter())
+            .map(|value| value.round().clamp(0.0, 255.0) as u8)
+            .collect();
+        Image::from_raw(width, height, data).unwrap()
+    }
 }
 
-fn hwb_fill_channels(mut channels: Channels, rgb: Rgb) {
-    let (min, max, _sum, _range) = hs_common_from_rgb(&mut channels, rgb);
-    channels.set_grey(1, min);
-    channels.set_grey(2, 255 - max);
+/// Returns height of the legend strip beneath each tile of decomposition of
+/// given image or zero if there is none.
+pub fn tile_legend_height(
+    space: &Space,
+    src_image: &Image,
+    settings: &Settings,
+) -> u32 {
+    if settings.legend && space.fill_legend.is_some() {
+        legend_height(src_image.height())
+    } else {
+        0
+    }
 }
 
-fn abuv_lstar(v: f32, min: f32, max: f32) -> f32 {
-    50.0 * (if v < 0.0 { v / min } else { v / max })
+/// Returns dimensions of a single tile of decomposition of given image
+/// including the legend strip beneath it (if any).
+pub fn tile_dimensions(
+    space: &Space,
+    src_image: &Image,
+    settings: &Settings,
+) -> (u32, u32) {
+    let (width, height) = src_image.dimensions();
+    let legend = tile_legend_height(space, src_image, settings);
+    (width, height.saturating_add(legend))
 }
 
-fn lab_fill_channels(mut channels: Channels, rgb: Rgb) {
-    fn set(channels: &mut Channels, channel: usize, l: f32, a: f32, b: f32) {
-        channels.set_rgb(channel, lab::Lab { l, a, b }.to_rgb());
+/// Resizes every tile of a decomposition image to given size.  The image is
+/// divided into tiles of `tile` dimensions (which must divide the image
+/// evenly) and each is resampled to exactly `size` dimensions ignoring its
+/// aspect ratio.  If tiles end with a legend strip of height `legend`, the
+/// strip is resampled separately so that tile and legend don’t bleed into
+/// each other; its new height is chosen as for a tile of `size` dimensions.
+/// Returns `None` if the resulting image would be too large.
+pub fn resize_tiles(
+    img: &Image,
+    tile: (u32, u32),
+    legend: u32,
+    size: (u32, u32),
+    filter: ResizeFilter,
+    linear: bool,
+) -> Option<Image> {
+    let columns = img.width() / tile.0;
+    let rows = img.height() / tile.1;
+    let src_height = tile.1 - legend;
+    let new_legend = if legend == 0 { 0 } else { legend_height(size.1) };
+    let tile_height = size.1.checked_add(new_legend)?;
+    let width = columns.checked_mul(size.0)?;
+    let height = rows.checked_mul(tile_height)?;
+    (width as usize * 3).checked_mul(height as usize)?;
+    let resize = |x: u32, y: u32, h: u32, new_h: u32| {
+        let view = image::imageops::crop_imm(img, x, y, tile.0, h);
+        resize_image(&view.to_image(), size.0, new_h, filter, linear)
+    };
+    let mut out = Image::new(width, height);
+    for row in 0..rows {
+        for column in 0..columns {
+            let (x, y) = (column * tile.0, row * tile.1);
+            let (out_x, out_y) = (column * size.0, row * tile_height);
+            let resized = resize(x, y, src_height, size.1);
+            image::imageops::replace(&mut out, &resized, out_x, out_y);
+            if legend != 0 {
+                let resized = resize(x, y + src_height, legend, new_legend);
+                let out_y = out_y + size.1;
+                image::imageops::replace(&mut out, &resized, out_x, out_y);
+            }
+        }
     }
-    let lab = lab::Lab::from_rgb(&rgb);
-    set(&mut channels, 0, lab.l, 0.0, 0.0);
-    set(
-        &mut channels,
-        1,
-        abuv_lstar(lab.a, -86.18078, 98.23698),
-        lab.a,
-        0.0,
-    );
-    set(
-        &mut channels,
-        2,
-        abuv_lstar(lab.b, -107.858345, 94.48001),
-        0.0,
-        lab.b,
-    );
+    Some(out)
 }
 
-fn lchab_fill_channels(mut channels: Channels, rgb: Rgb) {
-    fn set(channels: &mut Channels, channel: usize, l: f32, c: f32, h: f32) {
-        channels.set_rgb(channel, lab::LCh { l, c, h }.to_rgb());
+#[test]
+fn test_resize_tiles() {
+    let data = vec![255, 0, 0, 255, 0, 0, 0, 0, 255, 0, 0, 255];
+    let img = Image::from_raw(4, 1, data).unwrap();
+    for &filter in &[ResizeFilter::Lanczos3, ResizeFilter::Lanczos2] {
+        for &linear in &[false, true] {
+            let got =
+                resize_tiles(&img, (2, 1), 0, (1, 3), filter, linear).unwrap();
+            assert_eq!((2, 3), got.dimensions());
+            for y in 0..3 {
+                assert_eq!([255, 0, 0], got.get_pixel(0, y).0);
+                assert_eq!([0, 0, 255], got.get_pixel(1, y).0);
+            }
+        }
+    }
+
+    // Legend strip is resampled separately and doesn’t bleed into the tile.
+    let img = Image::from_fn(2, 12, |_, y| {
+        image::Rgb(if y < 8 { [255, 0, 0] } else { [0, 0, 255] })
+    });
+    let filter = ResizeFilter::Lanczos3;
+    let got = resize_tiles(&img, (2, 12), 4, (1, 64), filter, false).unwrap();
+    assert_eq!((1, 68), got.dimensions());
+    for y in 0..64 {
+        assert_eq!([255, 0, 0], got.get_pixel(0, y).0, "y: {}", y);
+    }
+    for y in 64..68 {
+        assert_eq!([0, 0, 255], got.get_pixel(0, y).0, "y: {}", y);
     }
-    let lch = lab::LCh::from_rgb(&rgb);
-    set(&mut channels, 0, lch.l, 0.0, 0.0);
-    set(&mut channels, 1, lch.c / 1.338088, 0.0, 0.0);
-    set(&mut channels, 2, 50.0, 133.8088 * 0.5, lch.h);
 }
 
-fn luv_fill_channels(mut channels: Channels, rgb: Rgb) {
-    fn set(channels: &mut Channels, channel: usize, l: f32, u: f32, v: f32) {
-        channels.set_rgb(channel, luv::Luv { l, u, v }.to_rgb());
+
+pub struct Space {
+    pub id: ColorSpaceId,
+    pub name: &'static str,
+    channels: usize,
+    fill_channels: fn(channels: Channels<'_>, rgb: Rgb),
+    /// Fills legend for channels whose colour mapping isn’t obvious.  The
+    /// second argument is position within the legend strip from 0 to 1.
+    /// Channels the function doesn’t set are left black.
+    fill_legend: Option<fn(channels: Channels<'_>, t: f32)>,
+}
+
+
+impl Space {
+    /// Returns whether adding channel tiles of the space together in linear
+    /// light gives back the source image.
+    fn is_additive(&self) -> bool {
+        matches!(
+            self.id,
+            ColorSpaceId::Rgb |
+                ColorSpaceId::AdobeRgb |
+                ColorSpaceId::DisplayP3 |
+                ColorSpaceId::Rec2020
+        )
+    }
+
+    /// Returns names of the channels of the space in the order of the tiles.
+    pub fn channel_names(&self) -> &'static [&'static str] {
+        match self.id {
+            ColorSpaceId::Rgb |
+            ColorSpaceId::LinRgb |
+            ColorSpaceId::AdobeRgb |
+            ColorSpaceId::DisplayP3 |
+            ColorSpaceId::Rec2020 => &["R", "G", "B"],
+            ColorSpaceId::Xyz => &["X", "Y", "Z"],
+            ColorSpaceId::XyY => &["x", "y", "Y"],
+            ColorSpaceId::Ucs => &["u", "v", "Y"],
+            ColorSpaceId::Hsl => &["H", "S", "L"],
+            ColorSpaceId::Hsv => &["H", "S", "V"],
+            ColorSpaceId::Hwb => &["H", "W", "B"],
+            ColorSpaceId::Hsp => &["H", "S", "P"],
+            ColorSpaceId::Tsl => &["T", "S", "L"],
+            ColorSpaceId::Yuv => &["Y", "U", "V"],
+            ColorSpaceId::Lab => &["L*", "a*", "b*"],
+            ColorSpaceId::LChab | ColorSpaceId::LChuv => &["L*", "C*", "h"],
+            ColorSpaceId::Luv => &["L*", "u*", "v*"],
+            ColorSpaceId::Din99 => &["L99", "a99", "b99"],
+            ColorSpaceId::Cmy => &["C", "M", "Y"],
+            ColorSpaceId::Cmyk => &["C", "M", "Y", "K"],
+            ColorSpaceId::Complement => &["warm", "cool"],
+            ColorSpaceId::Original => &[],
+        }
+    }
+
+    /// Returns short human-readable description of the space.
+    pub fn description(&self) -> &'static str {
+        match self.id {
+            ColorSpaceId::Rgb => "sRGB",
+            ColorSpaceId::LinRgb => "linear sRGB without gamma correction",
+            ColorSpaceId::AdobeRgb => "Adobe RGB (1998)",
+            ColorSpaceId::DisplayP3 => "Display P3",
+            ColorSpaceId::Rec2020 => "ITU-R BT.2020",
+            ColorSpaceId::Xyz => "CIE 1931 XYZ",
+            ColorSpaceId::XyY => "CIE 1931 xyY (chromaticity and luminance)",
+            ColorSpaceId::Ucs => "CIE 1960 UCS chromaticity and luminance",
+            ColorSpaceId::Hsl => "hue, saturation and lightness",
+            ColorSpaceId::Hsv => "hue, saturation and value",
+            ColorSpaceId::Hwb => "hue, whiteness and blackness",
+            ColorSpaceId::Hsp => "hue, saturation and perceived brightness",
+            ColorSpaceId::Tsl => "tint, saturation and lightness",
+            ColorSpaceId::Yuv => "luma and two chroma components (Rec. 601)",
+            ColorSpaceId::Lab => "CIE L*a*b*",
+            ColorSpaceId::LChab => "cylindrical CIE L*a*b*",
+            ColorSpaceId::Luv => "CIE L*u*v*",
+            ColorSpaceId::LChuv => "cylindrical CIE L*u*v*",
+            ColorSpaceId::Din99 => "DIN 99 perceptually uniform Lab variant",
+            ColorSpaceId::Cmy => "cyan, magenta and yellow",
+            ColorSpaceId::Cmyk => "cyan, magenta, yellow and black",
+            ColorSpaceId::Complement => "warm and cool components of colours",
+            ColorSpaceId::Original => "copy of the source image",
+        }
+    }
+
+    /// Returns kind of quantity given channel measures together with factor
+    /// which scales value of the channel (as returned by [`channel_values`])
+    /// to [0, 1] range (or to turns for hue).  Returns `None` if the channel
+    /// isn’t comparable with channels of other spaces.
+    fn channel_quantity(&self, channel: usize) -> Option<(Quantity, f32)> {
+        use Quantity::*;
+        const DEGREES: f32 = 1.0 / 360.0;
+        Some(match (self.id, channel) {
+            (ColorSpaceId::Xyz, 1) |
+            (ColorSpaceId::XyY, 2) |
+            (ColorSpaceId::Ucs, 2) => (Luminance, 1.0),
+            (ColorSpaceId::Yuv, 0) => (Lightness, 1.0),
+            (ColorSpaceId::Hsl, _) |
+            (ColorSpaceId::Hsv, _) |
+            (ColorSpaceId::Hsp, _) => match channel {
+                0 => (Hue, DEGREES),
+                1 => (Saturation, 1.0),
+                _ => (Lightness, 1.0),
+            },
+            // Tint’s angle starts at a different direction than hue’s so
+            // the two aren’t comparable.
+            (ColorSpaceId::Tsl, 1) => (Saturation, 1.0),
+            (ColorSpaceId::Tsl, 2) => (Lightness, 1.0),
+            (ColorSpaceId::Hwb, 0) => (Hue, DEGREES),
+            (ColorSpaceId::Lab, 0) |
+            (ColorSpaceId::Luv, 0) |
+            (ColorSpaceId::LChab, 0) |
+            (ColorSpaceId::LChuv, 0) |
+            (ColorSpaceId::Din99, 0) => (Lightness, 0.01),
+            (ColorSpaceId::LChab, 2) | (ColorSpaceId::LChuv, 2) => {
+                (Hue, DEGREES)
+            }
+            _ => return None,
+        })
     }
-    let luv = luv::Luv::from_rgb(&rgb);
-    set(&mut channels, 0, luv.l, 0.0, 0.0);
-    set(
-        &mut channels,
-        1,
-        abuv_lstar(luv.u, -83.07059, 175.01141),
-        luv.u,
-        0.0,
-    );
-    set(
-        &mut channels,
-        2,
-        abuv_lstar(luv.v, -134.10574, 107.40619),
-        0.0,
-        luv.v,
-    );
 }
 
-fn lchuv_fill_channels(mut channels: Channels, rgb: Rgb) {
-    fn set(channels: &mut Channels, channel: usize, l: f32, c: f32, h: f32) {
-        channels.set_rgb(channel, luv::LCh { l, c, h }.to_rgb());
+/// Kind of quantity measured by a channel.  Channels measuring the same kind
+/// of quantity in different spaces are compared in `--space-diff` mode.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Quantity {
+    /// Lightness, brightness, value or luma; all of them gamma-encoded or
+    /// perceptual.
+    Lightness,
+    /// Linear relative luminance, i.e. the Y of CIE XYZ.
+    Luminance,
+    Hue,
+    /// Saturation of the cylindrical RGB-based spaces.
+    Saturation,
+}
+
+/// Options affecting how the decomposition images are rendered.
+#[derive(Default)]
+pub struct Settings {
+    /// Whether to invert colours of the channel tiles.  The copy of the source
+    /// image is left untouched.
+    pub invert: bool,
+    /// Whether to add legend strips beneath channels of spaces which define
+    /// them.
+    pub legend: bool,
+    /// Where to put copy of the source image.
+    pub original: OriginalPosition,
+    /// Colour of the area beneath tiles which have no legend strip.
+    pub background: Rgb,
+    /// Colour used in hue channels for pixels whose hue is undefined.
+    pub undefined_hue: Rgb,
+    /// Whether to add a tile with the image reconstructed from the channel
+    /// tiles.  Has effect only for additive spaces.
+    pub reconstruct: bool,
+    /// Whether to add a tile with luminance of the image after the channel
+    /// tiles regardless of the colour space.
+    pub luma_tile: bool,
+    /// Constant lightness to render opponent-axis channels (a and b of Lab and
+    /// DIN99, u and v of Luv) with.  If `None`, lightness is derived from the
+    /// value of the channel.
+    pub chroma_lightness: Option<f32>,
+    /// Colour to mark pixels with whose colour in a channel falls outside of
+    /// the sRGB gamut (and would otherwise be clipped).  Has effect only on
+    /// channels of RGB spaces with different primaries (e.g. Adobe RGB) and
+    /// of xyY, UCS, Lab, Luv and DIN99 spaces.
+    pub gamut_warning: Option<Rgb>,
+    /// Whether to lay the tiles out in the most square grid possible rather
+    /// than in a single row.  Unused cells of the grid are filled with the
+    /// background colour.
+    pub grid: bool,
+    /// Gamma of the curve applied to the channel tiles (and their legends)
+    /// after they are rendered.  Values above one brighten the midtones.  The
+    /// copy of the source image is left untouched.
+    pub display_gamma: Option<f32>,
+    /// Thresholds to render black and white masks for.  Each threshold of
+    /// the space being decomposed adds a tile after the channel tiles (and
+    /// the luminance tile) which is white where value of the channel is
+    /// greater than the threshold.
+    pub thresholds: Vec<Threshold>,
+    /// Mask restricting which pixels are decomposed.  Pixels whose mask value
+    /// is below 128 are rendered with the background colour in the channel
+    /// tiles (as well as luminance and threshold tiles).  Ignored if its
+    /// dimensions differ from those of the source image.
+    pub mask: Option<std::sync::Arc<image::GrayImage>>,
+    /// Whether to draw name of the channel in top-left corner of each channel
+    /// tile.
+    pub channel_labels: bool,
+    /// Dithering applied to channel values when they are quantised to 8 bits.
+    /// Dithering adds fine noise which prevents smooth gradients from turning
+    /// into visible bands when the image is saved with lossy compression.
+    pub dither: Option<Dither>,
+}
+
+/// Threshold of a single channel of a colour space.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Threshold {
+    pub space: ColorSpaceId,
+    /// Index of the channel in the space.
+    pub channel: usize,
+    /// The threshold in the channel’s raw units as returned by
+    /// [`channel_values`].
+    pub value: f32,
+}
+
+/// Position of the copy of the source image relative to the channel tiles.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OriginalPosition {
+    First,
+    Last,
+    None,
+}
+
+impl Default for OriginalPosition {
+    fn default() -> Self { Self::First }
+}
+
+impl OriginalPosition {
+    /// Splits row of the output image into tile with copy of the source image
+    /// (if any) and the channel tiles.  `width` is width of a single tile in
+    /// elements of the row.
+    fn split_row<T>(
+        self,
+        row: &mut [T],
+        width: usize,
+    ) -> (Option<&mut [T]>, &mut [T]) {
+        match self {
+            Self::First => {
+                let (cpy_row, dst_row) = row.split_at_mut(width);
+                (Some(cpy_row), dst_row)
+            }
+            Self::Last => {
+                let (dst_row, cpy_row) = row.split_at_mut(row.len() - width);
+                (Some(cpy_row), dst_row)
+            }
+            Self::None => (None, row),
+        }
+    }
+
+    /// Splits channel part of a row of the output image into reconstruction
+    /// tile and the channel tiles.  The reconstruction tile is placed next to
+    /// the copy of the source image or at the end if there’s no copy.
+    fn split_reconstruction<T>(
+        self,
+        row: &mut [T],
+        width: usize,
+    ) -> (&mut [T], &mut [T]) {
+        match self {
+            Self::First => row.split_at_mut(width),
+            Self::Last | Self::None => {
+                let (dst_row, rec_row) = row.split_at_mut(row.len() - width);
+                (rec_row, dst_row)
+            }
+        }
     }
-    let lch = luv::LCh::from_rgb(&rgb);
-    set(&mut channels, 0, lch.l, 0.0, 0.0);
-    set(&mut channels, 1, lch.c / 1.790383, 0.0, 0.0);
-    set(&mut channels, 2, 50.0, 179.0383 * 0.5, lch.h);
 }
 
+/// Returns height of the legend strip for image of given height.
+fn legend_height(height: u32) -> u32 { (height / 16).clamp(4, 32) }
 
-fn cmy_fill_channels(mut channels: Channels, rgb: Rgb) {
-    let [r, g, b] = rgb;
-    channels.set_rgb(0, [0, 255 - r, 255 - r]);
-    channels.set_rgb(1, [255 - g, 0, 255 - g]);
-    channels.set_rgb(2, [255 - b, 255 - b, 0]);
+/// Returns number of columns of the most square grid which fits given number
+/// of tiles, i.e. ⌈√tiles⌉.
+fn grid_columns(tiles: usize) -> usize {
+    let mut columns = 1;
+    while columns * columns < tiles {
+        columns += 1;
+    }
+    columns
 }
 
-fn cmyk_fill_channels(mut channels: Channels, rgb: Rgb) {
-    let [r, g, b] = rgb;
-    let max = std::cmp::max(std::cmp::max(r, g), b);
-    let c = round_u8(1.0 - r as f32 / max as f32);
-    let m = round_u8(1.0 - g as f32 / max as f32);
-    let y = round_u8(1.0 - b as f32 / max as f32);
-    channels.set_rgb(0, [0, c, c]);
-    channels.set_rgb(1, [m, 0, m]);
-    channels.set_rgb(2, [y, y, 0]);
-    channels.set_grey(3, 255 - max);
+
+pub fn build_image(
+    space: &Space,
+    src_image: &Image,
+    settings: &Settings,
+) -> Option<(u32, u32, Box<[u8]>)> {
+    build_image_with_original(space, src_image, src_image, settings)
 }
 
+/// Like `build_image` but returns the decomposition as an `image::RgbImage`.
+pub fn build_rgb_image(
+    space: &Space,
+    src_image: &Image,
+    settings: &Settings,
+) -> Option<Image> {
+    build_image(space, src_image, settings).and_then(image_from_raw)
+}
 
-#[rustfmt::skip]
-pub static SPACES: [Space; 13] = [
-    Space { name: "rgb",     channels: 3, fill_channels: rgb_fill_channels},
-    Space { name: "lin-rgb", channels: 3, fill_channels: lin_rgb_fill_channels},
-    Space { name: "XYZ",     channels: 3, fill_channels: xyz_fill_channels},
-    Space { name: "xyY",     channels: 3, fill_channels: xyy_fill_channels},
-    Space { name: "hsl",     channels: 3, fill_channels: hsl_fill_channels},
-    Space { name: "hsv",     channels: 3, fill_channels: hsv_fill_channels},
-    Space { name: "hwb",     channels: 3, fill_channels: hwb_fill_channels},
-    Space { name: "lab",     channels: 3, fill_channels: lab_fill_channels},
-    Space { name: "lchab",   channels: 3, fill_channels: lchab_fill_channels},
-    Space { name: "luv",     channels: 3, fill_channels: luv_fill_channels},
-    Space { name: "lchuv",   channels: 3, fill_channels: lchuv_fill_channels},
-    Space { name: "cmy",     channels: 3, fill_channels: cmy_fill_channels},
-    Space { name: "cmyk",    channels: 4, fill_channels: cmyk_fill_channels},
-];
+/// Converts raw image data returned by `build_image` and similar functions
+/// into an `image::RgbImage`.
+pub fn image_from_raw(
+    (width, height, data): (u32, u32, Box<[u8]>),
+) -> Option<Image> {
+    Image::from_raw(width, height, data.into_vec())
+}
+
+/// Like `build_image` but uses `original` image for the copy of the source.
+/// This lets callers render the copy differently than the image being
+/// decomposed, e.g. composited over a background.  Both images must have the
+/// same dimensions.
+pub fn build_image_with_original(
+    space: &Space,
+    src_image: &Image,
+    original: &Image,
+    settings: &Settings,
+) -> Option<(u32, u32, Box<[u8]>)> {
+    let rows = Decomposition::new(space, src_image, original, settings)?;
+    let (total_width, total_height) = rows.dimensions();
+    let mut dst_buffer = Box::<[u8]>::new_uninit_slice(rows.byte_size());
+    let dst_rows = dst_buffer
+        .as_chunks_mut::<3>()
+        .0
+        .chunks_exact_mut(total_width as usize);
+    for (y, dst_row) in dst_rows.enumerate() {
+        rows.fill_row(y as u32, dst_row);
+    }
+    // SAFETY: All data has been initialised.
+    let dst_buffer = unsafe { dst_buffer.assume_init() };
+    Some((total_width, total_height, dst_buffer))
+}
+
+
+/// Decomposition image which can be rendered one row at a time.  This lets
+/// callers encode the image without ever holding all of it in memory.
+pub struct Decomposition<'a> {
+    space: &'a Space,
+    src_image: &'a Image,
+    original: &'a Image,
+    settings: &'a Settings,
+    legend: Option<fn(channels: Channels<'_>, t: f32)>,
+    reconstruct: bool,
+    luma_tile: bool,
+    /// Channels and values of thresholds of the space to render masks for.
+    thresholds: Vec<(usize, f32)>,
+    /// Mask restricting which pixels are decomposed.
+    mask: Option<&'a image::GrayImage>,
+    /// Lookup table mapping values of channel tiles with `--display-gamma`.
+    gamma: Option<[u8; 256]>,
+    /// Number of tiles and number of tiles in a single row of the output.
+    /// The two are equal unless tiles are laid out in a grid.
+    tiles: usize,
+    columns: usize,
+    /// Height of a single row of tiles including the legend strip.
+    strip_height: u32,
+    total_width: u32,
+    total_height: u32,
+    /// Buffer for a row of the strip reused when rendering rows of the grid.
+    strip: std::sync::Mutex<Vec<UnRgb>>,
+}
+
+impl<'a> Decomposition<'a> {
+    /// Prepares decomposition of `src_image` in given colour space with copy
+    /// of the source taken from `original`.  Both images must have the same
+    /// dimensions.  Returns `None` if the image is empty or the decomposition
+    /// would be too large.
+    pub fn new(
+        space: &'a Space,
+        src_image: &'a Image,
+        original: &'a Image,
+        settings: &'a Settings,
+    ) -> Option<Self> {
+        assert_eq!(src_image.dimensions(), original.dimensions());
+        let (width, height) = src_image.dimensions();
+        let reconstruct = settings.reconstruct && space.is_additive();
+        let thresholds = settings
+            .thresholds
+            .iter()
+            .filter(|th| th.space == space.id && th.channel < space.channels)
+            .map(|th| (th.channel, th.value))
+            .collect::<Vec<_>>();
+        let tiles = space.channels +
+            (settings.original != OriginalPosition::None) as usize +
+            reconstruct as usize +
+            settings.luma_tile as usize +
+            thresholds.len();
+        // Callers are expected to reject empty images with a better error
+        // message.
+        if tiles == 0 || width == 0 || height == 0 {
+            return None;
+        }
+        let columns = if settings.grid { grid_columns(tiles) } else { tiles };
+        let rows = (tiles + columns - 1) / columns;
+        let total_width = width.checked_mul(columns as u32)?;
+        // Buffer for the strip spans all tiles even when laid out in a grid.
+        width.checked_mul(tiles as u32)?;
+        let legend = match space.fill_legend {
+            Some(fill_legend) if settings.legend => Some(fill_legend),
+            _ => None,
+        };
+        let legend_height = legend.map_or(0, |_| legend_height(height));
+        let strip_height = height.checked_add(legend_height)?;
+        let total_height = strip_height.checked_mul(rows as u32)?;
+        (total_width as usize * 3).checked_mul(total_height as usize)?;
+        let mask = settings
+            .mask
+            .as_deref()
+            .filter(|mask| mask.dimensions() == (width, height));
+        let gamma = settings.display_gamma.map(|gamma| {
+            let mut lut = [0; 256];
+            for (value, out) in lut.iter_mut().enumerate() {
+                let value = (value as f32 / 255.0).powf(1.0 / gamma);
+                *out = round_u8(value);
+            }
+            lut
+        });
+        Some(Self {
+            space,
+            src_image,
+            original,
+            settings,
+            legend,
+            reconstruct,
+            luma_tile: settings.luma_tile,
+            thresholds,
+            mask,
+            gamma,
+            tiles,
+            columns,
+            strip_height,
+            total_width,
+            total_height,
+            strip: Default::default(),
+        })
+    }
+
+    /// Returns width and height of the decomposition image.
+    pub fn dimensions(&self) -> (u32, u32) {
+        (self.total_width, self.total_height)
+    }
+
+    /// Returns size of the decomposition image in bytes.
+    pub fn byte_size(&self) -> usize {
+        self.total_width as usize * 3 * self.total_height as usize
+    }
+
+    /// Renders row `y` of the decomposition into `row` which must be exactly
+    /// three times image width long.
+    pub fn render_row(&self, y: u32, row: &mut [u8]) {
+        // SAFETY: It’s safe to convert &mut [T] into &mut [MaybeUninit<T>] so
+        // long as only initialised values are written into it which is what
+        // fill_row does.
+        let row: &mut [std::mem::MaybeUninit<u8>] =
+            unsafe { std::mem::transmute(row) };
+        self.fill_row(y, row.as_chunks_mut::<3>().0);
+    }
+
+    /// Renders row `y` of the decomposition into `dst_row`.  Initialises all
+    /// of the pixels.
+    fn fill_row(&self, y: u32, dst_row: &mut [UnRgb]) {
+        assert_eq!(self.total_width as usize, dst_row.len());
+        if self.columns == self.tiles {
+            self.fill_strip_row(y, dst_row, 0..self.tiles);
+            return;
+        }
+        // With tiles laid out in a grid, render only the tiles which belong in
+        // this row of the grid and copy them over.
+        let width = self.src_image.width() as usize;
+        let first = (y / self.strip_height) as usize * self.columns;
+        let wanted = first..(first + self.columns).min(self.tiles);
+        let mut strip = self
+            .strip
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        // The buffer is initialised once so that tiles which aren’t rendered
+        // still hold initialised, if stale, values.
+        let zero = [std::mem::MaybeUninit::new(0); 3];
+        strip.resize(self.tiles * width, zero);
+        self.fill_strip_row(y % self.strip_height, &mut strip, wanted.clone());
+        let (dst_row, rest) = dst_row.split_at_mut(wanted.len() * width);
+        let start = wanted.start * width;
+        dst_row.copy_from_slice(&strip[start..start + dst_row.len()]);
+        let background = self.settings.background;
+        for pixel in rest {
+            std::mem::MaybeUninit::write_slice(pixel, &background);
+        }
+    }
+
+    /// Renders row `y` of the decomposition with all tiles laid out in
+    /// a single row into `dst_row`.  Only tiles whose indexes are in `wanted`
+    /// range are rendered though the cheap steps, i.e. inverting and masking,
+    /// still go over all of them.  Initialises all of the pixels if `wanted`
+    /// spans all tiles; otherwise `dst_row` must have been initialised.
+    fn fill_strip_row(
+        &self,
+        y: u32,
+        dst_row: &mut [UnRgb],
+        wanted: std::ops::Range<usize>,
+    ) {
+        let (width, height) = self.src_image.dimensions();
+        let width = width as usize;
+        let original = self.settings.original;
+        // Indexes of the tiles within the strip.
+        let want_cpy = match original {
+            OriginalPosition::First => wanted.contains(&0),
+            OriginalPosition::Last => wanted.contains(&(self.tiles - 1)),
+            OriginalPosition::None => false,
+        };
+        let first_channel = if original == OriginalPosition::First {
+            1 + self.reconstruct as usize
+        } else {
+            0
+        };
+        let luma_tile = first_channel + self.space.channels;
+        let first_threshold = luma_tile + self.luma_tile as usize;
+        let rec_tile = if original == OriginalPosition::First {
+            1
+        } else {
+            first_threshold + self.thresholds.len()
+        };
+        let want_rec = self.reconstruct && wanted.contains(&rec_tile);
+        let want_channels = want_rec ||
+            (wanted.start < luma_tile && first_channel < wanted.end);
+        let dither = self.settings.dither;
+        let channels = |dst: &mut UnRgb, x: usize| {
+            let threshold = dither.map_or(0.0, |dither| dither.threshold(x, y));
+            Channels(dst as *mut _, width, self.settings, threshold)
+        };
+        let (cpy_row, dst_row) = original.split_row(dst_row, width);
+        let (rec_row, dst_row) = if self.reconstruct {
+            let (rec_row, dst_row) =
+                original.split_reconstruction(dst_row, width);
+            (Some(rec_row), dst_row)
+        } else {
+            (None, dst_row)
+        };
+        if y < height {
+            let range = y as usize * width..(y as usize + 1) * width;
+            let src_row =
+                &self.src_image.as_raw().as_chunks::<3>().0[range.clone()];
+            if let Some(cpy_row) = cpy_row.filter(|_| want_cpy) {
+                let org_row = &self.original.as_raw().as_chunks::<3>().0[range];
+                // SAFETY: It’s safe to convert &[T; N] into
+                // &[MaybeUninit<T>; N].
+                cpy_row
+                    .copy_from_slice(unsafe { std::mem::transmute(org_row) });
+            }
+            if want_channels {
+                let dst_row = dst_row.iter_mut().zip(src_row).enumerate();
+                for (x, (dst, src)) in dst_row {
+                    (self.space.fill_channels)(channels(dst, x), *src);
+                }
+            }
+            if self.luma_tile && wanted.contains(&luma_tile) {
+                let luma_row = &mut dst_row[self.space.channels * width..];
+                for (dst, src) in luma_row.iter_mut().zip(src_row) {
+                    let luma = luma_from_rgb(*src);
+                    std::mem::MaybeUninit::write_slice(dst, &[luma; 3]);
+                }
+            }
+            if !self.thresholds.is_empty() {
+                let start = (first_threshold - first_channel) * width;
+                let len = self.thresholds.len();
+                let index =
+                    |tile: usize| tile.saturating_sub(first_threshold).min(len);
+                let wanted = index(wanted.start)..index(wanted.end);
+                self.fill_thresholds(&mut dst_row[start..], src_row, wanted);
+            }
+            if let Some(rec_row) = rec_row.filter(|_| want_rec) {
+                self.fill_reconstruction(rec_row, dst_row);
+            }
+        } else if let Some(fill_legend) = self.legend {
+            let background = self.settings.background;
+            let rest = cpy_row.into_iter().chain(rec_row).flatten();
+            for pixel in rest.chain(dst_row.iter_mut()) {
+                std::mem::MaybeUninit::write_slice(pixel, &background);
+            }
+            let scale = 1.0 / (width.max(2) - 1) as f32;
+            for (x, dst) in dst_row[..width].iter_mut().enumerate() {
+                fill_legend(channels(dst, x), x as f32 * scale);
+            }
+        }
+
+        let gamma = self.gamma.as_ref();
+        if let Some(lut) = gamma.filter(|_| want_channels || y >= height) {
+            let channel_row = &mut dst_row[..self.space.channels * width];
+            for pixel in channel_row.iter_mut() {
+                // SAFETY: All pixels of the row have been initialised above.
+                let pixel = unsafe { &mut *pixel.as_mut_ptr().cast::<Rgb>() };
+                for value in pixel.iter_mut() {
+                    *value = lut[*value as usize];
+                }
+            }
+        }
+        if self.settings.invert {
+            for pixel in dst_row.iter_mut() {
+                // SAFETY: All pixels of the row have been initialised above.
+                let pixel = unsafe { &mut *pixel.as_mut_ptr().cast::<Rgb>() };
+                for value in pixel.iter_mut() {
+                    *value = 255 - *value;
+                }
+            }
+        }
+        // Mask is applied last so that pixels outside of it don’t affect the
+        // reconstruction and are left with exactly the background colour.
+        if let Some(mask) = self.mask.filter(|_| y < height) {
+            let range = y as usize * width..(y as usize + 1) * width;
+            self.apply_mask(dst_row, &mask.as_raw()[range]);
+        }
+        if self.settings.channel_labels && y < height {
+            let names = self.space.channel_names();
+            let scale = label_scale(height);
+            for (tile, name) in dst_row.chunks_exact_mut(width).zip(names) {
+                draw_label(tile, y, name, scale);
+            }
+        }
+    }
+
+    /// Fills threshold mask tiles, one after another, for given row of the
+    /// source image.  Only masks of thresholds whose indexes are in `wanted`
+    /// range are rendered.
+    fn fill_thresholds(
+        &self,
+        dst_row: &mut [UnRgb],
+        src_row: &[Rgb],
+        wanted: std::ops::Range<usize>,
+    ) {
+        if wanted.is_empty() {
+            return;
+        }
+        let width = src_row.len();
+        let thresholds = &self.thresholds[wanted.clone()];
+        let mut values = [0.0; MAX_CHANNELS];
+        for (x, src) in src_row.iter().enumerate() {
+            write_channel_values(self.space, *src, &mut values);
+            for (idx, &(channel, threshold)) in
+                (wanted.start..).zip(thresholds.iter())
+            {
+                // Comparison is false for NaN (e.g. undefined hue).
+                let value = if values[channel] > threshold { 255 } else { 0 };
+                let dst = &mut dst_row[idx * width + x];
+                std::mem::MaybeUninit::write_slice(dst, &[value; 3]);
+            }
+        }
+    }
+
+    /// Fills pixels outside of the mask with the background colour in the
+    /// channel, luminance and threshold tiles.
+    fn apply_mask(&self, dst_row: &mut [UnRgb], mask_row: &[u8]) {
+        let width = mask_row.len();
+        let tiles = self.space.channels +
+            self.luma_tile as usize +
+            self.thresholds.len();
+        let background = self.settings.background;
+        for (x, &value) in mask_row.iter().enumerate() {
+            if value >= 128 {
+                continue;
+            }
+            for tile in 0..tiles {
+                let dst = &mut dst_row[tile * width + x];
+                std::mem::MaybeUninit::write_slice(dst, &background);
+            }
+        }
+    }
+
+    /// Fills reconstruction tile by adding channel tiles together in linear
+    /// light.  The channel tiles must have been initialised.
+    fn fill_reconstruction(&self, rec_row: &mut [UnRgb], dst_row: &[UnRgb]) {
+        let width = rec_row.len();
+        for (x, rec) in rec_row.iter_mut().enumerate() {
+            let mut sum = [0.0f32; 3];
+            for channel in 0..self.space.channels {
+                let pixel = &dst_row[channel * width + x];
+                // SAFETY: Caller guarantees channel tiles are initialised.
+                let pixel = unsafe { *pixel.as_ptr().cast::<Rgb>() };
+                let linear = srgb::gamma::linear_from_u8(pixel);
+                for (sum, value) in sum.iter_mut().zip(linear.iter()) {
+                    *sum += value;
+                }
+            }
+            for value in sum.iter_mut() {
+                *value = value.min(1.0);
+            }
+            let rgb = srgb::gamma::u8_from_linear(sum);
+            std::mem::MaybeUninit::write_slice(rec, &rgb);
+        }
+    }
+}
+
+
+/// 5×7 glyphs of characters used in channel names and commonly found in file
+/// names.  Each row of a glyph is stored in five least significant bits with
+/// the most significant one being the leftmost pixel.
+#[rustfmt::skip]
+const FONT: [(char, [u8; 7]); 79] = [
+    ('!', [0b00100, 0b00100, 0b00100, 0b00100, 0b00000, 0b00000, 0b00100]),
+    ('#', [0b01010, 0b01010, 0b11111, 0b01010, 0b11111, 0b01010, 0b01010]),
+    ('%', [0b11000, 0b11001, 0b00010, 0b00100, 0b01000, 0b10011, 0b00011]),
+    ('&', [0b01100, 0b10010, 0b10100, 0b01000, 0b10101, 0b10010, 0b01101]),
+    ('\'', [0b01100, 0b00100, 0b01000, 0b00000, 0b00000, 0b00000, 0b00000]),
+    ('(', [0b00010, 0b00100, 0b01000, 0b01000, 0b01000, 0b00100, 0b00010]),
+    (')', [0b01000, 0b00100, 0b00010, 0b00010, 0b00010, 0b00100, 0b01000]),
+    ('*', [0b00000, 0b00100, 0b10101, 0b01110, 0b10101, 0b00100, 0b00000]),
+    ('+', [0b00000, 0b00100, 0b00100, 0b11111, 0b00100, 0b00100, 0b00000]),
+    (',', [0b00000, 0b00000, 0b00000, 0b00000, 0b01100, 0b00100, 0b01000]),
+    ('-', [0b00000, 0b00000, 0b00000, 0b11111, 0b00000, 0b00000, 0b00000]),
+    ('.', [0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b01100, 0b01100]),
+    ('0', [0b01110, 0b10001, 0b10011, 0b10101, 0b11001, 0b10001, 0b01110]),
+    ('1', [0b00100, 0b01100, 0b00100, 0b00100, 0b00100, 0b00100, 0b01110]),
+    ('2', [0b01110, 0b10001, 0b00001, 0b00010, 0b00100, 0b01000, 0b11111]),
+    ('3', [0b11111, 0b00010, 0b00100, 0b00010, 0b00001, 0b10001, 0b01110]),
+    ('4', [0b00010, 0b00110, 0b01010, 0b10010, 0b11111, 0b00010, 0b00010]),
+    ('5', [0b11111, 0b10000, 0b11110, 0b00001, 0b00001, 0b10001, 0b01110]),
+    ('6', [0b00110, 0b01000, 0b10000, 0b11110, 0b10001, 0b10001, 0b01110]),
+    ('7', [0b11111, 0b00001, 0b00010, 0b00100, 0b01000, 0b01000, 0b01000]),
+    ('8', [0b01110, 0b10001, 0b10001, 0b01110, 0b10001, 0b10001, 0b01110]),
+    ('9', [0b01110, 0b10001, 0b10001, 0b01111, 0b00001, 0b00010, 0b01100]),
+    ('=', [0b00000, 0b00000, 0b11111, 0b00000, 0b11111, 0b00000, 0b00000]),
+    ('@', [0b01110, 0b10001, 0b00001, 0b01101, 0b10101, 0b10101, 0b01110]),
+    ('A', [0b01110, 0b10001, 0b10001, 0b10001, 0b11111, 0b10001, 0b10001]),
+    ('B', [0b11110, 0b10001, 0b10001, 0b11110, 0b10001, 0b10001, 0b11110]),
+    ('C', [0b01110, 0b10001, 0b10000, 0b10000, 0b10000, 0b10001, 0b01110]),
+    ('D', [0b11100, 0b10010, 0b10001, 0b10001, 0b10001, 0b10010, 0b11100]),
+    ('E', [0b11111, 0b10000, 0b10000, 0b11110, 0b10000, 0b10000, 0b11111]),
+    ('F', [0b11111, 0b10000, 0b10000, 0b11110, 0b10000, 0b10000, 0b10000]),
+    ('G', [0b01110, 0b10001, 0b10000, 0b10111, 0b10001, 0b10001, 0b01111]),
+    ('H', [0b10001, 0b10001, 0b10001, 0b11111, 0b10001, 0b10001, 0b10001]),
+    ('I', [0b01110, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b01110]),
+    ('J', [0b00111, 0b00010, 0b00010, 0b00010, 0b00010, 0b10010, 0b01100]),
+    ('K', [0b10001, 0b10010, 0b10100, 0b11000, 0b10100, 0b10010, 0b10001]),
+    ('L', [0b10000, 0b10000, 0b10000, 0b10000, 0b10000, 0b10000, 0b11111]),
+    ('M', [0b10001, 0b11011, 0b10101, 0b10101, 0b10001, 0b10001, 0b10001]),
+    ('N', [0b10001, 0b10001, 0b11001, 0b10101, 0b10011, 0b10001, 0b10001]),
+    ('O', [0b01110, 0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01110]),
+    ('P', [0b11110, 0b10001, 0b10001, 0b11110, 0b10000, 0b10000, 0b10000]),
+    ('Q', [0b01110, 0b10001, 0b10001, 0b10001, 0b10101, 0b10010, 0b01101]),
+    ('R', [0b11110, 0b10001, 0b10001, 0b11110, 0b10100, 0b10010, 0b10001]),
+    ('S', [0b01111, 0b10000, 0b10000, 0b01110, 0b00001, 0b00001, 0b11110]),
+    ('T', [0b11111, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100]),
+    ('U', [0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01110]),
+    ('V', [0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01010, 0b00100]),
+    ('W', [0b10001, 0b10001, 0b10001, 0b10101, 0b10101, 0b10101, 0b01010]),
+    ('X', [0b10001, 0b10001, 0b01010, 0b00100, 0b01010, 0b10001, 0b10001]),
+    ('Y', [0b10001, 0b10001, 0b01010, 0b00100, 0b00100, 0b00100, 0b00100]),
+    ('Z', [0b11111, 0b00001, 0b00010, 0b00100, 0b01000, 0b10000, 0b11111]),
+    ('[', [0b01110, 0b01000, 0b01000, 0b01000, 0b01000, 0b01000, 0b01110]),
+    (']', [0b01110, 0b00010, 0b00010, 0b00010, 0b00010, 0b00010, 0b01110]),
+    ('_', [0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b11111]),
+    ('a', [0b00000, 0b00000, 0b01110, 0b00001, 0b01111, 0b10001, 0b01111]),
+    ('b', [0b10000, 0b10000, 0b10110, 0b11001, 0b10001, 0b10001, 0b11110]),
+    ('c', [0b00000, 0b00000, 0b01110, 0b10000, 0b10000, 0b10001, 0b01110]),
+    ('d', [0b00001, 0b00001, 0b01101, 0b10011, 0b10001, 0b10001, 0b01111]),
+    ('e', [0b00000, 0b00000, 0b01110, 0b10001, 0b11111, 0b10000, 0b01110]),
+    ('f', [0b00110, 0b01001, 0b01000, 0b11100, 0b01000, 0b01000, 0b01000]),
+    ('g', [0b00000, 0b01111, 0b10001, 0b10001, 0b01111, 0b00001, 0b01110]),
+    ('h', [0b10000, 0b10000, 0b10110, 0b11001, 0b10001, 0b10001, 0b10001]),
+    ('i', [0b00100, 0b00000, 0b01100, 0b00100, 0b00100, 0b00100, 0b01110]),
+    ('j', [0b00010, 0b00000, 0b00110, 0b00010, 0b00010, 0b10010, 0b01100]),
+    ('k', [0b10000, 0b10000, 0b10010, 0b10100, 0b11000, 0b10100, 0b10010]),
+    ('l', [0b01100, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b01110]),
+    ('m', [0b00000, 0b00000, 0b11010, 0b10101, 0b10101, 0b10001, 0b10001]),
+    ('n', [0b00000, 0b00000, 0b10110, 0b11001, 0b10001, 0b10001, 0b10001]),
+    ('o', [0b00000, 0b00000, 0b01110, 0b10001, 0b10001, 0b10001, 0b01110]),
+    ('p', [0b00000, 0b00000, 0b11110, 0b10001, 0b11110, 0b10000, 0b10000]),
+    ('q', [0b00000, 0b00000, 0b01101, 0b10011, 0b01111, 0b00001, 0b00001]),
+    ('r', [0b00000, 0b00000, 0b10110, 0b11001, 0b10000, 0b10000, 0b10000]),
+    ('s', [0b00000, 0b00000, 0b01110, 0b10000, 0b01110, 0b00001, 0b11110]),
+    ('t', [0b01000, 0b01000, 0b11100, 0b01000, 0b01000, 0b01001, 0b00110]),
+    ('u', [0b00000, 0b00000, 0b10001, 0b10001, 0b10001, 0b10011, 0b01101]),
+    ('v', [0b00000, 0b00000, 0b10001, 0b10001, 0b10001, 0b01010, 0b00100]),
+    ('w', [0b00000, 0b00000, 0b10001, 0b10001, 0b10101, 0b10101, 0b01010]),
+    ('x', [0b00000, 0b00000, 0b10001, 0b01010, 0b00100, 0b01010, 0b10001]),
+    ('y', [0b00000, 0b00000, 0b10001, 0b10001, 0b01111, 0b00001, 0b01110]),
+    ('z', [0b00000, 0b00000, 0b11111, 0b00010, 0b00100, 0b01000, 0b11111]),
+];
+
+/// Returns magnification of labels drawn over an image of given height.
+fn label_scale(height: u32) -> u32 { (height / 96).clamp(1, 8) }
+
+/// Returns strip of given width with a label with given text in its top-left
+/// corner.  The label is sized as channel labels of an image of given height
+/// would be so that the strip can be put above such image to name it.
+pub fn label_strip(
+    width: u32,
+    height: u32,
+    text: &str,
+    background: Rgb,
+) -> Image {
+    let scale = label_scale(height);
+    let mut img = Image::from_pixel(width, 13 * scale, image::Rgb(background));
+    if width == 0 {
+        return img;
+    }
+    for (y, row) in img.chunks_exact_mut(width as usize * 3).enumerate() {
+        // SAFETY: Converting &mut [u8] into &mut [MaybeUninit<u8>] is safe
+        // since draw_label only ever writes initialised values.
+        let row: &mut [std::mem::MaybeUninit<u8>] =
+            unsafe { std::mem::transmute(row) };
+        draw_label(row.as_chunks_mut::<3>().0, y as u32, text, scale);
+    }
+    img
+}
+
+/// Draws row `y` of a label with given text into row of a tile.  The label
+/// is white text on a black box placed in the top-left corner of the tile
+/// with glyphs magnified `scale` times.  The label is skipped if it doesn’t
+/// fit in the tile.  Characters missing from `FONT` are rendered as spaces.
+fn draw_label(tile: &mut [UnRgb], y: u32, text: &str, scale: u32) {
+    let chars = text.chars().count() as u32;
+    let (x0, y0) = (2 * scale, 2 * scale);
+    let box_width = (chars * 6 + 1) * scale;
+    let box_height = 9 * scale;
+    let tile_width = tile.len() as u32;
+    if y < y0 || y >= y0 + box_height || x0 + box_width > tile_width {
+        return;
+    }
+    // Position within the box in glyph pixels, with the first row and column
+    // being padding.
+    let row = (y - y0) / scale;
+    for x in 0..box_width {
+        let col = x / scale;
+        let lit = (1..=7).contains(&row) && col >= 1 && (col - 1) % 6 < 5 && {
+            let ch = text.chars().nth(((col - 1) / 6) as usize);
+            let glyph = FONT.iter().find(|(c, _)| Some(*c) == ch);
+            glyph.map_or(false, |(_, rows)| {
+                rows[row as usize - 1] & (0x10 >> ((col - 1) % 6)) != 0
+            })
+        };
+        let value = if lit { 255 } else { 0 };
+        let dst = &mut tile[(x0 + x) as usize];
+        std::mem::MaybeUninit::write_slice(dst, &[value; 3]);
+    }
+}
+
+#[test]
+fn test_channel_labels() {
+    for space in SPACES.iter() {
+        for ch in space.channel_names().iter().flat_map(|name| name.chars()) {
+            assert!(FONT.iter().any(|&(c, _)| c == ch), "{}", ch);
+        }
+    }
+
+    let img = Image::from_fn(64, 32, |_, _| image::Rgb([128, 128, 128]));
+    let settings = Settings {
+        original: OriginalPosition::None,
+        channel_labels: true,
+        ..Settings::default()
+    };
+    let rgb = ColorSpaceId::Rgb.space();
+    let out = build_rgb_image(rgb, &img, &settings).unwrap();
+    // Padding of the box, top-left pixel of ‘R’ and background of the tile.
+    assert_eq!([0, 0, 0], out.get_pixel(2, 2).0);
+    assert_eq!([255, 255, 255], out.get_pixel(3, 3).0);
+    assert_eq!([128, 0, 0], out.get_pixel(20, 20).0);
+    // Label of the second tile.
+    assert_eq!([255, 255, 255], out.get_pixel(64 + 4, 3).0);
+
+    // Label doesn’t fit in a tile which is too narrow.
+    let img = Image::from_fn(4, 32, |_, _| image::Rgb([128, 128, 128]));
+    let out = build_rgb_image(rgb, &img, &settings).unwrap();
+    assert_eq!([128, 0, 0], out.get_pixel(2, 2).0);
+
+    // Strip naming an image.  Top-left pixel of ‘i’ is lit.
+    let strip = label_strip(64, 32, "img.png", [64, 64, 64]);
+    assert_eq!((64, 13), strip.dimensions());
+    assert_eq!([64, 64, 64], strip.get_pixel(0, 0).0);
+    assert_eq!([0, 0, 0], strip.get_pixel(2, 2).0);
+    assert_eq!([255, 255, 255], strip.get_pixel(5, 3).0);
+    assert_eq!([64, 64, 64], strip.get_pixel(63, 12).0);
+}
+
+
+/// Builds image showing per-pixel differences between decompositions of two
+/// images in given colour space.  Both images must have the same dimensions.
+/// Magnitude of the difference is rendered as a heat map going from black (no
+/// difference) through red and yellow to white.
+pub fn build_diff_image(
+    space: &Space,
+    src_image: &Image,
+    other_image: &Image,
+    settings: &Settings,
+) -> Option<(u32, u32, Box<[u8]>)> {
+    assert_eq!(src_image.dimensions(), other_image.dimensions());
+    let (width, height, mut dst_buffer) =
+        build_image(space, src_image, settings)?;
+    let (_, _, other_buffer) = build_image(space, other_image, settings)?;
+    let dst = dst_buffer.as_chunks_mut::<3>().0.iter_mut();
+    for (dst, other) in dst.zip(other_buffer.as_chunks::<3>().0) {
+        let diff = |i: usize| dst[i].max(other[i]) - dst[i].min(other[i]);
+        *dst = heat_map(diff(0).max(diff(1)).max(diff(2)));
+    }
+    Some((width, height, dst_buffer))
+}
+
+/// Returns pairs of indexes of channels of the two spaces which measure the
+/// same kind of quantity and can be compared with [`build_space_diff_image`].
+/// For example, L\* of Lab is paired with L of HSL and H of HSV with h of
+/// LCh(ab).  Comparing lightness channels shows where the perceptual
+/// lightness disagrees with the simple RGB-based models, while comparing
+/// hues shows how hue angles differ between the models.
+pub fn comparable_channels(a: &Space, b: &Space) -> Vec<(usize, usize)> {
+    (0..a.channels)
+        .filter_map(|ch_a| {
+            let (quantity, _) = a.channel_quantity(ch_a)?;
+            (0..b.channels)
+                .find(|&ch_b| {
+                    b.channel_quantity(ch_b).map(|(q, _)| q) == Some(quantity)
+                })
+                .map(|ch_b| (ch_a, ch_b))
+        })
+        .collect()
+}
+
+/// Builds image showing per-pixel differences between comparable channels of
+/// two colour spaces with one tile for each pair returned by
+/// [`comparable_channels`].  Differences are rendered as a heat map like in
+/// [`build_diff_image`]; for hues the largest difference is half a turn.
+/// Pixels whose hue is undefined in either space show no difference.
+/// Returns `None` if there are no comparable channels or the image would be
+/// too large.
+pub fn build_space_diff_image(
+    a: &Space,
+    b: &Space,
+    src_image: &Image,
+) -> Option<Image> {
+    let pairs = comparable_channels(a, b)
+        .into_iter()
+        .filter_map(|(ch_a, ch_b)| {
+            let (quantity, scale_a) = a.channel_quantity(ch_a)?;
+            let (_, scale_b) = b.channel_quantity(ch_b)?;
+            Some((quantity, (ch_a, scale_a), (ch_b, scale_b)))
+        })
+        .collect::<Vec<_>>();
+    if pairs.is_empty() {
+        return None;
+    }
+    let (width, height) = src_image.dimensions();
+    let total_width = width.checked_mul(pairs.len() as u32)?;
+    (total_width as usize * 3).checked_mul(height as usize)?;
+    let mut cache = std::collections::HashMap::new();
+    let mut out = Image::new(total_width, height);
+    for (x, y, pixel) in src_image.enumerate_pixels() {
+        let colours = cache.entry(pixel.0).or_insert_with(|| {
+            let values_a = channel_values(a, pixel.0);
+            let values_b = channel_values(b, pixel.0);
+            pairs
+                .iter()
+                .map(|&(quantity, (ch_a, scale_a), (ch_b, scale_b))| {
+                    let diff = quantity_diff(
+                        quantity,
+                        values_a[ch_a] * scale_a,
+                        values_b[ch_b] * scale_b,
+                    );
+                    heat_map(diff)
+                })
+                .collect::<Vec<_>>()
+        });
+        for (idx, rgb) in colours.iter().enumerate() {
+            out.put_pixel(idx as u32 * width + x, y, image::Rgb(*rgb));
+        }
+    }
+    Some(out)
+}
+
+/// Returns difference between two normalised values of given quantity scaled
+/// to [0, 255] range.  Hues are compared along the shorter arc.  NaN (i.e.
+/// undefined hue) is treated as no difference.
+fn quantity_diff(quantity: Quantity, a: f32, b: f32) -> u8 {
+    let diff = (a - b).abs();
+    let diff = if quantity == Quantity::Hue {
+        let diff = diff.rem_euclid(1.0);
+        2.0 * diff.min(1.0 - diff)
+    } else {
+        diff
+    };
+    // Comparison is false for NaN.
+    if diff > 0.0 {
+        round_u8(diff.min(1.0))
+    } else {
+        0
+    }
+}
+
+#[test]
+fn test_space_diff() {
+    let id = |id: ColorSpaceId| id.space();
+    let lab = id(ColorSpaceId::Lab);
+    let hsl = id(ColorSpaceId::Hsl);
+    let lchab = id(ColorSpaceId::LChab);
+    assert_eq!(vec![(0, 2)], comparable_channels(lab, hsl));
+    assert_eq!(vec![(0, 2), (2, 0)], comparable_channels(lchab, hsl));
+    assert_eq!(
+        Vec::<(usize, usize)>::new(),
+        comparable_channels(id(ColorSpaceId::Rgb), hsl)
+    );
+    let xyz = id(ColorSpaceId::Xyz);
+    assert_eq!(vec![(1, 2)], comparable_channels(xyz, id(ColorSpaceId::XyY)));
+    assert_eq!(Vec::<(usize, usize)>::new(), comparable_channels(xyz, lab));
+    let tsl = id(ColorSpaceId::Tsl);
+    assert_eq!(vec![(1, 1), (2, 2)], comparable_channels(tsl, hsl));
+
+    let img = Image::from_raw(2, 1, vec![0, 0, 0, 255, 0, 0]).unwrap();
+    assert_eq!(None, build_space_diff_image(id(ColorSpaceId::Rgb), hsl, &img));
+    let out = build_space_diff_image(lab, id(ColorSpaceId::Luv), &img).unwrap();
+    assert_eq!((2, 1), out.dimensions());
+    assert!(out.pixels().all(|pixel| pixel.0 == [0, 0, 0]));
+    // L* of pure red is about 53 while its HSL lightness is 0.5.
+    let out = build_space_diff_image(lab, hsl, &img).unwrap();
+    assert_eq!([0, 0, 0], out.get_pixel(0, 0).0);
+    assert!(out.get_pixel(1, 0).0[0] > 0, "{:?}", out.get_pixel(1, 0));
+}
+
+/// Chromaticity coordinates of the CIE 1931 2° spectral locus from 380 to
+/// 700 nm.  Closing the polyline gives the line of purples.
+#[rustfmt::skip]
+const SPECTRAL_LOCUS: [(f32, f32); 45] = [
+    (0.1741, 0.0050), (0.1738, 0.0049), (0.1733, 0.0048), (0.1726, 0.0048),
+    (0.1714, 0.0051), (0.1689, 0.0069), (0.1644, 0.0109), (0.1566, 0.0177),
+    (0.1440, 0.0297), (0.1241, 0.0578), (0.1096, 0.0868), (0.0913, 0.1327),
+    (0.0687, 0.2007), (0.0454, 0.2950), (0.0235, 0.4127), (0.0082, 0.5384),
+    (0.0039, 0.6548), (0.0139, 0.7502), (0.0389, 0.8120), (0.0743, 0.8338),
+    (0.1142, 0.8262), (0.1547, 0.8059), (0.1929, 0.7816), (0.2296, 0.7543),
+    (0.2658, 0.7243), (0.3016, 0.6923), (0.3373, 0.6589), (0.3731, 0.6245),
+    (0.4087, 0.5896), (0.4441, 0.5547), (0.4788, 0.5202), (0.5125, 0.4866),
+    (0.5448, 0.4544), (0.5752, 0.4242), (0.6029, 0.3965), (0.6270, 0.3725),
+    (0.6482, 0.3514), (0.6658, 0.3340), (0.6915, 0.3083), (0.7079, 0.2920),
+    (0.7190, 0.2809), (0.7260, 0.2740), (0.7300, 0.2700), (0.7334, 0.2666),
+    (0.7347, 0.2653),
+];
+
+/// Accumulates chromaticities of pixels of images and renders them as
+/// a density plot over the CIE 1931 xy chromaticity diagram.
+pub struct ChromaticityPlot {
+    counts: Vec<u32>,
+}
+
+impl ChromaticityPlot {
+    /// Width and height of the rendered plot.
+    pub const SIZE: u32 = 512;
+    /// Range of x and y coordinates covered by the plot.
+    const RANGE: f32 = 0.9;
+
+    pub fn new() -> Self {
+        let size = Self::SIZE as usize;
+        Self { counts: vec![0; size * size] }
+    }
+
+
+    /// Maps chromaticity coordinates to position on the plot.
+    fn position(lc_x: f32, lc_y: f32) -> Option<(u32, u32)> {
+        let scale = (Self::SIZE - 1) as f32 / Self::RANGE;
+        let x = (lc_x * scale).round();
+        let y = (Self::SIZE - 1) as f32 - (lc_y * scale).round();
+        let range = 0.0..Self::SIZE as f32;
+        if range.contains(&x) && range.contains(&y) {
+            Some((x as u32, y as u32))
+        } else {
+            None
+        }
+    }
+
+    /// Adds all pixels of given image to the plot.  Black pixels, whose
+    /// chromaticity is undefined, are skipped.
+    pub fn add(&mut self, img: &Image) {
+        let mut cache = std::collections::HashMap::new();
+        for pixel in img.pixels() {
+            let pos = *cache.entry(pixel.0).or_insert_with(|| {
+                let [x, y, z] = srgb::xyz_from_u8(pixel.0);
+                let sum = x + y + z;
+                if sum > 0.0 {
+                    Self::position(x / sum, y / sum)
+                } else {
+                    None
+                }
+            });
+            if let Some((x, y)) = pos {
+                let idx = y as usize * Self::SIZE as usize + x as usize;
+                self.counts[idx] = self.counts[idx].saturating_add(1);
+            }
+        }
+    }
+
+    /// Renders the plot.  Density of the pixels is shown on a logarithmic
+    /// heat map with spectral locus drawn in grey and sRGB gamut triangle
+    /// drawn in blue.
+    pub fn render(&self) -> Image {
+        let max = self.counts.iter().copied().max().unwrap_or(0);
+        let max = (max as f32).ln_1p();
+        let mut out = Image::new(Self::SIZE, Self::SIZE);
+        for (pixel, &count) in out.pixels_mut().zip(self.counts.iter()) {
+            if count > 0 {
+                let value = round_u8((count as f32).ln_1p() / max);
+                pixel.0 = heat_map(value.max(1));
+            }
+        }
+
+        let mut draw_polygon = |points: &[(f32, f32)], colour: Rgb| {
+            let next = points.iter().cycle().skip(1);
+            for (&start, &end) in points.iter().zip(next) {
+                draw_line(&mut out, start, end, colour);
+            }
+        };
+        draw_polygon(&SPECTRAL_LOCUS[..], [128, 128, 128]);
+        let primaries = [[255, 0, 0], [0, 255, 0], [0, 0, 255]]
+            .iter()
+            .map(|&rgb| {
+                let [x, y, z] = srgb::xyz_from_u8(rgb);
+                (x / (x + y + z), y / (x + y + z))
+            })
+            .collect::<Vec<_>>();
+        draw_polygon(&primaries[..], [64, 128, 255]);
+        out
+    }
+}
+
+impl Default for ChromaticityPlot {
+    fn default() -> Self { Self::new() }
+}
+
+/// Draws line between two chromaticity coordinates on the plot.
+fn draw_line(out: &mut Image, start: (f32, f32), end: (f32, f32), rgb: Rgb) {
+    let steps = (ChromaticityPlot::SIZE * 2) as usize;
+    for step in 0..=steps {
+        let t = step as f32 / steps as f32;
+        let lc_x = start.0 + (end.0 - start.0) * t;
+        let lc_y = start.1 + (end.1 - start.1) * t;
+        if let Some((x, y)) = ChromaticityPlot::position(lc_x, lc_y) {
+            out.put_pixel(x, y, image::Rgb(rgb));
+        }
+    }
+}
+
+#[test]
+fn test_chromaticity_plot() {
+    let mut plot = ChromaticityPlot::new();
+    let img = Image::from_raw(3, 1, vec![0, 0, 0, 255, 255, 255, 255, 255, 255])
+        .unwrap();
+    plot.add(&img);
+    assert_eq!(2, plot.counts.iter().sum::<u32>());
+    let [x, y] = [srgb::xyz::D65_xyY[0], srgb::xyz::D65_xyY[1]];
+    let (x, y) = ChromaticityPlot::position(x, y).unwrap();
+    assert_eq!(2, plot.counts[(y * ChromaticityPlot::SIZE + x) as usize]);
+
+    let out = plot.render();
+    let size = ChromaticityPlot::SIZE;
+    assert_eq!((size, size), out.dimensions());
+    assert_eq!([255, 255, 255], out.get_pixel(x, y).0);
+    let (x, y) = ChromaticityPlot::position(0.64, 0.33).unwrap();
+    assert_eq!([64, 128, 255], out.get_pixel(x, y).0);
+    assert_eq!([0, 0, 0], out.get_pixel(size - 1, 0).0);
+    assert_eq!(None, ChromaticityPlot::position(1.0, 0.5));
+}
+
+/// Maps value to a colour on a black-red-yellow-white heat map.
+fn heat_map(value: u8) -> Rgb {
+    let v = value as u16 * 3;
+    let component = |min: u16| (v.max(min) - min).min(255) as u8;
+    [component(0), component(255), component(510)]
+}
+
+
+/// Returns Rec. 709 luminance of the colour encoded with sRGB transfer
+/// function.  Used for the luminance tile added with `--luma-tile`.
+fn luma_from_rgb(rgb: Rgb) -> u8 {
+    srgb::gamma::compress_u8(srgb::xyz_from_u8(rgb)[1])
+}
+
+
+fn rgb_fill_channels(mut channels: Channels, rgb: Rgb) {
+    channels.set_rgb(0, [rgb[0], 0, 0]);
+    channels.set_rgb(1, [0, rgb[1], 0]);
+    channels.set_rgb(2, [0, 0, rgb[2]]);
+}
+
+fn lin_rgb_fill_channels(mut channels: Channels, rgb: Rgb) {
+    let [r, g, b] = srgb::gamma::linear_from_u8(rgb);
+    channels.set_rgb(0, [channels.round(r), 0, 0]);
+    channels.set_rgb(1, [0, channels.round(g), 0]);
+    channels.set_rgb(2, [0, 0, channels.round(b)]);
+}
+
+
+/// Fills channels of an RGB space with different primaries than sRGB.  The
+/// sRGB colour is converted to linear coordinates in the space using
+/// `from_xyz` matrix and encoded into 8-bit values with `compress` transfer
+/// function (which rounds the value with [`Channels::round`]).  Each channel
+/// is then rendered as the space’s primary of that intensity converted back to
+/// sRGB (with colours outside of the sRGB gamut being clipped).
+fn primaries_fill_channels(
+    channels: &mut Channels,
+    rgb: Rgb,
+    from_xyz: &[[f32; 3]; 3],
+    to_xyz: &[[f32; 3]; 3],
+    compress: fn(&Channels, f32) -> u8,
+    expand: fn(u8) -> f32,
+) {
+    let linear = matrix_product(from_xyz, srgb::xyz_from_u8(rgb));
+    for (channel, &value) in linear.iter().enumerate() {
+        let mut primary = [0.0; 3];
+        primary[channel] = expand(compress(channels, value));
+        let xyz = matrix_product(to_xyz, primary);
+        channels.set_xyz(channel, xyz);
+    }
+}
+
+fn adobe_rgb_fill_channels(mut channels: Channels, rgb: Rgb) {
+    fn compress(channels: &Channels, linear: f32) -> u8 {
+        channels.round(linear.max(0.0).min(1.0).powf(1.0 / ADOBE_RGB_GAMMA))
+    }
+    fn expand(encoded: u8) -> f32 {
+        (encoded as f32 / 255.0).powf(ADOBE_RGB_GAMMA)
+    }
+    primaries_fill_channels(
+        &mut channels,
+        rgb,
+        &ADOBE_RGB_FROM_XYZ_MATRIX,
+        &XYZ_FROM_ADOBE_RGB_MATRIX,
+        compress,
+        expand,
+    );
+}
+
+fn display_p3_fill_channels(mut channels: Channels, rgb: Rgb) {
+    // Display P3 uses the same transfer function as sRGB.
+    primaries_fill_channels(
+        &mut channels,
+        rgb,
+        &DISPLAY_P3_FROM_XYZ_MATRIX,
+        &XYZ_FROM_DISPLAY_P3_MATRIX,
+        Channels::compress,
+        srgb::gamma::expand_u8,
+    );
+}
+
+fn rec2020_fill_channels(mut channels: Channels, rgb: Rgb) {
+    fn compress(channels: &Channels, linear: f32) -> u8 {
+        let linear = linear.max(0.0).min(1.0);
+        channels.round(if linear < REC2020_BETA {
+            4.5 * linear
+        } else {
+            REC2020_ALPHA * linear.powf(0.45) - (REC2020_ALPHA - 1.0)
+        })
+    }
+    fn expand(encoded: u8) -> f32 {
+        let encoded = encoded as f32 / 255.0;
+        if encoded < 4.5 * REC2020_BETA {
+            encoded / 4.5
+        } else {
+            ((encoded + (REC2020_ALPHA - 1.0)) / REC2020_ALPHA).powf(1.0 / 0.45)
+        }
+    }
+    primaries_fill_channels(
+        &mut channels,
+        rgb,
+        &REC2020_FROM_XYZ_MATRIX,
+        &XYZ_FROM_REC2020_MATRIX,
+        compress,
+        expand,
+    );
+}
+
+
+fn xyz_fill_channels(mut channels: Channels, rgb: Rgb) {
+    let [x, y, z] = srgb::xyz_from_u8(rgb);
+    channels.set_grey(0, channels.compress(x / srgb::xyz::D65_XYZ[0]));
+    channels.set_grey(1, channels.compress(y));
+    channels.set_grey(2, channels.compress(z / srgb::xyz::D65_XYZ[2]));
+}
+
+/// Returns XYZ coordinates of colour of given chromaticity at half luminance.
+fn xyz_from_xyy(lc_x: f32, lc_y: f32) -> [f32; 3] {
+    let x = lc_x * 0.5 / lc_y;
+    let y = 0.5;
+    let z = (1.0 - lc_x - lc_y) * 0.5 / lc_y;
+    [x, y, z]
+}
+
+/// Converts XYZ coordinates into CIE xyY coordinates.  Chromaticity of black
+/// is undefined and is reported as that of the white point.
+fn xyy_from_xyz(xyz: [f32; 3]) -> [f32; 3] {
+    let [x, y, z] = xyz;
+    let sum = x + y + z;
+    if sum > 0.0 {
+        [x / sum, y / sum, y]
+    } else {
+        [srgb::xyz::D65_xyY[0], srgb::xyz::D65_xyY[1], 0.0]
+    }
+}
+
+fn xyy_fill_channels(mut channels: Channels, rgb: Rgb) {
+    let [lc_x, lc_y, y] = xyy_from_xyz(srgb::xyz_from_u8(rgb));
+    channels.set_xyz(0, xyz_from_xyy(lc_x, srgb::xyz::D65_xyY[1]));
+    channels.set_xyz(1, xyz_from_xyy(srgb::xyz::D65_xyY[0], lc_y));
+    channels.set_grey(2, channels.compress(y));
+}
+
+/// Converts XYZ coordinates into CIE 1960 UCS u and v chromaticity
+/// coordinates.  Returns `None` for black for which chromaticity is undefined.
+fn ucs_uv_from_xyz([x, y, z]: [f32; 3]) -> Option<(f32, f32)> {
+    let denominator = mul_add(15.0, y, mul_add(3.0, z, x));
+    if denominator > 0.0 {
+        Some((4.0 * x / denominator, 6.0 * y / denominator))
+    } else {
+        None
+    }
+}
+
+/// Converts XYZ coordinates into CIE 1960 UCS u, v and Y coordinates.
+/// Chromaticity of black is reported as that of the white point.
+fn ucs_from_xyz(xyz: [f32; 3]) -> [f32; 3] {
+    let white = ucs_uv_from_xyz(srgb::xyz::D65_XYZ).unwrap();
+    let (u, v) = ucs_uv_from_xyz(xyz).unwrap_or(white);
+    [u, v, xyz[1]]
+}
+
+fn ucs_fill_channels(mut channels: Channels, rgb: Rgb) {
+    fn xyz_from_uv(u: f32, v: f32) -> [f32; 3] {
+        let d = mul_add(2.0, u, mul_add(-8.0, v, 4.0));
+        xyz_from_xyy(3.0 * u / d, 2.0 * v / d)
+    }
+    let [wu, wv, _] = ucs_from_xyz(srgb::xyz::D65_XYZ);
+    let [u, v, y] = ucs_from_xyz(srgb::xyz_from_u8(rgb));
+    channels.set_xyz(0, xyz_from_uv(u, wv));
+    channels.set_xyz(1, xyz_from_uv(wu, v));
+    channels.set_grey(2, channels.compress(y));
+}
+
+
+/// Returns hue of the colour in [0, 6) range or NaN if it’s a shade of grey.
+fn hue_from_rgb(rgb: Rgb) -> f32 {
+    let [r, g, b] = rgb;
+    let (min, max) = min_max(rgb);
+    let range = max as i32 - min as i32;
+    if range == 0 {
+        f32::NAN
+    } else if max == r {
+        ((g as i32 - b as i32) as f32 / range as f32).rem_euclid(6.0)
+    } else if max == g {
+        (b as i32 - r as i32) as f32 / range as f32 + 2.0
+    } else {
+        (r as i32 - g as i32) as f32 / range as f32 + 4.0
+    }
+}
+
+/// Returns the smallest and the largest component of the colour.
+fn min_max([r, g, b]: Rgb) -> (u8, u8) {
+    let min = std::cmp::min(std::cmp::min(r, g), b);
+    let max = std::cmp::max(std::cmp::max(r, g), b);
+    (min, max)
+}
+
+/// Returns hue in [0, 6) range (NaN if undefined) as well as HSL saturation
+/// and lightness in [0, 1] range of the colour.
+fn hsl_from_rgb(rgb: Rgb) -> [f32; 3] {
+    let (min, max) = min_max(rgb);
+    let sum = min as i32 + max as i32;
+    let range = max as i32 - min as i32;
+    let saturation = if range == 0 {
+        0.0
+    } else {
+        range as f32 / (255 - (sum - 255).abs()) as f32
+    };
+    [hue_from_rgb(rgb), saturation, sum as f32 / 510.0]
+}
+
+/// Returns hue in [0, 6) range (NaN if undefined) as well as HSV saturation
+/// and value in [0, 1] range of the colour.
+fn hsv_from_rgb(rgb: Rgb) -> [f32; 3] {
+    let (min, max) = min_max(rgb);
+    let saturation = if max == 0 {
+        0.0
+    } else {
+        (max - min) as f32 / max as f32
+    };
+    [hue_from_rgb(rgb), saturation, max as f32 / 255.0]
+}
+
+/// Returns hue in [0, 6) range (NaN if undefined) as well as HSP saturation
+/// and perceived brightness in [0, 1] range of the colour.
+fn hsp_from_rgb(rgb: Rgb) -> [f32; 3] {
+    let [hue, saturation, _] = hsv_from_rgb(rgb);
+    [hue, saturation, hsp_brightness(rgb) / 255.0]
+}
+
+/// Returns hue in [0, 6) range (NaN if undefined) as well as whiteness and
+/// blackness in [0, 1] range of the colour.
+fn hwb_from_rgb(rgb: Rgb) -> [f32; 3] {
+    let (min, max) = min_max(rgb);
+    [hue_from_rgb(rgb), min as f32 / 255.0, 1.0 - max as f32 / 255.0]
+}
+
+/// Fills channels of a cylindrical RGB-based space from hue in [0, 6) range
+/// and two other coordinates in [0, 1] range.
+fn cylindrical_fill_channels(channels: &mut Channels, [hue, s, v]: [f32; 3]) {
+    channels.set_hue(0, hue);
+    channels.set_grey(1, channels.round(s));
+    channels.set_grey(2, channels.round(v));
+}
+
+/// Maps hue in [0, 6) range onto colour of the hue wheel used to render hue
+/// channels.  NaN, used for undefined hue, is mapped to black.  `threshold` is
+/// the dithering threshold as described in [`Channels`].
+fn hue_wheel_rgb(hue: f32, threshold: f32) -> Rgb {
+    if hue != hue {
+        [0, 0, 0]
+    } else {
+        let x = 0.5 - 0.5 * (hue % 2.0 - 1.0).abs();
+        let (r, g, b) = match hue as u8 {
+            0 => (0.5, x, 0.0),
+            1 => (x, 0.5, 0.0),
+            2 => (0.0, 0.5, x),
+            3 => (0.0, x, 0.5),
+            4 => (x, 0.0, 0.5),
+            5 => (0.5, 0.0, x),
+            _ => unreachable!(),
+        };
+        let map = |v: f32| mul_add(v, 255.0, 64.25 + threshold) as u8;
+        [map(r), map(g), map(b)]
+    }
+}
+
+fn hsl_fill_channels(mut channels: Channels, rgb: Rgb) {
+    cylindrical_fill_channels(&mut channels, hsl_from_rgb(rgb));
+}
+
+fn hsv_fill_channels(mut channels: Channels, rgb: Rgb) {
+    cylindrical_fill_channels(&mut channels, hsv_from_rgb(rgb));
+}
+
+fn hsp_fill_channels(mut channels: Channels, rgb: Rgb) {
+    cylindrical_fill_channels(&mut channels, hsp_from_rgb(rgb));
+}
+
+/// Returns HSP perceived brightness of the colour in [0, 255] range.
+fn hsp_brightness([r, g, b]: Rgb) -> f32 {
+    // Squares of 8-bit values overflow u8 so compute everything in f32.
+    let sq = |v: u8| v as f32 * v as f32;
+    mul_add(0.299, sq(r), mul_add(0.587, sq(g), 0.114 * sq(b))).sqrt()
+}
+
+fn tsl_fill_channels(mut channels: Channels, rgb: Rgb) {
+    let [tint, saturation, lightness] = tsl_from_rgb(rgb);
+    channels.set_hue(0, (tint * 6.0).rem_euclid(6.0));
+    channels.set_grey(1, channels.round(saturation.min(1.0)));
+    channels.set_grey(2, channels.round_scaled(lightness));
+}
+
+/// Returns TSL tint in [0, 1) range (or NaN if undefined), saturation and
+/// lightness in [0, 255] range of the colour.
+fn tsl_from_rgb(rgb: Rgb) -> [f32; 3] {
+    let [r, g, b] = rgb;
+    let sum = r as i32 + g as i32 + b as i32;
+    let lightness =
+        mul_add(0.299, r as f32, mul_add(0.587, g as f32, 0.114 * b as f32));
+    // Chromaticity is undefined for black; treat it as achromatic.
+    let (tint, saturation) = if sum == 0 {
+        (f32::NAN, 0.0)
+    } else {
+        let r = r as f32 / sum as f32 - 1.0 / 3.0;
+        let g = g as f32 / sum as f32 - 1.0 / 3.0;
+        let tint = if g > 0.0 {
+            (r / g).atan() / std::f32::consts::TAU + 0.25
+        } else if g < 0.0 {
+            (r / g).atan() / std::f32::consts::TAU + 0.75
+        } else {
+            f32::NAN
+        };
+        (tint, (1.8 * (r * r + g * g)).sqrt())
+    };
+    [tint, saturation, lightness]
+}
+
+/// Converts analog YUV coordinates into normalised sRGB colour clipping it to
+/// the gamut.
+fn rgb_from_yuv(y: f32, u: f32, v: f32) -> [f32; 3] {
+    let r = v / 0.877 + y;
+    let g = mul_add(-0.395, u, mul_add(-0.581, v, y));
+    let b = u / 0.492 + y;
+    [r.clamp(0.0, 1.0), g.clamp(0.0, 1.0), b.clamp(0.0, 1.0)]
+}
+
+/// Fills channels of analog (BT.601) YUV.  Unlike digital YCbCr, U and V are
+/// the colour-difference signals B′−Y′ and R′−Y′ scaled by 0.492 and 0.877
+/// respectively without any offset or quantisation to the 16–240 range.
+fn yuv_fill_channels(mut channels: Channels, rgb: Rgb) {
+    let [y, u, v] = yuv_from_rgb(rgb);
+    channels.set_grey(0, channels.round(y));
+    channels.set_rgb(1, channels.round_rgb(rgb_from_yuv(0.5, u, 0.0)));
+    channels.set_rgb(2, channels.round_rgb(rgb_from_yuv(0.5, 0.0, v)));
+}
+
+/// Converts sRGB colour into analog YUV coordinates.
+fn yuv_from_rgb([r, g, b]: Rgb) -> [f32; 3] {
+    let (r, g, b) = (r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0);
+    let y = mul_add(0.299, r, mul_add(0.587, g, 0.114 * b));
+    [y, 0.492 * (b - y), 0.877 * (r - y)]
+}
+
+fn hwb_fill_channels(mut channels: Channels, rgb: Rgb) {
+    cylindrical_fill_channels(&mut channels, hwb_from_rgb(rgb));
+}
+
+// Ranges of opponent axes within the sRGB gamut.
+const LAB_A_RANGE: (f32, f32) = (-86.18078, 98.23698);
+const LAB_B_RANGE: (f32, f32) = (-107.858345, 94.48001);
+const LUV_U_RANGE: (f32, f32) = (-83.07059, 175.01141);
+const LUV_V_RANGE: (f32, f32) = (-134.10574, 107.40619);
+const DIN99_A_RANGE: (f32, f32) = (-27.44909, 36.175274);
+const DIN99_B_RANGE: (f32, f32) = (-33.395064, 31.155535);
+
+fn abuv_lstar(v: f32, (min, max): (f32, f32)) -> f32 {
+    50.0 * (if v < 0.0 { v / min } else { v / max })
+}
+
+/// Fills legend of the two opponent-axis channels (second and third) with
+/// gradients spanning given ranges.  `to_rgb` converts lightness and the two
+/// opponent coordinates into sRGB the same way fill function of the space does.
+fn opponent_fill_legend(
+    mut channels: Channels,
+    t: f32,
+    ranges: [(f32, f32); 2],
+    to_rgb: impl Fn(f32, f32, f32) -> Rgb,
+) {
+    let lerp = |(min, max): (f32, f32)| mul_add(t, max - min, min);
+    let a = lerp(ranges[0]);
+    let l = channels.opponent_lightness(a, ranges[0]);
+    channels.set_rgb(1, to_rgb(l, a, 0.0));
+    let b = lerp(ranges[1]);
+    let l = channels.opponent_lightness(b, ranges[1]);
+    channels.set_rgb(2, to_rgb(l, 0.0, b));
+}
+
+fn lab_fill_channels(mut channels: Channels, rgb: Rgb) {
+    fn set(channels: &mut Channels, channel: usize, l: f32, a: f32, b: f32) {
+        channels.set_lab(channel, lab::Lab { l, a, b });
+    }
+    let lab = lab::Lab::from_rgb(&rgb);
+    set(&mut channels, 0, lab.l, 0.0, 0.0);
+    let l = channels.opponent_lightness(lab.a, LAB_A_RANGE);
+    set(&mut channels, 1, l, lab.a, 0.0);
+    let l = channels.opponent_lightness(lab.b, LAB_B_RANGE);
+    set(&mut channels, 2, l, 0.0, lab.b);
+}
+
+fn lab_fill_legend(channels: Channels, t: f32) {
+    opponent_fill_legend(channels, t, [LAB_A_RANGE, LAB_B_RANGE], |l, a, b| {
+        lab::Lab { l, a, b }.to_rgb()
+    });
+}
+
+fn lchab_fill_channels(mut channels: Channels, rgb: Rgb) {
+    fn set(channels: &mut Channels, channel: usize, l: f32, c: f32, h: f32) {
+        let rgb = channels.rgb_from_lab(lab::LCh { l, c, h }.to_lab());
+        channels.set_rgb(channel, rgb);
+    }
+    let lch = lab::LCh::from_rgb(&rgb);
+    set(&mut channels, 0, lch.l, 0.0, 0.0);
+    set(&mut channels, 1, lch.c / 1.338088, 0.0, 0.0);
+    set(&mut channels, 2, 50.0, 133.8088 * 0.5, lch.h);
+}
+
+fn luv_fill_channels(mut channels: Channels, rgb: Rgb) {
+    fn set(channels: &mut Channels, channel: usize, l: f32, u: f32, v: f32) {
+        channels.set_luv(channel, luv::Luv { l, u, v });
+    }
+    let luv = luv::Luv::from_rgb(&rgb);
+    set(&mut channels, 0, luv.l, 0.0, 0.0);
+    let l = channels.opponent_lightness(luv.u, LUV_U_RANGE);
+    set(&mut channels, 1, l, luv.u, 0.0);
+    let l = channels.opponent_lightness(luv.v, LUV_V_RANGE);
+    set(&mut channels, 2, l, 0.0, luv.v);
+}
+
+fn luv_fill_legend(channels: Channels, t: f32) {
+    opponent_fill_legend(channels, t, [LUV_U_RANGE, LUV_V_RANGE], |l, u, v| {
+        luv::Luv { l, u, v }.to_rgb()
+    });
+}
+
+fn lchuv_fill_channels(mut channels: Channels, rgb: Rgb) {
+    fn set(channels: &mut Channels, channel: usize, l: f32, c: f32, h: f32) {
+        let rgb = channels.rgb_from_luv(luv::LCh { l, c, h }.to_luv());
+        channels.set_rgb(channel, rgb);
+    }
+    let lch = luv::LCh::from_rgb(&rgb);
+    set(&mut channels, 0, lch.l, 0.0, 0.0);
+    set(&mut channels, 1, lch.c / 1.790383, 0.0, 0.0);
+    set(&mut channels, 2, 50.0, 179.0383 * 0.5, lch.h);
+}
+
+
+// cos(16°) and sin(16°) used in DIN99 formulae.
+const DIN99_COS: f32 = 0.9612617;
+const DIN99_SIN: f32 = 0.2756374;
+
+/// Converts CIELAB L\* into DIN99 L₉₉.
+fn din99_lightness(l: f32) -> f32 { 105.51 * (1.0 + 0.0158 * l).ln() }
+
+/// Converts CIELAB coordinates into DIN99 L₉₉, a₉₉ and b₉₉ coordinates.
+fn din99_from_lab(lab: lab::Lab) -> [f32; 3] {
+    let l = din99_lightness(lab.l);
+    let e = lab.a * DIN99_COS + lab.b * DIN99_SIN;
+    let f = 0.7 * (lab.b * DIN99_COS - lab.a * DIN99_SIN);
+    let g = e.hypot(f);
+    // Near the achromatic axis the hue is undefined; bail out before the
+    // division so no NaNs sneak in.
+    if g < 1e-6 {
+        return [l, 0.0, 0.0];
+    }
+    let c = (1.0 + 0.045 * g).ln() / 0.045;
+    [l, c * e / g, c * f / g]
+}
+
+/// Converts DIN99 coordinates back into CIELAB colour.
+fn lab_from_din99(l: f32, a: f32, b: f32) -> lab::Lab {
+    let l = ((l / 105.51).exp() - 1.0) / 0.0158;
+    let c = a.hypot(b);
+    if c < 1e-6 {
+        return lab::Lab { l, a: 0.0, b: 0.0 };
+    }
+    let g = ((0.045 * c).exp() - 1.0) / 0.045;
+    let e = g * a / c;
+    let f = g * b / c / 0.7;
+    lab::Lab {
+        l,
+        a: e * DIN99_COS - f * DIN99_SIN,
+        b: e * DIN99_SIN + f * DIN99_COS,
+    }
+}
+
+fn din99_fill_channels(mut channels: Channels, rgb: Rgb) {
+    fn set(channels: &mut Channels, channel: usize, l: f32, a: f32, b: f32) {
+        channels.set_lab(channel, lab_from_din99(l, a, b));
+    }
+    let [l, a, b] = din99_from_lab(lab::Lab::from_rgb(&rgb));
+    set(&mut channels, 0, l, 0.0, 0.0);
+    // Opponent lightness is L* so it needs converting into L₉₉.
+    let l = din99_lightness(channels.opponent_lightness(a, DIN99_A_RANGE));
+    set(&mut channels, 1, l, a, 0.0);
+    let l = din99_lightness(channels.opponent_lightness(b, DIN99_B_RANGE));
+    set(&mut channels, 2, l, 0.0, b);
+}
+
+fn din99_fill_legend(channels: Channels, t: f32) {
+    opponent_fill_legend(
+        channels,
+        t,
+        [DIN99_A_RANGE, DIN99_B_RANGE],
+        |l, a, b| lab_from_din99(din99_lightness(l), a, b).to_rgb(),
+    );
+}
+
+
+fn cmy_fill_channels(mut channels: Channels, rgb: Rgb) {
+    let [r, g, b] = rgb;
+    channels.set_rgb(0, [0, 255 - r, 255 - r]);
+    channels.set_rgb(1, [255 - g, 0, 255 - g]);
+    channels.set_rgb(2, [255 - b, 255 - b, 0]);
+}
+
+/// Returns cyan, magenta, yellow and key coordinates of the colour in [0, 1]
+/// range.
+fn cmyk_from_rgb(rgb: Rgb) -> [f32; 4] {
+    let [r, g, b] = rgb;
+    let (_, max) = min_max(rgb);
+    let key = 1.0 - max as f32 / 255.0;
+    // Black is pure key; avoid dividing by zero.
+    if max == 0 {
+        return [0.0, 0.0, 0.0, key];
+    }
+    let max = max as f32;
+    [1.0 - r as f32 / max, 1.0 - g as f32 / max, 1.0 - b as f32 / max, key]
+}
+
+fn cmyk_fill_channels(mut channels: Channels, rgb: Rgb) {
+    let [c, m, y, k] = cmyk_from_rgb(rgb);
+    let (c, m, y) = (channels.round(c), channels.round(m), channels.round(y));
+    channels.set_rgb(0, [0, c, c]);
+    channels.set_rgb(1, [m, 0, m]);
+    channels.set_rgb(2, [y, y, 0]);
+    channels.set_grey(3, channels.round(k));
+}
+
+/// Splits colour into its warm and cool components.  The first channel keeps
+/// only positive a\* and b\* coordinates of the colour (towards red and
+/// yellow) while the second only the negative ones (towards green and blue).
+/// Neutral colours are the same in both channels.
+fn complement_fill_channels(mut channels: Channels, rgb: Rgb) {
+    let [warm, cool] = complement_from_rgb(rgb);
+    channels.set_lab(0, warm);
+    channels.set_lab(1, cool);
+}
+
+/// Returns warm and cool components of the colour as described in
+/// [`complement_fill_channels`].
+fn complement_from_rgb(rgb: Rgb) -> [lab::Lab; 2] {
+    let lab::Lab { l, a, b } = lab::Lab::from_rgb(&rgb);
+    [
+        lab::Lab { l, a: a.max(0.0), b: b.max(0.0) },
+        lab::Lab { l, a: a.min(0.0), b: b.min(0.0) },
+    ]
+}
+
+
+/// Returns coordinates of the colour in given space, i.e. values of its
+/// channels before they are rendered as colours.  RGB-based values are in
+/// [0, 1] range with wide-gamut RGB spaces given in linear light, hues are in
+/// degrees (NaN if undefined) and CIE spaces use their customary scales.
+/// Values of the complement channels are chroma of its warm and cool
+/// components.
+pub fn channel_values(space: &Space, rgb: Rgb) -> Vec<f32> {
+    let mut values = [0.0; MAX_CHANNELS];
+    let len = write_channel_values(space, rgb, &mut values);
+    values[..len].to_vec()
+}
+
+/// Largest number of channels of any of the colour spaces.
+const MAX_CHANNELS: usize = 4;
+
+/// Like [`channel_values`] but writes the values into `out` rather than
+/// allocating a vector.  Returns number of values written, i.e. number of
+/// channels of the space.
+fn write_channel_values(
+    space: &Space,
+    rgb: Rgb,
+    out: &mut [f32; MAX_CHANNELS],
+) -> usize {
+    let unit = |v: u8| v as f32 / 255.0;
+    let [r, g, b] = rgb;
+    let degrees = |[hue, s, v]: [f32; 3]| [hue * 60.0, s, v];
+    let xyz = || srgb::xyz_from_u8(rgb);
+    let values = match space.id {
+        ColorSpaceId::Rgb => [unit(r), unit(g), unit(b)],
+        ColorSpaceId::LinRgb => srgb::gamma::linear_from_u8(rgb),
+        ColorSpaceId::AdobeRgb => {
+            matrix_product(&ADOBE_RGB_FROM_XYZ_MATRIX, xyz())
+        }
+        ColorSpaceId::DisplayP3 => {
+            matrix_product(&DISPLAY_P3_FROM_XYZ_MATRIX, xyz())
+        }
+        ColorSpaceId::Rec2020 => {
+            matrix_product(&REC2020_FROM_XYZ_MATRIX, xyz())
+        }
+        ColorSpaceId::Xyz => xyz(),
+        ColorSpaceId::XyY => xyy_from_xyz(xyz()),
+        ColorSpaceId::Ucs => ucs_from_xyz(xyz()),
+        ColorSpaceId::Hsl => degrees(hsl_from_rgb(rgb)),
+        ColorSpaceId::Hsv => degrees(hsv_from_rgb(rgb)),
+        ColorSpaceId::Hsp => degrees(hsp_from_rgb(rgb)),
+        ColorSpaceId::Hwb => degrees(hwb_from_rgb(rgb)),
+        ColorSpaceId::Tsl => {
+            let [tint, saturation, lightness] = tsl_from_rgb(rgb);
+            [tint * 360.0, saturation, lightness / 255.0]
+        }
+        ColorSpaceId::Yuv => yuv_from_rgb(rgb),
+        ColorSpaceId::Lab => {
+            let lab = lab::Lab::from_rgb(&rgb);
+            [lab.l, lab.a, lab.b]
+        }
+        ColorSpaceId::LChab => {
+            let lch = lab::LCh::from_rgb(&rgb);
+            [lch.l, lch.c, lch.h.to_degrees().rem_euclid(360.0)]
+        }
+        ColorSpaceId::Luv => {
+            let luv = luv::Luv::from_rgb(&rgb);
+            [luv.l, luv.u, luv.v]
+        }
+        ColorSpaceId::LChuv => {
+            let lch = luv::LCh::from_rgb(&rgb);
+            [lch.l, lch.c, lch.h.to_degrees().rem_euclid(360.0)]
+        }
+        ColorSpaceId::Din99 => din99_from_lab(lab::Lab::from_rgb(&rgb)),
+        ColorSpaceId::Cmy => [1.0 - unit(r), 1.0 - unit(g), 1.0 - unit(b)],
+        ColorSpaceId::Cmyk => {
+            *out = cmyk_from_rgb(rgb);
+            return 4;
+        }
+        ColorSpaceId::Complement => {
+            let chroma = |lab: lab::Lab| lab.a.hypot(lab.b);
+            let [warm, cool] = complement_from_rgb(rgb);
+            out[..2].copy_from_slice(&[chroma(warm), chroma(cool)]);
+            return 2;
+        }
+        ColorSpaceId::Original => return 0,
+    };
+    out[..3].copy_from_slice(&values);
+    3
+}
+
+#[test]
+fn test_channel_values() {
+    let close = |want: &[f32], got: Vec<f32>| {
+        assert_eq!(want.len(), got.len(), "{:?} != {:?}", want, got);
+        for (w, g) in want.iter().zip(got.iter()) {
+            assert!((w - g).abs() < 1e-3, "{:?} != {:?}", want, got);
+        }
+    };
+    for space in SPACES.iter() {
+        let got = channel_values(space, [12, 34, 56]);
+        assert_eq!(space.channels, got.len(), "{}", space.name);
+        assert!(space.channels <= MAX_CHANNELS, "{}", space.name);
+    }
+    let values = |id: ColorSpaceId, rgb: Rgb| channel_values(id.space(), rgb);
+    close(&[1.0, 0.0, 0.0], values(ColorSpaceId::Rgb, [255, 0, 0]));
+    close(&[0.0, 1.0, 0.5], values(ColorSpaceId::Hsl, [255, 0, 0]));
+    close(&[120.0, 1.0, 1.0], values(ColorSpaceId::Hsv, [0, 255, 0]));
+    close(&[240.0, 0.0, 0.0], values(ColorSpaceId::Hwb, [0, 0, 255]));
+    close(&[100.0, 0.0, 0.0], values(ColorSpaceId::Lab, [255, 255, 255]));
+    close(&[0.0, 0.0, 0.0, 1.0], values(ColorSpaceId::Cmyk, [0, 0, 0]));
+    close(&[0.0, 1.0, 1.0, 0.0], values(ColorSpaceId::Cmyk, [255, 0, 0]));
+    close(&[0.0, 0.0], values(ColorSpaceId::Complement, [128, 128, 128]));
+    let grey = values(ColorSpaceId::Hsl, [128, 128, 128]);
+    assert!(grey[0].is_nan(), "{:?}", grey);
+}
+
+
+/// Returns values of channels of the image in given space as a planar buffer:
+/// all values of the first channel in row-major order followed by all values
+/// of the second channel and so on.  Values are as returned by
+/// [`channel_values`].  Returns `None` for the ‘original’ space which has no
+/// channels or if the buffer would be too large.
+pub fn build_planar(space: &Space, img: &Image) -> Option<Vec<f32>> {
+    let channels = space.channel_names().len();
+    let plane = (img.width() as usize).checked_mul(img.height() as usize)?;
+    let len = plane.checked_mul(channels).filter(|&len| len > 0)?;
+    let mut data = vec![0.0; len];
+    let mut cache = std::collections::HashMap::new();
+    for (idx, pixel) in img.pixels().enumerate() {
+        let values = cache
+            .entry(pixel.0)
+            .or_insert_with(|| channel_values(space, pixel.0));
+        for (chan, &value) in values.iter().enumerate() {
+            data[chan * plane + idx] = value;
+        }
+    }
+    Some(data)
+}
+
+#[test]
+fn test_build_planar() {
+    let img = Image::from_raw(2, 1, vec![255, 0, 51, 0, 102, 255]).unwrap();
+    assert_eq!(
+        Some(vec![1.0, 0.0, 0.0, 0.4, 0.2, 1.0]),
+        build_planar(ColorSpaceId::Rgb.space(), &img)
+    );
+    let data = build_planar(ColorSpaceId::Cmyk.space(), &img);
+    assert_eq!(Some(8), data.map(|data| data.len()));
+    assert_eq!(None, build_planar(ColorSpaceId::Original.space(), &img));
+}
+
+
+/// Returns number of distinct values, as returned by [`channel_values`], of
+/// each channel of the image in given space.  If `mask` of the same
+/// dimensions as the image is given, pixels outside of it are not counted.
+/// All NaNs (i.e. undefined hues) count as a single value.
+pub fn distinct_channel_values(
+    space: &Space,
+    img: &Image,
+    mask: Option<&image::GrayImage>,
+) -> Vec<usize> {
+    let mask = mask.filter(|mask| mask.dimensions() == img.dimensions());
+    let mut sets = vec![std::collections::HashSet::new(); space.channels];
+    let mut seen = std::collections::HashSet::new();
+    let mut values = [0.0; MAX_CHANNELS];
+    for (idx, pixel) in img.pixels().enumerate() {
+        if mask.map_or(false, |mask| mask.as_raw()[idx] < 128) {
+            continue;
+        }
+        // Skip recomputing values of pixels which have already been seen.
+        if !seen.insert(pixel.0) {
+            continue;
+        }
+        write_channel_values(space, pixel.0, &mut values);
+        for (set, &value) in sets.iter_mut().zip(values.iter()) {
+            let value = if value.is_nan() { f32::NAN } else { value };
+            set.insert(value.to_bits());
+        }
+    }
+    sets.iter().map(|set| set.len()).collect()
+}
+
+/// Returns number of bits needed to represent given number of distinct
+/// values, i.e. ⌈log₂ count⌉.
+pub fn effective_bits(count: usize) -> u32 {
+    count.max(1).next_power_of_two().trailing_zeros()
+}
+
+#[test]
+fn test_distinct_channel_values() {
+    assert_eq!(
+        vec![0, 0, 1, 1, 2, 2, 3, 8, 9],
+        [0, 1, 2, 3, 4, 5, 8, 256, 257]
+            .iter()
+            .map(|&n| effective_bits(n))
+            .collect::<Vec<_>>()
+    );
+
+    let data = vec![10, 20, 30, 10, 20, 40, 10, 20, 30];
+    let img = Image::from_raw(3, 1, data).unwrap();
+    let rgb = ColorSpaceId::Rgb.space();
+    assert_eq!(vec![1, 1, 2], distinct_channel_values(rgb, &img, None));
+
+    // Only pixels within the mask are counted.
+    let mask = image::GrayImage::from_raw(3, 1, vec![255, 0, 255]).unwrap();
+    let counts = distinct_channel_values(rgb, &img, Some(&mask));
+    assert_eq!(vec![1, 1, 1], counts);
+
+    // Undefined hues count as a single value.
+    let data = vec![0, 0, 0, 128, 128, 128, 255, 255, 255];
+    let img = Image::from_raw(3, 1, data).unwrap();
+    let hsl = ColorSpaceId::Hsl.space();
+    assert_eq!(vec![1, 1, 3], distinct_channel_values(hsl, &img, None));
+}
+
+
+fn original_fill_channels(_channels: Channels, _rgb: Rgb) {}
+
+/// A pseudo-space with no channels.  Decomposition image in this space is just
+/// a copy of the source image.
+pub static ORIGINAL: Space = Space {
+    id: ColorSpaceId::Original,
+    name: "original",
+    channels: 0,
+    fill_channels: original_fill_channels,
+    fill_legend: None,
+};
+
+#[rustfmt::skip]
+pub static SPACES: [Space; 22] = [
+    Space { id: ColorSpaceId::Rgb,       name: "rgb",        channels: 3, fill_channels: rgb_fill_channels,        fill_legend: None},
+    Space { id: ColorSpaceId::LinRgb,    name: "lin-rgb",    channels: 3, fill_channels: lin_rgb_fill_channels,    fill_legend: None},
+    Space { id: ColorSpaceId::AdobeRgb,  name: "adobe-rgb",  channels: 3, fill_channels: adobe_rgb_fill_channels,  fill_legend: None},
+    Space { id: ColorSpaceId::DisplayP3, name: "display-p3", channels: 3, fill_channels: display_p3_fill_channels, fill_legend: None},
+    Space { id: ColorSpaceId::Rec2020,   name: "rec2020",    channels: 3, fill_channels: rec2020_fill_channels,    fill_legend: None},
+    Space { id: ColorSpaceId::Xyz,       name: "XYZ",        channels: 3, fill_channels: xyz_fill_channels,        fill_legend: None},
+    Space { id: ColorSpaceId::XyY,       name: "xyY",        channels: 3, fill_channels: xyy_fill_channels,        fill_legend: None},
+    Space { id: ColorSpaceId::Ucs,       name: "ucs",        channels: 3, fill_channels: ucs_fill_channels,        fill_legend: None},
+    Space { id: ColorSpaceId::Hsl,       name: "hsl",        channels: 3, fill_channels: hsl_fill_channels,        fill_legend: None},
+    Space { id: ColorSpaceId::Hsv,       name: "hsv",        channels: 3, fill_channels: hsv_fill_channels,        fill_legend: None},
+    Space { id: ColorSpaceId::Hwb,       name: "hwb",        channels: 3, fill_channels: hwb_fill_channels,        fill_legend: None},
+    Space { id: ColorSpaceId::Hsp,       name: "hsp",        channels: 3, fill_channels: hsp_fill_channels,        fill_legend: None},
+    Space { id: ColorSpaceId::Tsl,       name: "tsl",        channels: 3, fill_channels: tsl_fill_channels,        fill_legend: None},
+    Space { id: ColorSpaceId::Yuv,       name: "yuv",        channels: 3, fill_channels: yuv_fill_channels,        fill_legend: None},
+    Space { id: ColorSpaceId::Lab,       name: "lab",        channels: 3, fill_channels: lab_fill_channels,        fill_legend: Some(lab_fill_legend)},
+    Space { id: ColorSpaceId::LChab,     name: "lchab",      channels: 3, fill_channels: lchab_fill_channels,      fill_legend: None},
+    Space { id: ColorSpaceId::Luv,       name: "luv",        channels: 3, fill_channels: luv_fill_channels,        fill_legend: Some(luv_fill_legend)},
+    Space { id: ColorSpaceId::LChuv,     name: "lchuv",      channels: 3, fill_channels: lchuv_fill_channels,      fill_legend: None},
+    Space { id: ColorSpaceId::Din99,     name: "din99",      channels: 3, fill_channels: din99_fill_channels,      fill_legend: Some(din99_fill_legend)},
+    Space { id: ColorSpaceId::Cmy,       name: "cmy",        channels: 3, fill_channels: cmy_fill_channels,        fill_legend: None},
+    Space { id: ColorSpaceId::Cmyk,      name: "cmyk",       channels: 4, fill_channels: cmyk_fill_channels,       fill_legend: None},
+    Space { id: ColorSpaceId::Complement, name: "complement", channels: 2, fill_channels: complement_fill_channels, fill_legend: None},
+];
+
+
+#[test]
+fn test_space_ids() {
+    for (idx, space) in SPACES.iter().enumerate() {
+        assert_eq!(idx, space.id as usize, "{}", space.name);
+        assert!(std::ptr::eq(space, space.id.space()), "{}", space.name);
+        let names = space.channel_names();
+        assert_eq!(space.channels, names.len(), "{}", space.name);
+    }
+    assert_eq!(SPACES.len(), ColorSpaceId::all().count());
+    assert!(std::ptr::eq(&ORIGINAL, ColorSpaceId::Original.space()));
+}
+
+/// Calls fill function of a three-channel space with given colour and returns
+/// colours it set.
+#[cfg(test)]
+fn fill(fill_channels: fn(Channels, Rgb), rgb: Rgb) -> [Rgb; 3] {
+    let mut buf = [[std::mem::MaybeUninit::uninit(); 3]; 3];
+    let settings = Settings::default();
+    fill_channels(Channels(buf.as_mut_ptr(), 1, &settings, 0.0), rgb);
+    // SAFETY: Fill functions set all the channels.
+    unsafe { std::mem::transmute(buf) }
+}
+
+/// Calls fill function of given space with given colour and returns colours it
+/// set for each of the channels.
+#[cfg(test)]
+fn fill_space(space: &Space, rgb: Rgb) -> Vec<Rgb> {
+    let mut buf = vec![[std::mem::MaybeUninit::uninit(); 3]; space.channels];
+    let settings = Settings::default();
+    (space.fill_channels)(Channels(buf.as_mut_ptr(), 1, &settings, 0.0), rgb);
+    // SAFETY: Fill functions set all the channels.
+    buf.into_iter()
+        .map(|pixel| unsafe { std::mem::transmute::<_, Rgb>(pixel) })
+        .collect()
+}
+
+#[test]
+fn test_reference_colours() {
+    const COLOURS: [Rgb; 7] = [
+        [0, 0, 0],
+        [255, 255, 255],
+        [128, 128, 128],
+        [255, 0, 0],
+        [0, 255, 0],
+        [0, 0, 255],
+        [224, 172, 105],
+    ];
+
+    // Rather than snapshotting the implementation, XYZ tiles are derived from
+    // the IEC 61966-2-1 definition: expand sRGB, apply its four-digit matrix,
+    // normalise by the white point (the matrix’s row sums) and encode each
+    // coordinate as grey with the sRGB transfer function.
+    const SRGB_TO_XYZ: [[f64; 3]; 3] = [
+        [0.4124, 0.3576, 0.1805],
+        [0.2126, 0.7152, 0.0722],
+        [0.0193, 0.1192, 0.9505],
+    ];
+    let xyz_tiles = |rgb: Rgb| -> [Rgb; 3] {
+        let expand = |v: u8| {
+            let v = v as f64 / 255.0;
+            if v <= 0.04045 {
+                v / 12.92
+            } else {
+                ((v + 0.055) / 1.055).powf(2.4)
+            }
+        };
+        let encode = |v: f64| {
+            let v = v.max(0.0).min(1.0);
+            let v = if v <= 0.0031308 {
+                12.92 * v
+            } else {
+                1.055 * v.powf(1.0 / 2.4) - 0.055
+            };
+            (v * 255.0).round() as u8
+        };
+        let linear = [expand(rgb[0]), expand(rgb[1]), expand(rgb[2])];
+        let mut tiles = [[0; 3]; 3];
+        for (tile, row) in tiles.iter_mut().zip(SRGB_TO_XYZ.iter()) {
+            let value = row.iter().zip(linear.iter()).map(|(m, v)| m * v);
+            let white = row.iter().sum::<f64>();
+            *tile = [encode(value.sum::<f64>() / white); 3];
+        }
+        tiles
+    };
+    let xyz = COLOURS.iter().copied().map(xyz_tiles).collect::<Vec<_>>();
+
+    #[rustfmt::skip]
+    let want: [(ColorSpaceId, [&[Rgb]; 7]); 22] = [
+        (ColorSpaceId::Rgb, [
+            &[[  0,   0,   0], [  0,   0,   0], [  0,   0,   0]],
+            &[[255,   0,   0], [  0, 255,   0], [  0,   0, 255]],
+            &[[128,   0,   0], [  0, 128,   0], [  0,   0, 128]],
+            &[[255,   0,   0], [  0,   0,   0], [  0,   0,   0]],
+            &[[  0,   0,   0], [  0, 255,   0], [  0,   0,   0]],
+            &[[  0,   0,   0], [  0,   0,   0], [  0,   0, 255]],
+            &[[224,   0,   0], [  0, 172,   0], [  0,   0, 105]],
+        ]),
+        (ColorSpaceId::LinRgb, [
+            &[[  0,   0,   0], [  0,   0,   0], [  0,   0,   0]],
+            &[[255,   0,   0], [  0, 255,   0], [  0,   0, 255]],
+            &[[ 55,   0,   0], [  0,  55,   0], [  0,   0,  55]],
+            &[[255,   0,   0], [  0,   0,   0], [  0,   0,   0]],
+            &[[  0,   0,   0], [  0, 255,   0], [  0,   0,   0]],
+            &[[  0,   0,   0], [  0,   0,   0], [  0,   0, 255]],
+            &[[190,   0,   0], [  0, 105,   0], [  0,   0,  36]],
+        ]),
+        (ColorSpaceId::AdobeRgb, [
+            &[[  0,   0,   0], [  0,   0,   0], [  0,   0,   0]],
+            &[[255,   0,   0], [  0, 255,   0], [  0,   0, 255]],
+            &[[149,   0,   0], [  0, 128,   0], [  0,   0, 131]],
+            &[[255,   0,   0], [  0,   0,   0], [  0,   0,   0]],
+            &[[169,   0,   0], [  0, 255,   0], [  0,   0,  59]],
+            &[[  0,   0,   0], [  0,   0,   0], [  0,   0, 255]],
+            &[[245,   0,   0], [  0, 172,   0], [  0,   0, 111]],
+        ]),
+        (ColorSpaceId::DisplayP3, [
+            &[[  0,   0,   0], [  0,   0,   0], [  0,   0,   0]],
+            &[[255,   0,   0], [  0, 255,   0], [  0,   0, 255]],
+            &[[141,   0,   0], [  0, 130,   0], [  0,   0, 134]],
+            &[[255,   0,   0], [  0,  52,   0], [  0,   0,  37]],
+            &[[129,   0,   0], [  0, 255,   0], [  0,   0,  80]],
+            &[[  0,   0,   0], [  0,   0,   0], [  0,   0, 255]],
+            &[[236,   0,   0], [  0, 177,   0], [  0,   0, 120]],
+        ]),
+        (ColorSpaceId::Rec2020, [
+            &[[  0,   0,   0], [  0,   0,   0], [  0,   0,   0]],
+            &[[255,   0,   0], [  0, 255,   0], [  0,   0, 255]],
+            &[[161,   0,   0], [  0, 135,   0], [  0,   0, 135]],
+            &[[255,   0,   0], [  0,  79,   0], [  0,   0,  37]],
+            &[[195,   0,   0], [  0, 255,   0], [  0,   0,  89]],
+            &[[ 76,   0,   0], [  0,  30,   0], [  0,   0, 255]],
+            &[[255,   0,   0], [  0, 186,   0], [  0,   0, 123]],
+        ]),
+        (ColorSpaceId::Xyz, [
+            &xyz[0], &xyz[1], &xyz[2], &xyz[3], &xyz[4], &xyz[5], &xyz[6],
+        ]),
+        (ColorSpaceId::XyY, [
+            &[[188, 188, 188], [188, 188, 188], [  0,   0,   0]],
+            &[[188, 188, 188], [188, 188, 188], [255, 255, 255]],
+            &[[188, 188, 188], [188, 188, 188], [128, 128, 128]],
+            &[[255,   0,   6], [187, 188, 187], [127, 127, 127]],
+            &[[175, 191, 191], [ 56, 216,   0], [220, 220, 220]],
+            &[[  0, 225, 224], [255,   0, 255], [ 76,  76,  76]],
+            &[[255, 156, 157], [150, 200, 146], [181, 181, 181]],
+        ]),
+        (ColorSpaceId::Ucs, [
+            &[[188, 188, 188], [188, 188, 188], [  0,   0,   0]],
+            &[[188, 188, 188], [188, 188, 188], [255, 255, 255]],
+            &[[188, 188, 188], [188, 188, 188], [128, 128, 128]],
+            &[[255,   0, 153], [187, 193, 112], [127, 127, 127]],
+            &[[  0, 214, 196], [187, 197,   0], [220, 220, 220]],
+            &[[153, 196, 190], [195,   0, 255], [ 76,  76,  76]],
+            &[[236, 170, 182], [187, 193, 117], [181, 181, 181]],
+        ]),
+        (ColorSpaceId::Hsl, [
+            &[[  0,   0,   0], [  0,   0,   0], [  0,   0,   0]],
+            &[[  0,   0,   0], [  0,   0,   0], [255, 255, 255]],
+            &[[  0,   0,   0], [  0,   0,   0], [128, 128, 128]],
+            &[[191,  64,  64], [255, 255, 255], [127, 127, 127]],
+            &[[ 64, 191,  64], [255, 255, 255], [127, 127, 127]],
+            &[[ 64,  64, 191], [255, 255, 255], [127, 127, 127]],
+            &[[191, 136,  64], [168, 168, 168], [164, 164, 164]],
+        ]),
+        (ColorSpaceId::Hsv, [
+            &[[  0,   0,   0], [  0,   0,   0], [  0,   0,   0]],
+            &[[  0,   0,   0], [  0,   0,   0], [255, 255, 255]],
+            &[[  0,   0,   0], [  0,   0,   0], [128, 128, 128]],
+            &[[191,  64,  64], [255, 255, 255], [255, 255, 255]],
+            &[[ 64, 191,  64], [255, 255, 255], [255, 255, 255]],
+            &[[ 64,  64, 191], [255, 255, 255], [255, 255, 255]],
+            &[[191, 136,  64], [135, 135, 135], [224, 224, 224]],
+        ]),
+        (ColorSpaceId::Hwb, [
+            &[[  0,   0,   0], [  0,   0,   0], [255, 255, 255]],
+            &[[  0,   0,   0], [255, 255, 255], [  0,   0,   0]],
+            &[[  0,   0,   0], [128, 128, 128], [127, 127, 127]],
+            &[[191,  64,  64], [  0,   0,   0], [  0,   0,   0]],
+            &[[ 64, 191,  64], [  0,   0,   0], [  0,   0,   0]],
+            &[[ 64,  64, 191], [  0,   0,   0], [  0,   0,   0]],
+            &[[191, 136,  64], [105, 105, 105], [ 31,  31,  31]],
+        ]),
+        (ColorSpaceId::Hsp, [
+            &[[  0,   0,   0], [  0,   0,   0], [  0,   0,   0]],
+            &[[  0,   0,   0], [  0,   0,   0], [255, 255, 255]],
+            &[[  0,   0,   0], [  0,   0,   0], [128, 128, 128]],
+            &[[191,  64,  64], [255, 255, 255], [139, 139, 139]],
+            &[[ 64, 191,  64], [255, 255, 255], [195, 195, 195]],
+            &[[ 64,  64, 191], [255, 255, 255], [ 86,  86,  86]],
+            &[[191, 136,  64], [135, 135, 135], [183, 183, 183]],
+        ]),
+        (ColorSpaceId::Tsl, [
+            &[[  0,   0,   0], [  0,   0,   0], [  0,   0,   0]],
+            &[[  0,   0,   0], [  0,   0,   0], [255, 255, 255]],
+            &[[  0,   0,   0], [  0,   0,   0], [128, 128, 128]],
+            &[[ 64, 135, 191], [255, 255, 255], [ 76,  76,  76]],
+            &[[184, 191,  64], [255, 255, 255], [150, 150, 150]],
+            &[[191,  64, 159], [161, 161, 161], [ 29,  29,  29]],
+            &[[ 64, 191, 181], [ 39,  39,  39], [180, 180, 180]],
+        ]),
+        (ColorSpaceId::Yuv, [
+            &[[  0,   0,   0], [128, 128, 128], [128, 128, 128]],
+            &[[255, 255, 255], [128, 128, 128], [128, 128, 128]],
+            &[[128, 128, 128], [128, 128, 128], [128, 128, 128]],
+            &[[ 76,  76,  76], [128, 142,  51], [255,  36, 128]],
+            &[[150, 150, 150], [128, 157,   0], [  0, 204, 128]],
+            &[[ 29,  29,  29], [128,  84, 255], [ 98, 142, 128]],
+            &[[180, 180, 180], [128, 142,  53], [172, 105, 128]],
+        ]),
+        (ColorSpaceId::Lab, [
+            &[[  0,   0,   0], [  0,   0,   0], [  0,   0,   0]],
+            &[[255, 255, 255], [  0,   0,   0], [  0,   0,   0]],
+            &[[128, 128, 128], [  0,   0,   0], [  0,   0,   0]],
+            &[[127, 127, 127], [203,   0,  99], [104,  82,   0]],
+            &[[220, 220, 220], [  0, 148, 117], [128, 102,   0]],
+            &[[ 76,  76,  76], [201,   0,  98], [  0, 134, 255]],
+            &[[181, 181, 181], [ 33,  12,  19], [ 67,  51,   0]],
+        ]),
+        (ColorSpaceId::LChab, [
+            &[[  0,   0,   0], [  0,   0,   0], [216,  52, 121]],
+            &[[255, 255, 255], [  0,   0,   0], [216,  52, 121]],
+            &[[128, 128, 128], [  0,   0,   0], [  0, 126, 234]],
+            &[[127, 127, 127], [193, 193, 193], [207,  74,  48]],
+            &[[220, 220, 220], [225, 225, 225], [ 40, 137,  28]],
+            &[[ 76,  76,  76], [255, 255, 255], [133,  98, 212]],
+            &[[181, 181, 181], [ 76,  76,  76], [167, 106,   0]],
+        ]),
+        (ColorSpaceId::Luv, [
+            &[[  0,   0,   0], [  0,   0,   0], [  0,   0,   0]],
+            &[[255, 255, 255], [  0,   0,   0], [  0,   0,   0]],
+            &[[128, 128, 128], [  0,   0,   0], [  0,   0,   0]],
+            &[[127, 127, 127], [255,   0,  95], [ 43,  47,   0]],
+            &[[220, 220, 220], [  0, 148, 129], [118, 128,   0]],
+            &[[ 76,  76,  76], [  0,  26,  21], [117,  81, 255]],
+            &[[181, 181, 181], [ 75,   0,  22], [ 54,  60,   0]],
+        ]),
+        (ColorSpaceId::LChuv, [
+            &[[  0,   0,   0], [  0,   0,   0], [203,  72, 107]],
+            &[[255, 255, 255], [  0,   0,   0], [120, 102, 215]],
+            &[[128, 128, 128], [  0,   0,   0], [  0, 127, 208]],
+            &[[127, 127, 127], [255, 255, 255], [198,  80,  80]],
+            &[[220, 220, 220], [187, 187, 187], [  0, 141,   0]],
+            &[[ 76,  76,  76], [179, 179, 179], [106, 106, 216]],
+            &[[181, 181, 181], [ 83,  83,  83], [169, 107,   0]],
+        ]),
+        (ColorSpaceId::Din99, [
+            &[[  0,   0,   0], [  0,   0,   0], [  0,   0,   0]],
+            &[[255, 255, 255], [  0,   0,   0], [  0,   0,   0]],
+            &[[128, 128, 128], [  0,   0,   0], [  0,   0,   0]],
+            &[[127, 127, 127], [209,   0,  56], [ 33,  33,   0]],
+            &[[220, 220, 220], [  0,  89,  91], [ 70,  88,   0]],
+            &[[ 76,  76,  76], [ 75,  22,  31], [  0,  90, 255]],
+            &[[181, 181, 181], [ 56,  21,  26], [ 42,  44,   0]],
+        ]),
+        (ColorSpaceId::Cmy, [
+            &[[  0, 255, 255], [255,   0, 255], [255, 255,   0]],
+            &[[  0,   0,   0], [  0,   0,   0], [  0,   0,   0]],
+            &[[  0, 127, 127], [127,   0, 127], [127, 127,   0]],
+            &[[  0,   0,   0], [255,   0, 255], [255, 255,   0]],
+            &[[  0, 255, 255], [  0,   0,   0], [255, 255,   0]],
+            &[[  0, 255, 255], [255,   0, 255], [  0,   0,   0]],
+            &[[  0,  31,  31], [ 83,   0,  83], [150, 150,   0]],
+        ]),
+        (ColorSpaceId::Cmyk, [
+            &[[  0,   0,   0], [  0,   0,   0], [  0,   0,   0], [255, 255, 255]],
+            &[[  0,   0,   0], [  0,   0,   0], [  0,   0,   0], [  0,   0,   0]],
+            &[[  0,   0,   0], [  0,   0,   0], [  0,   0,   0], [127, 127, 127]],
+            &[[  0,   0,   0], [255,   0, 255], [255, 255,   0], [  0,   0,   0]],
+            &[[  0, 255, 255], [  0,   0,   0], [255, 255,   0], [  0,   0,   0]],
+            &[[  0, 255, 255], [255,   0, 255], [  0,   0,   0], [  0,   0,   0]],
+            &[[  0,   0,   0], [ 59,   0,  59], [135, 135,   0], [ 31,  31,  31]],
+        ]),
+        (ColorSpaceId::Complement, [
+            &[[  0,   0,   0], [  0,   0,   0]],
+            &[[255, 255, 255], [255, 255, 255]],
+            &[[128, 128, 128], [128, 128, 128]],
+            &[[255,   0,   0], [127, 127, 127]],
+            &[[255, 216,  36], [  0, 255, 217]],
+            &[[176,   0,  79], [  0,  93, 254]],
+            &[[224, 172, 105], [181, 181, 181]],
+        ]),
+    ];
+
+    let mut failures = Vec::new();
+    for &(id, tiles) in want.iter() {
+        let space = id.space();
+        for (&rgb, &want) in COLOURS.iter().zip(tiles.iter()) {
+            let got = fill_space(space, rgb);
+            let ok = got.len() == want.len() &&
+                got.iter().flatten().zip(want.iter().flatten()).all(
+                    |(&got, &want)| (got as i32 - want as i32).abs() <= 1,
+                );
+            if !ok {
+                failures.push(format!(
+                    "{} {:?}: got {:?}, want {:?}",
+                    space.name, rgb, got, want
+                ));
+            }
+        }
+    }
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+    assert_eq!(SPACES.len(), want.len());
+}
+
+#[test]
+fn test_adobe_rgb() {
+    // Black and white are the same in both spaces.
+    assert_eq!([[0, 0, 0]; 3], fill(adobe_rgb_fill_channels, [0, 0, 0]));
+    let [r, g, b] = fill(adobe_rgb_fill_channels, [255, 255, 255]);
+    assert!(r[0] > 250 && g[1] > 250 && b[2] > 250, "{:?}", [r, g, b]);
+
+    // sRGB green is less saturated than Adobe RGB green so red and blue
+    // primaries contribute to it.
+    let [r, g, b] = fill(adobe_rgb_fill_channels, [0, 255, 0]);
+    assert!(r[0] > 50 && g[1] > 200 && b[2] > 10, "{:?}", [r, g, b]);
+}
+
+#[test]
+fn test_display_p3() {
+    assert_eq!([[0, 0, 0]; 3], fill(display_p3_fill_channels, [0, 0, 0]));
+
+    // sRGB red and green are inside of the P3 gamut so each of them is a mix
+    // of P3 primaries.  (Blue primaries of the two spaces are the same.)
+    for &rgb in &[[255, 0, 0], [0, 255, 0]] {
+        let [r, g, b] = fill(display_p3_fill_channels, rgb);
+        assert_eq!(
+            3,
+            [r[0], g[1], b[2]].iter().filter(|&&v| v > 0).count(),
+            "{:?} → {:?}",
+            rgb,
+            [r, g, b]
+        );
+    }
+}
+
+#[test]
+fn test_primaries_matrices() {
+    fn check(from_xyz: &[[f32; 3]; 3], to_xyz: &[[f32; 3]; 3]) {
+        for &v in &[[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]] {
+            let got = matrix_product(from_xyz, matrix_product(to_xyz, v));
+            for i in 0..3 {
+                assert!((v[i] - got[i]).abs() < 1e-6, "{:?} → {:?}", v, got);
+            }
+        }
+    }
+    check(&DISPLAY_P3_FROM_XYZ_MATRIX, &XYZ_FROM_DISPLAY_P3_MATRIX);
+    check(&ADOBE_RGB_FROM_XYZ_MATRIX, &XYZ_FROM_ADOBE_RGB_MATRIX);
+    check(&REC2020_FROM_XYZ_MATRIX, &XYZ_FROM_REC2020_MATRIX);
+}
+
+#[test]
+fn test_ucs() {
+    // Black has no chromaticity so it’s rendered as the white point.
+    assert_eq!(None, ucs_uv_from_xyz([0.0, 0.0, 0.0]));
+    let white = fill(ucs_fill_channels, [255, 255, 255]);
+    let black = fill(ucs_fill_channels, [0, 0, 0]);
+    assert_eq!(white[..2], black[..2]);
+    assert_eq!([[255; 3], [0; 3]], [white[2], black[2]]);
+
+    let (u, v) = ucs_uv_from_xyz(srgb::xyz::D65_XYZ).unwrap();
+    assert!((u - 0.1978).abs() < 0.0001, "{}", u);
+    assert!((v - 0.3122).abs() < 0.0001, "{}", v);
+}
+
+#[test]
+fn test_hsp() {
+    assert_eq!([[0, 0, 0]; 3], fill(hsp_fill_channels, [0, 0, 0]));
+    assert_eq!(
+        [[0, 0, 0], [0, 0, 0], [255, 255, 255]],
+        fill(hsp_fill_channels, [255, 255, 255])
+    );
+    // Unlike HSV value, brightness depends on the hue.
+    let [_, _, red] = fill(hsp_fill_channels, [255, 0, 0]);
+    let [_, _, green] = fill(hsp_fill_channels, [0, 255, 0]);
+    let [_, _, blue] = fill(hsp_fill_channels, [0, 0, 255]);
+    assert_eq!([[139; 3], [195; 3], [86; 3]], [red, green, blue]);
+}
+
+#[test]
+fn test_tsl() {
+    // Black and greys have undefined tint and no saturation.
+    assert_eq!([[0, 0, 0]; 3], fill(tsl_fill_channels, [0, 0, 0]));
+    assert_eq!(
+        [[0, 0, 0], [0, 0, 0], [128, 128, 128]],
+        fill(tsl_fill_channels, [128, 128, 128])
+    );
+
+    let [tint, saturation, lightness] = fill(tsl_fill_channels, [255, 0, 0]);
+    assert_ne!([0, 0, 0], tint);
+    assert_eq!([255, 255, 255], saturation);
+    assert_eq!([76, 76, 76], lightness);
+}
+
+#[test]
+fn test_din99() {
+    for &rgb in &[[0, 0, 0], [255, 255, 255], [128, 128, 128], [255, 0, 0]] {
+        let lab = lab::Lab::from_rgb(&rgb);
+        let [l, a, b] = din99_from_lab(lab);
+        assert!(!l.is_nan() && !a.is_nan() && !b.is_nan(), "{:?}", rgb);
+        let got = lab_from_din99(l, a, b);
+        assert!((lab.l - got.l).abs() < 0.01, "{:?}", rgb);
+        assert!((lab.a - got.a).abs() < 0.01, "{:?}", rgb);
+        assert!((lab.b - got.b).abs() < 0.01, "{:?}", rgb);
+    }
+}
+
+#[test]
+fn test_din99_ranges() {
+    // Extremes of the gamut lie on its surface so it’s enough to check faces
+    // of the RGB cube.
+    let mut a_range = (f32::INFINITY, f32::NEG_INFINITY);
+    let mut b_range = a_range;
+    let update = |(min, max): &mut (f32, f32), v: f32| {
+        *min = min.min(v);
+        *max = max.max(v);
+    };
+    for i in 0..=255 {
+        for j in 0..=255 {
+            for &k in &[0, 255] {
+                for &rgb in &[[k, i, j], [i, k, j], [i, j, k]] {
+                    let lab = lab::Lab::from_rgb(&rgb);
+                    let [_, a, b] = din99_from_lab(lab);
+                    update(&mut a_range, a);
+                    update(&mut b_range, b);
+                }
+            }
+        }
+    }
+    let close = |want: (f32, f32), got: (f32, f32)| {
+        (want.0 - got.0).abs() < 0.001 && (want.1 - got.1).abs() < 0.001
+    };
+    assert!(close(DIN99_A_RANGE, a_range), "{:?}", a_range);
+    assert!(close(DIN99_B_RANGE, b_range), "{:?}", b_range);
+
+    // Middle of the legend is neutral grey at L* = 50.
+    let rgb = lab_from_din99(din99_lightness(50.0), 0.0, 0.0).to_rgb();
+    assert_eq!(lab::Lab { l: 50.0, a: 0.0, b: 0.0 }.to_rgb(), rgb);
+}
+
+#[test]
+fn test_resize_linear() {
+    let img = Image::from_raw(2, 1, vec![0, 0, 0, 255, 255, 255]).unwrap();
+    // Average of black and white in linear light is much brighter than the
+    // 50% grey one gets by averaging encoded values.
+    for &filter in &[ResizeFilter::Lanczos3, ResizeFilter::Lanczos2] {
+        let [r, g, b] = resize_linear(&img, 1, 1, filter).get_pixel(0, 0).0;
+        assert!(r > 180 && r < 196 && r == g && g == b, "{:?}", [r, g, b]);
+    }
+}
+
+#[test]
+fn test_composite_over_checker() {
+    let img = image::RgbaImage::from_raw(3, 1, vec![
+        10, 20, 30, 255, 10, 20, 30, 0, 255, 255, 255, 0,
+    ])
+    .unwrap();
+    let got = composite_over_checker(&img, 1);
+    assert_eq!(
+        &[10, 20, 30, 153, 153, 153, 204, 204, 204][..],
+        &got.as_raw()[..]
+    );
+
+    let rgb = image::DynamicImage::ImageRgba8(img).to_rgb8();
+    let settings = Settings::default();
+    let (_, _, data) =
+        build_image_with_original(&SPACES[0], &rgb, &got, &settings).unwrap();
+    assert_eq!(&got.as_raw()[..], &data[..9]);
+}
+
+#[test]
+fn test_empty_image() {
+    let settings = Settings::default();
+    for &(width, height) in &[(0, 0), (0, 1), (1, 0)] {
+        let img = Image::new(width, height);
+        for space in SPACES.iter() {
+            assert_eq!(None, build_image(space, &img, &settings));
+        }
+        assert_eq!(None, build_diff_image(&SPACES[0], &img, &img, &settings));
+    }
+}
+
+#[test]
+fn test_invert() {
+    let img = Image::from_raw(1, 1, vec![10, 20, 30]).unwrap();
+    let settings = Settings {
+        invert: true,
+        ..Settings::default()
+    };
+    let (width, height, data) =
+        build_image(&SPACES[0], &img, &settings).unwrap();
+    assert_eq!((4, 1), (width, height));
+    assert_eq!(
+        &[10, 20, 30, 245, 255, 255, 255, 235, 255, 255, 255, 225][..],
+        &data[..]
+    );
+}
+
+#[test]
+fn test_display_gamma() {
+    let img = Image::from_raw(1, 1, vec![64, 128, 255]).unwrap();
+    let settings = Settings {
+        display_gamma: Some(2.0),
+        luma_tile: true,
+        ..Settings::default()
+    };
+    let (width, height, data) =
+        build_image(&SPACES[0], &img, &settings).unwrap();
+    assert_eq!((5, 1), (width, height));
+    let luma = luma_from_rgb([64, 128, 255]);
+    assert_eq!(
+        &[
+            64, 128, 255, 128, 0, 0, 0, 181, 0, 0, 0, 255, luma, luma, luma
+        ][..],
+        &data[..]
+    );
+}
+
+#[test]
+fn test_original_position() {
+    let img = Image::from_raw(1, 1, vec![10, 20, 30]).unwrap();
+    let build = |original| {
+        let settings = Settings {
+            original,
+            ..Settings::default()
+        };
+        let (width, height, data) =
+            build_image(&SPACES[0], &img, &settings).unwrap();
+        assert_eq!(data.len(), width as usize * height as usize * 3);
+        data.into_vec()
+    };
+    let channels = [10, 0, 0, 0, 20, 0, 0, 0, 30];
+    assert_eq!(
+        [&img.as_raw()[..], &channels[..]].concat(),
+        build(OriginalPosition::First)
+    );
+    assert_eq!(
+        [&channels[..], &img.as_raw()[..]].concat(),
+        build(OriginalPosition::Last)
+    );
+    assert_eq!(&channels[..], &build(OriginalPosition::None)[..]);
+
+    let settings = Settings {
+        original: OriginalPosition::None,
+        ..Settings::default()
+    };
+    assert_eq!(None, build_image(&ORIGINAL, &img, &settings));
+}
+
+#[test]
+fn test_legend() {
+    let img = Image::from_raw(2, 1, vec![10, 20, 30, 40, 50, 60]).unwrap();
+    let settings = Settings {
+        legend: true,
+        ..Settings::default()
+    };
+
+    // Spaces without legend are not affected.
+    let (width, height, _) = build_image(&SPACES[0], &img, &settings).unwrap();
+    assert_eq!((8, 1), (width, height));
+
+    let lab = ColorSpaceId::Lab.space();
+    let (width, height, data) = build_image(lab, &img, &settings).unwrap();
+    assert_eq!((8, 1 + legend_height(1)), (width, height));
+    let rows = data.chunks_exact(width as usize * 3).collect::<Vec<_>>();
+    for row in &rows[1..] {
+        assert_eq!(rows[1], *row);
+        // Source copy and L* channel have no legend.
+        assert_eq!(&[0; 12][..], &row[..12]);
+    }
+    // Ends of a* strip are green and red.
+    let (green, red) = (&rows[1][12..15], &rows[1][15..18]);
+    assert!(green[1] > green[0] && red[0] > red[1], "{:?}", rows[1]);
+}
+
+#[test]
+fn test_grid() {
+    assert_eq!(
+        vec![1, 1, 2, 2, 2, 3, 3, 3, 3, 3],
+        (1..=10).map(grid_columns).collect::<Vec<_>>()
+    );
+
+    let img = Image::from_raw(1, 1, vec![10, 20, 30]).unwrap();
+    let settings = Settings {
+        original: OriginalPosition::None,
+        background: [1, 2, 3],
+        grid: true,
+        ..Settings::default()
+    };
+    let (width, height, data) =
+        build_image(&SPACES[0], &img, &settings).unwrap();
+    assert_eq!((2, 2), (width, height));
+    assert_eq!(
+        &[10, 0, 0, 0, 20, 0, 0, 0, 30, 1, 2, 3][..],
+        &data[..],
+        "{:?}",
+        data
+    );
+
+    // Four tiles make a square.
+    let settings = Settings { luma_tile: true, ..settings };
+    let (width, height, _) = build_image(&SPACES[0], &img, &settings).unwrap();
+    assert_eq!((2, 2), (width, height));
+
+    // Legend strips are kept beneath each row of tiles.
+    let settings = Settings { legend: true, luma_tile: false, ..settings };
+    let lab = ColorSpaceId::Lab.space();
+    let (width, height, _) = build_image(lab, &img, &settings).unwrap();
+    assert_eq!((2, 2 * (1 + legend_height(1))), (width, height));
+
+    // Rows of the grid hold the same tiles as the strip.
+    let rgb = ColorSpaceId::Rgb.space();
+    for &original in &[
+        OriginalPosition::First,
+        OriginalPosition::Last,
+        OriginalPosition::None,
+    ] {
+        let mut settings = Settings {
+            original,
+            background: [1, 2, 3],
+            reconstruct: true,
+            luma_tile: true,
+            invert: true,
+            ..Settings::default()
+        };
+        let (_, _, strip) = build_image(rgb, &img, &settings).unwrap();
+        settings.grid = true;
+        let (_, _, grid) = build_image(rgb, &img, &settings).unwrap();
+        assert_eq!(&strip[..], &grid[..strip.len()], "{:?}", original);
+    }
+}
+}
+
+#[test]
+fn test_undefined_hue() {
+    let img = Image::from_raw(2, 1, vec![128, 128, 128, 255, 0, 0]).unwrap();
+    let settings = Settings {
+        original: OriginalPosition::None,
+        undefined_hue: [255, 0, 255],
+        ..Settings::default()
+    };
+    for &id in &[ColorSpaceId::Hsl, ColorSpaceId::Hwb, ColorSpaceId::Tsl] {
+        let (_, _, data) = build_image(id.space(), &img, &settings).unwrap();
+        assert_eq!(&[255, 0, 255], &data[..3], "{:?}", id);
+        assert_ne!(&[255, 0, 255], &data[3..6], "{:?}", id);
+    }
+}
+
+#[test]
+fn test_build_rgb_image() {
+    let img = Image::from_raw(1, 1, vec![10, 20, 30]).unwrap();
+    let settings = Settings::default();
+    let (width, height, data) =
+        build_image(&SPACES[0], &img, &settings).unwrap();
+    let got = build_rgb_image(&SPACES[0], &img, &settings).unwrap();
+    assert_eq!((width, height), got.dimensions());
+    assert_eq!(&data[..], &got.as_raw()[..]);
+}
+
+#[test]
+fn test_background() {
+    let img = Image::from_raw(2, 1, vec![10, 20, 30, 40, 50, 61]).unwrap();
+    assert_eq!([25, 35, 46], average_colour(&img));
+    assert_eq!([0, 0, 0], average_colour(&Image::new(0, 0)));
+
+    let settings = Settings {
+        legend: true,
+        background: average_colour(&img),
+        ..Settings::default()
+    };
+    let lab = ColorSpaceId::Lab.space();
+    let (width, _, data) = build_image(lab, &img, &settings).unwrap();
+    let row = data.chunks_exact(width as usize * 3).nth(1).unwrap();
+    // Source copy and L* channel have no legend.
+    assert_eq!(&[25, 35, 46].repeat(4)[..], &row[..12]);
+}
+
+#[test]
+fn test_render_row() {
+    let img = Image::from_raw(2, 2, (0..12).map(|v| v * 20).collect()).unwrap();
+    let settings = Settings {
+        invert: true,
+        legend: true,
+        original: OriginalPosition::Last,
+        ..Settings::default()
+    };
+    let lab = ColorSpaceId::Lab.space();
+    let (width, height, want) = build_image(lab, &img, &settings).unwrap();
+    let rows = Decomposition::new(lab, &img, &img, &settings).unwrap();
+    assert_eq!((width, height), rows.dimensions());
+    assert_eq!(want.len(), rows.byte_size());
+    let mut got = vec![0; want.len()];
+    for (y, row) in got.chunks_exact_mut(width as usize * 3).enumerate() {
+        rows.render_row(y as u32, row);
+    }
+    assert_eq!(&want[..], &got[..]);
+}
+
+#[test]
+fn test_reconstruct() {
+    let img = Image::from_raw(1, 1, vec![10, 20, 30]).unwrap();
+    let rgb = ColorSpaceId::Rgb.space();
+    let mut settings = Settings { reconstruct: true, ..Settings::default() };
+    for &(original, pos) in &[
+        (OriginalPosition::First, 1),
+        (OriginalPosition::Last, 3),
+        (OriginalPosition::None, 3),
+    ] {
+        settings.original = original;
+        let (width, height, data) = build_image(rgb, &img, &settings).unwrap();
+        let tiles = 4 + (original != OriginalPosition::None) as u32;
+        assert_eq!((tiles, 1), (width, height));
+        assert_eq!(&[10, 20, 30][..], &data[pos * 3..pos * 3 + 3]);
+    }
+
+    // Non-additive spaces get no reconstruction tile.
+    let lab = ColorSpaceId::Lab.space();
+    let (width, _, _) = build_image(lab, &img, &settings).unwrap();
+    assert_eq!(3, width);
+}
+
+#[test]
+fn test_chroma_lightness() {
+    let mut buf = [[std::mem::MaybeUninit::uninit(); 3]; 3];
+    let rgb = [224, 172, 105];
+    let settings =
+        Settings { chroma_lightness: Some(70.0), ..Settings::default() };
+    let channels = Channels(buf.as_mut_ptr(), 1, &settings, 0.0);
+    lab_fill_channels(channels, rgb);
+    // SAFETY: Fill functions set all the channels.
+    let got: [Rgb; 3] = unsafe { std::mem::transmute(buf) };
+    let lab = lab::Lab::from_rgb(&rgb);
+    let want_a = lab::Lab { l: 70.0, a: lab.a, b: 0.0 }.to_rgb();
+    let want_b = lab::Lab { l: 70.0, a: 0.0, b: lab.b }.to_rgb();
+    assert_eq!(fill(lab_fill_channels, rgb)[0], got[0]);
+    assert_eq!([want_a, want_b], [got[1], got[2]]);
+}
+
+#[test]
+fn test_gamut_warning() {
+    for &rgb in &[[0, 0, 0], [255, 255, 255], [50, 100, 150], [0, 255, 0]] {
+        let want = srgb::xyz_from_u8(rgb);
+        let from_lab = xyz_from_lab(lab::Lab::from_rgb(&rgb));
+        let from_luv = xyz_from_luv(luv::Luv::from_rgb(&rgb));
+        for i in 0..3 {
+            assert!((want[i] - from_lab[i]).abs() < 1e-3, "{:?}", rgb);
+            assert!((want[i] - from_luv[i]).abs() < 1e-3, "{:?}", rgb);
+        }
+        assert!(is_in_srgb_gamut(want), "{:?}", rgb);
+    }
+    let lab = lab::Lab { l: 90.0, a: -80.0, b: 0.0 };
+    assert!(!is_in_srgb_gamut(xyz_from_lab(lab)));
+
+    // Adobe RGB green primary is outside of sRGB gamut so pure green in the
+    // green channel gets flagged.
+    let warning = [255, 0, 255];
+    let settings =
+        Settings { gamut_warning: Some(warning), ..Settings::default() };
+    let mut buf = [[std::mem::MaybeUninit::uninit(); 3]; 3];
+    let channels = Channels(buf.as_mut_ptr(), 1, &settings, 0.0);
+    adobe_rgb_fill_channels(channels, [0, 255, 0]);
+    // SAFETY: Fill functions set all the channels.
+    let got: [Rgb; 3] = unsafe { std::mem::transmute(buf) };
+    assert_eq!(warning, got[1]);
+    assert_eq!(fill(adobe_rgb_fill_channels, [0, 0, 0]), [[0, 0, 0]; 3]);
+}
+
+#[test]
+fn test_luma_tile() {
+    let img = Image::from_raw(3, 1, vec![0, 0, 0, 128, 128, 128, 255, 255, 255])
+        .unwrap();
+    let settings = Settings {
+        original: OriginalPosition::None,
+        luma_tile: true,
+        ..Settings::default()
+    };
+    for space in SPACES.iter() {
+        let (width, height, data) =
+            build_image(space, &img, &settings).unwrap();
+        assert_eq!((3 * (space.channels as u32 + 1), 1), (width, height));
+        let luma = &data[data.len() - 9..];
+        let want = [0, 0, 0, 128, 128, 128, 255, 255, 255];
+        assert_eq!(&want[..], luma, "{}", space.name);
+    }
+}
+
+#[test]
+fn test_thresholds() {
+    let img = Image::from_raw(3, 1, vec![0, 0, 0, 128, 128, 128, 255, 0, 0])
+        .unwrap();
+    let threshold = |channel, value| Threshold {
+        space: ColorSpaceId::Lab,
+        channel,
+        value,
+    };
+    let settings = Settings {
+        original: OriginalPosition::None,
+        thresholds: vec![
+            threshold(0, 50.0),
+            threshold(1, 10.0),
+            Threshold { space: ColorSpaceId::Hsl, channel: 0, value: 0.0 },
+        ],
+        ..Settings::default()
+    };
+    let lab = ColorSpaceId::Lab.space();
+    let (width, height, data) = build_image(lab, &img, &settings).unwrap();
+    assert_eq!((15, 1), (width, height));
+    let masks = &data[data.len() - 18..];
+    #[rustfmt::skip]
+    let want = [
+        0, 0, 0, 255, 255, 255, 255, 255, 255,
+        0, 0, 0, 0, 0, 0, 255, 255, 255,
+    ];
+    assert_eq!(&want[..], masks);
+
+    let rgb = ColorSpaceId::Rgb.space();
+    let (width, _, _) = build_image(rgb, &img, &settings).unwrap();
+    assert_eq!(9, width);
+}
+
+#[test]
+fn test_mask() {
+    let rgba = image::RgbaImage::from_raw(2, 1, vec![9, 9, 9, 255, 9, 9, 9, 0])
+        .unwrap();
+    let mask = mask_from_image(&image::DynamicImage::ImageRgba8(rgba));
+    assert_eq!(&[255, 0][..], mask.as_raw().as_slice());
+    let grey = Image::from_raw(2, 1, vec![0, 0, 0, 255, 255, 255]).unwrap();
+    let mask = mask_from_image(&image::DynamicImage::ImageRgb8(grey));
+    assert_eq!(&[0, 255][..], mask.as_raw().as_slice());
+    let fitted = fit_mask(&mask, (4, 2));
+    let want = [0, 0, 255, 255, 0, 0, 255, 255];
+    assert_eq!(&want[..], fitted.as_raw().as_slice());
+
+    let img = Image::from_raw(2, 1, vec![255, 0, 0, 255, 0, 0]).unwrap();
+    let settings = Settings {
+        original: OriginalPosition::None,
+        background: [1, 2, 3],
+        mask: Some(std::sync::Arc::new(mask)),
+        ..Settings::default()
+    };
+    let rgb = ColorSpaceId::Rgb.space();
+    let (_, _, data) = build_image(rgb, &img, &settings).unwrap();
+    #[rustfmt::skip]
+    let want = [
+        1, 2, 3, 255, 0, 0,
+        1, 2, 3, 0, 0, 0,
+        1, 2, 3, 0, 0, 0,
+    ];
+    assert_eq!(&want[..], &*data);
+
+    // Masked out pixels keep the background colour regardless of display
+    // gamma and inversion and don’t affect the reconstruction.
+    let settings = Settings {
+        reconstruct: true,
+        display_gamma: Some(2.0),
+        invert: true,
+        ..settings
+    };
+    let (_, _, data) = build_image(rgb, &img, &settings).unwrap();
+    let pixels = data.chunks_exact(3).collect::<Vec<_>>();
+    let count = |rgb: [u8; 3]| pixels.iter().filter(|px| **px == rgb).count();
+    assert_eq!(3, count([1, 2, 3]), "{:?}", pixels);
+    assert_eq!(2, count([255, 0, 0]), "{:?}", pixels);
+}
+
+#[test]
+fn test_build_diff_image() {
+    assert_eq!([0, 0, 0], heat_map(0));
+    assert_eq!([255, 255, 255], heat_map(255));
+    assert_eq!([255, 0, 0], heat_map(85));
+    assert_eq!([255, 255, 0], heat_map(170));
+
+    let img = Image::from_raw(1, 1, vec![10, 20, 30]).unwrap();
+    let other = Image::from_raw(1, 1, vec![10, 20, 115]).unwrap();
+    let (width, height, data) =
+        build_diff_image(&SPACES[0], &img, &other, &Settings::default())
+            .unwrap();
+    assert_eq!((4, 1), (width, height));
+    assert_eq!(&[255, 0, 0, 0, 0, 0, 0, 0, 0, 255, 0, 0][..], &data[..]);
+}
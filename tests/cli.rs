@@ -0,0 +1,188 @@
+//! End-to-end tests running the binary against images in a temporary
+//! directory.
+
+use assert_cmd::Command;
+
+/// Creates temporary directory holding a small ‘img.png’ test image.
+fn fixture() -> tempfile::TempDir {
+    let dir = tempfile::tempdir().unwrap();
+    let img = image::RgbImage::from_fn(8, 4, |x, y| {
+        image::Rgb([x as u8 * 32, y as u8 * 64, 128])
+    });
+    img.save(dir.path().join("img.png")).unwrap();
+    dir
+}
+
+/// Returns command running the binary in given directory with environment
+/// variables affecting defaults cleared.
+fn command(dir: &tempfile::TempDir) -> Command {
+    let mut cmd = Command::cargo_bin(env!("CARGO_PKG_NAME")).unwrap();
+    cmd.current_dir(dir.path())
+        .env_remove("IMAGE_DECOMPOSE_SPACES")
+        .env_remove("IMAGE_DECOMPOSE_QUALITY");
+    cmd
+}
+
+fn exists(dir: &tempfile::TempDir, name: &str) -> bool {
+    dir.path().join(name).exists()
+}
+
+fn read(dir: &tempfile::TempDir, name: &str) -> Vec<u8> {
+    std::fs::read(dir.path().join(name)).unwrap()
+}
+
+#[test]
+fn test_output_names() {
+    let dir = fixture();
+    command(&dir).args(&["-s", "lab,hsl", "img.png"]).assert().success();
+    assert!(exists(&dir, "img-lab.webp"));
+    assert!(exists(&dir, "img-hsl.webp"));
+    assert!(!exists(&dir, "img-rgb.webp"));
+}
+
+#[test]
+fn test_out_dir_and_format() {
+    let dir = fixture();
+    command(&dir)
+        .args(&["--out-dir", "out", "--format", "png", "-s", "rgb", "img.png"])
+        .assert()
+        .success();
+    assert!(!exists(&dir, "img-rgb.png"));
+    let img = image::open(dir.path().join("out/img-rgb.png")).unwrap();
+    // Copy of the source image followed by three channel tiles.
+    assert_eq!((32, 4), image::GenericImageView::dimensions(&img));
+}
+
+#[test]
+fn test_existing_files() {
+    let dir = fixture();
+    let marker = b"marker".to_vec();
+    let reset = || std::fs::write(dir.path().join("img-lab.webp"), &marker);
+
+    // Existing files are skipped by default.
+    reset().unwrap();
+    command(&dir).args(&["-s", "lab", "img.png"]).assert().success();
+    assert_eq!(marker, read(&dir, "img-lab.webp"));
+
+    // --yes overwrites them.
+    command(&dir).args(&["-y", "-s", "lab", "img.png"]).assert().success();
+    assert_ne!(marker, read(&dir, "img-lab.webp"));
+
+    // --interactive asks.
+    reset().unwrap();
+    command(&dir)
+        .args(&["-i", "-s", "lab", "img.png"])
+        .write_stdin("n\n")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("overwrite?"));
+    assert_eq!(marker, read(&dir, "img-lab.webp"));
+    command(&dir)
+        .args(&["-i", "-s", "lab", "img.png"])
+        .write_stdin("y\n")
+        .assert()
+        .success();
+    assert_ne!(marker, read(&dir, "img-lab.webp"));
+}
+
+#[test]
+fn test_exit_codes() {
+    let dir = fixture();
+    command(&dir)
+        .args(&["-s", "lab", "missing.png"])
+        .assert()
+        .failure();
+
+    // Remaining files are processed even if one of them fails.
+    command(&dir)
+        .args(&["-s", "lab", "missing.png", "img.png"])
+        .assert()
+        .failure();
+    assert!(exists(&dir, "img-lab.webp"));
+
+    // Invalid arguments are rejected before anything is processed.
+    command(&dir)
+        .args(&["-s", "no-such-space", "img.png"])
+        .assert()
+        .failure();
+    assert!(!exists(&dir, "img-no-such-space.webp"));
+}
+
+#[test]
+fn test_stream() {
+    let dir = fixture();
+    let args = ["--format", "farbfeld", "-s", "lab", "img.png"];
+    command(&dir).args(&args).assert().success();
+    let buffered = read(&dir, "img-lab.ff");
+    command(&dir).args(&["-y", "--stream"]).args(&args).assert().success();
+    assert_eq!(buffered, read(&dir, "img-lab.ff"));
+    assert!(!exists(&dir, ".img-lab.ff.tmp"));
+}
+
+#[test]
+fn test_unused_quality_warning() {
+    use predicates::prelude::PredicateBooleanExt;
+
+    let dir = fixture();
+    let args = ["-y", "--format", "png", "-s", "lab", "img.png"];
+    let warning = || predicates::str::contains("have no effect");
+    command(&dir)
+        .args(&["--quality", "50"])
+        .args(&args)
+        .assert()
+        .success()
+        .stderr(warning());
+    // Quality from the environment is just a default.
+    command(&dir)
+        .env("IMAGE_DECOMPOSE_QUALITY", "50")
+        .args(&args)
+        .assert()
+        .success()
+        .stderr(warning().not());
+}
+
+#[test]
+fn test_print_outputs() {
+    let dir = fixture();
+    command(&dir)
+        .args(&["--print-outputs", "-s", "lab", "img.png"])
+        .assert()
+        .success()
+        .stdout("img-lab.webp\n");
+    // Prompts would be mixed with the printed paths.
+    for flag in &["-i", "--preview-scale=0.5"] {
+        command(&dir)
+            .args(&["--print-outputs", flag, "img.png"])
+            .assert()
+            .failure();
+    }
+}
+
+#[test]
+fn test_from_tar() {
+    let dir = fixture();
+    let img = read(&dir, "img.png");
+    let mut builder = tar::Builder::new(Vec::new());
+    for name in &["a.png", "sub/b.png", "notes.txt"] {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(img.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, name, img.as_slice()).unwrap();
+    }
+    let plain = builder.into_inner().unwrap();
+    let level = flate2::Compression::default();
+    let mut enc = flate2::write::GzEncoder::new(Vec::new(), level);
+    std::io::Write::write_all(&mut enc, &plain).unwrap();
+    let gzip = enc.finish().unwrap();
+    for (name, data) in &[("plain", plain), ("gzip", gzip)] {
+        std::fs::write(dir.path().join("in.tar"), data).unwrap();
+        command(&dir)
+            .args(&["-s", "lab", "--out-dir", *name, "--from-tar", "in.tar"])
+            .assert()
+            .success();
+        assert!(exists(&dir, &format!("{}/a-lab.webp", name)));
+        assert!(exists(&dir, &format!("{}/b-lab.webp", name)));
+        assert!(!exists(&dir, &format!("{}/notes-lab.webp", name)));
+    }
+}